@@ -0,0 +1,80 @@
+//! Compares the lock-free token bucket behind `RateLimiter` with the governor limiter it replaced, with many threads hitting one limiter.
+
+use std::{
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use governor::{clock::MonotonicClock, state::InMemoryState, Quota};
+
+#[path = "../src/ratelimit/bucket.rs"]
+#[allow(dead_code, unused_imports)]
+mod bucket;
+
+use bucket::TokenBucket;
+
+/// Bytes per take. Tiny, so that the limiters never run dry and only their bookkeeping is measured.
+const TAKE: u32 = 1;
+
+/// The highest rate that governor supports, in bytes per second.
+const RATE: u32 = u32::MAX;
+
+/// Runs `iters` packets through the limiter, split across `threads` threads.
+fn contended(threads: u64, iters: u64, take: impl Fn() + Send + Sync + 'static) -> Duration {
+    let take = Arc::new(take);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let take = take.clone();
+            std::thread::spawn(move || {
+                for _ in 0..iters / threads {
+                    take();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_limiters(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended_take");
+    for threads in [1u64, 4, 16] {
+        group.bench_with_input(
+            BenchmarkId::new("governor", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    let limiter = governor::RateLimiter::new(
+                        Quota::per_second(NonZeroU32::new(RATE).unwrap()),
+                        InMemoryState::default(),
+                        &MonotonicClock,
+                    );
+                    contended(threads, iters, move || {
+                        let _ = limiter.check_n(NonZeroU32::new(TAKE).unwrap());
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("token_bucket", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    let bucket = TokenBucket::new(RATE as u64, RATE as u64);
+                    contended(threads, iters, move || {
+                        let _ = bucket.take(TAKE as u64);
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_limiters);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+//! Feeds arbitrary bytes into the IPv4/TCP/UDP header parsing of the VPN packet path.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/packet.rs"]
+#[allow(dead_code)]
+mod packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(headers) = packet::PacketHeaders::parse(data) {
+        let _ = headers.is_udp();
+    }
+});
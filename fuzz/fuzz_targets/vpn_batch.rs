@@ -0,0 +1,17 @@
+//! Feeds arbitrary bytes through the decoding that handle_vpn_session does on every unreliable datagram from the client.
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/packet.rs"]
+#[allow(dead_code)]
+mod packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(batch) = stdcode::deserialize::<Vec<Bytes>>(data) {
+        for pkt in batch {
+            let _ = packet::PacketHeaders::parse(&pkt);
+        }
+    }
+});
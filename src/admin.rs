@@ -0,0 +1,138 @@
+use std::{convert::Infallible, net::Ipv4Addr, time::Duration};
+
+use async_trait::async_trait;
+use nanorpc::nanorpc_derive;
+use nanorpc_http::server::HttpRpcServer;
+
+use crate::{
+    billing::{self, BillingRecord},
+    config::CONFIG,
+    listen::link::{self, LinkInfo},
+    pcap::{self, CaptureInfo},
+    ratelimit::RateOverride,
+    relay::RelayInfo,
+    root_ctx::ROOT_CTX,
+    vpn::LeaseInfo,
+};
+
+/// Largest packet capture the admin API starts, in MB.
+const MAX_CAPTURE_MB: u64 = 1024;
+
+/// Longest packet capture the admin API starts, in seconds.
+const MAX_CAPTURE_SECS: u64 = 3600;
+
+/// The admin API, served as JSON-RPC over HTTP on `admin_listen`.
+#[nanorpc_derive]
+#[async_trait]
+pub trait AdminProtocol {
+    /// Starts writing the decrypted inner packets of the VPN session with the given address to a pcap file. This records user traffic, so `confirm` must be true.
+    async fn start_capture(
+        &self,
+        session: Ipv4Addr,
+        max_mb: u64,
+        max_secs: u64,
+        confirm: bool,
+    ) -> Result<CaptureInfo, String>;
+
+    /// Stops the running packet capture, returning what it captured.
+    async fn stop_capture(&self) -> Option<CaptureInfo>;
+
+    /// Returns the running packet capture, if any.
+    async fn capture_status(&self) -> Option<CaptureInfo>;
+
+    /// Returns every assigned CGNAT address, with the token ID of its client and its age.
+    async fn leases(&self) -> Vec<LeaseInfo>;
+
+    /// Returns the pipe-level statistics of every live session: datagrams each way, duplicated datagrams, and how many pipes it has had.
+    async fn link_stats(&self) -> Vec<LinkInfo>;
+
+    /// Returns the proxied connections that the session with the given ID has open: bytes each way, and the round-trip time and retransmissions of the destination socket. Transparent-proxy connections of VPN clients belong to no session, so they are not listed.
+    async fn relay_stats(&self, session: String) -> Result<Vec<RelayInfo>, String>;
+
+    /// Returns the billing records of the latest finished period.
+    async fn billing_export(&self) -> Vec<BillingRecord>;
+
+    /// Overrides the speed limit of a user's live and future sessions, or removes the override if None.
+    async fn set_rate_override(&self, token_id: u64, rate: Option<RateOverride>);
+}
+
+struct AdminImpl;
+
+#[async_trait]
+impl AdminProtocol for AdminImpl {
+    async fn start_capture(
+        &self,
+        session: Ipv4Addr,
+        max_mb: u64,
+        max_secs: u64,
+        confirm: bool,
+    ) -> Result<CaptureInfo, String> {
+        if !confirm {
+            return Err("captures record user traffic; pass confirm = true to proceed".into());
+        }
+        if !(1..=MAX_CAPTURE_MB).contains(&max_mb) || !(1..=MAX_CAPTURE_SECS).contains(&max_secs) {
+            return Err(format!(
+                "limits must be 1-{} MB and 1-{} seconds",
+                MAX_CAPTURE_MB, MAX_CAPTURE_SECS
+            ));
+        }
+        let session_exists = ROOT_CTX
+            .vpn_if_started()
+            .map(|vpn| vpn.session_exists(session))
+            .unwrap_or_default();
+        if !session_exists {
+            return Err(format!("no VPN session has address {}", session));
+        }
+        pcap::start(session, max_mb * 1024 * 1024, Duration::from_secs(max_secs))
+            .map_err(|err| format!("{:?}", err))
+    }
+
+    async fn stop_capture(&self) -> Option<CaptureInfo> {
+        pcap::stop()
+    }
+
+    async fn capture_status(&self) -> Option<CaptureInfo> {
+        pcap::status()
+    }
+
+    async fn leases(&self) -> Vec<LeaseInfo> {
+        ROOT_CTX
+            .vpn_if_started()
+            .map(|vpn| vpn.leases())
+            .unwrap_or_default()
+    }
+
+    async fn link_stats(&self) -> Vec<LinkInfo> {
+        link::all_links()
+    }
+
+    async fn relay_stats(&self, session: String) -> Result<Vec<RelayInfo>, String> {
+        link::find_link(&session)
+            .map(|link| link.relays())
+            .ok_or_else(|| format!("no live session has ID {}", session))
+    }
+
+    async fn billing_export(&self) -> Vec<BillingRecord> {
+        billing::latest()
+    }
+
+    async fn set_rate_override(&self, token_id: u64, rate: Option<RateOverride>) {
+        log::info!(
+            "admin API set the rate override of {} to {:?}",
+            token_id,
+            rate
+        );
+        ROOT_CTX.set_rate_override(token_id, rate);
+    }
+}
+
+/// Serves the admin API, if `admin_listen` is set.
+pub async fn admin_loop() -> anyhow::Result<Infallible> {
+    let Some(listen) = CONFIG.admin_listen() else {
+        return smol::future::pending().await;
+    };
+    log::info!("serving the admin API on {}", listen);
+    let server = HttpRpcServer::bind(listen).await?;
+    server.run(AdminService(AdminImpl)).await?;
+    anyhow::bail!("admin API server stopped")
+}
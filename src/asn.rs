@@ -1,14 +1,25 @@
-
-
 use once_cell::sync::Lazy;
 
 use std::{
-    net::{Ipv4Addr},
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+
+use crate::{
+    config::CONFIG,
+    root_ctx::ROOT_CTX,
+    stats::{self, Metric},
 };
 
 /// my own IP address
 pub static MY_PUBLIC_IP: Lazy<Ipv4Addr> = Lazy::new(|| {
-    let resp = ureq::get("http://checkip.amazonaws.com").call();
+    if CONFIG.dev_mode() {
+        return Ipv4Addr::LOCALHOST;
+    }
+    let resp = crate::outbound::request("GET", "http://checkip.amazonaws.com").call();
     resp.into_string()
         .expect("cannot get my public IP")
         .trim()
@@ -20,3 +31,121 @@ pub static MY_PUBLIC_IP: Lazy<Ipv4Addr> = Lazy::new(|| {
 pub fn next_ip(ip: Ipv4Addr) -> Ipv4Addr {
     (u32::from_be_bytes(ip.octets()).saturating_add(1)).into()
 }
+
+/// Address ranges and the ASNs that announce them, loaded from an ip2asn-style TSV file.
+pub struct AsnDatabase {
+    /// Sorted, non-overlapping ranges of IPv6 (or IPv4-mapped) addresses, as (first, last, ASN).
+    ranges: Vec<(u128, u128, u32)>,
+}
+
+impl AsnDatabase {
+    /// Loads a database whose lines each start with the first address, last address, and ASN of a range, separated by tabs.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read ASN database {:?}", path))?;
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let mut fields = line.split('\t');
+            let (Some(first), Some(last), Some(asn)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("line {} of the ASN database is malformed", i + 1)
+            };
+            let first: IpAddr = first.parse()?;
+            let last: IpAddr = last.parse()?;
+            ranges.push((addr_key(first), addr_key(last), asn.parse()?));
+        }
+        ranges.sort_unstable();
+        Ok(Self { ranges })
+    }
+
+    /// The ASN that announces the address, or 0 if none is known.
+    pub fn lookup(&self, addr: IpAddr) -> u32 {
+        let key = addr_key(addr);
+        let idx = self.ranges.partition_point(|(first, _, _)| *first <= key);
+        match idx.checked_sub(1).map(|idx| self.ranges[idx]) {
+            Some((_, last, asn)) if key <= last => asn,
+            _ => 0,
+        }
+    }
+}
+
+/// Puts IPv4 and IPv6 addresses in one ordering, with IPv4 addresses as IPv4-mapped IPv6 ones.
+pub fn addr_key(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u128::from(addr.to_ipv6_mapped()),
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+/// The configured ASN database, if any.
+pub static ASN_DB: Lazy<Option<AsnDatabase>> = Lazy::new(|| {
+    let path = CONFIG.asn_database().as_ref()?;
+    match AsnDatabase::load(path) {
+        Ok(db) => {
+            log::info!("loaded {} ranges from the ASN database", db.ranges.len());
+            Some(db)
+        }
+        Err(err) => {
+            log::error!("ASN aggregation is off: {:?}", err);
+            None
+        }
+    }
+});
+
+/// Bytes carried to and from each destination ASN since the last flush. Only totals are kept, never individual destinations.
+static ASN_TRAFFIC: Lazy<DashMap<u32, u64>> = Lazy::new(Default::default);
+
+/// The ASN of a destination, or None if ASN aggregation is off.
+pub fn asn_of(addr: IpAddr) -> Option<u32> {
+    ASN_DB.as_ref().map(|db| db.lookup(addr))
+}
+
+/// Counts traffic to or from a destination towards its ASN, if ASN aggregation is on.
+pub fn record_traffic(addr: IpAddr, bytes: usize) {
+    if let Some(asn) = asn_of(addr) {
+        record_asn_traffic(asn, bytes);
+    }
+}
+
+/// Counts traffic towards an already looked-up ASN.
+pub fn record_asn_traffic(asn: u32, bytes: usize) {
+    *ASN_TRAFFIC.entry(asn).or_default() += bytes as u64;
+}
+
+/// Sends the traffic counted since the last flush to statsd, as one counter per ASN.
+pub fn flush_asn_traffic() {
+    ASN_TRAFFIC.retain(|asn, bytes| {
+        if let Some(client) = ROOT_CTX.stat_client.as_ref() {
+            client.count(
+                &stats::key(Metric::AsnTraffic, &[("asn", &format!("as{}", asn))]),
+                *bytes as f64,
+            );
+        }
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let db = AsnDatabase::parse(
+            "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+             8.8.8.0\t8.8.8.255\t15169\tUS\tGOOGLE\n\
+             2606:4700::\t2606:4700:ffff:ffff:ffff:ffff:ffff:ffff\t13335\tUS\tCLOUDFLARENET",
+        )
+        .unwrap();
+        assert_eq!(db.lookup("1.0.0.1".parse().unwrap()), 13335);
+        assert_eq!(db.lookup("8.8.8.8".parse().unwrap()), 15169);
+        assert_eq!(db.lookup("8.8.9.1".parse().unwrap()), 0);
+        assert_eq!(db.lookup("2606:4700::1111".parse().unwrap()), 13335);
+        assert_eq!(db.lookup("::1".parse().unwrap()), 0);
+    }
+}
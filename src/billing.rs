@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::Write,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{BillingFormat, CONFIG},
+    root_ctx::ROOT_CTX,
+};
+
+/// Bytes carried by every user in the current period, keyed by token ID.
+static USAGE: Lazy<DashMap<u64, u64>> = Lazy::new(Default::default);
+
+/// Start of the current period, in seconds since the Unix epoch.
+static PERIOD_START: AtomicU64 = AtomicU64::new(0);
+
+/// The records of the latest finished period.
+static LATEST: Lazy<Mutex<Vec<BillingRecord>>> = Lazy::new(Default::default);
+
+/// Key of the hashes that replace token IDs if `hash_user_ids` is set.
+static USER_HASH_KEY: Lazy<[u8; 32]> = Lazy::new(|| {
+    blake3::derive_key(
+        "geph4-exit billing user ids",
+        ROOT_CTX.main_identity().signing_sk.secret.as_bytes(),
+    )
+});
+
+/// The bytes that one user carried in one period.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BillingRecord {
+    /// The token ID, or its keyed hash in hex.
+    pub user: String,
+    /// Start of the period, in seconds since the Unix epoch.
+    pub period_start: u64,
+    /// End of the period, in seconds since the Unix epoch.
+    pub period_end: u64,
+    pub bytes: u64,
+}
+
+/// The usage of the current period, as handed to a new exit process on upgrade.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedUsage {
+    period_start: u64,
+    usage: HashMap<u64, u64>,
+}
+
+/// Counts bytes towards a user's usage in the current period, if billing exports are on.
+pub fn record(token_id: u64, bytes: usize) {
+    if CONFIG.billing().is_some() {
+        *USAGE.entry(token_id).or_default() += bytes as u64;
+    }
+}
+
+/// The records of the latest finished period.
+pub fn latest() -> Vec<BillingRecord> {
+    LATEST.lock().clone()
+}
+
+/// Ends a period every `interval_secs`, keeping its records for the admin API and appending them to the export file.
+pub async fn export_loop() -> anyhow::Result<Infallible> {
+    let Some(billing) = CONFIG.billing() else {
+        return smol::future::pending().await;
+    };
+    // a period that the exit process we took over from started goes on
+    let mut period_start = restore_from(billing.state_path()).unwrap_or_else(unix_secs);
+    loop {
+        PERIOD_START.store(period_start, Ordering::Relaxed);
+        let elapsed = unix_secs().saturating_sub(period_start);
+        smol::Timer::after(Duration::from_secs(
+            billing.interval_secs().saturating_sub(elapsed),
+        ))
+        .await;
+        let period_end = unix_secs();
+        let records = take_records(period_start, period_end, billing.hash_user_ids());
+        period_start = period_end;
+        if let Some(path) = billing.path() {
+            if let Err(err) = append(path, billing.format(), &records) {
+                log::error!("cannot write billing export to {:?}: {:?}", path, err);
+            }
+        }
+        *LATEST.lock() = records;
+    }
+}
+
+/// Saves the usage of the current period for the exit process taking over from this one, if billing exports are on. Failures are logged.
+pub async fn save() {
+    let Some(billing) = CONFIG.billing() else {
+        return;
+    };
+    if let Err(err) = save_to(billing.state_path()).await {
+        log::error!("cannot save billing usage: {:?}", err);
+    }
+}
+
+/// Deletes the saved usage after a failed handoff, since this process keeps counting it.
+pub fn discard_saved() {
+    if let Some(billing) = CONFIG.billing() {
+        let _ = std::fs::remove_file(billing.state_path());
+    }
+}
+
+async fn save_to(path: &Path) -> anyhow::Result<()> {
+    let saved = SavedUsage {
+        period_start: PERIOD_START.load(Ordering::Relaxed),
+        usage: USAGE
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect(),
+    };
+    let tmp_path = path.with_extension("tmp");
+    smol::fs::write(&tmp_path, serde_json::to_vec(&saved)?).await?;
+    smol::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Adds the usage saved by the exit process this one took over from, deleting it so that it's never counted twice. Returns the start of its period, if there was one.
+fn restore_from(path: &Path) -> Option<u64> {
+    let bts = std::fs::read(path).ok()?;
+    let _ = std::fs::remove_file(path);
+    match serde_json::from_slice::<SavedUsage>(&bts) {
+        Ok(saved) => {
+            for (token_id, bytes) in saved.usage {
+                *USAGE.entry(token_id).or_default() += bytes;
+            }
+            Some(saved.period_start)
+        }
+        Err(err) => {
+            log::warn!("cannot parse saved billing usage, starting over: {}", err);
+            None
+        }
+    }
+}
+
+/// Empties the usage counters into the records of a period.
+fn take_records(period_start: u64, period_end: u64, hash_user_ids: bool) -> Vec<BillingRecord> {
+    let mut records = vec![];
+    USAGE.retain(|token_id, bytes| {
+        let user = if hash_user_ids {
+            let hash = blake3::keyed_hash(&USER_HASH_KEY, &token_id.to_le_bytes());
+            hex::encode(&hash.as_bytes()[..16])
+        } else {
+            token_id.to_string()
+        };
+        records.push(BillingRecord {
+            user,
+            period_start,
+            period_end,
+            bytes: *bytes,
+        });
+        false
+    });
+    records
+}
+
+/// Appends records to an export file, starting CSV files with a header.
+fn append(path: &Path, format: BillingFormat, records: &[BillingRecord]) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut out = vec![];
+    match format {
+        BillingFormat::Csv => {
+            if file.metadata()?.len() == 0 {
+                writeln!(out, "user,period_start,period_end,bytes")?;
+            }
+            for record in records {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    record.user, record.period_start, record.period_end, record.bytes
+                )?;
+            }
+        }
+        BillingFormat::JsonLines => {
+            for record in records {
+                serde_json::to_writer(&mut out, record)?;
+                out.push(b'\n');
+            }
+        }
+    }
+    file.write_all(&out)?;
+    Ok(())
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bytes of one user in a period's records.
+    fn bytes_of(records: &[BillingRecord], token_id: u64) -> Option<u64> {
+        records
+            .iter()
+            .find(|record| record.user == token_id.to_string())
+            .map(|record| record.bytes)
+    }
+
+    #[test]
+    fn accumulation_and_handoff() {
+        record(9001, 100);
+        record(9001, 50);
+        record(9002, 10);
+        let path =
+            std::env::temp_dir().join(format!("geph4-exit-billing-{}.json", fastrand::u64(..)));
+        smol::block_on(save_to(&path)).unwrap();
+
+        let records = take_records(1000, 2000, false);
+        assert_eq!(bytes_of(&records, 9001), Some(150));
+        assert_eq!(bytes_of(&records, 9002), Some(10));
+        assert!(records
+            .iter()
+            .all(|record| (record.period_start, record.period_end) == (1000, 2000)));
+        assert_eq!(bytes_of(&take_records(2000, 3000, false), 9001), None);
+
+        // the new process picks up where the old one left off, only once
+        record(9001, 1);
+        assert!(restore_from(&path).is_some());
+        assert!(!path.exists());
+        assert_eq!(restore_from(&path), None);
+        let records = take_records(2000, 3000, false);
+        assert_eq!(bytes_of(&records, 9001), Some(151));
+        assert_eq!(bytes_of(&records, 9002), Some(10));
+
+        let export = path.with_extension("csv");
+        append(&export, BillingFormat::Csv, &records).unwrap();
+        append(&export, BillingFormat::Csv, &records).unwrap();
+        let csv = std::fs::read_to_string(&export).unwrap();
+        std::fs::remove_file(&export).unwrap();
+        assert_eq!(csv.matches("user,period_start,period_end,bytes").count(), 1);
+        assert!(csv.contains("9001,2000,3000,151\n"));
+    }
+}
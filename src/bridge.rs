@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use smol::future::FutureExt;
+use smol_timeout::TimeoutExt;
+use sosistab2::Pipe;
+use sosistab2_obfsudp::{ObfsUdpPipe, ObfsUdpPublic};
+
+use crate::{
+    config::{BridgeConfig, BridgeUpstream, CONFIG},
+    geoip, ratelimit, redact,
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+/// How long connecting to an upstream exit may take.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The upstream exit that clients from the given country, if known, are forwarded to.
+fn upstream_for<'a>(config: &'a BridgeConfig, country: Option<&str>) -> Option<&'a BridgeUpstream> {
+    let upstreams = config.upstreams();
+    country
+        .and_then(|country| {
+            upstreams.iter().find(|upstream| {
+                upstream
+                    .countries()
+                    .iter()
+                    .any(|code| code.eq_ignore_ascii_case(country))
+            })
+        })
+        .or_else(|| {
+            upstreams
+                .iter()
+                .find(|upstream| upstream.countries().is_empty())
+        })
+}
+
+/// Forwards a pipe accepted in bridge mode to its upstream exit. Datagrams are passed on as they are, so the session stays end-to-end encrypted between the client and the exit, which tells pipes of the same session apart by their metadata just as if they had come directly.
+pub async fn forward(pipe: impl Pipe) -> anyhow::Result<()> {
+    let config = CONFIG
+        .bridge()
+        .as_ref()
+        .expect("forwarding pipes outside bridge mode");
+    let country = pipe
+        .peer_addr()
+        .parse()
+        .ok()
+        .and_then(|addr: std::net::SocketAddr| geoip::country_of(addr.ip()));
+    let Some(upstream) = upstream_for(config, country) else {
+        ROOT_CTX.incr_stat(Metric::BridgeNoUpstream);
+        anyhow::bail!(
+            "no bridge upstream for {} ({})",
+            redact::client(pipe.peer_addr()),
+            country.unwrap_or("unknown country")
+        )
+    };
+    let mut cookie = [0; 32];
+    cookie.copy_from_slice(&hex::decode(upstream.cookie())?);
+    let upstream_pipe = ObfsUdpPipe::connect(
+        upstream.address(),
+        ObfsUdpPublic::from_bytes(cookie),
+        pipe.peer_metadata(),
+    )
+    .timeout(UPSTREAM_TIMEOUT)
+    .await
+    .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+    .map_err(|err| {
+        ROOT_CTX.incr_stat(Metric::BridgeUpstreamFailed);
+        err.context(format!("cannot connect to upstream {}", upstream.address()))
+    })?;
+    ROOT_CTX.incr_stat(Metric::BridgeForwarded);
+    let limiter = match config.pipe_limit_kb() {
+        Some(limit_kb) => ratelimit::global_limiter().child(limit_kb, limit_kb),
+        None => ratelimit::global_limiter().clone(),
+    };
+    let up = async {
+        loop {
+            let dgram = pipe.recv().await?;
+            limiter.wait(dgram.len()).await;
+            upstream_pipe.send(dgram);
+        }
+    };
+    let down = async {
+        loop {
+            let dgram = upstream_pipe.recv().await?;
+            limiter.wait(dgram.len()).await;
+            pipe.send(dgram);
+        }
+    };
+    up.race(down).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_by_country() {
+        let config: BridgeConfig = toml::from_str(
+            r#"
+[[upstreams]]
+address = "192.0.2.1:19831"
+cookie = "00"
+countries = ["ir", "TM"]
+
+[[upstreams]]
+address = "192.0.2.2:19831"
+cookie = "00"
+"#,
+        )
+        .unwrap();
+        let address = |country| upstream_for(&config, country).map(|upstream| upstream.address());
+        assert_eq!(
+            address(Some("IR")),
+            Some("192.0.2.1:19831".parse().unwrap())
+        );
+        assert_eq!(
+            address(Some("CN")),
+            Some("192.0.2.2:19831".parse().unwrap())
+        );
+        assert_eq!(address(None), Some("192.0.2.2:19831".parse().unwrap()));
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    convert::Infallible,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    config::CONFIG,
+    root_ctx::ROOT_CTX,
+    stats::{self, Metric},
+};
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_EPOCH_OFFSET: f64 = 2_208_988_800.0;
+
+/// How long an NTP server may take to answer.
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the latest check found the clock within `warn_skew_secs` of NTP time, which decides whether the gateway's NTP answers claim to be synchronized.
+static SYNCHRONIZED: AtomicBool = AtomicBool::new(true);
+
+/// Checks the clock against the configured NTP servers at startup and then periodically, since binder tokens and TLS fail in confusing ways on a skewed clock.
+pub async fn clock_check_loop() -> anyhow::Result<Infallible> {
+    let config = CONFIG.clock_check();
+    if config.servers().is_empty() {
+        return smol::future::pending().await;
+    }
+    let key = stats::key(Metric::ClockSkew, &[]);
+    loop {
+        match measure_skew(config.servers()).await {
+            Some(skew) => {
+                SYNCHRONIZED.store(
+                    skew.abs() < config.warn_skew_secs() as f64,
+                    Ordering::Relaxed,
+                );
+                if skew.abs() >= config.warn_skew_secs() as f64 {
+                    log::error!(
+                        "the system clock is {:.1} seconds {} NTP time! Auth tokens and TLS may fail until it is fixed",
+                        skew.abs(),
+                        if skew > 0.0 { "ahead of" } else { "behind" }
+                    );
+                } else {
+                    log::debug!("the system clock is {:.3} seconds off NTP time", skew);
+                }
+                if let Some(client) = ROOT_CTX.stat_client.as_ref() {
+                    client.gauge(&key, skew);
+                }
+            }
+            None => log::warn!("cannot check the clock: no NTP server answered"),
+        }
+        smol::Timer::after(Duration::from_secs(config.interval_secs())).await;
+    }
+}
+
+/// How far ahead of NTP time the system clock is, in seconds, according to the first server that answers.
+async fn measure_skew(servers: &[String]) -> Option<f64> {
+    for server in servers {
+        match query(server).timeout(NTP_TIMEOUT).await {
+            Some(Ok(skew)) => return Some(skew),
+            Some(Err(err)) => log::debug!("NTP query to {} failed: {:?}", server, err),
+            None => log::debug!("NTP query to {} timed out", server),
+        }
+    }
+    None
+}
+
+/// Asks one server for the time with an SNTP request, returning the clock's skew.
+async fn query(server: &str) -> anyhow::Result<f64> {
+    let socket = smol::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(server).await?;
+    let mut request = [0u8; 48];
+    // no leap second warning, version 4, client mode
+    request[0] = 0x23;
+    let sent = now();
+    request[40..48].copy_from_slice(&to_timestamp(sent));
+    socket.send(&request).await?;
+    let mut response = [0u8; 48];
+    let n = socket.recv(&mut response).await?;
+    anyhow::ensure!(n == 48, "NTP response is {} bytes long", n);
+    anyhow::ensure!(
+        response[24..32] == request[40..48],
+        "NTP response doesn't answer our request"
+    );
+    skew(sent, &response, now()).context("NTP server is not synchronized")
+}
+
+/// The skew of the local clock from an NTP response, given when the request was sent and the response received. None if the server itself isn't synchronized.
+fn skew(sent: f64, response: &[u8; 48], received: f64) -> Option<f64> {
+    let stratum = response[1];
+    if stratum == 0 || stratum >= 16 {
+        return None;
+    }
+    let server_received = from_timestamp(&response[32..40]);
+    let server_sent = from_timestamp(&response[40..48]);
+    // the usual NTP offset is how far the server is ahead of us
+    Some(-((server_received - sent) + (server_sent - received)) / 2.0)
+}
+
+/// Answers an SNTP client request with the system clock, as the VPN gateway's time source. None if it isn't a client request.
+pub fn answer_ntp(request: &[u8]) -> Option<[u8; 48]> {
+    if request.len() < 48 || request[0] & 0x07 != 3 {
+        return None;
+    }
+    let mut response = [0u8; 48];
+    // the client's version in server mode, flagged as unsynchronized if the last check found the clock off
+    response[0] = (request[0] & 0x38) | 4;
+    if SYNCHRONIZED.load(Ordering::Relaxed) {
+        response[1] = 3;
+    } else {
+        response[0] |= 0xc0;
+        response[1] = 16;
+    }
+    // the client's poll interval, and microsecond precision
+    response[2] = request[2];
+    response[3] = -20i8 as u8;
+    response[12..16].copy_from_slice(b"GEPH");
+    let now = to_timestamp(now());
+    response[16..24].copy_from_slice(&now);
+    response[24..32].copy_from_slice(&request[40..48]);
+    response[32..40].copy_from_slice(&now);
+    response[40..48].copy_from_slice(&now);
+    Some(response)
+}
+
+/// The current time, in seconds since the Unix epoch.
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Encodes seconds since the Unix epoch as an NTP timestamp.
+fn to_timestamp(unix: f64) -> [u8; 8] {
+    let ntp = unix + NTP_EPOCH_OFFSET;
+    let secs = ntp.trunc() as u32;
+    let frac = (ntp.fract() * 4_294_967_296.0) as u32;
+    let mut out = [0u8; 8];
+    out[..4].copy_from_slice(&secs.to_be_bytes());
+    out[4..].copy_from_slice(&frac.to_be_bytes());
+    out
+}
+
+/// Decodes an NTP timestamp into seconds since the Unix epoch.
+fn from_timestamp(bytes: &[u8]) -> f64 {
+    let secs = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64;
+    let frac = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as f64 / 4_294_967_296.0;
+    secs + frac - NTP_EPOCH_OFFSET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skew_from_response() {
+        let sent = 1_700_000_000.0;
+        // the server's clock is 10 seconds ahead, with 50ms of delay each way
+        let mut response = [0u8; 48];
+        response[1] = 2;
+        response[32..40].copy_from_slice(&to_timestamp(sent + 10.05));
+        response[40..48].copy_from_slice(&to_timestamp(sent + 10.06));
+        let skew = skew(sent, &response, sent + 0.11).unwrap();
+        assert!((skew + 10.0).abs() < 0.001, "{}", skew);
+        response[1] = 0;
+        assert!(super::skew(sent, &response, sent + 0.11).is_none());
+
+        // the gateway's answers look synchronized to our own client
+        let mut request = [0u8; 48];
+        request[0] = 0x23;
+        request[40..48].copy_from_slice(&to_timestamp(sent));
+        let answer = answer_ntp(&request).unwrap();
+        assert_eq!(answer[0] & 0x07, 4);
+        assert_eq!(answer[24..32], request[40..48]);
+        assert!(super::skew(sent, &answer, sent).is_some());
+        assert!(answer_ntp(&answer).is_none());
+    }
+}
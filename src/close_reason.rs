@@ -0,0 +1,125 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use sosistab2::Stream;
+
+/// Prefix of a close frame. Close frames are sent as unreliable datagrams on the stream being closed, so clients that don't know about them just see the stream close as before.
+pub const CLOSE_FRAME_MAGIC: &[u8] = b"geph-close:";
+
+/// Prefix of a notice frame, which is sent like a close frame but leaves the stream open.
+pub const NOTICE_FRAME_MAGIC: &[u8] = b"geph-notice:";
+
+/// Why the exit closed a stream, or is holding it back.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The destination port is blacklisted, or not on the whitelist
+    BlockedPort,
+    /// The user went over a data quota
+    QuotaExceeded,
+    /// The exit is killing connections because it has too many
+    Overloaded,
+    /// The exit is draining
+    Draining,
+    /// The exit has hit one of its hard caps
+    ExitFull,
+    /// The destination address is blocked by the exit
+    BlockedDestination,
+    /// The session was idle for longer than its tier allows
+    Idle,
+    /// The connection carries a protocol that the exit blocks
+    BlockedProtocol,
+    /// The session has opened too many mail connections
+    MailLimited,
+    /// The exit is under a handshake flood, and the session must solve a challenge on a `@pow` stream first
+    PowRequired,
+    /// The session must authenticate before opening streams
+    Unauthenticated,
+    /// The destination couldn't be resolved or connected to
+    Unreachable,
+    /// The destination refused the connection
+    ConnectionRefused,
+    /// Connecting to the destination timed out
+    ConnectTimeout,
+    /// The destination is one of the exit's bypass routes, which clients should reach directly or through another exit
+    NotServed,
+    /// The stream carried enough data that the free tier throttles it. Only sent in notice frames.
+    BulkThrottled,
+}
+
+impl CloseReason {
+    /// A numeric code that is stable across versions.
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseReason::BlockedPort => 1,
+            CloseReason::QuotaExceeded => 2,
+            CloseReason::Overloaded => 3,
+            CloseReason::Draining => 4,
+            CloseReason::ExitFull => 5,
+            CloseReason::BlockedDestination => 6,
+            CloseReason::Idle => 7,
+            CloseReason::BlockedProtocol => 8,
+            CloseReason::MailLimited => 9,
+            CloseReason::PowRequired => 10,
+            CloseReason::Unauthenticated => 11,
+            CloseReason::Unreachable => 12,
+            CloseReason::NotServed => 13,
+            CloseReason::ConnectionRefused => 14,
+            CloseReason::ConnectTimeout => 15,
+            CloseReason::BulkThrottled => 16,
+        }
+    }
+
+    /// Sends a close frame with this reason on the stream. Failures are ignored, since the stream is about to close anyway.
+    pub async fn send_frame(&self, stream: &Stream) {
+        let _ = stream
+            .send_urel(self.encode(CLOSE_FRAME_MAGIC).into())
+            .await;
+    }
+
+    /// Sends a notice frame with this reason on the stream, which stays open.
+    pub async fn send_notice(&self, stream: &Stream) {
+        let _ = stream
+            .send_urel(self.encode(NOTICE_FRAME_MAGIC).into())
+            .await;
+    }
+
+    fn encode(&self, magic: &[u8]) -> Vec<u8> {
+        let frame = serde_json::json!({
+            "code": self.code(),
+            "reason": self,
+            "message": self.to_string(),
+        });
+        let mut bts = magic.to_vec();
+        bts.extend_from_slice(frame.to_string().as_bytes());
+        bts
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            CloseReason::BlockedPort => "this port is blocked by the exit",
+            CloseReason::QuotaExceeded => "data quota exceeded",
+            CloseReason::Overloaded => "exit is overloaded",
+            CloseReason::Draining => "exit is draining",
+            CloseReason::ExitFull => "exit is full",
+            CloseReason::BlockedDestination => "this destination is blocked by the exit",
+            CloseReason::Idle => "session closed after being idle",
+            CloseReason::BlockedProtocol => "this protocol is blocked by the exit",
+            CloseReason::MailLimited => "too many mail connections",
+            CloseReason::PowRequired => "proof of work required",
+            CloseReason::Unauthenticated => "session is not authenticated",
+            CloseReason::Unreachable => "destination is unreachable",
+            CloseReason::ConnectionRefused => "destination refused the connection",
+            CloseReason::ConnectTimeout => "connecting to the destination timed out",
+            CloseReason::NotServed => "this destination is not served by the exit",
+            CloseReason::BulkThrottled => {
+                "free tier speed is reduced for large transfers; upgrade for full speed"
+            }
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for CloseReason {}
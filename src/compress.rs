@@ -0,0 +1,233 @@
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::AsyncRead;
+use zstd::stream::write::Encoder;
+
+use crate::{config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Frame kinds. Every frame is a kind byte, a big-endian u32 length and that many bytes. The payloads of a stream's ZSTD frames, concatenated, are one zstd stream.
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// A chunk that compresses by less than this, in tenths, is sent raw and ends compression of its stream, which carries media or encrypted data.
+const MIN_SAVING_TENTHS: usize = 1;
+
+/// Bytes of compressed chunks before and after compression, for the compression ratio.
+pub static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// Whether streams of the given tier may be compressed.
+pub fn allowed(is_plus: bool) -> bool {
+    CONFIG.compression().as_ref().is_some_and(|config| {
+        if is_plus {
+            config.plus()
+        } else {
+            config.free()
+        }
+    })
+}
+
+/// How much compressed chunks shrank on average, as their size after over their size before.
+pub fn ratio() -> f64 {
+    let before = BYTES_IN.load(Ordering::Relaxed);
+    if before == 0 {
+        return 1.0;
+    }
+    BYTES_OUT.load(Ordering::Relaxed) as f64 / before as f64
+}
+
+/// A reader that frames what it reads for a client that negotiated compression, compressing chunks for as long as they compress well. Unframed, it reads straight through.
+pub struct CompressReader<R> {
+    inner: R,
+    framed: bool,
+    /// Whether the stream still looks compressible
+    compressing: bool,
+    first: bool,
+    /// The stream's zstd encoder, flushed after every chunk so that each frame decodes as soon as it arrives, while later chunks still refer back to earlier ones
+    encoder: Option<Encoder<'static, Vec<u8>>>,
+    scratch: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> CompressReader<R> {
+    pub fn new(inner: R, framed: bool) -> Self {
+        if framed {
+            ROOT_CTX.incr_stat(Metric::CompressedStream);
+        }
+        Self {
+            inner,
+            framed,
+            compressing: true,
+            first: true,
+            encoder: None,
+            scratch: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Frames a chunk read from the inner reader into `out`.
+    fn encode(&mut self, n: usize) {
+        let chunk = &self.scratch[..n];
+        if std::mem::take(&mut self.first) && looks_encrypted(chunk) {
+            self.compressing = false;
+        }
+        self.out.clear();
+        self.pos = 0;
+        let config = CONFIG.compression().as_ref();
+        if let Some(config) = config.filter(|_| self.compressing) {
+            if ROOT_CTX.cpu_usage.load(Ordering::Relaxed) > config.max_cpu() {
+                ROOT_CTX.incr_stat(Metric::CompressionSkippedCpu);
+            } else if let Some(compressed) = compress(&mut self.encoder, chunk, config.level()) {
+                if compressed.len() * 10 <= n * (10 - MIN_SAVING_TENTHS) {
+                    BYTES_IN.fetch_add(n as u64, Ordering::Relaxed);
+                    BYTES_OUT.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+                    frame(&mut self.out, ZSTD, &compressed);
+                    return;
+                }
+                // the client never sees this chunk compressed, so no later chunk may be either
+                self.compressing = false;
+                self.encoder = None;
+            }
+        }
+        frame(&mut self.out, RAW, &self.scratch[..n]);
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CompressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if !this.framed {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if this.pos == this.out.len() {
+            this.scratch.resize(buf.len(), 0);
+            let n = match Pin::new(&mut this.inner).poll_read(cx, &mut this.scratch) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(n)) => n,
+                other => return other,
+            };
+            this.encode(n);
+        }
+        let n = buf.len().min(this.out.len() - this.pos);
+        buf[..n].copy_from_slice(&this.out[this.pos..][..n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Whether a stream's first chunk is a TLS record, whose payload won't compress.
+fn looks_encrypted(first: &[u8]) -> bool {
+    matches!(first, [0x14..=0x17, 0x03, ..])
+}
+
+/// Compresses a chunk with the stream's encoder, starting it if needed, and returns everything it flushed out.
+fn compress(
+    encoder: &mut Option<Encoder<'static, Vec<u8>>>,
+    chunk: &[u8],
+    level: u32,
+) -> Option<Vec<u8>> {
+    let encoder = match encoder {
+        Some(encoder) => encoder,
+        None => encoder.insert(Encoder::new(Vec::new(), level as i32).ok()?),
+    };
+    encoder.write_all(chunk).ok()?;
+    encoder.flush().ok()?;
+    Some(std::mem::take(encoder.get_mut()))
+}
+
+fn frame(out: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::AsyncReadExt;
+
+    use super::*;
+
+    /// Reads every frame of a compressed stream, returning the kinds and the decompressed data.
+    fn unframe(mut framed: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut kinds, mut data) = (vec![], vec![]);
+        let mut decoder = zstd::stream::write::Decoder::new(Vec::new()).unwrap();
+        while let [kind, a, b, c, d, rest @ ..] = framed {
+            let len = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+            let payload = &rest[..len];
+            kinds.push(*kind);
+            match *kind {
+                ZSTD => {
+                    // each frame must decode fully on arrival
+                    decoder.write_all(payload).unwrap();
+                    decoder.flush().unwrap();
+                    data.append(decoder.get_mut());
+                }
+                _ => data.extend_from_slice(payload),
+            }
+            framed = &rest[len..];
+        }
+        (kinds, data)
+    }
+
+    fn read_all(input: &[u8]) -> Vec<u8> {
+        smol::block_on(async {
+            let mut reader = CompressReader::new(input, true);
+            let mut out = vec![];
+            let mut buf = [0; 4096];
+            loop {
+                let n = reader.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return out;
+                }
+                out.extend_from_slice(&buf[..n]);
+            }
+        })
+    }
+
+    #[test]
+    fn compressible_only() {
+        let text = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>hello</p>".repeat(500);
+        let framed = read_all(&text);
+        assert!(framed.len() < text.len() / 4);
+        let (kinds, data) = unframe(&framed);
+        assert!(kinds.iter().all(|kind| *kind == ZSTD));
+        assert_eq!(data, text);
+
+        // TLS is never compressed
+        let mut tls = vec![0x16, 0x03, 0x03];
+        tls.extend_from_slice(&text);
+        let (kinds, data) = unframe(&read_all(&tls));
+        assert!(kinds.iter().all(|kind| *kind == RAW));
+        assert_eq!(data, tls);
+
+        // neither is noise, once it fails to shrink
+        let noise: Vec<u8> = (0..20_000).map(|_| rand::random()).collect();
+        let (kinds, data) = unframe(&read_all(&noise));
+        assert!(kinds.iter().all(|kind| *kind == RAW));
+        assert_eq!(data, noise);
+    }
+
+    #[test]
+    fn context_carries_over() {
+        // a block that barely compresses on its own, but is free to repeat
+        let mut block: Vec<u8> = (0..3000).map(|_| rand::random::<u8>() % 64).collect();
+        block[0] = b'x';
+        let input = block.repeat(10);
+        let framed = read_all(&input);
+        assert!(framed.len() < block.len() * 2);
+        let (kinds, data) = unframe(&framed);
+        assert!(kinds.iter().all(|kind| *kind == ZSTD));
+        assert_eq!(data, input);
+    }
+}
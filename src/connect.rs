@@ -1,26 +1,49 @@
 use std::{
-    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
-    sync::Arc,
-    time::Duration,
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{config::CONFIG, ratelimit::RateLimiter, root_ctx::ROOT_CTX};
+use crate::{
+    close_reason::CloseReason,
+    compress::CompressReader,
+    config::CONFIG,
+    error::ExitError,
+    identity::ExitIdentity,
+    listen::link::LinkStats,
+    peer,
+    quota::QuotaHandle,
+    ratelimit::{BulkThrottle, RateLimiter},
+    redact,
+    relay::{self, RelayStats},
+    root_ctx::ROOT_CTX,
+    runtime,
+    sniff::{sniff_client, sniff_server, ConnSniffer, SniffReader},
+    sockbuf,
+    stats::Metric,
+};
 use anyhow::Context;
 use cidr_utils::cidr::Ipv6Cidr;
 
 use moka::sync::Cache;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use smol::prelude::*;
 use smol::{
     io::{AsyncRead, AsyncWrite},
     Async,
 };
 use smol_timeout::TimeoutExt;
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use tap::TapFallible;
 
-async fn resolve_name_inner(name: String) -> anyhow::Result<SocketAddr> {
-    static DNS_CACHE: Lazy<Cache<String, SocketAddr>> = Lazy::new(|| {
+async fn resolve_name_inner(name: String) -> anyhow::Result<Vec<SocketAddr>> {
+    static DNS_CACHE: Lazy<Cache<String, Vec<SocketAddr>>> = Lazy::new(|| {
         Cache::builder()
             .max_capacity(1_000_000)
             .time_to_live(Duration::from_secs(3600))
@@ -31,17 +54,21 @@ async fn resolve_name_inner(name: String) -> anyhow::Result<SocketAddr> {
         return Ok(v);
     }
 
-    let vec: Vec<SocketAddr> = smol::net::resolve(&name).await?.into_iter().collect();
+    let vec: Vec<SocketAddr> = smol::net::resolve(&name)
+        .await?
+        .into_iter()
+        .map(canonical_socket_addr)
+        .collect();
 
-    if let Some(s) = vec.get(0) {
-        DNS_CACHE.insert(name, *s);
-        Ok(*s)
-    } else {
+    if vec.is_empty() {
         anyhow::bail!("no suitable IP address")
+    } else {
+        DNS_CACHE.insert(name, vec.clone());
+        Ok(vec)
     }
 }
 
-async fn resolve_name(name: String) -> anyhow::Result<SocketAddr> {
+async fn resolve_name(name: String) -> anyhow::Result<Vec<SocketAddr>> {
     for _ in 0..3 {
         if let Ok(a) = resolve_name_inner(name.clone()).await {
             return Ok(a);
@@ -50,15 +77,390 @@ async fn resolve_name(name: String) -> anyhow::Result<SocketAddr> {
     resolve_name_inner(name.clone()).await
 }
 
-/// Connects to a remote host and forwards traffic to/from it and a given client.
+/// Turns IPv4-mapped IPv6 addresses into the IPv4 ones they map, so that they are dialed and checked against policies as such.
+fn canonical_socket_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Canonicalizes the hostname of a `host:port` destination, lowercasing it and converting internationalized names to punycode, so that policies on domains can't be bypassed with Unicode or case tricks, and so that DNS sees the name they checked. IP literals, with IPv6 ones in brackets, are put in their canonical form.
+fn canonical_destination(addr: &str) -> anyhow::Result<String> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(canonical_socket_addr(addr).to_string());
+    }
+    let (host, port) = addr
+        .rsplit_once(':')
+        .context("destination without a port")?;
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        anyhow::bail!("malformed IP address, or IPv6 address without brackets")
+    }
+    let host = idna::domain_to_ascii(host)
+        .map_err(|err| anyhow::anyhow!("invalid hostname: {:?}", err))?;
+    let host = host.trim_end_matches('.');
+    if host.is_empty() {
+        anyhow::bail!("empty hostname")
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Every address of the exit host itself, other than loopback ones, which are among the `internal_destinations` unless configured otherwise.
+static EXIT_OWN_ADDRS: Lazy<HashSet<IpAddr>> = Lazy::new(|| {
+    let mut ips: HashSet<IpAddr> = HashSet::new();
+    ips.insert((*crate::asn::MY_PUBLIC_IP).into());
+    match nix::ifaddrs::getifaddrs() {
+        Ok(ifaddrs) => ips.extend(ifaddrs.filter_map(|ifaddr| {
+            let addr = ifaddr.address?;
+            if let Some(addr) = addr.as_sockaddr_in() {
+                Some(IpAddr::V4(addr.ip().into()))
+            } else {
+                addr.as_sockaddr_in6().map(|addr| IpAddr::V6(addr.ip()))
+            }
+        })),
+        Err(err) => log::warn!("cannot list the exit's own addresses: {:?}", err),
+    }
+    for identity in ROOT_CTX.identities.iter() {
+        if let Ok(addr) = identity.sosistab2_listen.parse::<SocketAddr>() {
+            ips.insert(addr.ip());
+        }
+    }
+    ips.retain(|ip| !ip.is_unspecified() && !ip.is_loopback());
+    ips
+});
+
+/// Whether a resolved destination is internal to the exit: in the `internal_destinations`, or one of its own addresses. IPv4-mapped addresses count as the IPv4 ones they map.
+pub fn is_internal(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    CONFIG.destination_internal(ip) || EXIT_OWN_ADDRS.contains(&ip)
+}
+
+/// Whether this host has a route to the IPv6 internet, checked once.
+static HAS_IPV6_EGRESS: Lazy<bool> = Lazy::new(|| {
+    let has_route = std::net::UdpSocket::bind("[::]:0")
+        .and_then(|socket| socket.connect("[2001:4860:4860::8888]:53"))
+        .is_ok();
+    if !has_route {
+        log::warn!("prefer_ipv6_egress is set, but this host has no IPv6 route");
+    }
+    has_route
+});
+
+/// Puts IPv6 addresses ahead of IPv4 ones if that is configured and possible, otherwise keeps the resolver's order.
+fn order_for_egress(addrs: &mut [SocketAddr]) {
+    if *CONFIG.prefer_ipv6_egress() && *HAS_IPV6_EGRESS {
+        prefer_ipv6(addrs);
+    }
+}
+
+/// Stably moves IPv6 addresses to the front.
+fn prefer_ipv6(addrs: &mut [SocketAddr]) {
+    addrs.sort_by_key(|addr| addr.is_ipv4());
+}
+
+/// Longest time spent on one address when there are others left to try.
+const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Addresses that were recently refused or unreachable, with why, which are skipped until they expire.
+static FAILED: Lazy<Cache<SocketAddr, CloseReason>> = Lazy::new(|| {
+    Cache::builder()
+        .max_capacity(100_000)
+        .time_to_live(Duration::from_secs(30))
+        .build()
+});
+
+/// Why connecting failed, as told to the client.
+fn failure_reason(err: &anyhow::Error) -> CloseReason {
+    match err.downcast_ref::<std::io::Error>().map(|err| err.kind()) {
+        Some(std::io::ErrorKind::ConnectionRefused) => CloseReason::ConnectionRefused,
+        Some(std::io::ErrorKind::TimedOut) | None => CloseReason::ConnectTimeout,
+        Some(_) => CloseReason::Unreachable,
+    }
+}
+
+/// Whether a failure shows that the address itself is refusing or unreachable, which is all that gets cached. Timeouts are not, since they may only mean that the attempt got little of the budget.
+fn is_cacheable(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        err.downcast_ref::<std::io::Error>().map(|err| err.kind()),
+        Some(
+            ErrorKind::ConnectionRefused
+                | ErrorKind::NetworkUnreachable
+                | ErrorKind::HostUnreachable
+        )
+    )
+}
+
+/// Connects to the first reachable address out of the resolved ones, in order, spending at most `budget` across all of them. If every address recently failed, fails right away with the latest reason.
+async fn connect_any(
+    addrs: &[SocketAddr],
+    client_id: u64,
+    budget: Duration,
+) -> anyhow::Result<Async<std::net::TcpStream>> {
+    let deadline = Instant::now() + budget;
+    let candidates: Vec<SocketAddr> = addrs
+        .iter()
+        .copied()
+        .filter(|addr| !FAILED.contains_key(addr))
+        .collect();
+    if candidates.is_empty() {
+        ROOT_CTX.incr_stat(Metric::ConnectFailureCached);
+        let reason = FAILED.get(&addrs[0]).unwrap_or(CloseReason::Unreachable);
+        return Err(anyhow::anyhow!(
+            "{} recently failed: {}",
+            redact::destination(addrs[0]),
+            reason
+        ))
+        .context(ExitError::Unreachable(reason));
+    }
+    let mut last_err = None;
+    for (i, addr) in candidates.iter().enumerate() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let timeout = if i + 1 == candidates.len() {
+            remaining
+        } else {
+            remaining.min(CONNECT_ATTEMPT_TIMEOUT)
+        };
+        if i > 0 {
+            ROOT_CTX.incr_stat(Metric::ConnectFailover);
+        }
+        match connect_one(*addr, client_id)
+            .timeout(timeout)
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("connect timed out")))
+        {
+            Ok(stream) => return Ok(stream),
+            Err(err) => {
+                log::debug!("cannot connect to {}: {:?}", redact::destination(addr), err);
+                let reason = failure_reason(&err);
+                if is_cacheable(&err) {
+                    FAILED.insert(*addr, reason);
+                }
+                last_err = Some((
+                    reason,
+                    err.context(format!("cannot connect to {}", redact::destination(addr))),
+                ));
+            }
+        }
+    }
+    let (reason, err) = last_err.unwrap_or_else(|| {
+        (
+            CloseReason::ConnectTimeout,
+            anyhow::anyhow!("connect timed out"),
+        )
+    });
+    Err(err.context(ExitError::Unreachable(reason)))
+}
+
+/// Connects to one address, from the source IP and with the firewall mark of its egress route if one matches, or otherwise from a random source IP if `random_ipv6_range` applies.
+async fn connect_one(
+    addr: SocketAddr,
+    client_id: u64,
+) -> anyhow::Result<Async<std::net::TcpStream>> {
+    let route = CONFIG.egress_route(addr.ip(), crate::asn::asn_of(addr.ip()));
+    let fwmark = route.and_then(|route| route.fwmark());
+    let source = route
+        .and_then(|route| route.source_ip())
+        .filter(|source| source.is_ipv6() == addr.is_ipv6())
+        .or_else(|| {
+            let pool: Ipv6Cidr = CONFIG.random_ipv6_range().filter(|_| addr.is_ipv6())?;
+            fastrand::seed(client_id);
+            let random_ipv6 = Ipv6Addr::from(fastrand::u128(pool.first()..=pool.last()));
+            log::trace!("assigned {:?}", random_ipv6);
+            Some(random_ipv6.into())
+        });
+    if route.is_some() {
+        ROOT_CTX.incr_stat(Metric::EgressRouted);
+    }
+    if source.is_some() || fwmark.is_some() || CONFIG.tcp_congestion().is_some() {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        set_congestion(&socket);
+        if let Some(mark) = fwmark {
+            socket.set_mark(mark).context("can't set fwmark")?;
+        }
+        if let Some(source) = source {
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+            let sock_addr = SocketAddr::new(source, 0);
+            socket.bind(&sock_addr.into()).context("can't bind")?;
+        }
+        let _ = socket.connect(&addr.into()); // this is gonna return einprogress and it's fine
+        let stream = Async::new(std::net::TcpStream::from(socket)).context("can't make Async")?;
+        stream.writable().await?;
+        if let Some(err) = stream.get_ref().take_error()? {
+            return Err(err.into());
+        }
+        Ok(stream)
+    } else {
+        Ok(Async::<std::net::TcpStream>::connect(addr).await?)
+    }
+}
+
+/// Connects to a destination on behalf of a peer exit, which has already applied its own policies to the connection. This exit's blocked destinations still apply, and its own peers are never tried, so that connections can't loop between peers.
+pub async fn connect_for_peer(addr: &str) -> anyhow::Result<Async<std::net::TcpStream>> {
+    let (dns_timeout, connect_timeout) = CONFIG.connect_timeouts().of(false);
+    let addrs = resolve_name(canonical_destination(addr)?)
+        .timeout(dns_timeout)
+        .await
+        .unwrap_or_else(|| Err(anyhow::anyhow!("DNS resolution timed out")))?;
+    let mut addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| !CONFIG.destination_blocked(addr.ip()) && !is_internal(addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        anyhow::bail!("every address is in a blocked or internal range")
+    }
+    order_for_egress(&mut addrs);
+    let remote = connect_any(&addrs, fastrand::u64(..), connect_timeout).await?;
+    remote.as_ref().set_nodelay(true)?;
+    set_keepalive(remote.as_ref())?;
+    Ok(remote)
+}
+
+/// Where a proxied connection leaves the exit: straight to its destination, or through a peer exit.
+#[derive(Clone)]
+enum Egress {
+    Direct(async_dup::Arc<Async<std::net::TcpStream>>),
+    Peer(sosistab2::Stream),
+}
+
+impl Egress {
+    /// The destination address, when connected to it directly.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Egress::Direct(stream) => stream.get_ref().peer_addr().ok(),
+            Egress::Peer(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Egress {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Egress::Direct(stream) => std::pin::Pin::new(stream).poll_read(cx, buf),
+            Egress::Peer(stream) => std::pin::Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Egress {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Egress::Direct(stream) => std::pin::Pin::new(stream).poll_write(cx, buf),
+            Egress::Peer(stream) => std::pin::Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Egress::Direct(stream) => std::pin::Pin::new(stream).poll_flush(cx),
+            Egress::Peer(stream) => std::pin::Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Egress::Direct(stream) => std::pin::Pin::new(stream).poll_close(cx),
+            Egress::Peer(stream) => std::pin::Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// Applies the configured TCP keepalive, if any, to a proxied connection's socket.
+pub fn set_keepalive(stream: &std::net::TcpStream) -> anyhow::Result<()> {
+    if let Some(config) = CONFIG.tcp_keepalive() {
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(config.idle_secs()))
+            .with_interval(Duration::from_secs(config.interval_secs()))
+            .with_retries(config.probes());
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Whether setting the congestion control algorithm has failed, after which it is no longer tried.
+static CONGESTION_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Applies the configured congestion control algorithm, if any, to a destination socket before it connects. Failing leaves the system default in place.
+fn set_congestion(socket: &Socket) {
+    let Some(algorithm) = CONFIG.tcp_congestion() else {
+        return;
+    };
+    if CONGESTION_UNSUPPORTED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(err) = set_tcp_congestion(socket, algorithm) {
+        if !CONGESTION_UNSUPPORTED.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "cannot use congestion control {:?}, falling back to the system default: {}",
+                algorithm,
+                err
+            );
+        }
+    }
+}
+
+fn set_tcp_congestion(socket: &Socket, algorithm: &str) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algorithm.as_ptr().cast(),
+            algorithm.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Connects to a remote host and forwards traffic to/from it and a given client. `client_id` seeds the random source address, and `session_id` keys per-session limits such as the SMTP policy. With `compress`, what goes to the client is framed by [`CompressReader`]. For transparent-proxy connections, `transparent` is the client's socket. The connection is listed in the `link` of its session, if any, and goes through the `bulk` policy step of free-tier sessions.
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_loop(
     rate_limit: Arc<RateLimiter>,
     client: impl AsyncRead + AsyncWrite + Clone + Unpin + Send + 'static,
     client_id: u64,
+    session_id: u64,
     addr: String,
-    _count_stats: bool,
+    transparent: Option<&std::net::TcpStream>,
+    link: Option<Arc<LinkStats>>,
+    bulk: Option<Arc<BulkThrottle>>,
+    identity: Arc<ExitIdentity>,
+    quota: Option<Arc<QuotaHandle>>,
+    compress: bool,
 ) -> anyhow::Result<()> {
     let f = async move {
+        if ROOT_CTX.connections_full() {
+            ROOT_CTX.incr_stat(Metric::ConnRejectedFull);
+            log::debug!(
+                "exit is full, refusing connection to {}",
+                redact::destination(&addr)
+            );
+            return Err(ExitError::Refused(CloseReason::ExitFull).into());
+        }
+        // transparent-proxy connections also hold the client's socket
+        let Some(_fds) = runtime::reserve_fds(if transparent.is_some() { 2 } else { 1 }) else {
+            ROOT_CTX.incr_stat(Metric::ConnRejectedFds);
+            log::warn!("out of file descriptors, refusing a proxied connection");
+            return Err(ExitError::Refused(CloseReason::ExitFull).into());
+        };
         // Incr/decr the connection count
         ROOT_CTX
             .conn_count
@@ -69,81 +471,197 @@ pub async fn proxy_loop(
                 .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
         });
 
+        let addr = canonical_destination(&addr)
+            .context(ExitError::Refused(CloseReason::BlockedDestination))?;
+        let host = addr
+            .rsplit_once(':')
+            .map_or(addr.as_str(), |(host, _)| host);
+        if CONFIG.bypass_routes().covers_host(host) {
+            ROOT_CTX.incr_stat(Metric::BypassRouteRefused);
+            return Err(ExitError::Refused(CloseReason::NotServed))
+                .context("destination is a bypass route");
+        }
+
         // First, we establish a TCP connection
-        let addr = resolve_name(addr.clone())
+        let (dns_timeout, connect_timeout) = CONFIG.connect_timeouts().of(transparent.is_some());
+        let addrs = resolve_name(addr.clone())
+            .timeout(dns_timeout)
             .await
-            .tap_err(|err| log::warn!("cannot resolve remote {}: {}", addr, err))?;
+            .unwrap_or_else(|| Err(anyhow::anyhow!("DNS resolution timed out")))
+            .context(ExitError::Unreachable(CloseReason::Unreachable))
+            .tap_err(|err| {
+                log::warn!(
+                    "cannot resolve remote {}: {}",
+                    redact::destination(&addr),
+                    err
+                )
+            })?;
+        let port = addrs[0].port();
 
         // Reject if blacklisted
-        if crate::lists::BLACK_PORTS.contains(&addr.port()) {
-            anyhow::bail!("port blacklisted")
+        if crate::lists::BLACK_PORTS.contains(&port) {
+            return Err(ExitError::Refused(CloseReason::BlockedPort)).context("port blacklisted");
+        }
+        if CONFIG.port_whitelist() && !crate::lists::WHITE_PORTS.contains(&port) {
+            return Err(ExitError::Refused(CloseReason::BlockedPort))
+                .context(format!("port {} not whitelisted", port));
+        }
+        let bulk = bulk.filter(|bulk| bulk.covers(port));
+        let _smtp_guard = crate::smtp::admit(session_id, port)
+            .map_err(ExitError::from)
+            .context("refused by the SMTP policy")?;
+        let bypassed = addrs
+            .iter()
+            .any(|addr| CONFIG.bypass_routes().covers_ip(addr.ip()));
+        let internal = addrs.iter().any(|addr| is_internal(addr.ip()));
+        let mut addrs: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| {
+                !CONFIG.destination_blocked(addr.ip())
+                    && !CONFIG.bypass_routes().covers_ip(addr.ip())
+                    && !is_internal(addr.ip())
+            })
+            .collect();
+        if addrs.is_empty() && bypassed {
+            ROOT_CTX.incr_stat(Metric::BypassRouteRefused);
+            return Err(ExitError::Refused(CloseReason::NotServed))
+                .context("every address is in a bypass route");
         }
-        if CONFIG.port_whitelist() && !crate::lists::WHITE_PORTS.contains(&addr.port()) {
-            anyhow::bail!("port {} not whitelisted", addr.port())
+        if addrs.is_empty() && internal {
+            ROOT_CTX.incr_stat(Metric::InternalDestinationRefused);
+            return Err(ExitError::Refused(CloseReason::BlockedDestination))
+                .context("every address is internal to the exit");
         }
+        if addrs.is_empty() {
+            return Err(ExitError::Refused(CloseReason::BlockedDestination))
+                .context("every address is in a blocked range");
+        }
+        order_for_egress(&mut addrs);
 
         // Obtain ASN
         log::debug!(
             "got connection request to {}  (conn_count = {})",
-            CONFIG.redact(addr),
+            redact::destination(addrs[0]),
             ROOT_CTX
                 .conn_count
                 .load(std::sync::atomic::Ordering::Relaxed)
         );
 
+        let remote = match connect_any(&addrs, client_id, connect_timeout).await {
+            Ok(remote) => {
+                remote.as_ref().set_nodelay(true)?;
+                set_keepalive(remote.as_ref())?;
+                Egress::Direct(async_dup::Arc::new(remote))
+            }
+            // a destination that blocks this exit may still be reachable from a peer
+            Err(err) => match peer::connect(&addr).await {
+                Some(stream) => Egress::Peer(stream),
+                None => return Err(err),
+            },
+        };
+
+        // transparent-proxy connections can carry on in the next exit process, since their clients' VPN packets do
+        #[cfg(feature = "tcp-repair")]
+        let migration = match (transparent, &remote) {
+            (Some(client), Egress::Direct(remote)) if CONFIG.migrate_connections() => {
+                crate::migrate::register(client, remote.get_ref()).ok()
+            }
+            _ => None,
+        };
+
         // Upload official stats
-        let upload_stat = Arc::new(move |n| ROOT_CTX.incr_throughput(n));
+        let asn = remote
+            .peer_addr()
+            .and_then(|addr| crate::asn::asn_of(addr.ip()));
+        let upload_stat = Arc::new(move |n| {
+            ROOT_CTX.incr_throughput(&identity, n);
+            if let Some(asn) = asn {
+                crate::asn::record_asn_traffic(asn, n);
+            }
+        });
 
-        let remote = if let Some(pool) =
-            CONFIG
-                .random_ipv6_range()
-                .and_then(|a| if addr.is_ipv6() { Some(a) } else { None })
-        {
-            let pool: Ipv6Cidr = pool;
-            fastrand::seed(client_id);
-            let random_ipv6 = Ipv6Addr::from(fastrand::u128(pool.first()..=pool.last()));
-            log::trace!("assigned {:?}", random_ipv6);
-            let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-            socket.set_nonblocking(true)?;
-            socket.set_reuse_address(true)?;
-            socket.set_reuse_port(true)?;
-            let sock_addr = SocketAddrV6::new(random_ipv6, 0, 0, 0);
-            socket.bind(&sock_addr.into()).context("can't bind")?;
-            let _ = socket.connect(&addr.into()); // this is gonna return einprogress and it's fine
-            let stream =
-                Async::new(std::net::TcpStream::from(socket)).context("can't make Async")?;
-            stream.writable().await?;
-            stream
-        } else {
-            Async::<std::net::TcpStream>::connect(addr)
-                .timeout(Duration::from_secs(60))
-                .await
-                .ok_or_else(|| anyhow::anyhow!("connect timed out for {}", addr))??
+        // what the connection carries, from which its socket buffers are sized, and which the admin API shows for its session
+        let socket = match &remote {
+            Egress::Direct(stream) => Some(stream.clone()),
+            Egress::Peer(_) => None,
         };
-        remote.as_ref().set_nodelay(true)?;
+        let relay_stats = Arc::new(RelayStats::new(redact::destination(&addr), socket.clone()));
+        let _tracked = link.map(|link| link.track_relay(relay_stats.clone()));
+        let relay_stats2 = relay_stats.clone();
+        let relay_stats3 = relay_stats.clone();
 
-        let remote = async_dup::Arc::new(remote);
-        let remote2 = remote.clone();
+        let sniffer = Arc::new(ConnSniffer::default());
+        let remote2 = CompressReader::new(
+            SniffReader::new(remote.clone(), sniffer.clone(), sniff_server),
+            compress,
+        );
         let client2 = client.clone();
+        let client = SniffReader::new(client, sniffer.clone(), sniff_client);
         // let _t = smolscale::spawn(async move {
         //     let _ = smol::io::copy(remote2, client2).await;
         // });
         // smol::io::copy(client, remote).await?;
 
+        // when either direction last carried data, for the handshake and idle timeouts
+        let connected_at = Instant::now();
+        let last_active: Arc<Mutex<Option<Instant>>> = Default::default();
+        let last_active2 = last_active.clone();
+        let last_active3 = last_active.clone();
         let us1 = upload_stat.clone();
-        let _up = smolscale::spawn(geph4_aioutils::copy_with_stats_async(
-            remote2,
-            client2,
-            move |n| {
-                us1(n);
-                let rate_limit = rate_limit.clone();
+        let quota2 = quota.clone();
+        let sniffer2 = sniffer.clone();
+        let sniffer3 = sniffer.clone();
+        let buffers = CONFIG.relay_buffers();
+        let up = smolscale::spawn(relay::relay(remote2, client2, buffers, move |n| {
+            us1(n);
+            relay_stats2.record_down(n);
+            *last_active2.lock() = Some(Instant::now());
+            let rate_limit = rate_limit.clone();
+            let quota = quota2.clone();
+            let sniffer = sniffer2.clone();
+            let bulk = bulk.clone();
+            let transferred = relay_stats2.transferred();
+            async move {
+                rate_limit.wait(n).await;
+                if let Some(throttle) = sniffer.throttle() {
+                    throttle.wait(n).await;
+                }
+                if let Some(bulk) = bulk {
+                    bulk.wait(transferred, n).await;
+                }
+                if let Some(quota) = quota {
+                    quota.charge(n).await;
+                }
+            }
+        }));
+        let killed = async {
+            relay::relay(client, remote, buffers, move |n| {
+                upload_stat(n);
+                relay_stats3.record_up(n);
+                *last_active3.lock() = Some(Instant::now());
+                if let Some(quota) = quota.as_ref() {
+                    quota.record(n);
+                }
+                let sniffer = sniffer3.clone();
                 async move {
-                    rate_limit.wait(n).await;
+                    if let Some(throttle) = sniffer.throttle() {
+                        throttle.wait(n).await;
+                    }
                 }
-            },
-        ));
-        geph4_aioutils::copy_with_stats(client, remote, move |n| {
-            upload_stat(n);
+            })
+            .await
+            .map(|_| false)
+        }
+        .or(async {
+            // a blocked protocol seen by the other direction also ends this one
+            sniffer.wait_blocked().await;
+            Ok(false)
+        })
+        .or(async {
+            let Some(stream) = socket else {
+                return smol::future::pending().await;
+            };
+            match sockbuf::tune_loop(stream.get_ref(), || relay_stats.transferred()).await {}
         })
         .or(async {
             // "grace period"
@@ -151,9 +669,50 @@ pub async fn proxy_loop(
             let killer = ROOT_CTX.kill_event.listen();
             killer.await;
             log::warn!("killing connection due to connection kill event");
-            Ok(())
+            Ok(true)
         })
-        .await?;
+        .or(async {
+            let handshake_timeout = Duration::from_secs(CONFIG.handshake_timeout_secs());
+            let idle_timeout = CONFIG.tcp_idle_timeout_secs().map(Duration::from_secs);
+            loop {
+                let last_active = *last_active.lock();
+                let (since, limit, stat) = match last_active {
+                    None => (
+                        connected_at,
+                        Some(handshake_timeout),
+                        Metric::ConnHandshakeTimeout,
+                    ),
+                    Some(last) => (last, idle_timeout, Metric::ConnIdleTimeout),
+                };
+                let Some(limit) = limit else {
+                    return smol::future::pending().await;
+                };
+                let elapsed = since.elapsed();
+                if elapsed >= limit {
+                    ROOT_CTX.incr_stat(stat);
+                    log::debug!("closing connection silent for {:?}", elapsed);
+                    return Ok(false);
+                }
+                smol::Timer::after(limit - elapsed).await;
+            }
+        })
+        .await;
+        if sniffer.blocked() {
+            return Err(ExitError::Refused(CloseReason::BlockedProtocol).into());
+        }
+        #[cfg(feature = "tcp-repair")]
+        if let Some(migration) = migration.filter(|_| crate::migrate::frozen()) {
+            // the freeze ended the relays, which must both write out what they hold before the new process takes over
+            if matches!(killed, Ok(false)) && up.await.is_ok() {
+                migration.drained().await;
+            }
+            return Ok(());
+        }
+        drop(up);
+        let killed = killed?;
+        if killed {
+            return Err(ExitError::Refused(CloseReason::Overloaded).into());
+        }
 
         Ok(())
         // let down = smolscale::spawn();
@@ -172,6 +731,143 @@ pub async fn proxy_loop(
     };
     if let Err(err) = f.await {
         log::trace!("conn failed w/ {:?}", err);
+        let error = ExitError::of(&err);
+        error.record();
+        // errors worth telling the client about are passed on, so that the caller can send a close frame
+        if error.close_reason().is_some() {
+            return Err(error.into());
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn congestion_fallback() {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        // reno is built into every kernel
+        set_tcp_congestion(&socket, "reno").unwrap();
+        assert!(set_tcp_congestion(&socket, "no-such-algo").is_err());
+    }
+
+    #[test]
+    fn ipv6_first() {
+        let mut addrs: Vec<SocketAddr> = ["1.1.1.1:443", "[2606:4700::1]:443", "1.0.0.1:443"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        prefer_ipv6(&mut addrs);
+        assert!(addrs[0].is_ipv6());
+        assert_eq!(addrs[1], "1.1.1.1:443".parse().unwrap());
+        assert_eq!(addrs[2], "1.0.0.1:443".parse().unwrap());
+    }
+
+    #[test]
+    fn canonical_hostnames() {
+        let canonical = |addr| canonical_destination(addr).unwrap();
+        assert_eq!(canonical("ExAmple.COM:443"), "example.com:443");
+        // fullwidth letters and a trailing dot are the same name
+        assert_eq!(canonical("ｅｘａｍｐｌｅ.com.:443"), "example.com:443");
+        assert_eq!(canonical("bücher.example:80"), "xn--bcher-kva.example:80");
+        assert_eq!(canonical("BÜCHER.example:80"), "xn--bcher-kva.example:80");
+        assert_eq!(canonical("1.2.3.4:53"), "1.2.3.4:53");
+        assert_eq!(canonical("[2001:DB8::0:1]:443"), "[2001:db8::1]:443");
+        // IPv4-mapped addresses are checked and dialed as IPv4
+        assert_eq!(canonical("[::ffff:192.0.2.1]:443"), "192.0.2.1:443");
+        assert!(canonical_destination("2001:db8::1:443").is_err());
+        assert!(canonical_destination("[2001:db8::1:443").is_err());
+        assert!(canonical_destination("example.com").is_err());
+        assert!(canonical_destination(".:443").is_err());
+
+        let routes: crate::config::BypassRoutes =
+            toml::from_str(r#"domains = ["example.com"]"#).unwrap();
+        for attempt in ["WWW.EXAMPLE.COM:443", "ｗｗｗ.ｅｘａｍｐｌｅ.ｃｏｍ:443"] {
+            let addr = canonical(attempt);
+            assert!(routes.covers_host(addr.rsplit_once(':').unwrap().0));
+        }
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        smol::block_on(async {
+            let addrs = resolve_name("[2606:4700::1]:443".into()).await.unwrap();
+            assert_eq!(addrs, vec!["[2606:4700::1]:443".parse().unwrap()]);
+            let addrs = resolve_name("[::ffff:93.184.216.34]:80".into())
+                .await
+                .unwrap();
+            assert_eq!(addrs, vec!["93.184.216.34:80".parse().unwrap()]);
+        });
+        assert!(CONFIG.destination_blocked(
+            canonical_socket_addr("[::ffff:192.0.2.1]:80".parse().unwrap()).ip()
+        ));
+    }
+
+    #[test]
+    fn internal_destinations() {
+        for ip in ["10.1.2.3", "169.254.169.254", "::ffff:10.1.2.3"] {
+            assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(!is_internal("93.184.216.34".parse().unwrap()));
+        assert!(!is_internal("2606:4700::1".parse().unwrap()));
+        assert!(crate::config::internal_destinations_default()
+            .iter()
+            .any(|range| range.contains("127.0.0.1".parse::<IpAddr>().unwrap())));
+    }
+
+    #[test]
+    fn failures_cached() {
+        // nothing listens on a port that was just released
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        smol::block_on(async {
+            let err = connect_any(&[dead], 0, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            let refused = ExitError::Unreachable(CloseReason::ConnectionRefused);
+            assert_eq!(ExitError::of(&err), refused);
+            // the next attempt fails right away, for the same reason
+            let err = connect_any(&[dead], 0, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            assert_eq!(ExitError::of(&err), refused);
+            assert!(format!("{:?}", err).contains("recently failed"));
+        })
+    }
+
+    #[test]
+    fn timeouts_not_cached() {
+        let io = |kind| anyhow::Error::from(std::io::Error::from(kind));
+        assert!(is_cacheable(&io(std::io::ErrorKind::ConnectionRefused)));
+        assert!(is_cacheable(&io(std::io::ErrorKind::HostUnreachable)));
+        assert!(!is_cacheable(&io(std::io::ErrorKind::TimedOut)));
+        assert!(!is_cacheable(&anyhow::anyhow!("connect timed out")));
+
+        // a listener whose accept queue is full drops further handshakes, so connecting to it times out
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let slow = listener.local_addr().unwrap().as_socket().unwrap();
+        let _queued: Vec<_> = (0..4)
+            .filter_map(|_| {
+                std::net::TcpStream::connect_timeout(&slow, Duration::from_millis(100)).ok()
+            })
+            .collect();
+        smol::block_on(async {
+            let err = connect_any(&[slow], 0, Duration::from_millis(200))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                ExitError::of(&err),
+                ExitError::Unreachable(CloseReason::ConnectTimeout)
+            );
+        });
+        assert!(!FAILED.contains_key(&slow));
+    }
+}
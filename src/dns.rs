@@ -0,0 +1,260 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use smol::net::UdpSocket;
+use smol_timeout::TimeoutExt;
+
+use crate::{config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Answers shared by all users, keyed by the question section of the query.
+static DNS_CACHE: Lazy<Cache<Bytes, (Bytes, Instant)>> =
+    Lazy::new(|| Cache::builder().max_capacity(100_000).build());
+
+/// Record type of IPv4 addresses.
+const TYPE_A: u16 = 1;
+
+/// Record type of IPv6 addresses.
+const TYPE_AAAA: u16 = 28;
+
+/// Longest time an answer is cached, regardless of its TTL.
+const MAX_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// The resolver that intercepted queries are sent to: `force_dns` if set, otherwise the first nameserver of the exit host.
+static UPSTREAM_RESOLVER: Lazy<SocketAddr> = Lazy::new(|| {
+    if let Some(force_dns) = CONFIG.force_dns() {
+        return *force_dns;
+    }
+    std::fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines()
+                .filter_map(|line| line.trim().strip_prefix("nameserver"))
+                .find_map(|addr| addr.trim().parse::<std::net::IpAddr>().ok())
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::new(1, 1, 1, 1).into(), 53))
+});
+
+/// Answers a raw DNS query, from the shared cache if possible.
+pub async fn answer_query(query: &[u8]) -> anyhow::Result<Bytes> {
+    let question = question_section(query).context("malformed DNS query")?;
+    let key = Bytes::copy_from_slice(question);
+    if let Some((response, expiry)) = DNS_CACHE.get(&key) {
+        if Instant::now() < expiry {
+            ROOT_CTX.incr_stat(Metric::DnsCacheHit);
+            return Ok(with_id(&response, &query[..2]));
+        }
+    }
+    let mut response = ask_upstream(query).await?;
+    if CONFIG.nat64().is_some() && qtype(question) == Some(TYPE_A) {
+        if let Some(synthesized) = synthesize_a(query, &response).await {
+            ROOT_CTX.incr_stat(Metric::Nat64Synthesized);
+            response = synthesized;
+        }
+    }
+    if let Some(ttl) = min_ttl(&response) {
+        let ttl = Duration::from_secs(ttl.into()).min(MAX_CACHE_TTL);
+        DNS_CACHE.insert(key, (response.clone(), Instant::now() + ttl));
+    }
+    Ok(response)
+}
+
+/// Sends a query to the upstream resolver from a fresh socket, so that each query has its own random source port as well as a random ID, and waits for its answer.
+async fn ask_upstream(query: &[u8]) -> anyhow::Result<Bytes> {
+    let question = question_section(query).context("malformed DNS query")?;
+    let socket = UdpSocket::bind(if UPSTREAM_RESOLVER.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    // a connected socket only receives from the resolver
+    socket.connect(*UPSTREAM_RESOLVER).await?;
+    let id: [u8; 2] = rand::thread_rng().gen();
+    socket.send(&with_id(query, &id)).await?;
+    let mut buf = vec![0u8; 4096];
+    let n = async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            let response = &buf[..n];
+            // only a response to this very query, repeating its ID and question, is taken
+            if n >= 12
+                && response[2] & 0x80 != 0
+                && response[..2] == id
+                && response.get(12..12 + question.len()) == Some(question)
+            {
+                return anyhow::Ok(n);
+            }
+        }
+    }
+    .timeout(Duration::from_secs(5))
+    .await
+    .context("upstream resolver timed out")??;
+    Ok(with_id(&buf[..n], &query[..2]))
+}
+
+/// If an A query got no addresses, looks up AAAA records for the same name and answers with synthetic addresses for them.
+async fn synthesize_a(query: &[u8], response: &[u8]) -> Option<Bytes> {
+    let records = answers(response)?;
+    if records.iter().any(|record| record.rtype == TYPE_A) {
+        return None;
+    }
+    let question = question_section(query)?;
+    let mut aaaa_query = query.to_vec();
+    // same question, but for AAAA
+    let qtype_offset = 12 + question.len() - 4;
+    aaaa_query[qtype_offset..qtype_offset + 2].copy_from_slice(&TYPE_AAAA.to_be_bytes());
+    let aaaa_response = ask_upstream(&aaaa_query).await.ok()?;
+    let mut synthesized = response[..12].to_vec();
+    let mut count = 0u16;
+    synthesized.extend_from_slice(question);
+    for record in answers(&aaaa_response)? {
+        let Ok(v6) = <[u8; 16]>::try_from(record.rdata) else {
+            continue;
+        };
+        if record.rtype != TYPE_AAAA {
+            continue;
+        }
+        let Some(v4) = crate::nat64::synthesize(v6.into()) else {
+            continue;
+        };
+        // the owner name points at the question
+        synthesized.extend_from_slice(&[0xc0, 12]);
+        synthesized.extend_from_slice(&TYPE_A.to_be_bytes());
+        synthesized.extend_from_slice(&1u16.to_be_bytes());
+        synthesized.extend_from_slice(&record.ttl.to_be_bytes());
+        synthesized.extend_from_slice(&4u16.to_be_bytes());
+        synthesized.extend_from_slice(&v4.octets());
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    // one question, the synthetic answers, and nothing else
+    synthesized[3] &= 0xf0;
+    synthesized[4..6].copy_from_slice(&1u16.to_be_bytes());
+    synthesized[6..8].copy_from_slice(&count.to_be_bytes());
+    synthesized[8..12].fill(0);
+    Some(synthesized.into())
+}
+
+/// Replaces the transaction ID of a DNS message.
+fn with_id(msg: &[u8], id: &[u8]) -> Bytes {
+    let mut msg = msg.to_vec();
+    msg[..2].copy_from_slice(id);
+    msg.into()
+}
+
+/// Returns the question section of a standard query with exactly one question.
+fn question_section(query: &[u8]) -> Option<&[u8]> {
+    let flags = u16::from_be_bytes(query.get(2..4)?.try_into().ok()?);
+    let qdcount = u16::from_be_bytes(query.get(4..6)?.try_into().ok()?);
+    // must be a standard query (QR = 0, opcode = 0) with one question
+    if flags & 0xf800 != 0 || qdcount != 1 {
+        return None;
+    }
+    let end = skip_name(query, 12)? + 4;
+    query.get(12..end)
+}
+
+/// The smallest TTL among the answers of a successful response, or None if it shouldn't be cached.
+fn min_ttl(response: &[u8]) -> Option<u32> {
+    answers(response)?
+        .into_iter()
+        .map(|record| record.ttl)
+        .min()
+}
+
+/// The record type of a question section.
+fn qtype(question: &[u8]) -> Option<u16> {
+    let offset = question.len().checked_sub(4)?;
+    Some(u16::from_be_bytes(
+        question.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// A record in the answer section of a response.
+struct Record<'a> {
+    rtype: u16,
+    ttl: u32,
+    rdata: &'a [u8],
+}
+
+/// The answer section of a successful response, or None if the response is an error or malformed.
+fn answers(response: &[u8]) -> Option<Vec<Record<'_>>> {
+    let rcode = response.get(3)? & 0x0f;
+    let qdcount = u16::from_be_bytes(response.get(4..6)?.try_into().ok()?);
+    let ancount = u16::from_be_bytes(response.get(6..8)?.try_into().ok()?);
+    if rcode != 0 {
+        return None;
+    }
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = skip_name(response, offset)? + 4;
+    }
+    let mut records = Vec::with_capacity(ancount as usize);
+    for _ in 0..ancount {
+        offset = skip_name(response, offset)?;
+        let rtype = u16::from_be_bytes(response.get(offset..offset + 2)?.try_into().ok()?);
+        let ttl = u32::from_be_bytes(response.get(offset + 4..offset + 8)?.try_into().ok()?);
+        let rdlength = u16::from_be_bytes(response.get(offset + 8..offset + 10)?.try_into().ok()?);
+        let rdata = response.get(offset + 10..offset + 10 + rdlength as usize)?;
+        offset += 10 + rdlength as usize;
+        records.push(Record { rtype, ttl, rdata });
+    }
+    Some(records)
+}
+
+/// Skips over a possibly compressed domain name, returning the offset right after it.
+fn skip_name(msg: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(offset)?;
+        if len == 0 {
+            return Some(offset + 1);
+        } else if len & 0xc0 == 0xc0 {
+            // compression pointer: the name ends here
+            msg.get(offset + 1)?;
+            return Some(offset + 2);
+        } else {
+            offset += 1 + len as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to an A query for example.com, with two answers using name compression.
+    const RESPONSE: &[u8] = &[
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0, // header
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0,
+        1, // question
+        0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 93, 184, 216, 34, // TTL 3600
+        0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 35, // TTL 60
+    ];
+
+    #[test]
+    fn parse_messages() {
+        let mut query = RESPONSE[..29].to_vec();
+        query[2] = 0x01;
+        query[3] = 0x00;
+        query[7] = 0;
+        assert_eq!(question_section(&query), Some(&RESPONSE[12..29]));
+        assert_eq!(question_section(RESPONSE), None);
+        assert_eq!(min_ttl(RESPONSE), Some(60));
+        assert_eq!(min_ttl(&RESPONSE[..40]), None);
+        assert_eq!(qtype(&RESPONSE[12..29]), Some(TYPE_A));
+        let records = answers(RESPONSE).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].rdata, [93, 184, 216, 35]);
+        assert_eq!(with_id(RESPONSE, &[0xab, 0xcd])[..2], [0xab, 0xcd]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt::Display;
+
+use crate::{
+    close_reason::CloseReason,
+    root_ctx::ROOT_CTX,
+    stats::{self, Metric},
+};
+
+/// Why something in the data path failed, by category. Errors carry it as their root or as context, where [`ExitError::of`] finds it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitError {
+    /// The session hasn't authenticated
+    Auth,
+    /// The exit's policy or capacity refused it, for the given reason
+    Refused(CloseReason),
+    /// The client went over a data quota
+    Quota,
+    /// The destination couldn't be resolved or connected to, for the given reason
+    Unreachable(CloseReason),
+    /// Anything else, including connections that broke midway
+    Internal,
+}
+
+impl ExitError {
+    /// The category of an error, from the [`ExitError`] or [`CloseReason`] it carries.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<ExitError>() {
+            return *err;
+        }
+        match err.downcast_ref::<CloseReason>() {
+            Some(reason) => (*reason).into(),
+            None => ExitError::Internal,
+        }
+    }
+
+    /// The category's name, as its metrics are tagged.
+    pub fn category(&self) -> &'static str {
+        match self {
+            ExitError::Auth => "auth",
+            ExitError::Refused(_) => "refused",
+            ExitError::Quota => "quota",
+            ExitError::Unreachable(_) => "unreachable",
+            ExitError::Internal => "internal",
+        }
+    }
+
+    /// The reason to give the client in a close frame, if it is worth telling.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            ExitError::Auth => Some(CloseReason::Unauthenticated),
+            ExitError::Refused(reason) => Some(*reason),
+            ExitError::Quota => Some(CloseReason::QuotaExceeded),
+            ExitError::Unreachable(reason) => Some(*reason),
+            ExitError::Internal => None,
+        }
+    }
+
+    /// Counts the error towards its category.
+    pub fn record(&self) {
+        if let Some(client) = ROOT_CTX.stat_client.as_ref() {
+            client.incr(&stats::key(
+                Metric::DataPathError,
+                &[("category", self.category())],
+            ));
+        }
+    }
+}
+
+impl From<CloseReason> for ExitError {
+    fn from(reason: CloseReason) -> Self {
+        match reason {
+            CloseReason::QuotaExceeded => ExitError::Quota,
+            CloseReason::Unreachable
+            | CloseReason::ConnectionRefused
+            | CloseReason::ConnectTimeout => ExitError::Unreachable(reason),
+            reason => ExitError::Refused(reason),
+        }
+    }
+}
+
+impl Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitError::Refused(reason) => write!(f, "refused: {}", reason),
+            ExitError::Internal => f.write_str("internal error"),
+            _ => match self.close_reason() {
+                Some(reason) => reason.fmt(f),
+                None => f.write_str(self.category()),
+            },
+        }
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn categories() {
+        let unreachable = Err::<(), _>(anyhow::anyhow!("connection refused"))
+            .context(ExitError::Unreachable(CloseReason::ConnectionRefused))
+            .context("cannot connect")
+            .unwrap_err();
+        assert_eq!(
+            ExitError::of(&unreachable),
+            ExitError::Unreachable(CloseReason::ConnectionRefused)
+        );
+        assert_eq!(
+            ExitError::of(&unreachable).close_reason(),
+            Some(CloseReason::ConnectionRefused)
+        );
+
+        let blocked = anyhow::Error::new(ExitError::from(CloseReason::BlockedPort))
+            .context("port blacklisted");
+        assert_eq!(
+            ExitError::of(&blocked),
+            ExitError::Refused(CloseReason::BlockedPort)
+        );
+        assert_eq!(
+            ExitError::of(&CloseReason::QuotaExceeded.into()),
+            ExitError::Quota
+        );
+        let internal = anyhow::anyhow!("broken pipe");
+        assert_eq!(ExitError::of(&internal), ExitError::Internal);
+        assert_eq!(ExitError::Internal.close_reason(), None);
+    }
+}
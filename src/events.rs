@@ -0,0 +1,279 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use event_listener::Event;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use smol::future::FutureExt;
+use smol_timeout::TimeoutExt;
+
+use crate::{config::CONFIG, stats::Metric};
+
+/// Events kept for subscribers that fall behind or reconnect with `Last-Event-ID`.
+const BACKLOG: usize = 1024;
+
+/// Most subscribers at once.
+const MAX_SUBSCRIBERS: usize = 16;
+
+/// How often an idle stream gets a comment, so that proxies and clients don't time it out.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long mitigations of one kind are counted up before they are published as one event.
+const MITIGATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Metrics that count abuse mitigations, which are also published as events, coalesced by kind.
+const MITIGATIONS: &[Metric] = &[
+    Metric::HandshakeRateLimited,
+    Metric::PowFailed,
+    Metric::QuotaTerminated,
+    Metric::QuotaThrottled,
+    Metric::SessionCountryRejected,
+    Metric::SessionRevoked,
+    Metric::SmtpBlocked,
+    Metric::SmtpCapped,
+    Metric::SmtpRateLimited,
+    Metric::SniffedBitTorrent,
+    Metric::TransparentSourceRejected,
+    Metric::UdpFlowLimit,
+    Metric::VpnBitTorrentUdp,
+];
+
+/// Mitigations of each kind in `MITIGATIONS` since they were last published.
+static PENDING_MITIGATIONS: [AtomicU64; MITIGATIONS.len()] =
+    [const { AtomicU64::new(0) }; MITIGATIONS.len()];
+
+/// Something that happened on the exit, as streamed to operator tooling.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExitEvent {
+    /// A session got past admission and was given its state
+    SessionStart { session: String },
+    /// A session ended
+    SessionEnd { session: String, duration_secs: u64 },
+    /// An abuse mitigation kicked in `count` times since the last such event, named after the metric that counts it
+    Mitigation { kind: &'static str, count: u64 },
+    /// Drain mode was turned on or off
+    DrainChanged { draining: bool },
+    /// The egress probes started or stopped getting through
+    EgressHealthChanged { healthy: bool },
+}
+
+/// An event with when it happened.
+#[derive(Serialize)]
+struct Stamped<'a> {
+    time: u64,
+    #[serde(flatten)]
+    event: &'a ExitEvent,
+}
+
+/// The latest events, as JSON with their sequence numbers, and the next sequence number.
+struct EventLog {
+    events: VecDeque<(u64, Arc<str>)>,
+    next: u64,
+}
+
+static LOG: Mutex<EventLog> = Mutex::new(EventLog {
+    events: VecDeque::new(),
+    next: 1,
+});
+
+/// Notified whenever an event is published.
+static PUBLISHED: Lazy<Event> = Lazy::new(Event::new);
+
+/// Publishes an event to the subscribers of the event stream.
+pub fn publish(event: ExitEvent) {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let Ok(json) = serde_json::to_string(&Stamped {
+        time,
+        event: &event,
+    }) else {
+        return;
+    };
+    let mut log = LOG.lock();
+    let seq = log.next;
+    log.next += 1;
+    log.events.push_back((seq, json.into()));
+    if log.events.len() > BACKLOG {
+        log.events.pop_front();
+    }
+    drop(log);
+    PUBLISHED.notify(usize::MAX);
+}
+
+/// Counts a mitigation towards its next event if the metric counts one. This is on the data path, so it only touches an atomic.
+pub fn on_metric(metric: Metric) {
+    if let Some(idx) = MITIGATIONS.iter().position(|m| *m == metric) {
+        PENDING_MITIGATIONS[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Publishes one event for each kind of mitigation that kicked in since the last call.
+fn flush_mitigations() {
+    for (metric, pending) in MITIGATIONS.iter().zip(PENDING_MITIGATIONS.iter()) {
+        let count = pending.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            publish(ExitEvent::Mitigation {
+                kind: metric.name(),
+                count,
+            });
+        }
+    }
+}
+
+/// The events after the given sequence number that are still kept.
+fn events_after(seq: u64) -> Vec<(u64, Arc<str>)> {
+    LOG.lock()
+        .events
+        .iter()
+        .filter(|(event_seq, _)| *event_seq > seq)
+        .cloned()
+        .collect()
+}
+
+/// The sequence number of the latest event.
+fn latest_seq() -> u64 {
+    LOG.lock().next - 1
+}
+
+/// Serves the event stream as server-sent events on `GET /events`, if `admin_events_listen` is set.
+pub async fn events_loop() -> anyhow::Result<Infallible> {
+    let Some(listen) = CONFIG.admin_events_listen() else {
+        return smol::future::pending().await;
+    };
+    let listener = smol::Async::<std::net::TcpListener>::bind(listen)?;
+    log::info!("serving the admin event stream on {}", listen);
+    smolscale::spawn(async {
+        loop {
+            smol::Timer::after(MITIGATION_INTERVAL).await;
+            flush_mitigations();
+        }
+    })
+    .detach();
+    static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let (conn, _) = listener.accept().await?;
+        if SUBSCRIBERS.fetch_add(1, Ordering::Relaxed) >= MAX_SUBSCRIBERS {
+            SUBSCRIBERS.fetch_sub(1, Ordering::Relaxed);
+            continue;
+        }
+        smolscale::spawn(async move {
+            if let Err(err) = subscribe(conn).await {
+                log::debug!("event stream subscriber left: {:?}", err);
+            }
+            SUBSCRIBERS.fetch_sub(1, Ordering::Relaxed);
+        })
+        .detach();
+    }
+}
+
+/// Streams events to one subscriber until it goes away.
+async fn subscribe(mut conn: smol::Async<std::net::TcpStream>) -> anyhow::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn
+            .read(&mut buf)
+            .timeout(Duration::from_secs(10))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("request timed out"))??;
+        anyhow::ensure!(n > 0, "connection closed before the request ended");
+        head.extend_from_slice(&buf[..n]);
+        anyhow::ensure!(head.len() <= 8192, "request head too long");
+    }
+    let head = String::from_utf8_lossy(&head);
+    let Some(mut seq) = start_seq(&head) else {
+        conn.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Ok(());
+    };
+    conn.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n",
+    )
+    .await?;
+    loop {
+        let published = PUBLISHED.listen();
+        let events = events_after(seq);
+        if let Some((first, _)) = events.first() {
+            if *first > seq + 1 {
+                conn.write_all(format!(": skipped {} events\n\n", first - seq - 1).as_bytes())
+                    .await?;
+            }
+        }
+        for (event_seq, json) in events {
+            conn.write_all(format!("id: {}\ndata: {}\n\n", event_seq, json).as_bytes())
+                .await?;
+            seq = event_seq;
+        }
+        let woken = async {
+            published.await;
+            true
+        }
+        .or(async {
+            smol::Timer::after(KEEPALIVE_INTERVAL).await;
+            false
+        })
+        .await;
+        if !woken {
+            conn.write_all(b": keepalive\n\n").await?;
+        }
+    }
+}
+
+/// Where a request for the event stream starts: after its `Last-Event-ID` if it has one, otherwise at the latest event. None if it isn't such a request.
+fn start_seq(head: &str) -> Option<u64> {
+    let mut lines = head.lines();
+    let mut request = lines.next()?.split(' ');
+    if (request.next(), request.next()) != (Some("GET"), Some("/events")) {
+        return None;
+    }
+    let last_event_id = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("last-event-id"))
+        .and_then(|(_, value)| value.trim().parse().ok());
+    Some(last_event_id.unwrap_or_else(latest_seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog() {
+        let before = latest_seq();
+        publish(ExitEvent::DrainChanged { draining: true });
+        on_metric(Metric::SmtpBlocked);
+        on_metric(Metric::SmtpBlocked);
+        // not a mitigation
+        on_metric(Metric::SessionCount);
+        flush_mitigations();
+        let events: Vec<_> = events_after(before)
+            .into_iter()
+            .map(|(_, json)| serde_json::from_str::<serde_json::Value>(&json).unwrap())
+            .collect();
+        assert!(events
+            .iter()
+            .any(|event| event["type"] == "drain_changed" && event["draining"] == true));
+        assert!(events.iter().any(|event| event["type"] == "mitigation"
+            && event["kind"] == "smtp_blocked"
+            && event["count"].as_u64() >= Some(2)));
+        assert!(!events.iter().any(|event| event["kind"] == "session_count"));
+
+        assert_eq!(
+            start_seq("GET /events HTTP/1.1\r\nLast-Event-ID: 7\r\n\r\n"),
+            Some(7)
+        );
+        assert_eq!(start_seq("GET /status HTTP/1.1\r\n\r\n"), None);
+    }
+}
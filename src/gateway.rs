@@ -0,0 +1,200 @@
+use std::net::Ipv4Addr;
+
+use once_cell::sync::Lazy;
+use pnet_packet::tcp::TcpFlags;
+
+use crate::{
+    asn::next_ip,
+    clock,
+    config::CONFIG,
+    packet::{build_tcp, build_udp, tcp_segment, udp_payload, PacketHeaders, TcpSegment},
+    root_ctx::ROOT_CTX,
+};
+
+/// Port of the gateway's NTP source.
+const NTP_PORT: u16 = 123;
+
+/// Port of the gateway's status page.
+const STATUS_PORT: u16 = 80;
+
+/// Most bytes of a status page response, so that it fits in one segment within the VPN's MTU.
+const MAX_RESPONSE: usize = 1200;
+
+/// Keys the initial sequence numbers of status page connections, which are derived rather than stored.
+static ISN_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// What the status page shows about a session.
+#[derive(Clone, Debug)]
+pub struct SessionStatus {
+    pub vpn_ipv4: Ipv4Addr,
+    pub plus: bool,
+    pub limit_kb: Option<u32>,
+    pub daily_quota_left: Option<u64>,
+    pub monthly_quota_left: Option<u64>,
+}
+
+/// Whether the address is the gateway of a CGNAT pool.
+pub fn is_gateway(ip: Ipv4Addr) -> bool {
+    CONFIG
+        .all_cgnat_pools()
+        .any(|pool| next_ip(pool.first_as_ipv4_addr()) == ip)
+}
+
+/// If gateway services are on and the packet is an NTP request or part of a status page connection to the gateway from this session, answers it and returns true. DNS queries to the gateway are answered by the DNS interception instead.
+pub fn intercept(vpn_ipv4: Ipv4Addr, pkt: &[u8], status: impl FnOnce() -> SessionStatus) -> bool {
+    if !CONFIG.gateway_services() {
+        return false;
+    }
+    let Some(headers) = PacketHeaders::parse(pkt) else {
+        return false;
+    };
+    let (Some(source_port), Some(dest_port)) = (headers.source_port, headers.dest_port) else {
+        return false;
+    };
+    if headers.source != vpn_ipv4 || !is_gateway(headers.destination) {
+        return false;
+    }
+    let gateway = headers.destination;
+    let reply = if headers.is_udp() && dest_port == NTP_PORT {
+        udp_payload(pkt)
+            .and_then(clock::answer_ntp)
+            .and_then(|response| build_udp((gateway, NTP_PORT), (vpn_ipv4, source_port), &response))
+    } else if headers.is_tcp() && dest_port == STATUS_PORT {
+        tcp_segment(pkt).and_then(|segment| {
+            let (reply, response) = answer_status(source_port, segment, status)?;
+            build_tcp(
+                (gateway, STATUS_PORT),
+                (vpn_ipv4, source_port),
+                TcpSegment {
+                    payload: &response,
+                    ..reply
+                },
+            )
+        })
+    } else {
+        return false;
+    };
+    if let Some(reply) = reply {
+        ROOT_CTX.vpn().dispatch_down(&reply);
+    }
+    true
+}
+
+/// Answers a segment of a status page connection without keeping any state: the handshake is acknowledged with a derived sequence number, the first data segment gets the whole response and a FIN, and the client's FIN is acknowledged. Returns the reply's sequencing and payload, or None if nothing needs to be sent.
+fn answer_status(
+    client_port: u16,
+    segment: TcpSegment<'_>,
+    status: impl FnOnce() -> SessionStatus,
+) -> Option<(TcpSegment<'static>, Vec<u8>)> {
+    let isn = initial_seq(client_port);
+    let reply = |seq, ack, flags| TcpSegment {
+        seq,
+        ack,
+        flags,
+        payload: &[],
+    };
+    if segment.flags & TcpFlags::RST != 0 {
+        return None;
+    }
+    if segment.flags & TcpFlags::SYN != 0 {
+        let ack = segment.seq.wrapping_add(1);
+        return Some((reply(isn, ack, TcpFlags::SYN | TcpFlags::ACK), vec![]));
+    }
+    // anything else must acknowledge our SYN or response
+    if segment.flags & TcpFlags::ACK == 0 {
+        return None;
+    }
+    if !segment.payload.is_empty() {
+        if segment.ack != isn.wrapping_add(1) {
+            return None;
+        }
+        let ack = segment.seq.wrapping_add(segment.payload.len() as u32);
+        let flags = TcpFlags::PSH | TcpFlags::ACK | TcpFlags::FIN;
+        return Some((reply(segment.ack, ack, flags), http_response(&status())));
+    }
+    if segment.flags & TcpFlags::FIN != 0 {
+        let ack = segment.seq.wrapping_add(1);
+        return Some((reply(segment.ack, ack, TcpFlags::ACK), vec![]));
+    }
+    None
+}
+
+/// The initial sequence number of a status page connection from the given port.
+fn initial_seq(client_port: u16) -> u32 {
+    let hash = blake3::keyed_hash(&ISN_KEY, &client_port.to_be_bytes());
+    u32::from_be_bytes(hash.as_bytes()[..4].try_into().unwrap())
+}
+
+/// The status page as a complete HTTP response.
+fn http_response(status: &SessionStatus) -> Vec<u8> {
+    let quota = |left: Option<u64>| {
+        left.map(|bytes| format!("{} MB", bytes / 1_000_000))
+            .unwrap_or_else(|| "no quota".into())
+    };
+    let mut body = format!(
+        "exit: {}\nyour address: {}\ntier: {}\nspeed limit: {}\ndaily quota left: {}\nmonthly quota left: {}\n",
+        ROOT_CTX.exit_hostname(),
+        status.vpn_ipv4,
+        if status.plus { "plus" } else { "free" },
+        status
+            .limit_kb
+            .map(|kb| format!("{} KB/s", kb))
+            .unwrap_or_else(|| "unlimited".into()),
+        quota(status.daily_quota_left),
+        quota(status.monthly_quota_left),
+    );
+    body.truncate(MAX_RESPONSE - 128);
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_page() {
+        let status = || SessionStatus {
+            vpn_ipv4: Ipv4Addr::new(100, 64, 3, 4),
+            plus: false,
+            limit_kb: Some(100),
+            daily_quota_left: Some(5_000_000),
+            monthly_quota_left: None,
+        };
+        let segment = |seq, ack, flags, payload| TcpSegment {
+            seq,
+            ack,
+            flags,
+            payload,
+        };
+        let (syn_ack, _) =
+            answer_status(5555, segment(1000, 0, TcpFlags::SYN, &[]), status).unwrap();
+        assert_eq!(syn_ack.flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn_ack.ack, 1001);
+        // the bare ACK that finishes the handshake needs no answer
+        let ack = syn_ack.seq.wrapping_add(1);
+        assert!(answer_status(5555, segment(1001, ack, TcpFlags::ACK, &[]), status).is_none());
+
+        let request = b"GET / HTTP/1.1\r\nHost: 100.64.0.1\r\n\r\n";
+        let (reply, response) = answer_status(
+            5555,
+            segment(1001, ack, TcpFlags::ACK | TcpFlags::PSH, request),
+            status,
+        )
+        .unwrap();
+        assert_eq!(reply.seq, ack);
+        assert_eq!(reply.ack, 1001 + request.len() as u32);
+        assert_ne!(reply.flags & TcpFlags::FIN, 0);
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("your address: 100.64.3.4"));
+        assert!(response.contains("speed limit: 100 KB/s"));
+        assert!(response.contains("daily quota left: 5 MB"));
+        // a connection that never shook hands with us gets nothing
+        assert!(answer_status(5555, segment(1001, 7, TcpFlags::ACK, request), status).is_none());
+    }
+}
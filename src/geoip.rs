@@ -0,0 +1,107 @@
+use std::{net::IpAddr, path::Path};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+
+use crate::{asn::addr_key, config::CONFIG};
+
+/// Address ranges and the countries they are in, loaded from an ip2country-style file.
+pub struct CountryDatabase {
+    /// Sorted, non-overlapping ranges of IPv6 (or IPv4-mapped) addresses, as (first, last, country code).
+    ranges: Vec<(u128, u128, [u8; 2])>,
+}
+
+impl CountryDatabase {
+    /// Loads a database whose lines each hold the first address, last address, and two-letter country code of a range, separated by tabs or commas.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read GeoIP database {:?}", path))?;
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let mut fields = line
+                .split(['\t', ','])
+                .map(|field| field.trim().trim_matches('"'));
+            let (Some(first), Some(last), Some(country)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("line {} of the GeoIP database is malformed", i + 1)
+            };
+            let country: [u8; 2] = country
+                .to_ascii_uppercase()
+                .as_bytes()
+                .try_into()
+                .with_context(|| format!("bad country code on line {}", i + 1))?;
+            ranges.push((addr_key(first.parse()?), addr_key(last.parse()?), country));
+        }
+        ranges.sort_unstable();
+        Ok(Self { ranges })
+    }
+
+    /// The country code of the address, if it is in any range.
+    pub fn lookup(&self, addr: IpAddr) -> Option<&str> {
+        let key = addr_key(addr);
+        let idx = self.ranges.partition_point(|(first, _, _)| *first <= key);
+        match idx.checked_sub(1).map(|idx| &self.ranges[idx]) {
+            Some((_, last, country)) if key <= *last => std::str::from_utf8(country).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// The configured GeoIP database, if any.
+pub static GEOIP_DB: Lazy<Option<CountryDatabase>> = Lazy::new(|| {
+    let path = CONFIG.geoip_database().as_ref()?;
+    match CountryDatabase::load(path) {
+        Ok(db) => {
+            log::info!("loaded {} ranges from the GeoIP database", db.ranges.len());
+            Some(db)
+        }
+        Err(err) => {
+            // listeners with a country ACL then refuse everyone, rather than silently serving everyone
+            log::error!("cannot load the GeoIP database: {:?}", err);
+            None
+        }
+    }
+});
+
+/// The country of an address, or None if it is unknown or there's no GeoIP database.
+pub fn country_of(addr: IpAddr) -> Option<&'static str> {
+    GEOIP_DB.as_ref()?.lookup(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CountryAcl;
+
+    #[test]
+    fn lookup() {
+        let db = CountryDatabase::parse(
+            "1.0.0.0\t1.0.0.255\tAU\n\
+             \"8.8.8.0\",\"8.8.8.255\",\"us\"\n\
+             2001:db8::,2001:db8::ffff,DE",
+        )
+        .unwrap();
+        assert_eq!(db.lookup("1.0.0.1".parse().unwrap()), Some("AU"));
+        assert_eq!(db.lookup("8.8.8.8".parse().unwrap()), Some("US"));
+        assert_eq!(db.lookup("8.8.9.1".parse().unwrap()), None);
+        assert_eq!(db.lookup("2001:db8::1".parse().unwrap()), Some("DE"));
+        assert!(CountryDatabase::parse("1.0.0.0\t1.0.0.255\tAUS").is_err());
+    }
+
+    #[test]
+    fn acl() {
+        let acl: CountryAcl = serde_json::from_str(r#"{"allow": ["de", "FR"]}"#).unwrap();
+        assert!(acl.admits(Some("DE")));
+        assert!(!acl.admits(Some("US")));
+        assert!(!acl.admits(None));
+        let acl: CountryAcl = serde_json::from_str(r#"{"deny": ["US"]}"#).unwrap();
+        assert!(!acl.admits(Some("US")));
+        assert!(acl.admits(Some("DE")));
+        assert!(acl.admits(None));
+    }
+}
@@ -0,0 +1,503 @@
+//! In-process test harness. Runs the exit's session handling against a simulated sosistab2 client over loopback pipes, with the TUN device replaced by in-memory queues.
+#![cfg_attr(not(test), allow(dead_code))]
+
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{AsyncBufReadExt, AsyncWriteExt};
+use geph4_protocol::client_exit::CLIENT_EXIT_PSEUDOHOST;
+use nanorpc::JrpcResponse;
+use once_cell::sync::Lazy;
+use pnet_packet::{
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::MutableIpv4Packet,
+    tcp::MutableTcpPacket,
+    udp::MutableUdpPacket,
+};
+use smol::{
+    channel::{Receiver, Sender},
+    io::BufReader,
+};
+use smol_timeout::TimeoutExt;
+use sosistab2::{Multiplex, MuxSecret, Pipe, Stream};
+
+use crate::{
+    listen::handle_pipe_v2, packet::build_udp, root_ctx::ROOT_CTX, tun_backend::MemoryTun,
+};
+
+/// The in-memory TUN device that the exit's VPN writes into.
+pub static HARNESS_TUN: Lazy<MemoryTun> = Lazy::new(MemoryTun::new);
+
+/// Waits for the next packet written into the in-memory TUN device.
+pub async fn next_captured_up() -> anyhow::Result<Bytes> {
+    HARNESS_TUN
+        .recv()
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("timed out waiting for a TUN packet")?
+}
+
+/// One end of an in-memory pipe.
+pub struct LoopbackPipe {
+    send: Sender<Bytes>,
+    recv: Receiver<Bytes>,
+    metadata: String,
+}
+
+/// Creates a connected pair of in-memory pipes. The metadata identifies the client to the exit.
+pub fn pipe_pair(metadata: &str) -> (LoopbackPipe, LoopbackPipe) {
+    let (send_a, recv_a) = smol::channel::unbounded();
+    let (send_b, recv_b) = smol::channel::unbounded();
+    (
+        LoopbackPipe {
+            send: send_a,
+            recv: recv_b,
+            metadata: metadata.into(),
+        },
+        LoopbackPipe {
+            send: send_b,
+            recv: recv_a,
+            metadata: metadata.into(),
+        },
+    )
+}
+
+#[async_trait]
+impl Pipe for LoopbackPipe {
+    fn send(&self, to_send: Bytes) {
+        let _ = self.send.try_send(to_send);
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "pipe closed"))
+    }
+
+    fn protocol(&self) -> &str {
+        "loopback"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.metadata
+    }
+
+    fn peer_addr(&self) -> String {
+        "loopback".into()
+    }
+}
+
+/// A simulated client, connected to the in-process exit.
+pub struct SimulatedClient {
+    mux: Arc<Multiplex>,
+    control: Stream,
+    lines: futures_util::io::Lines<BufReader<Stream>>,
+    rpc_id: i64,
+}
+
+impl SimulatedClient {
+    /// Connects a new simulated client and starts its VPN session.
+    pub async fn connect() -> anyhow::Result<Self> {
+        let client = Self::connect_session().await?;
+        client.start_vpn().await?;
+        Ok(client)
+    }
+
+    /// Connects a new simulated client that resumes the session with the given token, then starts its VPN session.
+    pub async fn resume(token: &str) -> anyhow::Result<Self> {
+        let mut client = Self::connect_session().await?;
+        if client.call("exit_resume", vec![token.into()]).await? != true {
+            anyhow::bail!("exit refused to resume the session")
+        }
+        client.start_vpn().await?;
+        Ok(client)
+    }
+
+    /// Connects a new simulated client without starting its VPN session.
+    async fn connect_session() -> anyhow::Result<Self> {
+        let metadata = format!("harness-{}", fastrand::u64(..));
+        let (client_pipe, exit_pipe) = pipe_pair(&metadata);
+        let identity = ROOT_CTX.main_identity().clone();
+        let exit_pk = identity.sosistab2_sk.to_public();
+        handle_pipe_v2(exit_pipe, identity, "harness");
+        let mux = Arc::new(Multiplex::new(MuxSecret::generate(), Some(exit_pk)));
+        mux.add_pipe(client_pipe);
+        let control = mux.open_conn(CLIENT_EXIT_PSEUDOHOST).await?;
+        Ok(Self {
+            mux,
+            lines: BufReader::new(control.clone()).lines(),
+            control,
+            rpc_id: 0,
+        })
+    }
+
+    /// Starts the VPN session, which the first unreliable datagram does.
+    async fn start_vpn(&self) -> anyhow::Result<()> {
+        self.control.send_urel(Bytes::new()).await?;
+        Ok(())
+    }
+
+    /// Calls a client-exit RPC method.
+    pub async fn call(
+        &mut self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        self.rpc_id += 1;
+        let req = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": self.rpc_id,
+        });
+        let mut line = serde_json::to_vec(&req)?;
+        line.push(b'\n');
+        self.control.write_all(&line).await?;
+        let resp = futures_util::StreamExt::next(&mut self.lines)
+            .timeout(Duration::from_secs(10))
+            .await
+            .context("RPC timed out")?
+            .context("control stream closed")??;
+        let resp: JrpcResponse = serde_json::from_str(&resp)?;
+        resp.result.context("RPC returned an error")
+    }
+
+    /// Asks the exit for the assigned VPN address.
+    pub async fn vpn_ipv4(&mut self) -> anyhow::Result<Ipv4Addr> {
+        let ip = self.call("get_vpn_ipv4", vec![]).await?;
+        serde_json::from_value::<Option<Ipv4Addr>>(ip)?.context("no VPN address assigned")
+    }
+
+    /// Opens a proxied stream to the given host and port.
+    pub async fn open_stream(&self, destination: &str) -> anyhow::Result<Stream> {
+        Ok(self.mux.open_conn(destination).await?)
+    }
+
+    /// Sends a batch of raw IPv4 packets up the VPN.
+    pub async fn send_packets(&self, pkts: Vec<Bytes>) -> anyhow::Result<()> {
+        self.control
+            .send_urel(stdcode::serialize(&pkts)?.into())
+            .await?;
+        Ok(())
+    }
+
+    /// Receives a batch of raw IPv4 packets from the VPN.
+    pub async fn recv_packets(&self, timeout: Duration) -> anyhow::Result<Vec<Bytes>> {
+        let batch = self
+            .control
+            .recv_urel()
+            .timeout(timeout)
+            .await
+            .context("timed out waiting for VPN packets")??;
+        Ok(stdcode::deserialize(&batch)?)
+    }
+}
+
+/// Crafts a minimal IPv4 packet carrying an empty TCP or UDP segment.
+pub fn craft_packet(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    dest_port: u16,
+) -> Bytes {
+    let l4_len = if protocol == IpNextHeaderProtocols::Tcp {
+        20
+    } else {
+        8
+    };
+    let mut buf = vec![0u8; 20 + l4_len];
+    let mut ip = MutableIpv4Packet::new(&mut buf).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + l4_len) as u16);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(protocol);
+    ip.set_source(source);
+    ip.set_destination(destination);
+    if protocol == IpNextHeaderProtocols::Tcp {
+        let mut tcp = MutableTcpPacket::new(&mut buf[20..]).unwrap();
+        tcp.set_source(40000);
+        tcp.set_destination(dest_port);
+        tcp.set_data_offset(5);
+    } else {
+        let mut udp = MutableUdpPacket::new(&mut buf[20..]).unwrap();
+        udp.set_source(40000);
+        udp.set_destination(dest_port);
+        udp.set_length(8);
+    }
+    buf.into()
+}
+
+/// Runs simulated sessions that push UDP packets of the given size through the VPN packet path in both directions for a while, then prints the throughput.
+pub async fn bench(sessions: usize, duration: Duration, packet_size: usize) -> anyhow::Result<()> {
+    let payload = vec![0u8; packet_size.saturating_sub(28)];
+    let remote = (Ipv4Addr::new(203, 0, 113, 100), 5000);
+    let mut clients = Vec::with_capacity(sessions);
+    for _ in 0..sessions {
+        let mut client = SimulatedClient::connect().await?;
+        let ip = client.vpn_ipv4().await?;
+        let up = Bytes::from(build_udp((ip, 40000), remote, &payload).context("packet too big")?);
+        let down = build_udp(remote, (ip, 40000), &payload).context("packet too big")?;
+        clients.push((client, up, down));
+    }
+    eprintln!(
+        "running {} sessions with {}-byte packets for {:?}",
+        sessions, packet_size, duration
+    );
+
+    let up_count = Arc::new(AtomicU64::new(0));
+    let down_count = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + duration;
+    let mut tasks = vec![];
+    let counter = up_count.clone();
+    tasks.push(smolscale::spawn(async move {
+        while HARNESS_TUN.recv().await.is_ok() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }));
+    for (client, up, down) in clients {
+        let client = Arc::new(client);
+        let sender = client.clone();
+        tasks.push(smolscale::spawn(async move {
+            while Instant::now() < deadline {
+                if sender.send_packets(vec![up.clone(); 20]).await.is_err() {
+                    break;
+                }
+                for _ in 0..20 {
+                    ROOT_CTX.vpn().dispatch_down(&down);
+                }
+                smol::future::yield_now().await;
+            }
+        }));
+        let counter = down_count.clone();
+        tasks.push(smolscale::spawn(async move {
+            while let Ok(batch) = client.recv_packets(Duration::from_secs(1)).await {
+                counter.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+        }));
+    }
+    smol::Timer::at(deadline).await;
+    let secs = duration.as_secs_f64();
+    for (direction, count) in [("up", &up_count), ("down", &down_count)] {
+        let pps = count.load(Ordering::Relaxed) as f64 / secs;
+        println!(
+            "{:>4}: {:>10.0} packets/s {:>10.1} Mbps",
+            direction,
+            pps,
+            pps * packet_size as f64 * 8.0 / 1_000_000.0
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet_packet::ipv4::Ipv4Packet;
+
+    use super::*;
+    use crate::{
+        close_reason::CLOSE_FRAME_MAGIC, config::CONFIG, listen::DEV_TOKEN_ID,
+        ratelimit::RateOverride,
+    };
+
+    #[test]
+    fn ip_assignment() {
+        smolscale::block_on(async {
+            let mut first = SimulatedClient::connect().await.unwrap();
+            let mut second = SimulatedClient::connect().await.unwrap();
+            let first_ip = first.vpn_ipv4().await.unwrap();
+            let second_ip = second.vpn_ipv4().await.unwrap();
+            assert_ne!(first_ip, second_ip);
+            assert!(CONFIG.cgnat_pool().contains(first_ip));
+            assert!(CONFIG.cgnat_pool().contains(second_ip));
+            // idempotent within a session
+            assert_eq!(first.vpn_ipv4().await.unwrap(), first_ip);
+        })
+    }
+
+    #[test]
+    fn session_resumption() {
+        smolscale::block_on(async {
+            let mut first = SimulatedClient::connect().await.unwrap();
+            let ip = first.vpn_ipv4().await.unwrap();
+            let token = first.call("exit_resumption_token", vec![]).await.unwrap();
+            let token = token.as_str().unwrap().to_owned();
+            drop(first);
+            let mut resumed = SimulatedClient::resume(&token).await.unwrap();
+            assert_eq!(resumed.vpn_ipv4().await.unwrap(), ip);
+            // tokens work only once
+            assert!(SimulatedClient::resume(&token).await.is_err());
+        })
+    }
+
+    #[test]
+    fn bypass_routes() {
+        let routes = CONFIG.bypass_routes();
+        assert!(routes.covers_host("video.EXAMPLE.net."));
+        assert!(!routes.covers_host("notexample.net"));
+        assert!(routes.covers_ip(Ipv4Addr::new(198, 51, 100, 9).into()));
+        smolscale::block_on(async {
+            let mut client = SimulatedClient::connect().await.unwrap();
+            let advertised = client.call("exit_bypass_routes", vec![]).await.unwrap();
+            assert_eq!(advertised["domains"], serde_json::json!(["example.net"]));
+            assert_eq!(advertised["cidrs"].as_array().unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn upstream_filtering() {
+        smolscale::block_on(async {
+            let mut client = SimulatedClient::connect().await.unwrap();
+            let my_ip = client.vpn_ipv4().await.unwrap();
+            // every packet in this test goes to its own destination, so concurrent tests don't interfere
+            let dest = Ipv4Addr::new(203, 0, 113, 7);
+            let tcp = IpNextHeaderProtocols::Tcp;
+            let udp = IpNextHeaderProtocols::Udp;
+            let blocked = vec![
+                craft_packet(Ipv4Addr::new(100, 64, 0, 2), dest, tcp, 80),
+                craft_packet(my_ip, Ipv4Addr::new(10, 0, 0, 1), tcp, 80),
+                craft_packet(my_ip, Ipv4Addr::LOCALHOST, tcp, 80),
+                craft_packet(my_ip, dest, udp, 443),
+                craft_packet(my_ip, dest, tcp, 25),
+            ];
+            let allowed = craft_packet(my_ip, dest, tcp, 81);
+            client.send_packets(blocked).await.unwrap();
+            client.send_packets(vec![allowed.clone()]).await.unwrap();
+            loop {
+                let captured = next_captured_up().await.unwrap();
+                let pkt = Ipv4Packet::new(&captured).unwrap();
+                if pkt.get_source() == my_ip || pkt.get_destination() == dest {
+                    assert_eq!(captured, allowed, "a filtered packet leaked into the TUN");
+                    break;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn downstream_delivery() {
+        smolscale::block_on(async {
+            let mut client = SimulatedClient::connect().await.unwrap();
+            let my_ip = client.vpn_ipv4().await.unwrap();
+            let pkt = craft_packet(
+                Ipv4Addr::new(203, 0, 113, 8),
+                my_ip,
+                IpNextHeaderProtocols::Udp,
+                5000,
+            );
+            // the session subscribes to downstream packets asynchronously, so retry a few times
+            for _ in 0..10 {
+                ROOT_CTX.vpn().dispatch_down(&pkt);
+                if let Ok(received) = client.recv_packets(Duration::from_millis(500)).await {
+                    assert_eq!(received, vec![pkt]);
+                    return;
+                }
+            }
+            panic!("downstream packet never arrived");
+        })
+    }
+
+    #[test]
+    fn hairpinning() {
+        smolscale::block_on(async {
+            let mut sender = SimulatedClient::connect().await.unwrap();
+            let mut receiver = SimulatedClient::connect().await.unwrap();
+            let sender_ip = sender.vpn_ipv4().await.unwrap();
+            let receiver_ip = receiver.vpn_ipv4().await.unwrap();
+            let pkt = craft_packet(sender_ip, receiver_ip, IpNextHeaderProtocols::Udp, 5000);
+            // the session subscribes to downstream packets asynchronously, so retry a few times
+            for _ in 0..10 {
+                sender.send_packets(vec![pkt.clone()]).await.unwrap();
+                if let Ok(received) = receiver.recv_packets(Duration::from_millis(500)).await {
+                    assert_eq!(received, vec![pkt]);
+                    return;
+                }
+            }
+            panic!("hairpinned packet never arrived");
+        })
+    }
+
+    #[test]
+    fn smtp_limits_per_session() {
+        smolscale::block_on(async {
+            let client = SimulatedClient::connect().await.unwrap();
+            let per_hour = CONFIG.smtp().connects_per_hour();
+            // every stream of the session counts towards the same hourly limit
+            for n in 0..=per_hour {
+                let stream = client.open_stream("127.0.0.1:587").await.unwrap();
+                let frame = stream
+                    .recv_urel()
+                    .timeout(Duration::from_secs(10))
+                    .await
+                    .expect("no close frame")
+                    .unwrap();
+                let frame = frame.strip_prefix(CLOSE_FRAME_MAGIC).unwrap();
+                let frame: serde_json::Value = serde_json::from_slice(frame).unwrap();
+                assert_eq!(frame["reason"] == "mail_limited", n == per_hour, "{}", n);
+            }
+        })
+    }
+
+    #[test]
+    fn rate_limiting() {
+        smolscale::block_on(async {
+            let mut client = SimulatedClient::connect().await.unwrap();
+            let my_ip = client.vpn_ipv4().await.unwrap();
+            // every harness session is the dev user, so the limit applies to the other tests too, which only send a few packets
+            let limit_kb = 200;
+            ROOT_CTX.set_rate_override(
+                DEV_TOKEN_ID,
+                Some(RateOverride {
+                    limit_kb: Some(limit_kb),
+                    burst_kb: Some(20),
+                }),
+            );
+            scopeguard::defer!(ROOT_CTX.set_rate_override(DEV_TOKEN_ID, None));
+            let pkt = Bytes::from(
+                build_udp(
+                    (Ipv4Addr::new(203, 0, 113, 9), 5000),
+                    (my_ip, 40000),
+                    &[0; 972],
+                )
+                .unwrap(),
+            );
+            let client = Arc::new(client);
+            let received = Arc::new(AtomicU64::new(0));
+            let counter = received.clone();
+            let receiver = client.clone();
+            let _receiving = smolscale::spawn(async move {
+                while let Ok(batch) = receiver.recv_packets(Duration::from_secs(5)).await {
+                    let bytes: usize = batch.iter().map(|pkt| pkt.len()).sum();
+                    counter.fetch_add(bytes as u64, Ordering::Relaxed);
+                }
+            });
+            // the TUN device offers about 10 MB/s for two seconds, far more than the limit lets through
+            let duration = Duration::from_secs(2);
+            let start = Instant::now();
+            while start.elapsed() < duration {
+                for _ in 0..10 {
+                    ROOT_CTX.vpn().dispatch_down_owned(pkt.clone());
+                }
+                smol::Timer::after(Duration::from_millis(1)).await;
+            }
+            smol::Timer::after(Duration::from_millis(200)).await;
+            let rate_kb = received.load(Ordering::Relaxed) as f64 / 1000.0 / duration.as_secs_f64();
+            assert!(
+                rate_kb > limit_kb as f64 / 4.0 && rate_kb < limit_kb as f64 * 1.5,
+                "{} KB/s through a {} KB/s limit",
+                rate_kb,
+                limit_kb
+            );
+        })
+    }
+}
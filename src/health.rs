@@ -0,0 +1,116 @@
+use std::{
+    convert::Infallible,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use smol::stream::StreamExt;
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    config::CONFIG,
+    events::{self, ExitEvent},
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+/// Whether the latest egress probes got through. Exits start out healthy, so that a slow first round doesn't hide them.
+static HEALTHY: AtomicBool = AtomicBool::new(true);
+
+/// Connect latency of the fastest target in the latest successful round, in milliseconds.
+static LATENCY_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the exit can reach the internet, as far as the egress probes can tell.
+pub fn is_healthy() -> bool {
+    HEALTHY.load(Ordering::Relaxed)
+}
+
+/// Connect latency of the latest successful probe round, in milliseconds.
+pub fn latency_ms() -> u64 {
+    LATENCY_MS.load(Ordering::Relaxed)
+}
+
+/// Periodically dials the probe targets, marking the exit unhealthy once enough rounds in a row reach none of them.
+pub async fn egress_probe_loop() -> anyhow::Result<Infallible> {
+    let config = CONFIG.egress_probes();
+    if config.targets().is_empty() {
+        return smol::future::pending().await;
+    }
+    let mut failed_rounds = 0;
+    let mut timer = smol::Timer::interval(Duration::from_secs(config.interval_secs()));
+    loop {
+        timer.next().await;
+        let latency =
+            probe_round(config.targets(), Duration::from_secs(config.timeout_secs())).await;
+        match latency {
+            Some(latency) => {
+                failed_rounds = 0;
+                LATENCY_MS.store(latency.as_millis() as u64, Ordering::Relaxed);
+            }
+            None => {
+                ROOT_CTX.incr_stat(Metric::EgressProbeFailed);
+                failed_rounds += 1;
+            }
+        }
+        let healthy = failed_rounds < config.failures_before_unhealthy();
+        if HEALTHY.swap(healthy, Ordering::Relaxed) != healthy {
+            events::publish(ExitEvent::EgressHealthChanged { healthy });
+            if healthy {
+                log::info!("egress probes are getting through again, exit is healthy");
+            } else {
+                log::error!(
+                    "no egress probe got through in {} rounds, exit is unhealthy",
+                    failed_rounds
+                );
+            }
+        }
+    }
+}
+
+/// Dials every target at once, returning the connect latency of the fastest one, or None if none could be reached.
+async fn probe_round(targets: &[String], timeout: Duration) -> Option<Duration> {
+    let probes = targets.iter().map(|target| async move {
+        let start = Instant::now();
+        match smol::net::TcpStream::connect(target.as_str())
+            .timeout(timeout)
+            .await
+        {
+            Some(Ok(_)) => Some(start.elapsed()),
+            Some(Err(err)) => {
+                log::debug!("egress probe to {} failed: {}", target, err);
+                None
+            }
+            None => {
+                log::debug!("egress probe to {} timed out", target);
+                None
+            }
+        }
+    });
+    futures_util::future::join_all(probes)
+        .await
+        .into_iter()
+        .flatten()
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_round_latency() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().to_string();
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let timeout = Duration::from_secs(1);
+        smol::block_on(async {
+            assert!(probe_round(&[closed.clone(), open], timeout)
+                .await
+                .is_some());
+            assert!(probe_round(&[closed], timeout).await.is_none());
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use std::{net::SocketAddr, path::Path, time::Instant};
+
+use anyhow::Context;
+
+use serde::{de::DeserializeOwned, Serialize};
+use smol::fs::unix::PermissionsExt;
+use sosistab2::MuxSecret;
+
+use crate::{config::CountryAcl, geoip, redact};
+
+/// A logical exit served by this process. Usually there is only one, but an official config can list extra identities, each with its own hostname, keys, and listener.
+pub struct ExitIdentity {
+    pub hostname: String,
+    pub signing_sk: ed25519_dalek::Keypair,
+    pub sosistab2_sk: MuxSecret,
+    /// Where to listen for incoming direct sosistab2 connections for this identity.
+    pub sosistab2_listen: String,
+    /// If set, these are old keys kept around during a key rotation. They are not advertised, and new sessions are refused after this time.
+    pub retire_at: Option<Instant>,
+    /// Source countries allowed to establish sessions on the listener, if restricted.
+    pub country_acl: Option<CountryAcl>,
+}
+
+impl ExitIdentity {
+    /// Loads an identity, creating and saving its keys if they don't exist yet.
+    pub fn load(
+        hostname: String,
+        secret_key: &Path,
+        secret_sosistab2_key: &Path,
+        sosistab2_listen: String,
+        country_acl: Option<CountryAcl>,
+    ) -> Self {
+        let sosistab2_sk = load_or_create_key(secret_sosistab2_key, MuxSecret::generate);
+        let signing_sk = load_or_create_key(secret_key, || {
+            ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng {})
+        });
+        log::info!(
+            "signing_sk for {:?} = {}",
+            hostname,
+            hex::encode(signing_sk.public)
+        );
+        Self {
+            hostname,
+            signing_sk,
+            sosistab2_sk,
+            sosistab2_listen,
+            retire_at: None,
+            country_acl,
+        }
+    }
+
+    /// Whether a client connecting from the given outer address may establish sessions on this identity's listener.
+    pub fn admits(&self, peer_addr: &str) -> bool {
+        let Some(acl) = self.country_acl.as_ref() else {
+            return true;
+        };
+        let country = peer_addr
+            .parse::<SocketAddr>()
+            .ok()
+            .and_then(|addr| geoip::country_of(addr.ip()));
+        let admitted = acl.admits(country);
+        if !admitted && acl.log_rejections() {
+            log::info!(
+                "refusing a handshake from {} ({}) on {}",
+                redact::client(peer_addr),
+                country.unwrap_or("unknown country"),
+                self.hostname
+            );
+        }
+        admitted
+    }
+
+    /// Whether these are old keys whose overlap window is over, so that no new sessions may use them.
+    pub fn is_retired(&self) -> bool {
+        self.retire_at
+            .is_some_and(|retire_at| Instant::now() >= retire_at)
+    }
+
+    /// Loads the old keys of an identity that is being rotated. Unlike [`ExitIdentity::load`], the keys must already exist.
+    pub fn load_retiring(
+        hostname: String,
+        secret_key: &Path,
+        secret_sosistab2_key: &Path,
+        sosistab2_listen: String,
+        retire_at: Instant,
+        country_acl: Option<CountryAcl>,
+    ) -> anyhow::Result<Self> {
+        let sosistab2_sk = read_key(secret_sosistab2_key)?;
+        let signing_sk: ed25519_dalek::Keypair = read_key(secret_key)?;
+        log::info!(
+            "retiring signing_sk for {:?} = {}",
+            hostname,
+            hex::encode(signing_sk.public)
+        );
+        Ok(Self {
+            hostname,
+            signing_sk,
+            sosistab2_sk,
+            sosistab2_listen,
+            retire_at: Some(retire_at),
+            country_acl,
+        })
+    }
+}
+
+/// Reads a secret key from disk.
+fn read_key<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let vec = std::fs::read(path).with_context(|| format!("cannot read {:?}", path))?;
+    bincode::deserialize(&vec).with_context(|| format!("cannot deserialize {:?}", path))
+}
+
+/// Reads a secret key from disk, or generates one and saves it with restrictive permissions.
+fn load_or_create_key<T: Serialize + DeserializeOwned>(
+    path: &Path,
+    generate: impl FnOnce() -> T,
+) -> T {
+    match std::fs::read(path) {
+        Ok(vec) => bincode::deserialize(&vec).expect("failed to deserialize my own secret key"),
+        Err(err) => {
+            log::warn!(
+                "can't read {:?}, so creating one and saving it! {}",
+                path,
+                err
+            );
+            let new_keypair = generate();
+            if let Err(err) = std::fs::write(path, bincode::serialize(&new_keypair).unwrap()) {
+                log::error!("cannot save {:?} persistently!!! {}", path, err);
+            } else {
+                let mut perms = std::fs::metadata(path).unwrap().permissions();
+                perms.set_readonly(true);
+                perms.set_mode(0o600);
+                std::fs::set_permissions(path, perms).unwrap();
+            }
+            new_keypair
+        }
+    }
+}
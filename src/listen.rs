@@ -1,20 +1,44 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     convert::Infallible,
     net::{IpAddr, SocketAddr},
-    sync::atomic::Ordering,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
-    asn::MY_PUBLIC_IP, config::CONFIG, listen::control::dummy_tls_config, ratelimit::BW_MULTIPLIER,
-    root_ctx::ROOT_CTX, stats_pipe::StatsPipe, vpn,
+    admin,
+    asn::MY_PUBLIC_IP,
+    billing, bridge, clock, compress,
+    config::{StatusField, CONFIG},
+    events::{self, ExitEvent},
+    health,
+    identity::ExitIdentity,
+    listen::control::dummy_tls_config,
+    peer,
+    quota::QUOTAS,
+    ratelimit::{self, RateOverride, BW_MULTIPLIER},
+    redact,
+    relay::{self, RELAY_BUFFERED_BYTES},
+    rollout::{self, Staged},
+    root_ctx::ROOT_CTX,
+    smartchan::{self, BUFFERED_BYTES},
+    sockbuf,
+    stats::{self, Metric},
+    stats_pipe::StatsPipe,
+    status_api, tun_backend, upgrade, vpn,
 };
 
 use anyhow::Context;
+use async_signal::{Signal, Signals};
 use bytes::Bytes;
 use ed25519_dalek::{ed25519::signature::Signature, Signer};
 
-use geph4_protocol::{binder::protocol::BridgeDescriptor, bridge_exit::serve_bridge_exit};
+use geph4_protocol::{
+    binder::protocol::{BinderClient, BridgeDescriptor},
+    bridge_exit::serve_bridge_exit,
+};
+use nanorpc::RpcTransport;
 
 use smol::prelude::*;
 
@@ -23,9 +47,20 @@ use sosistab2_obfstls::ObfsTlsListener;
 use sosistab2_obfsudp::{ObfsUdpListener, ObfsUdpSecret};
 use sysinfo::{CpuExt, System, SystemExt};
 
-use self::{control::ControlService, session_v2::handle_pipe_v2};
+pub use self::session_v2::handle_pipe_v2;
+#[cfg(all(test, feature = "harness"))]
+pub use self::session_v2::DEV_TOKEN_ID;
+
+use self::control::ControlService;
 
-mod control;
+mod bond;
+pub mod control;
+mod handshake_limit;
+pub mod link;
+mod meter;
+mod obfs;
+mod pow;
+mod resume;
 mod session_v2;
 
 /// the main listening loop
@@ -36,7 +71,24 @@ pub async fn main_loop() -> anyhow::Result<()> {
         .race(smolscale::spawn(control_protocol()))
         .race(smolscale::spawn(run_gauges()))
         .race(smolscale::spawn(pipe_listen()))
+        .race(smolscale::spawn(obfs::obfs_listen()))
+        .race(smolscale::spawn(peer::peer_listen()))
+        .race(smolscale::spawn(peer::health_check_loop()))
         .race(smolscale::spawn(set_ratelimit_loop()))
+        .race(smolscale::spawn(status_report_loop()))
+        .race(smolscale::spawn(drain_signal_loop()))
+        .race(smolscale::spawn(revocation_loop()))
+        .race(smolscale::spawn(rate_override_loop()))
+        .race(smolscale::spawn(QUOTAS.persist_loop()))
+        .race(smolscale::spawn(ratelimit::schedule_loop()))
+        .race(smolscale::spawn(admin::admin_loop()))
+        .race(smolscale::spawn(vpn::lease_snapshot_loop()))
+        .race(smolscale::spawn(billing::export_loop()))
+        .race(smolscale::spawn(upgrade::handoff_loop()))
+        .race(smolscale::spawn(status_api::status_api_loop()))
+        .race(smolscale::spawn(health::egress_probe_loop()))
+        .race(smolscale::spawn(clock::clock_check_loop()))
+        .race(smolscale::spawn(events::events_loop()))
         .await?;
     Ok(())
 }
@@ -47,16 +99,14 @@ async fn idlejitter() -> anyhow::Result<Infallible> {
         let start = Instant::now();
         smol::Timer::after(INTERVAL).await;
         let elapsed = start.elapsed();
-        if let Some(official) = CONFIG.official() {
-            if rand::random::<f32>() < 0.01 {
-                let key = format!("idlejitter.{}", official.exit_hostname().replace('.', "-"));
-                ROOT_CTX
-                    .stat_client
-                    .as_ref()
-                    .as_ref()
-                    .context("wtf")?
-                    .timer(&key, elapsed.as_secs_f64() * 1000.0);
-            }
+        if CONFIG.official().is_some() && rand::random::<f32>() < 0.01 {
+            let key = stats::key(Metric::IdleJitter, &[]);
+            ROOT_CTX
+                .stat_client
+                .as_ref()
+                .as_ref()
+                .context("wtf")?
+                .timer(&key, elapsed.as_secs_f64() * 1000.0);
         }
     }
 }
@@ -74,13 +124,15 @@ async fn killconn() -> anyhow::Result<Infallible> {
 
 async fn control_protocol() -> anyhow::Result<Infallible> {
     if CONFIG.official().is_some() {
-        let secret = blake3::hash(dbg!(CONFIG
-            .official()
-            .as_ref()
-            .unwrap()
-            .bridge_secret()
-            .as_bytes()));
-        log::debug!("bridge secret {:?}", secret);
+        let secret = blake3::hash(
+            CONFIG
+                .official()
+                .as_ref()
+                .unwrap()
+                .bridge_secret()
+                .as_bytes(),
+        );
+        log::debug!("bridge secret {}", redact::sensitive(secret));
         let socket = smol::net::UdpSocket::bind("0.0.0.0:28080").await.unwrap();
         log::info!("starting bridge exit listener");
         serve_bridge_exit(
@@ -97,21 +149,43 @@ async fn control_protocol() -> anyhow::Result<Infallible> {
 }
 
 async fn run_gauges() -> anyhow::Result<Infallible> {
-    let key = format!("session_count.{}", ROOT_CTX.exit_hostname_dashed());
-    let memkey = format!("bytes_allocated.{}", ROOT_CTX.exit_hostname_dashed());
-    let connkey = format!("conn_count.{}", ROOT_CTX.exit_hostname_dashed());
-    let threadkey = format!("thread_key.{}", ROOT_CTX.exit_hostname_dashed());
-    let ctrlkey = format!("control_count.{}", ROOT_CTX.exit_hostname_dashed());
-    let taskkey = format!("task_count.{}", ROOT_CTX.exit_hostname_dashed());
-
-    let cpukey = format!("cpu_usage.{}", ROOT_CTX.exit_hostname_dashed());
-    let loadkey = format!("load_factor.{}", ROOT_CTX.exit_hostname_dashed());
+    let key = stats::key(Metric::SessionCount, &[]);
+    let memkey = stats::key(Metric::BytesAllocated, &[]);
+    let connkey = stats::key(Metric::ConnCount, &[]);
+    let threadkey = stats::key(Metric::ThreadCount, &[]);
+    let ctrlkey = stats::key(Metric::ControlCount, &[]);
+    let taskkey = stats::key(Metric::TaskCount, &[]);
+    let bufkey = stats::key(Metric::BufferedBytes, &[]);
+    let relaybufkey = stats::key(Metric::RelayBufferedBytes, &[]);
+    let relayutilkey = stats::key(Metric::RelayBufferUtilization, &[]);
+    let sockbufkey = stats::key(Metric::SocketBufferBytes, &[]);
+    let compressionkey = stats::key(Metric::CompressionRatio, &[]);
+    let coalescingkey = stats::key(Metric::VpnCoalescingRatio, &[]);
+    let slabkey = stats::key(Metric::VpnTunPacketsPerSlab, &[]);
+    let cgnatkey = stats::key(Metric::CgnatOccupancy, &[]);
+    let mut cgnat_alerted = false;
+
+    let cpukey = stats::key(Metric::CpuUsage, &[]);
+    let loadkey = stats::key(Metric::LoadFactor, &[]);
     let mut sys = System::new_all();
 
     loop {
         sys.refresh_all();
 
+        let cgnat_occupancy = ROOT_CTX
+            .vpn_if_started()
+            .map(|vpn| vpn.occupancy())
+            .unwrap_or_default();
+        if cgnat_occupancy >= CONFIG.cgnat_alert_occupancy() && !cgnat_alerted {
+            log::warn!(
+                "CGNAT pool is {:.0}% full; new VPN sessions are refused once it is full",
+                cgnat_occupancy * 100.0
+            );
+        }
+        cgnat_alerted = cgnat_occupancy >= CONFIG.cgnat_alert_occupancy();
+
         if let Some(stat_client) = ROOT_CTX.stat_client.as_ref() {
+            stat_client.flush();
             let cpus = sys.cpus();
             let usage = cpus.iter().map(|c| c.cpu_usage()).sum::<f32>() / cpus.len() as f32;
 
@@ -128,36 +202,73 @@ async fn run_gauges() -> anyhow::Result<Infallible> {
             let thread_count = smolscale::running_threads();
             stat_client.gauge(&taskkey, task_count as f64);
             stat_client.gauge(&threadkey, thread_count as f64);
+            let buffered_bytes = BUFFERED_BYTES.load(Ordering::Relaxed);
+            stat_client.gauge(&bufkey, buffered_bytes as f64);
+            let relay_buffered = RELAY_BUFFERED_BYTES.load(Ordering::Relaxed);
+            stat_client.gauge(&relaybufkey, relay_buffered as f64);
+            stat_client.gauge(&relayutilkey, relay::utilization(CONFIG.relay_buffers()));
+            let tuned = sockbuf::TUNED_BUFFER_BYTES.load(Ordering::Relaxed);
+            stat_client.gauge(&sockbufkey, tuned as f64);
+            stat_client.gauge(&compressionkey, compress::ratio());
+            stat_client.gauge(&coalescingkey, session_v2::coalescing_ratio());
+            stat_client.gauge(&slabkey, tun_backend::packets_per_slab());
+            stat_client.gauge(&cgnatkey, cgnat_occupancy);
+            for (label, report) in link::listener_reports() {
+                let tags = [("listener", label.as_str())];
+                stat_client.gauge(
+                    &stats::key(Metric::ListenerHandshakeRate, &tags),
+                    report.handshakes_per_sec,
+                );
+                stat_client.gauge(
+                    &stats::key(Metric::ListenerActivePipes, &tags),
+                    report.active as f64,
+                );
+                stat_client.gauge(
+                    &stats::key(Metric::ListenerDuplicateRate, &tags),
+                    report.duplicate_rate,
+                );
+                stat_client.count(
+                    &stats::key(Metric::ListenerDecodeFailures, &tags),
+                    report.decode_failures as f64,
+                );
+            }
 
             stat_client.gauge(&cpukey, usage as f64);
             stat_client.gauge(&loadkey, BW_MULTIPLIER.load(Ordering::Relaxed));
         }
+        crate::asn::flush_asn_traffic();
         smol::Timer::after(Duration::from_secs(10)).await;
     }
 }
 
+/// Listens for direct sosistab2 connections for every exit identity.
 async fn pipe_listen() -> anyhow::Result<Infallible> {
-    let exit_hostname = CONFIG
-        .official()
-        .as_ref()
-        .map(|official| official.exit_hostname().to_owned())
-        .unwrap_or_default();
-    let exit_hostname2 = exit_hostname.to_string();
-    let bridge_pkt_key = move |bridge_group: &str| {
-        format!(
-            "raw_flow.{}.{}",
-            exit_hostname2.replace('.', "-"),
-            bridge_group.replace('.', "-")
-        )
-    };
+    let mut listeners: Vec<_> = ROOT_CTX
+        .identities
+        .iter()
+        .map(|identity| smolscale::spawn(identity_listen(identity.clone())))
+        .collect();
+    let first = listeners.remove(0);
+    listeners
+        .into_iter()
+        .fold(first.boxed(), |a, b| a.race(b).boxed())
+        .await
+}
 
+/// Listens for direct sosistab2 connections for one exit identity, uploading its "self-bridge" to the binder.
+async fn identity_listen(identity: Arc<ExitIdentity>) -> anyhow::Result<Infallible> {
     // TODO this key reuse is *probably* fine security-wise, but we might wanna switch this to something else
     // This hack allows the client to deterministically get the correct ObfsUdpPublic, which is important for selfhosted instances having constant keys.
-    let secret = ObfsUdpSecret::from_bytes(ROOT_CTX.sosistab2_sk.to_bytes());
-    let listen_addr = CONFIG
-        .sosistab2_listen()
+    let secret = ObfsUdpSecret::from_bytes(identity.sosistab2_sk.to_bytes());
+    let listen_addr: SocketAddr = identity
+        .sosistab2_listen
         .parse()
         .expect("cannot parse sosistab2 listening address");
+    let public_ip = if listen_addr.ip().is_unspecified() {
+        IpAddr::from(*MY_PUBLIC_IP)
+    } else {
+        listen_addr.ip()
+    };
 
     let udp_listener = ObfsUdpListener::bind(listen_addr, secret.clone())
         .await
@@ -167,78 +278,362 @@ async fn pipe_listen() -> anyhow::Result<Infallible> {
         .await
         .unwrap();
     // Upload a "self-bridge". sosistab2 bridges have the key field be the bincode-encoded pair of bridge key and e2e key
+    // a bridge is left out, since clients must reach its upstream exit through it rather than directly
     let mut _task = None;
-    if let Some(client) = ROOT_CTX.binder_client.clone() {
-        _task = Some(smolscale::spawn(async move {
-            loop {
-                let fallible = async {
-                    let mut unsigned_udp = BridgeDescriptor {
-                        is_direct: true,
-                        protocol: "sosistab2-obfsudp".into(),
-                        endpoint: SocketAddr::new((*MY_PUBLIC_IP).into(), listen_addr.port()),
-                        cookie: secret.to_public().as_bytes().to_vec().into(),
-                        exit_hostname: CONFIG.official().as_ref().unwrap().exit_hostname().into(),
-                        alloc_group: "direct".into(),
-                        update_time: SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                        exit_signature: Bytes::new(),
-                    };
-                    let sig = ROOT_CTX
-                        .signing_sk
-                        .sign(&bincode::serialize(&unsigned_udp).unwrap());
-                    unsigned_udp.exit_signature = sig.as_bytes().to_vec().into();
-                    client.add_bridge_route(unsigned_udp).await??;
-
-                    let mut unsigned_tcp = BridgeDescriptor {
-                        is_direct: true,
-                        protocol: "sosistab2-obfstls".into(),
-                        endpoint: SocketAddr::new((*MY_PUBLIC_IP).into(), listen_addr.port()),
-                        cookie: tls_cookie.clone(),
-                        exit_hostname: ROOT_CTX.exit_hostname().into(),
-                        alloc_group: "direct".into(),
-                        update_time: SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                        exit_signature: Bytes::new(),
-                    };
-                    let sig = ROOT_CTX
-                        .signing_sk
-                        .sign(&bincode::serialize(&unsigned_tcp).unwrap());
-                    unsigned_tcp.exit_signature = sig.as_bytes().to_vec().into();
-                    client.add_bridge_route(unsigned_tcp).await??;
-                    anyhow::Ok(())
-                };
-                if let Err(err) = fallible.await {
-                    log::warn!("failed to upload direct route: {:?}", err);
-                }
-                smol::Timer::after(Duration::from_secs(1)).await;
-            }
-        }));
+    if let Some(client) = ROOT_CTX
+        .binder_client
+        .clone()
+        .filter(|_| identity.retire_at.is_none() && CONFIG.bridge().is_none())
+    {
+        _task = Some(smolscale::spawn(upload_routes(
+            client,
+            identity.clone(),
+            SocketAddr::new(public_ip, listen_addr.port()),
+            tls_cookie,
+            "direct".into(),
+        )));
     }
     // we now enter the usual feeding loop
     log::info!(
         "listening on {}@{}:{}",
-        hex::encode(ROOT_CTX.sosistab2_sk.to_public().as_bytes()),
-        if listen_addr.ip().is_unspecified() {
-            IpAddr::from(*MY_PUBLIC_IP)
-        } else {
-            listen_addr.ip()
-        },
+        hex::encode(identity.sosistab2_sk.to_public().as_bytes()),
+        public_ip,
         listen_addr.port()
     );
 
+    let accept_loop = accept_pipes(
+        udp_listener,
+        tls_listener,
+        identity.clone(),
+        listen_addr.port().to_string(),
+    );
+    let Some(retire_at) = identity.retire_at else {
+        return accept_loop.await;
+    };
+    let retirement = async {
+        smol::Timer::at(retire_at).await;
+        anyhow::Ok(())
+    };
+    retirement
+        .race(async { accept_loop.await.map(|never| match never {}) })
+        .await?;
+    log::warn!(
+        "overlap window is over, no longer accepting old keys for {}",
+        identity.hostname
+    );
+    // the listeners are dropped, but the task stays, since ending it would end the whole listening loop
+    smol::future::pending().await
+}
+
+/// Keeps uploading the obfsudp and obfstls routes of a direct listener to the binder, while the exit is healthy.
+async fn upload_routes(
+    client: Arc<BinderClient>,
+    identity: Arc<ExitIdentity>,
+    endpoint: SocketAddr,
+    cookie: Bytes,
+    alloc_group: String,
+) -> Infallible {
+    loop {
+        let fallible = async {
+            // left to expire at the binder, so that clients stop being sent here
+            if !health::is_healthy() {
+                return anyhow::Ok(());
+            }
+            for protocol in ["sosistab2-obfsudp", "sosistab2-obfstls"] {
+                client
+                    .add_bridge_route(direct_route(
+                        &identity,
+                        protocol,
+                        endpoint,
+                        cookie.clone(),
+                        &alloc_group,
+                    ))
+                    .await??;
+            }
+            anyhow::Ok(())
+        };
+        if let Err(err) = fallible.await {
+            log::warn!(
+                "failed to upload direct route for {}: {:?}",
+                identity.hostname,
+                err
+            );
+        }
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// A signed route for the binder to hand out, through which clients reach the identity directly.
+fn direct_route(
+    identity: &ExitIdentity,
+    protocol: &str,
+    endpoint: SocketAddr,
+    cookie: Bytes,
+    alloc_group: &str,
+) -> BridgeDescriptor {
+    let mut unsigned = BridgeDescriptor {
+        is_direct: true,
+        protocol: protocol.into(),
+        endpoint,
+        cookie,
+        exit_hostname: identity.hostname.as_str().into(),
+        alloc_group: alloc_group.into(),
+        update_time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        exit_signature: Bytes::new(),
+    };
+    let sig = identity
+        .signing_sk
+        .sign(&bincode::serialize(&unsigned).unwrap());
+    unsigned.exit_signature = sig.as_bytes().to_vec().into();
+    unsigned
+}
+
+/// Accepts pipes for an identity from a pair of listeners, labelled in the listener stats by protocol and the given name.
+async fn accept_pipes(
+    udp_listener: ObfsUdpListener,
+    tls_listener: ObfsTlsListener,
+    identity: Arc<ExitIdentity>,
+    name: String,
+) -> anyhow::Result<Infallible> {
     loop {
         let pipe = udp_listener
             .accept_pipe()
             .race(tls_listener.accept_pipe())
             .await?;
+        if !admit_pipe(&identity, &pipe.peer_addr()) {
+            continue;
+        }
+        let label = format!("{}-{}", pipe.protocol(), name);
+        if CONFIG.bridge().is_some() {
+            link::listener(&label).on_handshake();
+            smolscale::spawn(async move {
+                if let Err(err) = bridge::forward(pipe).await {
+                    log::debug!("bridged pipe ended: {:?}", err);
+                }
+            })
+            .detach();
+            continue;
+        }
         if let Some(client) = ROOT_CTX.stat_client.as_ref() {
-            handle_pipe_v2(StatsPipe::new(pipe, client.clone(), bridge_pkt_key("SELF")));
+            handle_pipe_v2(
+                StatsPipe::new(
+                    pipe,
+                    client.clone(),
+                    stats::identity_key(
+                        Metric::RawFlow,
+                        &identity.hostname,
+                        &[("bridge_group", "SELF")],
+                    ),
+                ),
+                identity.clone(),
+                &label,
+            );
         } else {
-            handle_pipe_v2(pipe);
+            handle_pipe_v2(pipe, identity.clone(), &label);
+        }
+    }
+}
+
+/// Whether a new pipe from the given outer address may go on to a session with the identity: its keys must not be retired, and the handshake rate limit and country ACL must let it through.
+fn admit_pipe(identity: &ExitIdentity, peer_addr: &str) -> bool {
+    if identity.is_retired() {
+        log::debug!(
+            "dropping a pipe for the retired keys of {}",
+            identity.hostname
+        );
+        return false;
+    }
+    if !handshake_limit::admit(peer_addr) {
+        ROOT_CTX.incr_stat(Metric::HandshakeRateLimited);
+        return false;
+    }
+    if !identity.admits(peer_addr) {
+        ROOT_CTX.incr_stat(Metric::SessionCountryRejected);
+        return false;
+    }
+    true
+}
+
+/// Periodically publishes the exit's live status to the binder.
+async fn status_report_loop() -> anyhow::Result<Infallible> {
+    let (Some(official), Some(binder_client)) =
+        (CONFIG.official().as_ref(), ROOT_CTX.binder_client.as_ref())
+    else {
+        return smol::future::pending().await;
+    };
+    let report_config = official.status_report();
+    if report_config.fields().is_empty() {
+        log::info!("NOT sending status reports to the binder");
+        return smol::future::pending().await;
+    }
+    let mut timer = smol::Timer::interval(Duration::from_secs(report_config.interval()));
+    loop {
+        timer.next().await;
+        let mut report = serde_json::Map::new();
+        report.insert("exit_hostname".into(), ROOT_CTX.exit_hostname().into());
+        let main_identity = ROOT_CTX.main_identity();
+        for field in report_config.fields() {
+            match field {
+                StatusField::Sessions => {
+                    report.insert(
+                        "sessions".into(),
+                        ROOT_CTX.live_sessions.load(Ordering::Relaxed).into(),
+                    );
+                    report.insert("max_sessions".into(), CONFIG.max_sessions().into());
+                }
+                StatusField::Connections => {
+                    report.insert(
+                        "connections".into(),
+                        ROOT_CTX.conn_count.load(Ordering::Relaxed).into(),
+                    );
+                    report.insert(
+                        "max_connections".into(),
+                        CONFIG.max_proxied_connections().into(),
+                    );
+                }
+                StatusField::Bandwidth => {
+                    report.insert(
+                        "bandwidth".into(),
+                        ROOT_CTX.bw_usage.load(Ordering::Relaxed).into(),
+                    );
+                }
+                StatusField::Cpu => {
+                    report.insert(
+                        "cpu".into(),
+                        ROOT_CTX.cpu_usage.load(Ordering::Relaxed).into(),
+                    );
+                }
+                StatusField::LoadFactor => {
+                    report.insert(
+                        "load_factor".into(),
+                        ROOT_CTX.load_factor.load(Ordering::Relaxed).into(),
+                    );
+                }
+                StatusField::Keys => {
+                    report.insert(
+                        "signing_key".into(),
+                        hex::encode(main_identity.signing_sk.public).into(),
+                    );
+                    report.insert(
+                        "sosistab2_key".into(),
+                        hex::encode(main_identity.sosistab2_sk.to_public().as_bytes()).into(),
+                    );
+                }
+                StatusField::Health => {
+                    report.insert("egress_healthy".into(), health::is_healthy().into());
+                    report.insert("egress_latency_ms".into(), health::latency_ms().into());
+                }
+                StatusField::Draining => {
+                    report.insert(
+                        "draining".into(),
+                        ROOT_CTX.draining.load(Ordering::Relaxed).into(),
+                    );
+                }
+            }
+        }
+        match binder_client
+            .0
+            .call("report_exit_status", &[report.into()])
+            .await
+        {
+            Ok(Some(Ok(_))) => {}
+            Ok(Some(Err(err))) => log::warn!("binder rejected status report: {:?}", err),
+            Ok(None) => log::debug!("binder does not support status reports"),
+            Err(err) => log::warn!("failed to send status report: {:?}", err),
+        }
+    }
+}
+
+/// Periodically fetches the list of revoked client tokens from the binder. With `list_rollout`, a new list that kills sessions much faster than the last one has its added tokens taken back out.
+async fn revocation_loop() -> anyhow::Result<Infallible> {
+    let (Some(official), Some(binder_client)) =
+        (CONFIG.official().as_ref(), ROOT_CTX.binder_client.as_ref())
+    else {
+        return smol::future::pending().await;
+    };
+    let mut timer = smol::Timer::interval(Duration::from_secs(official.revocation_poll_secs()));
+    let staged = Staged::new("revocation list", &rollout::REVOKED_SESSIONS);
+    loop {
+        match binder_client.0.call("get_revoked_tokens", &[]).await {
+            Ok(Some(Ok(list))) => match serde_json::from_value::<HashSet<u64>>(list) {
+                Ok(list) => {
+                    let active = ROOT_CTX.revoked_tokens.read().clone();
+                    if let Some(list) =
+                        staged.offer(official.list_rollout().as_ref(), &active, list)
+                    {
+                        log::info!("revocation list now has {} tokens", list.len());
+                        *ROOT_CTX.revoked_tokens.write() = list;
+                        ROOT_CTX.revocation_event.notify(usize::MAX);
+                    }
+                }
+                Err(err) => log::warn!("binder sent a malformed revocation list: {:?}", err),
+            },
+            Ok(Some(Err(err))) => log::warn!("binder refused the revocation list: {:?}", err),
+            Ok(None) => log::debug!("binder does not support revocation lists"),
+            Err(err) => log::warn!("failed to fetch the revocation list: {:?}", err),
+        }
+        timer.next().await;
+    }
+}
+
+/// Applies the per-user speed limits that the binder hands out, e.g. to users who upgraded mid-session. They are polled as often as the revocation list, and with `list_rollout`, rolled back if smart channels start dropping much more.
+async fn rate_override_loop() -> anyhow::Result<Infallible> {
+    let (Some(official), Some(binder_client)) =
+        (CONFIG.official().as_ref(), ROOT_CTX.binder_client.as_ref())
+    else {
+        return smol::future::pending().await;
+    };
+    let mut timer = smol::Timer::interval(Duration::from_secs(official.revocation_poll_secs()));
+    let mut current: HashMap<u64, RateOverride> = HashMap::new();
+    let staged = Staged::new("rate overrides", &smartchan::DROPPED_ITEMS);
+    loop {
+        match binder_client.0.call("get_rate_overrides", &[]).await {
+            Ok(Some(Ok(list))) => {
+                match serde_json::from_value::<HashMap<u64, RateOverride>>(list) {
+                    Ok(list) => {
+                        if let Some(list) =
+                            staged.offer(official.list_rollout().as_ref(), &current, list)
+                        {
+                            for token_id in current
+                                .keys()
+                                .filter(|token_id| !list.contains_key(token_id))
+                            {
+                                ROOT_CTX.set_rate_override(*token_id, None);
+                            }
+                            for (token_id, rate) in list.iter() {
+                                if current.get(token_id) != Some(rate) {
+                                    ROOT_CTX.set_rate_override(*token_id, Some(*rate));
+                                }
+                            }
+                            current = list;
+                        }
+                    }
+                    Err(err) => log::warn!("binder sent malformed rate overrides: {:?}", err),
+                }
+            }
+            Ok(Some(Err(err))) => log::warn!("binder refused the rate overrides: {:?}", err),
+            Ok(None) => log::debug!("binder does not support rate overrides"),
+            Err(err) => log::warn!("failed to fetch the rate overrides: {:?}", err),
+        }
+        timer.next().await;
+    }
+}
+
+/// Enters drain mode on SIGUSR1 and leaves it on SIGUSR2.
+async fn drain_signal_loop() -> anyhow::Result<Infallible> {
+    let mut signals = Signals::new([Signal::Usr1, Signal::Usr2])?;
+    loop {
+        let signal = signals.next().await.context("signal stream ended")??;
+        let draining = signal == Signal::Usr1;
+        log::warn!("drain mode is now {}", if draining { "ON" } else { "OFF" });
+        ROOT_CTX.draining.store(draining, Ordering::Relaxed);
+        events::publish(ExitEvent::DrainChanged { draining });
+        if draining {
+            // a restart usually follows
+            vpn::save_lease_snapshot();
         }
     }
 }
@@ -248,7 +643,6 @@ async fn set_ratelimit_loop() -> anyhow::Result<Infallible> {
         .nat_external_iface()
         .clone()
         .unwrap_or_else(|| String::from("lo"));
-    let all_limit = *CONFIG.all_limit() as f64;
     let mut sys = System::new_all();
     let mut i = 0.0;
     let target_usage = 0.95f32;
@@ -256,9 +650,11 @@ async fn set_ratelimit_loop() -> anyhow::Result<Infallible> {
     let seconds = 10.0;
     let mut timer = smol::Timer::interval(Duration::from_secs_f64(seconds));
     let mut last_bw_used = 0u128;
+    let mut recent_deltas = VecDeque::new();
     loop {
         let first_time = last_bw_used == 0;
         timer.next().await;
+        let all_limit = ratelimit::current_all_limit() as f64;
         sys.refresh_all();
         let cpus = sys.cpus();
         let cpu_usage = cpus.iter().map(|c| c.cpu_usage() / 100.0).sum::<f32>() / cpus.len() as f32;
@@ -271,12 +667,28 @@ async fn set_ratelimit_loop() -> anyhow::Result<Infallible> {
 
         if let Some(client) = ROOT_CTX.stat_client.as_ref() {
             if !first_time {
-                let stat_key = format!("raw_exit_usage.{}", ROOT_CTX.exit_hostname_dashed());
-                client.count(&stat_key, bw_delta as f64);
+                client.count(&stats::key(Metric::RawExitUsage, &[]), bw_delta as f64);
             }
         }
 
         last_bw_used = bw_used;
+        if !first_time {
+            ROOT_CTX
+                .bw_usage
+                .store(bw_delta as f64 / seconds, Ordering::Relaxed);
+            // the samples of the last minute
+            recent_deltas.push_back(bw_delta);
+            if recent_deltas.len() as f64 * seconds > 60.0 {
+                recent_deltas.pop_front();
+            }
+            ROOT_CTX.bw_last_minute.store(
+                recent_deltas.iter().sum::<u128>() as f64 / (recent_deltas.len() as f64 * seconds),
+                Ordering::Relaxed,
+            );
+        }
+        ROOT_CTX
+            .cpu_usage
+            .store(cpu_usage as f64, Ordering::Relaxed);
         let bw_usage = (bw_delta as f64 / 1000.0 / all_limit / seconds) as f32;
         let total_usage = bw_usage.max(cpu_usage);
         let multiplier = if total_usage < target_usage * 0.8 {
@@ -288,7 +700,7 @@ async fn set_ratelimit_loop() -> anyhow::Result<Infallible> {
             let p = total_usage - target_usage;
             i += p;
             i = i.clamp(-20.0, 20.0);
-            divider = 1.0 + (1.0 * p + 0.4 * i).min(100.0).max(0.0);
+            divider = 1.0 + (1.0 * p + 0.4 * i).clamp(0.0, 100.0);
             log::info!("PID divider {divider}, p {p}, i {i}");
             BW_MULTIPLIER.swap(divider as f64, Ordering::Relaxed)
         };
@@ -297,3 +709,34 @@ async fn set_ratelimit_loop() -> anyhow::Result<Infallible> {
             .store(total_usage as f64 * multiplier, Ordering::Relaxed);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use sosistab2::MuxSecret;
+
+    use super::*;
+
+    fn identity(retire_at: Option<Instant>) -> ExitIdentity {
+        ExitIdentity {
+            hostname: "test.exit".into(),
+            signing_sk: ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng {}),
+            sosistab2_sk: MuxSecret::generate(),
+            sosistab2_listen: "[::]:0".into(),
+            retire_at,
+            country_acl: None,
+        }
+    }
+
+    #[test]
+    fn retired_keys_refuse_pipes() {
+        let current = identity(None);
+        let retiring = identity(Some(Instant::now() + Duration::from_secs(60)));
+        let retired = identity(Some(Instant::now() - Duration::from_secs(1)));
+        assert!(admit_pipe(&current, "203.0.113.7:1000"));
+        // within the overlap window, the old keys still work
+        assert!(admit_pipe(&retiring, "203.0.113.8:1000"));
+        assert!(!admit_pipe(&retired, "203.0.113.9:1000"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Instant,
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use smol::channel::{Receiver, Sender};
+use sosistab2::Stream;
+
+/// Upstream batches that may wait for the bonded session to take them.
+const UPSTREAM_BACKLOG: usize = 64;
+
+/// One logical VPN session carried over several sessions, such as one over Wi-Fi and one over cellular. The session that started the VPN owns it; sessions attached to it hand it their upstream packets and offer their streams as paths for its downstream ones.
+pub struct Bond {
+    pub paths: Arc<Paths<Stream>>,
+    up: Sender<Bytes>,
+}
+
+impl Bond {
+    /// Creates a bond, along with where the owning session receives the upstream batches of attached sessions.
+    pub fn new() -> (Arc<Self>, Receiver<Bytes>) {
+        let (up, recv) = smol::channel::bounded(UPSTREAM_BACKLOG);
+        let bond = Self {
+            paths: Default::default(),
+            up,
+        };
+        (Arc::new(bond), recv)
+    }
+
+    /// Hands an upstream batch to the owning session. False once that session's VPN has stopped.
+    pub async fn send_up(&self, batch: Bytes) -> bool {
+        self.up.send(batch).await.is_ok()
+    }
+}
+
+/// What a bonding token attaches a session to: the bond, and the user the attached session acts as.
+#[derive(Clone)]
+pub struct Attachment {
+    pub token_id: u64,
+    pub is_plus: bool,
+    pub bond: Weak<Bond>,
+}
+
+/// Bonds that sessions can attach to, by bonding token.
+static BONDS: Lazy<DashMap<[u8; 32], Attachment>> = Lazy::new(Default::default);
+
+/// Registers a bond, returning the token that attaches sessions to it.
+pub fn issue(attachment: Attachment) -> [u8; 32] {
+    let token: [u8; 32] = rand::thread_rng().gen();
+    BONDS.insert(token, attachment);
+    token
+}
+
+/// Forgets a bonding token, once the bond's VPN has stopped.
+pub fn revoke(token: [u8; 32]) {
+    BONDS.remove(&token);
+}
+
+/// What the given token attaches to, if the bond is still running.
+pub fn find(token: [u8; 32]) -> Option<Attachment> {
+    let attachment = BONDS.get(&token)?.clone();
+    attachment.bond.upgrade()?;
+    Some(attachment)
+}
+
+/// The streams that downstream packets of a bond may take, with when each last carried upstream packets.
+pub struct Paths<S> {
+    paths: Mutex<Vec<(u64, S, Instant)>>,
+    next_id: AtomicU64,
+}
+
+impl<S> Default for Paths<S> {
+    fn default() -> Self {
+        Self {
+            paths: Default::default(),
+            next_id: Default::default(),
+        }
+    }
+}
+
+impl<S: Clone> Paths<S> {
+    /// Adds a path, which stays until the returned guard is dropped.
+    pub fn add(self: &Arc<Self>, stream: S) -> PathGuard<S> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.paths.lock().push((id, stream, Instant::now()));
+        PathGuard {
+            paths: self.clone(),
+            id,
+        }
+    }
+
+    /// The path to send downstream packets on: the one that most recently carried upstream packets, since that is the network the client is using.
+    pub fn active(&self) -> Option<S> {
+        self.paths
+            .lock()
+            .iter()
+            .max_by_key(|(_, _, last_recv)| *last_recv)
+            .map(|(_, stream, _)| stream.clone())
+    }
+}
+
+/// A path of a [`Paths`], removed when this is dropped.
+pub struct PathGuard<S> {
+    paths: Arc<Paths<S>>,
+    id: u64,
+}
+
+impl<S> PathGuard<S> {
+    /// Records that the path just carried upstream packets.
+    pub fn on_recv(&self) {
+        if let Some(path) = self
+            .paths
+            .paths
+            .lock()
+            .iter_mut()
+            .find(|(id, _, _)| *id == self.id)
+        {
+            path.2 = Instant::now();
+        }
+    }
+}
+
+impl<S> Drop for PathGuard<S> {
+    fn drop(&mut self) {
+        self.paths.paths.lock().retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_path() {
+        let paths = Arc::new(Paths::default());
+        assert_eq!(paths.active(), None);
+        let wifi = paths.add("wifi");
+        let cellular = paths.add("cellular");
+        // a new path is used until the others carry something
+        assert_eq!(paths.active(), Some("cellular"));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        wifi.on_recv();
+        assert_eq!(paths.active(), Some("wifi"));
+        drop(wifi);
+        assert_eq!(paths.active(), Some("cellular"));
+        drop(cellular);
+        assert_eq!(paths.active(), None);
+
+        let (bond, _up) = Bond::new();
+        let token = issue(Attachment {
+            token_id: 7,
+            is_plus: true,
+            bond: Arc::downgrade(&bond),
+        });
+        assert_eq!(find(token).unwrap().token_id, 7);
+        drop(bond);
+        assert!(find(token).is_none());
+        revoke(token);
+    }
+}
@@ -1,4 +1,10 @@
-use crate::{asn::MY_PUBLIC_IP, root_ctx::ROOT_CTX, stats_pipe::StatsPipe};
+use crate::{
+    asn::MY_PUBLIC_IP,
+    health,
+    root_ctx::ROOT_CTX,
+    stats::{self, Metric},
+    stats_pipe::StatsPipe,
+};
 
 use super::session_v2::handle_pipe_v2;
 
@@ -27,6 +33,9 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+/// The load factor reported to the binder when the exit has hit one of its hard caps, is draining, or can't reach the internet.
+const FULL_LOAD_FACTOR: f64 = 100.0;
+
 /// The control protocol service.
 #[allow(clippy::type_complexity)]
 pub struct ControlService {
@@ -50,7 +59,12 @@ impl ControlService {
 }
 
 pub fn dummy_tls_config() -> TlsAcceptor {
-    let cert = rcgen::generate_simple_self_signed(vec!["helloworld.com".to_string()]).unwrap();
+    self_signed_tls_config("helloworld.com")
+}
+
+/// A TLS acceptor with a freshly generated self-signed certificate for the given hostname.
+pub fn self_signed_tls_config(hostname: &str) -> TlsAcceptor {
+    let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
     let cert_pem = cert.serialize_pem().unwrap();
     let cert_key = cert.serialize_private_key_pem();
     let identity = native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), cert_key.as_bytes())
@@ -59,24 +73,18 @@ pub fn dummy_tls_config() -> TlsAcceptor {
 }
 
 async fn forward_and_upload(
-    listener: impl PipeListener + Send + Sync + 'static,
+    listener: impl PipeListener + 'static,
     bd_template: BridgeDescriptor,
 ) -> Infallible {
     ROOT_CTX.control_count.fetch_add(1, Ordering::Relaxed);
     scopeguard::defer!({
         ROOT_CTX.control_count.fetch_sub(1, Ordering::Relaxed);
     });
-    let bridge_pkt_key = {
-        let exit_hostname = ROOT_CTX.exit_hostname_dashed();
-        move |bridge_group: &str| {
-            format!(
-                "raw_flow.{}.{}",
-                exit_hostname.replace('.', "-"),
-                bridge_group.replace('.', "-")
-            )
-        }
-    };
-    let flow_key = bridge_pkt_key(&bd_template.alloc_group);
+    let flow_key = stats::key(
+        Metric::RawFlow,
+        &[("bridge_group", bd_template.alloc_group.as_str())],
+    );
+    let label = format!("{}-{}", bd_template.protocol, bd_template.alloc_group);
     let _forwarder = {
         smolscale::spawn(async move {
             loop {
@@ -85,9 +93,13 @@ async fn forward_and_upload(
                     .await
                     .expect("oh no how did this happen");
                 if let Some(stat_client) = ROOT_CTX.stat_client.as_ref() {
-                    handle_pipe_v2(StatsPipe::new(pipe, stat_client.clone(), flow_key.clone()));
+                    handle_pipe_v2(
+                        StatsPipe::new(pipe, stat_client.clone(), flow_key.clone()),
+                        ROOT_CTX.main_identity().clone(),
+                        &label,
+                    );
                 } else {
-                    handle_pipe_v2(pipe);
+                    handle_pipe_v2(pipe, ROOT_CTX.main_identity().clone(), &label);
                 }
             }
         })
@@ -98,7 +110,15 @@ async fn forward_and_upload(
 #[async_trait]
 impl BridgeExitProtocol for ControlService {
     async fn load_factor(&self) -> f64 {
-        ROOT_CTX.load_factor.load(Ordering::Relaxed)
+        let load_factor = ROOT_CTX.load_factor.load(Ordering::Relaxed);
+        if ROOT_CTX.session_refusal().is_some()
+            || ROOT_CTX.connections_full()
+            || !health::is_healthy()
+        {
+            load_factor.max(FULL_LOAD_FACTOR)
+        } else {
+            load_factor
+        }
     }
     async fn advertise_raw_v2(
         &self,
@@ -114,7 +134,7 @@ impl BridgeExitProtocol for ControlService {
                 };
                 let cookie = *blake3::hash(
                     &(
-                        ROOT_CTX.signing_sk.secret.to_bytes(),
+                        ROOT_CTX.main_identity().signing_sk.secret.to_bytes(),
                         bridge_addr,
                         protocol,
                         "tls-cookie-hash-gen-lala",
@@ -174,7 +194,7 @@ impl BridgeExitProtocol for ControlService {
                 let secret_key = {
                     let mut hash = *blake3::hash(
                         &(
-                            ROOT_CTX.signing_sk.secret.to_bytes(),
+                            ROOT_CTX.main_identity().signing_sk.secret.to_bytes(),
                             bridge_addr,
                             protocol,
                             "x25519-hash-gen-lala-ohno-v2",
@@ -257,6 +277,7 @@ async fn binder_upload_loop(bd_template: BridgeDescriptor) -> Infallible {
             let mut unsigned = bd_template.clone();
             unsigned.update_time = route_unixtime;
             let signature = ROOT_CTX
+                .main_identity()
                 .signing_sk
                 .sign(&bincode::serialize(&unsigned).unwrap())
                 .to_bytes()
@@ -0,0 +1,113 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+
+use crate::{config::CONFIG, ratelimit::TokenBucket, redact};
+
+/// Sources whose buckets are remembered. Past this, the least recently seen are forgotten, which only ever gives them a fresh burst.
+const MAX_SOURCES: u64 = 100_000;
+
+/// How long a quiet source's bucket is kept. Any bucket is full again well before this.
+const SOURCE_IDLE: Duration = Duration::from_secs(600);
+
+/// Token buckets of handshakes by source address and by source subnet.
+struct HandshakeLimiter {
+    per_ip: Cache<IpAddr, Arc<TokenBucket>>,
+    per_subnet: Cache<IpAddr, Arc<TokenBucket>>,
+    ip_rate: (u64, u64),
+    subnet_rate: (u64, u64),
+}
+
+impl HandshakeLimiter {
+    fn new(ip_rate: (u64, u64), subnet_rate: (u64, u64)) -> Self {
+        let cache = || {
+            Cache::builder()
+                .max_capacity(MAX_SOURCES)
+                .time_to_idle(SOURCE_IDLE)
+                .build()
+        };
+        Self {
+            per_ip: cache(),
+            per_subnet: cache(),
+            ip_rate,
+            subnet_rate,
+        }
+    }
+
+    /// Takes a handshake out of the buckets of the source, returning whether there was one to take.
+    fn admit(&self, source: IpAddr) -> bool {
+        let take = |cache: &Cache<IpAddr, Arc<TokenBucket>>, key, (rate, burst)| {
+            rate == 0
+                || cache
+                    .get_with(key, || Arc::new(TokenBucket::full(rate, burst)))
+                    .try_take(1)
+        };
+        // the subnet bucket is only charged for handshakes that the address bucket lets through, so that one flooding address doesn't lock out its neighbours on its own
+        take(&self.per_ip, mask(source, 32, 64), self.ip_rate)
+            && take(&self.per_subnet, mask(source, 24, 48), self.subnet_rate)
+    }
+}
+
+/// The address with all but its first `v4_bits` or `v6_bits` bits cleared.
+fn mask(addr: IpAddr, v4_bits: u32, v6_bits: u32) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - v4_bits).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - v6_bits).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
+
+static LIMITER: Lazy<HandshakeLimiter> = Lazy::new(|| {
+    let limits = CONFIG.handshake_limits();
+    HandshakeLimiter::new(
+        (limits.per_ip_rate(), limits.per_ip_burst()),
+        (limits.per_subnet_rate(), limits.per_subnet_burst()),
+    )
+});
+
+/// Whether a pipe from the given outer address is within the handshake limits of its source.
+pub fn admit(peer_addr: &str) -> bool {
+    let Ok(addr) = peer_addr.parse::<SocketAddr>() else {
+        return true;
+    };
+    let source = match addr.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr.ip()),
+        ip => ip,
+    };
+    let admitted = LIMITER.admit(source);
+    if !admitted {
+        log::debug!("too many handshakes from {}", redact::client(source));
+    }
+    admitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let limiter = HandshakeLimiter::new((1, 3), (1, 5));
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.admit(ip("192.0.2.1")));
+        }
+        assert!(!limiter.admit(ip("192.0.2.1")));
+        // the neighbours share what is left of the /24's burst
+        assert!(limiter.admit(ip("192.0.2.2")));
+        assert!(limiter.admit(ip("192.0.2.3")));
+        assert!(!limiter.admit(ip("192.0.2.4")));
+        assert!(limiter.admit(ip("198.51.100.1")));
+        assert_eq!(mask(ip("2001:db8:1:2:3::1"), 24, 48), ip("2001:db8:1::"));
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::Hasher,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
+use sosistab2::Pipe;
+
+use crate::{
+    config::CONFIG,
+    redact,
+    relay::{RelayInfo, RelayStats},
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+/// How many recent datagrams of a session are remembered to spot duplicates.
+const DUPLICATE_WINDOW: usize = 4096;
+
+/// Link statistics of every live session, by multiplex key.
+pub static LINKS: Lazy<DashMap<blake3::Hash, Arc<LinkStats>>> = Lazy::new(Default::default);
+
+/// Transport statistics of every listener, by label.
+static LISTENERS: Lazy<DashMap<String, Arc<ListenerStats>>> = Lazy::new(Default::default);
+
+/// The statistics of the listener with the given label: the protocol and port of a direct listener, or the protocol and bridge group of bridge listeners, whose ports come and go.
+pub fn listener(label: &str) -> Arc<ListenerStats> {
+    if let Some(stats) = LISTENERS.get(label) {
+        return stats.clone();
+    }
+    LISTENERS.entry(label.to_owned()).or_default().clone()
+}
+
+/// Reports of every listener since the previous call, by label.
+pub fn listener_reports() -> Vec<(String, ListenerReport)> {
+    LISTENERS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().report()))
+        .collect()
+}
+
+/// What the pipes accepted by one listener carried. Handshakes that keep coming with pipes failing early and duplicates rising point at interference with the listener, rather than load.
+#[derive(Default)]
+pub struct ListenerStats {
+    handshakes: AtomicU64,
+    /// Pipes attached to a session
+    active: AtomicU64,
+    datagrams_up: AtomicU64,
+    duplicates: AtomicU64,
+    /// Pipes that failed before carrying any datagram, as when their handshake was mangled
+    decode_failures: AtomicU64,
+    /// Counters and time as of the previous report
+    reported: Mutex<Option<(Instant, [u64; 4])>>,
+}
+
+/// What a listener did since its previous report.
+#[derive(Debug, PartialEq)]
+pub struct ListenerReport {
+    pub handshakes_per_sec: f64,
+    pub active: u64,
+    /// Share of datagrams from clients that were duplicates, which is as close to a retransmission rate as can be seen outside sosistab2
+    pub duplicate_rate: f64,
+    pub decode_failures: u64,
+}
+
+impl ListenerStats {
+    /// Counts a pipe accepted by the listener.
+    pub fn on_handshake(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> ListenerReport {
+        let now = Instant::now();
+        let counters = [
+            self.handshakes.load(Ordering::Relaxed),
+            self.datagrams_up.load(Ordering::Relaxed),
+            self.duplicates.load(Ordering::Relaxed),
+            self.decode_failures.load(Ordering::Relaxed),
+        ];
+        let (since, last) = self
+            .reported
+            .lock()
+            .replace((now, counters))
+            .unwrap_or((now, [0; 4]));
+        let [handshakes, datagrams_up, duplicates, decode_failures] =
+            [0, 1, 2, 3].map(|i| counters[i] - last[i]);
+        let secs = now.duration_since(since).as_secs_f64();
+        ListenerReport {
+            handshakes_per_sec: if secs > 0.0 {
+                handshakes as f64 / secs
+            } else {
+                0.0
+            },
+            active: self.active.load(Ordering::Relaxed),
+            duplicate_rate: if datagrams_up > 0 {
+                duplicates as f64 / datagrams_up as f64
+            } else {
+                0.0
+            },
+            decode_failures,
+        }
+    }
+}
+
+/// What the pipes of one session carried. sosistab2 doesn't expose its own loss and retransmission counters, so these are counted on the encrypted datagrams as they pass between the pipes and the multiplex.
+pub struct LinkStats {
+    started: Instant,
+    /// The token ID of the client, or 0 before it authenticates
+    pub token_id: AtomicU64,
+    pipes: AtomicU64,
+    /// Outer address of the latest pipe, never shown to operators
+    peer_addr: Mutex<String>,
+    rebinds: AtomicU64,
+    protocol: Mutex<String>,
+    datagrams_up: AtomicU64,
+    datagrams_down: AtomicU64,
+    duplicates: AtomicU64,
+    recent: Mutex<(HashSet<u64>, VecDeque<u64>)>,
+    /// Proxied connections open right now, by an ID unique within the session
+    relays: Mutex<HashMap<u64, Arc<RelayStats>>>,
+    next_relay: AtomicU64,
+}
+
+impl Default for LinkStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            token_id: AtomicU64::new(0),
+            pipes: AtomicU64::new(0),
+            peer_addr: Mutex::new(String::new()),
+            rebinds: AtomicU64::new(0),
+            protocol: Mutex::new(String::new()),
+            datagrams_up: AtomicU64::new(0),
+            datagrams_down: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            recent: Default::default(),
+            relays: Default::default(),
+            next_relay: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The link statistics of a session, as shown by the admin API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LinkInfo {
+    /// Prefix of the multiplex key, which tells sessions apart
+    pub session: String,
+    /// The token ID of the client, once it has authenticated
+    pub client: Option<u64>,
+    /// Protocol of the latest pipe
+    pub protocol: String,
+    pub age_secs: u64,
+    /// Pipes attached so far. More than one means the client reconnected its transport.
+    pub pipes: u64,
+    /// Pipes attached from a different outer address than the one before, as when the client's NAT rebinds or it switches networks.
+    pub rebinds: u64,
+    pub datagrams_up: u64,
+    pub datagrams_down: u64,
+    /// Datagrams from the client that were exact copies of recent ones, which sosistab2's anti-replay then drops. Duplicated by the network, or replayed.
+    pub duplicates: u64,
+}
+
+impl LinkStats {
+    /// Wraps a newly attached pipe of the session to count what it carries. None if it comes from a new outer address and `roaming` is off, in which case the pipe must be dropped.
+    pub fn attach(
+        self: &Arc<Self>,
+        pipe: impl Pipe,
+        listener: Arc<ListenerStats>,
+    ) -> Option<CountedPipe<impl Pipe>> {
+        if !self.rebind(pipe.peer_addr()) {
+            ROOT_CTX.incr_stat(Metric::PipeRebindRefused);
+            return None;
+        }
+        if self.pipes.fetch_add(1, Ordering::Relaxed) > 0 {
+            ROOT_CTX.incr_stat(Metric::PipeReattached);
+        }
+        *self.protocol.lock() = pipe.protocol().to_owned();
+        listener.active.fetch_add(1, Ordering::Relaxed);
+        Some(CountedPipe {
+            inner: pipe,
+            stats: self.clone(),
+            listener,
+            received: AtomicBool::new(false),
+        })
+    }
+
+    /// Records the outer address of a new pipe, counting a rebind if it differs from the latest one. Returns whether the pipe may join the session.
+    fn rebind(&self, peer_addr: String) -> bool {
+        let mut current = self.peer_addr.lock();
+        if current.is_empty() || *current == peer_addr {
+            *current = peer_addr;
+            return true;
+        }
+        if !CONFIG.roaming() {
+            return false;
+        }
+        log::debug!(
+            "session rebound from {} to {}",
+            redact::client(&*current),
+            redact::client(&peer_addr)
+        );
+        *current = peer_addr;
+        self.rebinds.fetch_add(1, Ordering::Relaxed);
+        ROOT_CTX.incr_stat(Metric::PipeRebound);
+        true
+    }
+
+    /// Counts a datagram from the client, returning whether it duplicates a recent one.
+    fn record_up(&self, dgram: &[u8]) -> bool {
+        self.datagrams_up.fetch_add(1, Ordering::Relaxed);
+        // sosistab2 datagrams start with a random nonce, so their first bytes tell them apart
+        let mut hasher = FxHasher::default();
+        hasher.write(&dgram[..dgram.len().min(32)]);
+        hasher.write_usize(dgram.len());
+        let fingerprint = hasher.finish();
+        let mut recent = self.recent.lock();
+        let (seen, order) = &mut *recent;
+        if !seen.insert(fingerprint) {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            ROOT_CTX.incr_stat(Metric::PipeDuplicate);
+            return true;
+        }
+        order.push_back(fingerprint);
+        if order.len() > DUPLICATE_WINDOW {
+            if let Some(oldest) = order.pop_front() {
+                seen.remove(&oldest);
+            }
+        }
+        false
+    }
+
+    /// Lists a proxied connection of the session until the returned guard is dropped.
+    pub fn track_relay(self: &Arc<Self>, relay: Arc<RelayStats>) -> TrackedRelay {
+        let id = self.next_relay.fetch_add(1, Ordering::Relaxed);
+        self.relays.lock().insert(id, relay);
+        TrackedRelay {
+            link: self.clone(),
+            id,
+        }
+    }
+
+    /// The statistics of the session's open proxied connections, oldest first.
+    pub fn relays(&self) -> Vec<RelayInfo> {
+        let mut relays: Vec<_> = self
+            .relays
+            .lock()
+            .iter()
+            .map(|(id, relay)| (*id, relay.clone()))
+            .collect();
+        relays.sort_unstable_by_key(|(id, _)| *id);
+        // TCP_INFO is read outside the lock
+        relays.into_iter().map(|(_, relay)| relay.info()).collect()
+    }
+
+    pub fn info(&self, key: &blake3::Hash) -> LinkInfo {
+        let token_id = self.token_id.load(Ordering::Relaxed);
+        LinkInfo {
+            session: session_id(key),
+            client: (token_id != 0).then_some(token_id),
+            protocol: self.protocol.lock().clone(),
+            age_secs: self.started.elapsed().as_secs(),
+            pipes: self.pipes.load(Ordering::Relaxed),
+            rebinds: self.rebinds.load(Ordering::Relaxed),
+            datagrams_up: self.datagrams_up.load(Ordering::Relaxed),
+            datagrams_down: self.datagrams_down.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps a proxied connection in its session's list while it lives.
+pub struct TrackedRelay {
+    link: Arc<LinkStats>,
+    id: u64,
+}
+
+impl Drop for TrackedRelay {
+    fn drop(&mut self) {
+        self.link.relays.lock().remove(&self.id);
+    }
+}
+
+/// A pipe that counts its datagrams towards its session's link statistics and its listener's.
+pub struct CountedPipe<P> {
+    inner: P,
+    stats: Arc<LinkStats>,
+    listener: Arc<ListenerStats>,
+    /// Whether any datagram came through
+    received: AtomicBool,
+}
+
+impl<P> Drop for CountedPipe<P> {
+    fn drop(&mut self) {
+        self.listener.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl<P: Pipe> Pipe for CountedPipe<P> {
+    fn send(&self, to_send: Bytes) {
+        self.stats.datagrams_down.fetch_add(1, Ordering::Relaxed);
+        self.inner.send(to_send)
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let dgram = match self.inner.recv().await {
+            Ok(dgram) => dgram,
+            Err(err) => {
+                if !self.received.swap(true, Ordering::Relaxed) {
+                    self.listener
+                        .decode_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                return Err(err);
+            }
+        };
+        self.received.store(true, Ordering::Relaxed);
+        self.listener.datagrams_up.fetch_add(1, Ordering::Relaxed);
+        if self.stats.record_up(&dgram) {
+            self.listener.duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(dgram)
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.inner.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.inner.peer_addr()
+    }
+}
+
+/// How a session is named to operators: a prefix of its multiplex key, which identifies nothing about the client.
+pub fn session_id(key: &blake3::Hash) -> String {
+    key.to_hex()[..16].to_owned()
+}
+
+/// The link statistics of the live session with the given ID, as [`session_id`] names it.
+pub fn find_link(session: &str) -> Option<Arc<LinkStats>> {
+    LINKS
+        .iter()
+        .find(|entry| session_id(entry.key()) == session)
+        .map(|entry| entry.value().clone())
+}
+
+/// The link statistics of every live session.
+pub fn all_links() -> Vec<LinkInfo> {
+    LINKS
+        .iter()
+        .map(|entry| entry.value().info(entry.key()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates() {
+        let stats = LinkStats::default();
+        let first = [1u8; 100];
+        let second = [2u8; 100];
+        assert!(!stats.record_up(&first));
+        assert!(!stats.record_up(&second));
+        assert!(stats.record_up(&first));
+        // forgotten once it falls out of the window
+        for i in 0..DUPLICATE_WINDOW as u32 {
+            stats.record_up(&i.to_be_bytes());
+        }
+        assert!(!stats.record_up(&first));
+        let info = stats.info(&blake3::hash(b"session"));
+        assert_eq!(info.duplicates, 1);
+        assert_eq!(info.datagrams_up, DUPLICATE_WINDOW as u64 + 4);
+    }
+
+    #[test]
+    fn rebinds() {
+        let stats = LinkStats::default();
+        assert!(stats.rebind("203.0.113.5:4000".into()));
+        assert!(stats.rebind("203.0.113.5:4000".into()));
+        // a NAT rebinding, then a switch to another network
+        assert!(stats.rebind("203.0.113.5:4001".into()));
+        assert!(stats.rebind("198.51.100.7:5000".into()));
+        assert_eq!(stats.info(&blake3::hash(b"session")).rebinds, 2);
+    }
+
+    #[test]
+    fn listener_report() {
+        let stats = ListenerStats::default();
+        stats.on_handshake();
+        stats.datagrams_up.store(10, Ordering::Relaxed);
+        stats.duplicates.store(1, Ordering::Relaxed);
+        stats.decode_failures.store(2, Ordering::Relaxed);
+        let first = stats.report();
+        assert_eq!(first.duplicate_rate, 0.1);
+        assert_eq!(first.decode_failures, 2);
+        // later reports only cover what happened since
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        stats.on_handshake();
+        stats.datagrams_up.store(20, Ordering::Relaxed);
+        let second = stats.report();
+        assert_eq!(second.duplicate_rate, 0.0);
+        assert_eq!(second.decode_failures, 0);
+        assert!(second.handshakes_per_sec > 0.0 && second.handshakes_per_sec <= 100.0);
+    }
+
+    #[test]
+    fn tracked_relays() {
+        let stats = Arc::new(LinkStats::default());
+        let first = Arc::new(RelayStats::new("example.com:443".into(), None));
+        let second = Arc::new(RelayStats::new("example.org:80".into(), None));
+        let tracked_first = stats.track_relay(first.clone());
+        let _tracked_second = stats.track_relay(second);
+        first.record_up(100);
+        first.record_down(5000);
+        let relays = stats.relays();
+        assert_eq!(relays.len(), 2);
+        assert_eq!(relays[0].destination, "example.com:443");
+        assert_eq!((relays[0].bytes_up, relays[0].bytes_down), (100, 5000));
+        assert_eq!(relays[0].rtt_ms, None);
+        drop(tracked_first);
+        let relays = stats.relays();
+        assert_eq!(relays.len(), 1);
+        assert_eq!(relays[0].destination, "example.org:80");
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use serde::Serialize;
+use smol::io::{AsyncRead, AsyncWrite};
+
+/// Prefix of a stats frame. Like close frames, stats frames are unreliable datagrams on the @client-exit stream, and only clients that ask for them get them.
+pub const STATS_FRAME_MAGIC: &[u8] = b"geph-stats:";
+
+/// Traffic counters of one session.
+#[derive(Default)]
+pub struct SessionMeter {
+    /// Bytes from the client
+    pub up: AtomicU64,
+    /// Bytes to the client
+    pub down: AtomicU64,
+    /// VPN packets to the client dropped because they couldn't be sent fast enough
+    pub dropped: AtomicU64,
+}
+
+/// What a stats frame tells the client.
+#[derive(Serialize, Debug)]
+pub struct StatsFrame {
+    /// Bytes per second from the client since the last frame
+    pub up_bps: u64,
+    /// Bytes per second to the client since the last frame
+    pub down_bps: u64,
+    /// VPN packets dropped so far
+    pub dropped: u64,
+    /// Current speed limit in KB/s, if any
+    pub limit_kb: Option<u32>,
+    /// Bytes left in the daily quota, if any
+    pub daily_quota_left: Option<u64>,
+    /// Bytes left in the monthly quota, if any
+    pub monthly_quota_left: Option<u64>,
+}
+
+impl StatsFrame {
+    /// The frame as sent on the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bts = STATS_FRAME_MAGIC.to_vec();
+        bts.extend_from_slice(serde_json::to_string(self).unwrap().as_bytes());
+        bts
+    }
+}
+
+/// A stream that counts the bytes read from and written to it towards a session's meter.
+#[derive(Clone)]
+pub struct Metered<S> {
+    inner: S,
+    meter: Arc<SessionMeter>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, meter: Arc<SessionMeter>) -> Self {
+        Self { inner, meter }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &res {
+            self.meter.up.fetch_add(*n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &res {
+            self.meter.down.fetch_add(*n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
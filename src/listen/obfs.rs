@@ -0,0 +1,188 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use smol::{prelude::*, Task};
+use sosistab2_obfstls::ObfsTlsListener;
+use sosistab2_obfsudp::{ObfsUdpListener, ObfsUdpSecret};
+
+use crate::{
+    asn::MY_PUBLIC_IP,
+    config::{ObfsListenerConfig, CONFIG},
+    identity::ExitIdentity,
+    listen::control::self_signed_tls_config,
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+use super::{accept_pipes, upload_routes};
+
+/// Ports tried before a generation gives up on binding.
+const BIND_ATTEMPTS: usize = 10;
+
+/// Runs every configured obfs listener, forever.
+pub async fn obfs_listen() -> anyhow::Result<Infallible> {
+    let listeners: Vec<_> = CONFIG
+        .obfs_listeners()
+        .iter()
+        .map(|config| smolscale::spawn(serve(config.clone())))
+        .collect();
+    if listeners.is_empty() {
+        return smol::future::pending().await;
+    }
+    futures_util::future::select_all(listeners).await.0
+}
+
+/// Runs one obfs listener, moving it to fresh keys and a fresh port every `rotate_secs`.
+async fn serve(config: ObfsListenerConfig) -> anyhow::Result<Infallible> {
+    let identity = ROOT_CTX.main_identity().clone();
+    let mut port = None;
+    loop {
+        let mut current = Generation::start(&config, identity.clone(), port).await?;
+        port = Some(current.port);
+        let Some(rotate_secs) = config.rotate_secs() else {
+            return current.accept.await;
+        };
+        let rotated = async {
+            smol::Timer::after(Duration::from_secs(rotate_secs)).await;
+            anyhow::Ok(())
+        };
+        let failed = async {
+            let never = (&mut current.accept).await?;
+            match never {}
+        };
+        rotated.race(failed).await?;
+        // the binder stops handing out the old route right away, but clients that already got it can still connect for a while
+        current.upload = None;
+        let overlap = Duration::from_secs(config.rotation_overlap_secs());
+        smolscale::spawn(async move {
+            smol::Timer::after(overlap).await;
+            drop(current);
+        })
+        .detach();
+        ROOT_CTX.incr_stat(Metric::ListenerRotated);
+    }
+}
+
+/// One set of keys, certificate, and port of an obfs listener. Dropping it closes its listeners.
+struct Generation {
+    port: u16,
+    accept: Task<anyhow::Result<Infallible>>,
+    upload: Option<Task<Infallible>>,
+}
+
+impl Generation {
+    /// Binds a new generation on a random port of the listener's range, other than the previous one.
+    async fn start(
+        config: &ObfsListenerConfig,
+        identity: Arc<ExitIdentity>,
+        previous: Option<u16>,
+    ) -> anyhow::Result<Self> {
+        let mut attempts = 0;
+        loop {
+            let port = next_port(config.ports(), previous);
+            match Self::bind(config, identity.clone(), port).await {
+                Ok(generation) => return Ok(generation),
+                Err(err) if attempts + 1 < BIND_ATTEMPTS => {
+                    log::warn!(
+                        "obfs listener {} cannot use port {}: {:?}",
+                        config.name(),
+                        port,
+                        err
+                    );
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn bind(
+        config: &ObfsListenerConfig,
+        identity: Arc<ExitIdentity>,
+        port: u16,
+    ) -> anyhow::Result<Self> {
+        let listen_addr = SocketAddr::new(config.listen_ip(), port);
+        let secret = ObfsUdpSecret::generate();
+        let cookie = Bytes::copy_from_slice(secret.to_public().as_bytes());
+        let udp_listener = ObfsUdpListener::bind(listen_addr, secret)
+            .await
+            .context("cannot bind obfsudp")?;
+        let tls_listener = ObfsTlsListener::bind(
+            listen_addr,
+            self_signed_tls_config(config.tls_name()),
+            cookie.clone(),
+        )
+        .await
+        .context("cannot bind obfstls")?;
+        log::info!(
+            "obfs listener {} listening on {}@{}",
+            config.name(),
+            hex::encode(&cookie),
+            listen_addr
+        );
+
+        let public_ip = if listen_addr.ip().is_unspecified() {
+            IpAddr::from(*MY_PUBLIC_IP)
+        } else {
+            listen_addr.ip()
+        };
+        // as with the main listener, a bridge's routes would send clients past their upstream exit
+        let upload = ROOT_CTX
+            .binder_client
+            .clone()
+            .filter(|_| CONFIG.bridge().is_none())
+            .map(|client| {
+                smolscale::spawn(upload_routes(
+                    client,
+                    identity.clone(),
+                    SocketAddr::new(public_ip, port),
+                    cookie,
+                    config.alloc_group().clone(),
+                ))
+            });
+        let accept = smolscale::spawn(accept_pipes(
+            udp_listener,
+            tls_listener,
+            identity,
+            config.name().clone(),
+        ));
+        Ok(Self {
+            port,
+            accept,
+            upload,
+        })
+    }
+}
+
+/// A random port in the inclusive range, other than the current one whenever the range allows it.
+fn next_port(ports: [u16; 2], current: Option<u16>) -> u16 {
+    let [first, last] = ports;
+    loop {
+        let port = fastrand::u16(first..=last);
+        if first == last || Some(port) != current {
+            return port;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_ports() {
+        assert_eq!(next_port([4000, 4000], Some(4000)), 4000);
+        for _ in 0..1000 {
+            let port = next_port([4000, 4001], Some(4000));
+            assert_eq!(port, 4001);
+            let port = next_port([5000, 5999], Some(5500));
+            assert!((5000..=5999).contains(&port) && port != 5500);
+        }
+    }
+}
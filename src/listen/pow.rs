@@ -0,0 +1,143 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use smol::{
+    io::{AsyncBufReadExt, BufReader},
+    stream::StreamExt,
+};
+use smol_timeout::TimeoutExt;
+use sosistab2::{Multiplex, Stream};
+
+use crate::{close_reason::CloseReason, config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Label of the stream on which clients solve proof-of-work challenges.
+pub const POW_PSEUDOHOST: &str = "@pow";
+
+/// A challenge, sent as the first line of a `@pow` stream.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Challenge {
+    /// Hex-encoded random bytes
+    challenge: String,
+    /// Leading zero bits that blake3(challenge || nonce as 8 little-endian bytes) must have
+    difficulty: u32,
+}
+
+/// A client's answer to a [`Challenge`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Solution {
+    nonce: u64,
+}
+
+/// New sessions in the current and previous second, as (current second, count, previous count).
+static NEW_SESSIONS: Mutex<(u64, u64, u64)> = Mutex::new((0, 0, 0));
+
+/// Counts a new session, returning the difficulty that it must solve before getting any session state, if the exit is under a handshake flood.
+pub fn on_new_session() -> Option<u32> {
+    let pow = CONFIG.pow().as_ref()?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let rate = count_session(&mut NEW_SESSIONS.lock(), now);
+    (rate > pow.sessions_per_sec()).then_some(pow.difficulty())
+}
+
+/// Counts a session in the window, returning the sessions per second so far.
+fn count_session(window: &mut (u64, u64, u64), now: u64) -> u64 {
+    let (second, count, previous) = window;
+    if *second != now {
+        *previous = if *second + 1 == now { *count } else { 0 };
+        *second = now;
+        *count = 0;
+    }
+    *count += 1;
+    (*count).max(*previous)
+}
+
+/// Whether the nonce solves the challenge.
+fn verify(challenge: &[u8], nonce: u64, difficulty: u32) -> bool {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(challenge);
+    hasher.update(&nonce.to_le_bytes());
+    let hash = hasher.finalize();
+    u128::from_be_bytes(*arrayref::array_ref![hash.as_bytes(), 0, 16]).leading_zeros() >= difficulty
+}
+
+/// Waits for the client to solve a challenge on a `@pow` stream. Streams it opens in the meantime are closed with [`CloseReason::PowRequired`], which tells newer clients to open one, while older clients just fail until the flood passes.
+pub async fn demand(mux: &Multiplex, difficulty: u32) -> anyhow::Result<()> {
+    ROOT_CTX.incr_stat(Metric::PowChallenged);
+    let deadline = Duration::from_secs(CONFIG.handshake_timeout_secs());
+    let solve = async {
+        loop {
+            let stream = mux.accept_conn().await?;
+            if stream.label() != POW_PSEUDOHOST {
+                CloseReason::PowRequired.send_frame(&stream).await;
+                continue;
+            }
+            if serve(stream, difficulty).await? {
+                return anyhow::Ok(());
+            }
+        }
+    };
+    let result = solve
+        .timeout(deadline)
+        .await
+        .context("no proof of work in time")
+        .and_then(|result| result);
+    ROOT_CTX.incr_stat(if result.is_ok() {
+        Metric::PowSolved
+    } else {
+        Metric::PowFailed
+    });
+    result
+}
+
+/// Runs one challenge on a `@pow` stream, returning whether the client solved it. Clients may open one even when the exit isn't asking, and get a difficulty of zero.
+pub async fn serve(mut stream: Stream, difficulty: u32) -> anyhow::Result<bool> {
+    let challenge: [u8; 16] = rand::thread_rng().gen();
+    let line = serde_json::to_string(&Challenge {
+        challenge: hex::encode(challenge),
+        difficulty,
+    })?;
+    stream.write_all(format!("{}\n", line).as_bytes()).await?;
+    let mut lines = BufReader::new(stream.clone()).take(1024).lines();
+    let line = lines
+        .next()
+        .await
+        .context("client closed the proof-of-work stream")??;
+    let solution: Solution = serde_json::from_str(&line)?;
+    let solved = verify(&challenge, solution.nonce, difficulty);
+    stream
+        .write_all(format!("{}\n", serde_json::json!({ "ok": solved })).as_bytes())
+        .await?;
+    Ok(solved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solve_and_verify() {
+        let challenge = [7u8; 16];
+        let nonce = (0..).find(|nonce| verify(&challenge, *nonce, 12)).unwrap();
+        assert!(verify(&challenge, nonce, 12));
+        assert!(verify(&challenge, 12345, 0));
+        assert!(!verify(&challenge, nonce, 128));
+    }
+
+    #[test]
+    fn session_rate() {
+        let mut window = (0, 0, 0);
+        assert_eq!(count_session(&mut window, 100), 1);
+        assert_eq!(count_session(&mut window, 100), 2);
+        assert_eq!(count_session(&mut window, 100), 3);
+        // the previous second counts until this one overtakes it
+        assert_eq!(count_session(&mut window, 101), 3);
+        assert_eq!(count_session(&mut window, 105), 1);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use event_listener::Event;
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+
+use crate::{config::CONFIG, vpn::AssignedIpv4Addr};
+
+/// What a resumed session takes over from the session it replaces. The rate limiter and quota are keyed by the token ID, so they carry over too.
+pub struct ResumeState {
+    pub token_id: u64,
+    pub is_plus: bool,
+    /// Kept assigned until the state expires or is resumed, so that the client gets the same address back.
+    pub vpn_ipv4: Option<AssignedIpv4Addr>,
+}
+
+/// A session that handed out a resumption token.
+struct Resumable {
+    state: ResumeState,
+    /// Notified when another session resumes this one, so that it stops using its address.
+    superseded: Arc<Event>,
+}
+
+/// Resumable sessions that are still running, by resumption token.
+static LIVE: Lazy<DashMap<[u8; 32], Resumable>> = Lazy::new(Default::default);
+
+/// The state of an ended session, emptied when resumed.
+type ParkedState = Arc<Mutex<Option<ResumeState>>>;
+
+/// Resumable sessions that have ended, by resumption token. They expire after `session_resumption_secs`.
+static PARKED: Lazy<Cache<[u8; 32], ParkedState>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(
+            CONFIG.session_resumption_secs().unwrap_or_default(),
+        ))
+        .build()
+});
+
+/// Hands out a resumption token for a running session, or None if resumption is disabled.
+pub fn issue(state: ResumeState, superseded: Arc<Event>) -> Option<[u8; 32]> {
+    CONFIG.session_resumption_secs()?;
+    let token: [u8; 32] = rand::thread_rng().gen();
+    LIVE.insert(token, Resumable { state, superseded });
+    Some(token)
+}
+
+/// Parks the state of a session that has ended, so that it can be resumed until it expires.
+pub fn park(token: [u8; 32]) {
+    if let Some((_, resumable)) = LIVE.remove(&token) {
+        PARKED.insert(token, Arc::new(Mutex::new(Some(resumable.state))));
+    }
+}
+
+/// Whether the running session with the given resumption token hasn't been resumed by another one.
+pub fn is_live(token: [u8; 32]) -> bool {
+    LIVE.contains_key(&token)
+}
+
+/// Takes over the session with the given resumption token, ending it if it is still running. Every token works only once.
+pub fn resume(token: [u8; 32]) -> Option<ResumeState> {
+    if let Some((_, resumable)) = LIVE.remove(&token) {
+        resumable.superseded.notify(usize::MAX);
+        return Some(resumable.state);
+    }
+    let state = PARKED.get(&token)?.lock().take();
+    PARKED.invalidate(&token);
+    state
+}
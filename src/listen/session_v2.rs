@@ -1,7 +1,7 @@
 use anyhow::Context;
 use arrayref::array_ref;
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use dashmap::DashMap;
 use futures_util::{AsyncReadExt, AsyncWriteExt, TryFutureExt};
 use geph4_protocol::{
@@ -9,222 +9,1286 @@ use geph4_protocol::{
     client_exit::{ClientExitProtocol, ClientExitService, ClientTelemetry, CLIENT_EXIT_PSEUDOHOST},
 };
 
-use nanorpc::{JrpcRequest, RpcService};
+use event_listener::Event;
+use nanorpc::{JrpcError, JrpcId, JrpcRequest, JrpcResponse, RpcService};
 use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
 use rand::Rng;
 use smol::{
     future::FutureExt,
     io::{AsyncBufReadExt, BufReader},
+    lock::Semaphore,
     stream::StreamExt,
     Executor, Task,
 };
 
 use smol_timeout::TimeoutExt;
 
-use sosistab2::MuxStream;
+use sosistab2::Stream;
 use stdcode::StdcodeSerializeExt;
 
 use std::{
     net::Ipv4Addr,
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Weak,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::CONFIG,
+    asn::MY_PUBLIC_IP,
+    close_reason::CloseReason,
+    compress,
+    config::{CgnatAssignment, PoolTier, SniffAction, CONFIG},
     connect::proxy_loop,
-    ratelimit::RateLimiter,
-    vpn::{vpn_send_up, vpn_subscribe_down, IpAddrAssigner},
+    dns,
+    error::ExitError,
+    events::{self, ExitEvent},
+    gateway::{self, SessionStatus},
+    identity::ExitIdentity,
+    packet::{build_tcp_reset, build_udp, udp_payload, PacketHeaders},
+    panics, pcp,
+    port_forward::PortForward,
+    priority::{PriorityQueue, TrafficClass},
+    quota::QuotaHandle,
+    ratelimit::{tier_limiter, BulkThrottle, RateLimiter},
+    redact,
+    smartchan::SmartReceiver,
+    sniff::sniff_udp,
+    stats::Metric,
+    udp_relay::{udp_relay_loop, UDP_SESSION_PSEUDOHOST},
+    vpn::{AssignedIpv4Addr, CGNAT_HASH_KEY},
 };
 
-use super::ROOT_CTX;
+use super::{
+    bond::{self, Attachment, Bond, Paths},
+    link::{self, session_id, LinkStats, LINKS},
+    meter::{Metered, SessionMeter, StatsFrame},
+    pow::{self, POW_PSEUDOHOST},
+    resume::{self, ResumeState},
+    ROOT_CTX,
+};
+
+/// JSON-RPC error code returned to clients of a session that was refused because the exit is full or draining.
+const EXIT_FULL_ERROR_CODE: i64 = -32001;
+
+/// JSON-RPC error code returned to clients that went over a data quota with the `terminate` action.
+const QUOTA_EXCEEDED_ERROR_CODE: i64 = -32002;
+
+/// Intercepted DNS queries that a VPN session may have waiting on the upstream resolver. Queries past it are dropped, as a congested resolver would.
+const DNS_QUERIES_PER_SESSION: usize = 16;
 
 type TableEntry = (Weak<sosistab2::Multiplex>, Arc<Task<anyhow::Result<()>>>);
 
-/// Handles a sosistab2 pipe, redirecting it to the appropriate multiplex.
-pub fn handle_pipe_v2(pipe: impl sosistab2::Pipe) {
+/// Handles a sosistab2 pipe that arrived for the given exit identity on the listener with the given label, redirecting it to the appropriate multiplex.
+pub fn handle_pipe_v2(pipe: impl sosistab2::Pipe, identity: Arc<ExitIdentity>, listener: &str) {
+    let listener = link::listener(listener);
+    listener.on_handshake();
     static BIG_MULTIPLEX_TABLE: Lazy<DashMap<blake3::Hash, TableEntry>> =
         Lazy::new(Default::default);
-    let key = blake3::hash(pipe.peer_metadata().as_bytes());
+    let key = blake3::hash(
+        &(
+            identity.sosistab2_sk.to_public().as_bytes(),
+            pipe.peer_metadata(),
+        )
+            .stdcode(),
+    );
 
     let mplex = BIG_MULTIPLEX_TABLE.entry(key).or_insert_with(move || {
-        // TODO actually put this SK somewhere
         let mplex = Arc::new(sosistab2::Multiplex::new(
-            ROOT_CTX.sosistab2_sk.clone(),
+            identity.sosistab2_sk.clone(),
             None,
         ));
+        let link = Arc::new(LinkStats::default());
+        LINKS.insert(key, link.clone());
         mplex.add_drop_friend(scopeguard::guard((), move |_| {
             BIG_MULTIPLEX_TABLE.remove(&key);
+            LINKS.remove(&key);
         }));
-        let task = smolscale::spawn(handle_session_v2(mplex.clone()));
+        let task = smolscale::spawn(panics::guarded(
+            format!("session {}", redact::client(key)),
+            handle_session_v2(mplex.clone(), identity, link, session_id(&key)),
+        ));
         (Arc::downgrade(&mplex), task.into())
     });
     if let Some(mplex) = mplex.value().0.upgrade() {
-        mplex.add_pipe(pipe);
+        // a pipe from a new address joins the session, which survives the client roaming
+        match LINKS.get(&key) {
+            Some(link) => {
+                if let Some(pipe) = link.attach(pipe, listener) {
+                    mplex.add_pipe(pipe)
+                }
+            }
+            None => mplex.add_pipe(pipe),
+        }
     }
 }
 
 /// Handles a sosistab2 multiplex. We do not try to timeout etc here. The Big Multiplex Table handles this.
-async fn handle_session_v2(mux: Arc<sosistab2::Multiplex>) -> anyhow::Result<()> {
+async fn handle_session_v2(
+    mux: Arc<sosistab2::Multiplex>,
+    identity: Arc<ExitIdentity>,
+    link: Arc<LinkStats>,
+    session: String,
+) -> anyhow::Result<()> {
+    if let Some(reason) = ROOT_CTX.session_refusal() {
+        ROOT_CTX.incr_stat(Metric::SessionRejected);
+        return reject_session(mux, reason).await;
+    }
+    if let Some(difficulty) = pow::on_new_session() {
+        pow::demand(&mux, difficulty).await?;
+    }
     let vpn_ipv4 = if CONFIG.nat_external_iface().is_some() {
-        Some(IpAddrAssigner::global().assign())
+        match ROOT_CTX.vpn().assigner().assign() {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                log::warn!("refusing session: {:?}", err);
+                ROOT_CTX.incr_stat(Metric::CgnatPoolFull);
+                return reject_session(mux, CloseReason::ExitFull).await;
+            }
+        }
     } else {
         None
     };
+    ROOT_CTX.live_sessions.fetch_add(1, Ordering::Relaxed);
+    events::publish(ExitEvent::SessionStart {
+        session: session.clone(),
+    });
+    let started = Instant::now();
+    scopeguard::defer!({
+        ROOT_CTX.live_sessions.fetch_sub(1, Ordering::Relaxed);
+        events::publish(ExitEvent::SessionEnd {
+            session,
+            duration_secs: started.elapsed().as_secs(),
+        });
+    });
     let client_exit = Arc::new(ClientExitService(ClientExitImpl::new(
-        vpn_ipv4.map(|v| v.addr()),
+        vpn_ipv4, identity, link,
     )));
+    scopeguard::defer!(client_exit.0.park());
     let exec = Executor::new();
-    exec.run(async {
+    let accept_loop = exec.run(async {
         let id = rand::thread_rng().gen();
+        // clients that never open a stream shouldn't hold a session for an hour
+        let mut accept_timeout = Duration::from_secs(CONFIG.handshake_timeout_secs());
         loop {
             let conn = mux
                 .accept_conn()
-                .timeout(Duration::from_secs(3600))
+                .timeout(accept_timeout)
                 .await
                 .context("timeout")??;
+            accept_timeout = Duration::from_secs(3600);
             ROOT_CTX.session_keepalive(id);
-            let to_spawn = handle_conn(client_exit.clone(), conn, rand::thread_rng().gen())
+            client_exit.0.on_activity();
+            let to_spawn = handle_conn(client_exit.clone(), conn, id)
                 .unwrap_or_else(|e| log::debug!("connection handler died with {:?}", e));
 
             exec.spawn(to_spawn).detach();
         }
+    });
+    accept_loop
+        .race(watch_revocation(&client_exit.0))
+        .race(auth_deadline(&client_exit.0))
+        .race(watch_superseded(&client_exit.0))
+        .race(reap_idle(&client_exit.0))
+        .await
+}
+
+/// Returns an error once the session has been idle for longer than its tier allows, after warning the client.
+async fn reap_idle(client_exit: &ClientExitImpl) -> anyhow::Result<()> {
+    let Some(idle_reap) = CONFIG.idle_reap() else {
+        return smol::future::pending().await;
+    };
+    loop {
+        let timeout = Duration::from_secs(if client_exit.is_plus() {
+            idle_reap.plus_secs()
+        } else {
+            idle_reap.free_secs()
+        });
+        // open proxied connections may be busy without the session seeing it
+        if client_exit.active_conns.load(Ordering::Relaxed) > 0 {
+            client_exit.on_activity();
+        }
+        let idle = client_exit.last_active.lock().elapsed();
+        if idle >= timeout {
+            break;
+        }
+        smol::Timer::after((timeout - idle).min(Duration::from_secs(30))).await;
+    }
+    ROOT_CTX.incr_stat(if client_exit.is_plus() {
+        Metric::SessionReapedPlus
+    } else {
+        Metric::SessionReapedFree
+    });
+    let control = client_exit.control.lock().clone();
+    if let Some(control) = control {
+        CloseReason::Idle.send_frame(&control).await;
+    }
+    anyhow::bail!("session was idle for too long")
+}
+
+/// Returns an error once another session resumes this one, ending the session.
+async fn watch_superseded(client_exit: &ClientExitImpl) -> anyhow::Result<()> {
+    loop {
+        let superseded = client_exit.superseded.listen();
+        if let Some(token) = *client_exit.resume_token.lock() {
+            if !resume::is_live(token) {
+                ROOT_CTX.incr_stat(Metric::SessionSuperseded);
+                anyhow::bail!("session was resumed elsewhere")
+            }
+        }
+        superseded.await;
+    }
+}
+
+/// Returns an error if an official session hasn't authenticated within the handshake timeout, ending the session.
+async fn auth_deadline(client_exit: &ClientExitImpl) -> anyhow::Result<()> {
+    if CONFIG.official().is_none() {
+        return smol::future::pending().await;
+    }
+    smol::Timer::after(Duration::from_secs(CONFIG.handshake_timeout_secs())).await;
+    if client_exit.authed().is_none() {
+        ROOT_CTX.incr_stat(Metric::SessionHandshakeTimeout);
+        anyhow::bail!("session did not authenticate in time")
+    }
+    smol::future::pending().await
+}
+
+/// Returns an error once the session's token is revoked, ending the session.
+async fn watch_revocation(client_exit: &ClientExitImpl) -> anyhow::Result<()> {
+    loop {
+        let changed = ROOT_CTX.revocation_event.listen();
+        if let Some(token_id) = client_exit.authed() {
+            if ROOT_CTX.is_revoked(token_id) {
+                ROOT_CTX.incr_stat(Metric::SessionRevoked);
+                anyhow::bail!("token {} was revoked, killing session", token_id)
+            }
+        }
+        // sessions can authenticate at any time, so also recheck every now and then
+        changed.timeout(Duration::from_secs(30)).await;
+    }
+}
+
+/// Serves a session that was refused because the exit is full or draining. Every RPC call gets an error with the given reason, and every other stream is closed right away.
+async fn reject_session(mux: Arc<sosistab2::Multiplex>, reason: CloseReason) -> anyhow::Result<()> {
+    ExitError::from(reason).record();
+    let exec = Executor::new();
+    exec.run(async {
+        loop {
+            let mut stream = mux
+                .accept_conn()
+                .timeout(Duration::from_secs(60))
+                .await
+                .context("timeout")??;
+            if stream.label() != CLIENT_EXIT_PSEUDOHOST {
+                exec.spawn(async move { reason.send_frame(&stream).await })
+                    .detach();
+                continue;
+            }
+            exec.spawn(async move {
+                let mut lines = BufReader::new(stream.clone()).take(100_000).lines();
+                while let Some(line) = lines.next().await {
+                    let req: JrpcRequest = serde_json::from_str(&line?)?;
+                    let resp = error_response(req.id, EXIT_FULL_ERROR_CODE, &reason.to_string());
+                    stream.write_all(&serde_json::to_vec(&resp)?).await?;
+                    stream.write_all(b"\n").await?;
+                }
+                anyhow::Ok(())
+            })
+            .detach();
+        }
     })
     .await
 }
 
+/// Builds a JSON-RPC error response.
+fn error_response(id: JrpcId, code: i64, message: &str) -> JrpcResponse {
+    JrpcResponse {
+        jsonrpc: "2.0".into(),
+        result: None,
+        error: Some(JrpcError {
+            code,
+            message: message.into(),
+            data: serde_json::Value::Null,
+        }),
+        id,
+    }
+}
+
 async fn handle_conn(
     client_exit: Arc<ClientExitService<ClientExitImpl>>,
-    mut stream: MuxStream,
-    sess_random: u64,
+    mut stream: Stream,
+    session_id: u64,
 ) -> anyhow::Result<()> {
-    let hostname = stream.additional_info();
+    let hostname = stream.label();
 
     if hostname == CLIENT_EXIT_PSEUDOHOST {
+        *client_exit.0.control.lock() = Some(stream.clone());
         // also run the VPN!
         let vpn_stream = stream.clone();
         let start_vpn = CONFIG.nat_external_iface().is_some();
-        let _vpn_task = {
-            let client_exit = client_exit.clone();
-
-            smolscale::spawn::<anyhow::Result<()>>(async move {
-                vpn_stream
-                    .recv_urel()
-                    .await
-                    .context("could not receive from VPN")?;
-                if start_vpn {
-                    let limiter = client_exit
-                        .0
-                        .limiter()
-                        .unwrap_or_else(RateLimiter::unlimited);
-                    let vpn_ipv4 = client_exit.0.get_vpn_ipv4().await.unwrap();
-                    let downstream = vpn_subscribe_down(vpn_ipv4);
-
-                    let send_loop = async {
-                        let mut buff = vec![];
-                        loop {
-                            buff.clear();
-                            let next = downstream.recv().await?;
-                            ROOT_CTX.incr_throughput(next.len());
-                            limiter.wait(next.len()).await;
-                            buff.push(next);
-                            while let Ok(next) = downstream.try_recv() {
-                                ROOT_CTX.incr_throughput(next.len());
-                                buff.push(next.clone());
-
-                                let mut break_now = false;
-                                limiter
-                                    .wait(next.len())
-                                    .or(async {
-                                        smol::future::yield_now().await;
-                                        break_now = true;
-                                        smol::future::pending().await
-                                    })
-                                    .await;
-
-                                if break_now || buff.len() >= 20 {
-                                    break;
-                                }
-                            }
-
-                            vpn_stream
-                                .send_urel(stdcode::serialize(&buff)?.into())
-                                .await?;
-                        }
-                    };
-                    let recv_loop = async {
-                        loop {
-                            let next = vpn_stream.recv_urel().await?;
-                            ROOT_CTX.incr_throughput(next.len());
-                            let next: Vec<Bytes> = stdcode::deserialize(&next)?;
-                            for next in next {
-                                vpn_send_up(vpn_ipv4, &next).await;
-                            }
-                        }
-                    };
-                    send_loop.race(recv_loop).await
-                } else {
-                    Ok(())
-                }
-            })
-        };
+        let _vpn_task = panics::spawn(handle_vpn_session(
+            vpn_stream,
+            client_exit.clone(),
+            start_vpn,
+        ));
         // run the loop
         let up_read = BufReader::with_capacity(1024, stream.clone()).take(1_000_000);
         let mut lines = up_read.lines();
 
-        while let Some(line) = lines.next().await {
-            let line = line.context("could not read a line from @client-exit")?;
-            log::debug!("LINE received {:?}", line);
-            let line: JrpcRequest = serde_json::from_str(&line)
-                .context("could not deserialize JSON from @client-exit")?;
-            let resp = client_exit.respond_raw(line).await;
-            stream.write_all(&serde_json::to_vec(&resp)?).await?;
-            stream.write_all(b"\n").await?;
-        }
-
+        let control = stream.clone();
+        let lines_loop = async {
+            while let Some(line) = lines.next().await {
+                let line = line.context("could not read a line from @client-exit")?;
+                log::debug!("LINE received {:?}", redact::sensitive(&line));
+                client_exit.0.on_activity();
+                let line: JrpcRequest = serde_json::from_str(&line)
+                    .context("could not deserialize JSON from @client-exit")?;
+                let resp = if client_exit.0.quota_terminated() {
+                    error_response(line.id, QUOTA_EXCEEDED_ERROR_CODE, "data quota exceeded")
+                } else if let Some(resp) = client_exit.0.respond_extension(&line).await {
+                    resp
+                } else {
+                    client_exit.respond_raw(line).await
+                };
+                stream.write_all(&serde_json::to_vec(&resp)?).await?;
+                stream.write_all(b"\n").await?;
+            }
+            anyhow::Ok(())
+        };
+        return lines_loop.race(client_exit.0.stats_loop(&control)).await;
+    }
+    if hostname == POW_PSEUDOHOST {
+        pow::serve(stream, 0).await?;
         return Ok(());
     }
     // check auth
     if client_exit.0.authed().is_none() && CONFIG.official().is_some() {
-        anyhow::bail!("not authed yet, cannot do anything")
+        ExitError::Auth.record();
+        CloseReason::Unauthenticated.send_frame(&stream).await;
+        return Err(ExitError::Auth).context("not authed yet, cannot do anything");
     }
 
+    if client_exit.0.quota_terminated() {
+        ExitError::Quota.record();
+        CloseReason::QuotaExceeded.send_frame(&stream).await;
+        return Err(ExitError::Quota).context("over quota, cannot do anything");
+    }
+
+    client_exit.0.active_conns.fetch_add(1, Ordering::Relaxed);
+    scopeguard::defer!({
+        client_exit.0.active_conns.fetch_sub(1, Ordering::Relaxed);
+        client_exit.0.on_activity();
+    });
+
     // MAIN STUFF HERE
     let limiter = client_exit
         .0
         .limiter()
         .unwrap_or_else(RateLimiter::unlimited);
-    smolscale::spawn(proxy_loop(
+    let quota = client_exit.0.quota().map(Arc::new);
+    let quota_watch = {
+        let quota = quota.clone();
+        async move {
+            match quota {
+                Some(quota) => quota.wait_terminated().await,
+                None => smol::future::pending().await,
+            }
+            ExitError::Quota.record();
+            Err(ExitError::Quota.into())
+        }
+    };
+    if hostname == UDP_SESSION_PSEUDOHOST {
+        return udp_relay_loop(
+            limiter.into(),
+            Metered::new(stream.clone(), client_exit.0.meter.clone()),
+            client_exit.0.identity.clone(),
+            quota,
+        )
+        .race(quota_watch)
+        .await;
+    }
+    // free-tier clients learn why a big transfer slowed down, so that they can suggest upgrading
+    let bulk = (!client_exit.0.is_plus())
+        .then(BulkThrottle::new)
+        .flatten()
+        .map(Arc::new);
+    let bulk_notice = {
+        let bulk = bulk.clone();
+        let stream = stream.clone();
+        async move {
+            if let Some(bulk) = bulk {
+                bulk.engaged().await;
+                CloseReason::BulkThrottled.send_notice(&stream).await;
+            }
+            smol::future::pending().await
+        }
+    };
+    // each stream gets its own random source address, unlike the session-wide SMTP limits
+    let source_seed: u64 = rand::thread_rng().gen();
+    let result = panics::spawn(proxy_loop(
         limiter.into(),
-        stream.clone(),
-        sess_random,
+        Metered::new(stream.clone(), client_exit.0.meter.clone()),
+        source_seed,
+        session_id,
         hostname.into(),
-        true,
+        None,
+        Some(client_exit.0.link.clone()),
+        bulk,
+        client_exit.0.identity.clone(),
+        quota,
+        client_exit.0.compress.load(Ordering::Relaxed),
     ))
+    .or(quota_watch)
+    .or(bulk_notice)
     .timeout(Duration::from_secs(600))
     .await
     .context("timeout")
-    .context("failed in proxy_loop")??;
+    .and_then(|res| res);
+    if let Err(err) = &result {
+        if let Some(reason) = ExitError::of(err).close_reason() {
+            reason.send_frame(&stream).await;
+        }
+    }
+    result.context("failed in proxy_loop")
+}
+
+/// The token ID every session is authenticated with in dev mode.
+pub const DEV_TOKEN_ID: u64 = 0xdead_beef;
+
+/// Handles the VPN half of the @client-exit stream, shuttling packets between the client and the TUN device.
+async fn handle_vpn_session(
+    vpn_stream: Stream,
+    client_exit: Arc<ClientExitService<ClientExitImpl>>,
+    start_vpn: bool,
+) -> anyhow::Result<()> {
+    vpn_stream
+        .recv_urel()
+        .await
+        .context("could not receive from VPN")?;
+    {
+        // under the lock, so that resuming, attaching and binding a lease see the VPN either started or not
+        let _vpn_ipv4 = client_exit.0.vpn_ipv4.write();
+        client_exit.0.vpn_started.store(true, Ordering::SeqCst);
+    }
+    let bonded = client_exit.0.bonded.lock().clone();
+    if let Some(bond) = bonded {
+        return bonded_vpn_session(vpn_stream, &client_exit.0, bond).await;
+    }
+    if start_vpn {
+        let vpn_ipv4 = client_exit
+            .0
+            .get_vpn_ipv4()
+            .await
+            .context("no VPN address")?;
+        // a tier pool's limit replaces the user's usual one, but still counts towards the tier's
+        let limiter = CONFIG
+            .tier_pool_of(vpn_ipv4)
+            .and_then(|pool| Some((pool.limit()?, pool.burst())))
+            .map(|(limit, burst)| {
+                tier_limiter(client_exit.0.is_plus()).child(limit, burst.unwrap_or(limit))
+            })
+            .or_else(|| client_exit.0.limiter())
+            .unwrap_or_else(RateLimiter::unlimited);
+        let quota = client_exit.0.quota();
+        let bittorrent_throttle = {
+            let limit = CONFIG.protocol_sniffing().throttle_kb();
+            RateLimiter::new(limit, limit)
+        };
+        let dns_slots = Arc::new(Semaphore::new(DNS_QUERIES_PER_SESSION));
+        let vpn = ROOT_CTX.vpn();
+        let downstream = vpn.subscribe_down(vpn_ipv4, !client_exit.0.is_plus());
+        scopeguard::defer!(vpn.unsubscribe_down(vpn_ipv4, &downstream));
+        let (bond, bonded_up) = Bond::new();
+        let path = bond.paths.add(vpn_stream.clone());
+        *client_exit.0.bond.lock() = Some(bond.clone());
+        scopeguard::defer!(client_exit.0.unbond());
+
+        let send_loop = async {
+            if CONFIG.vpn_priority() {
+                return priority_send_loop(
+                    &bond.paths,
+                    &downstream,
+                    &limiter,
+                    quota.as_ref(),
+                    &client_exit.0,
+                    vpn_ipv4,
+                )
+                .await;
+            }
+            let mut buff = vec![];
+            let mut encoded = BytesMut::new();
+            loop {
+                buff.clear();
+                let next = downstream.recv().await?;
+                if valid_downstream(vpn_ipv4, &next).is_none() {
+                    continue;
+                }
+                let started = Instant::now();
+                ROOT_CTX.incr_throughput(&client_exit.0.identity, next.len());
+                limiter.wait(next.len()).await;
+                if let Some(quota) = quota.as_ref() {
+                    quota.charge(next.len()).await;
+                }
+                buff.push(next);
+                let mut coalesced = 0;
+                while let Some(next) = next_coalesced(&downstream, &buff, started).await {
+                    if valid_downstream(vpn_ipv4, &next).is_none() {
+                        continue;
+                    }
+                    ROOT_CTX.incr_throughput(&client_exit.0.identity, next.len());
+                    coalesced += next.len();
+                    buff.push(next.clone());
+
+                    let mut break_now = false;
+                    limiter
+                        .wait(next.len())
+                        .or(async {
+                            smol::future::yield_now().await;
+                            break_now = true;
+                            smol::future::pending().await
+                        })
+                        .await;
+
+                    if break_now || buff.len() >= 20 {
+                        break;
+                    }
+                }
+                // the rest of the batch is charged at once, so that a throttled user waits for all of it without holding up coalescing
+                if let Some(quota) = quota.as_ref().filter(|_| coalesced > 0) {
+                    quota.charge(coalesced).await;
+                }
+
+                send_batch(
+                    &bond.paths,
+                    &buff,
+                    &mut encoded,
+                    &downstream,
+                    &client_exit.0.meter,
+                )
+                .await?;
+            }
+        };
+        let recv_loop = async {
+            loop {
+                // batches from attached sessions are handled like the session's own
+                let next = async {
+                    let next = vpn_stream.recv_urel().await?;
+                    path.on_recv();
+                    anyhow::Ok(next)
+                }
+                .race(async { Ok(bonded_up.recv().await?) })
+                .await?;
+                client_exit.0.on_activity();
+                client_exit
+                    .0
+                    .meter
+                    .up
+                    .fetch_add(next.len() as u64, Ordering::Relaxed);
+                ROOT_CTX.incr_throughput(&client_exit.0.identity, next.len());
+                if let Some(quota) = quota.as_ref() {
+                    quota.record(next.len());
+                }
+                let next: Vec<Bytes> = stdcode::deserialize(&next)?;
+                for next in next {
+                    if intercept_dns(vpn_ipv4, &next, &dns_slots, &limiter) {
+                        continue;
+                    }
+                    if gateway::intercept(vpn_ipv4, &next, || {
+                        client_exit.0.gateway_status(vpn_ipv4)
+                    }) {
+                        continue;
+                    }
+                    if intercept_pcp(&client_exit, vpn_ipv4, &next) {
+                        continue;
+                    }
+                    if drop_bittorrent(&bittorrent_throttle, &next) {
+                        continue;
+                    }
+                    vpn.send_up(&client_exit.0.identity, vpn_ipv4, &next).await;
+                }
+            }
+        };
+        let quota_watch = async {
+            match quota.as_ref() {
+                Some(quota) => quota.wait_terminated().await,
+                None => smol::future::pending().await,
+            }
+            ExitError::Quota.record();
+            CloseReason::QuotaExceeded.send_frame(&vpn_stream).await;
+            Err(ExitError::Quota).context("over quota, stopping VPN")
+        };
+        send_loop.race(recv_loop).race(quota_watch).await
+    } else {
+        Ok(())
+    }
+}
+
+/// Carries the VPN traffic of the session that this one attached to: upstream batches are handed to that session, and this session's stream becomes one of the paths its downstream packets may take.
+async fn bonded_vpn_session(
+    vpn_stream: Stream,
+    client_exit: &ClientExitImpl,
+    bond: Weak<Bond>,
+) -> anyhow::Result<()> {
+    let bond = bond
+        .upgrade()
+        .context("bonded session has stopped its VPN")?;
+    let path = bond.paths.add(vpn_stream.clone());
+    loop {
+        let next = vpn_stream.recv_urel().await?;
+        client_exit.on_activity();
+        path.on_recv();
+        if !bond.send_up(next).await {
+            anyhow::bail!("bonded session has stopped its VPN")
+        }
+    }
+}
+
+/// Applies the `bittorrent_udp` policy to an upstream packet, returning true if it must be dropped.
+fn drop_bittorrent(throttle: &RateLimiter, pkt: &[u8]) -> bool {
+    let action = CONFIG.protocol_sniffing().bittorrent_udp();
+    if action == SniffAction::Allow {
+        return false;
+    }
+    if udp_payload(pkt).and_then(sniff_udp).is_none() {
+        return false;
+    }
+    ROOT_CTX.incr_stat(Metric::VpnBitTorrentUdp);
+    match action {
+        SniffAction::Allow => false,
+        SniffAction::Throttle => !throttle.check(pkt.len()),
+        SniffAction::Block => true,
+    }
+}
+
+/// If the packet is a DNS query from this session, to any resolver with `vpn_dns_intercept` or to the gateway with `gateway_services`, answers it in the background with the exit's resolver and returns true. The query counts against the session's speed limit, and is dropped if the session already has all its slots waiting on the resolver. Queries over TCP are refused with a reset, so that clients retry over UDP rather than reach another resolver.
+fn intercept_dns(
+    vpn_ipv4: Ipv4Addr,
+    pkt: &[u8],
+    slots: &Arc<Semaphore>,
+    limiter: &RateLimiter,
+) -> bool {
+    let Some(headers) = PacketHeaders::parse(pkt) else {
+        return false;
+    };
+    let (Some(source_port), Some(53)) = (headers.source_port, headers.dest_port) else {
+        return false;
+    };
+    if headers.source != vpn_ipv4 {
+        return false;
+    }
+    let to_gateway = CONFIG.gateway_services() && gateway::is_gateway(headers.destination);
+    if !CONFIG.vpn_dns_intercept() && !to_gateway {
+        return false;
+    }
+    if headers.is_tcp() {
+        ROOT_CTX.incr_stat(Metric::DnsTcpRefused);
+        if let Some(reset) = build_tcp_reset(pkt) {
+            ROOT_CTX.vpn().dispatch_down(&reset);
+        }
+        return true;
+    }
+    if !headers.is_udp() {
+        return false;
+    }
+    let Some(query) = udp_payload(pkt).map(Bytes::copy_from_slice) else {
+        return false;
+    };
+    let Some(slot) = slots.try_acquire_arc() else {
+        ROOT_CTX.incr_stat(Metric::DnsQueryDropped);
+        return true;
+    };
+    let limiter = limiter.clone();
+    let len = pkt.len();
+    panics::spawn(async move {
+        let _slot = slot;
+        limiter.wait(len).await;
+        match dns::answer_query(&query).await {
+            Ok(response) => {
+                // the answer appears to come from the resolver the client asked
+                if let Some(pkt) = build_udp(
+                    (headers.destination, 53),
+                    (vpn_ipv4, source_port),
+                    &response,
+                ) {
+                    ROOT_CTX.vpn().dispatch_down(&pkt);
+                }
+            }
+            Err(err) => log::debug!("cannot answer intercepted DNS query: {:?}", err),
+        }
+        anyhow::Ok(())
+    })
+    .detach();
+    true
+}
+
+/// If PCP is enabled and the packet is a PCP request to the gateway from this session, answers it in the background and returns true.
+fn intercept_pcp(
+    client_exit: &Arc<ClientExitService<ClientExitImpl>>,
+    vpn_ipv4: Ipv4Addr,
+    pkt: &[u8],
+) -> bool {
+    if !CONFIG
+        .port_forwarding()
+        .as_ref()
+        .is_some_and(|config| config.pcp())
+    {
+        return false;
+    }
+    let Some(headers) = PacketHeaders::parse(pkt) else {
+        return false;
+    };
+    let (Some(source_port), Some(pcp::PCP_PORT)) = (headers.source_port, headers.dest_port) else {
+        return false;
+    };
+    if !headers.is_udp() || headers.source != vpn_ipv4 || !gateway::is_gateway(headers.destination)
+    {
+        return false;
+    }
+    let Some(request) = udp_payload(pkt).map(Bytes::copy_from_slice) else {
+        return false;
+    };
+    let client_exit = client_exit.clone();
+    panics::spawn(async move {
+        if let Some(response) = pcp::respond(&request, *MY_PUBLIC_IP, async |request| {
+            client_exit.0.map_pcp(request).await
+        })
+        .await
+        {
+            if let Some(pkt) = build_udp(
+                (headers.destination, pcp::PCP_PORT),
+                (vpn_ipv4, source_port),
+                &response,
+            ) {
+                ROOT_CTX.vpn().dispatch_down(&pkt);
+            }
+        }
+        anyhow::Ok(())
+    })
+    .detach();
+    true
+}
+
+/// Checks that a downstream packet is well-formed IPv4 addressed to this session, dropping and counting it otherwise.
+fn valid_downstream(vpn_ipv4: Ipv4Addr, pkt: &[u8]) -> Option<PacketHeaders> {
+    match PacketHeaders::parse(pkt) {
+        Some(headers) if headers.destination == vpn_ipv4 => Some(headers),
+        Some(headers) => {
+            log::debug!(
+                "dropping downstream packet for {} in session {}",
+                redact::client(headers.destination),
+                redact::client(vpn_ipv4)
+            );
+            ROOT_CTX.incr_stat(Metric::VpnBadPacket);
+            None
+        }
+        None => {
+            log::debug!(
+                "dropping invalid downstream packet in session {}",
+                redact::client(vpn_ipv4)
+            );
+            ROOT_CTX.incr_stat(Metric::VpnBadPacket);
+            None
+        }
+    }
+}
+
+/// Sends downstream packets to the client, serving more urgent traffic classes first whenever the rate limit makes packets queue up.
+async fn priority_send_loop(
+    paths: &Paths<Stream>,
+    downstream: &SmartReceiver<Bytes>,
+    limiter: &RateLimiter,
+    quota: Option<&QuotaHandle>,
+    client_exit: &ClientExitImpl,
+    vpn_ipv4: Ipv4Addr,
+) -> anyhow::Result<()> {
+    let mut queue = PriorityQueue::new(1000);
+    let enqueue = |queue: &mut PriorityQueue, pkt: Bytes| {
+        if let Some(headers) = valid_downstream(vpn_ipv4, &pkt) {
+            queue.push(pkt, TrafficClass::of_downstream(&headers));
+        }
+    };
+    let mut buff = vec![];
+    let mut encoded = BytesMut::new();
+    loop {
+        if queue.is_empty() {
+            let next = downstream.recv().await?;
+            enqueue(&mut queue, next);
+        }
+        let started = Instant::now();
+        buff.clear();
+        loop {
+            // pick up everything that arrived while we were waiting, so that urgent packets can jump ahead
+            while let Ok(next) = downstream.try_recv() {
+                enqueue(&mut queue, next);
+            }
+            let Some(next) = queue.pop() else {
+                match next_coalesced(downstream, &buff, started).await {
+                    Some(next) => {
+                        enqueue(&mut queue, next);
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+            ROOT_CTX.incr_throughput(&client_exit.identity, next.len());
+            limiter.wait(next.len()).await;
+            if let Some(quota) = quota {
+                quota.charge(next.len()).await;
+            }
+            buff.push(next);
+            if buff.len() >= 20 {
+                break;
+            }
+        }
+        if !buff.is_empty() {
+            send_batch(paths, &buff, &mut encoded, downstream, &client_exit.meter).await?;
+        }
+    }
+}
+
+/// Packets and messages sent downstream to VPN clients, for the coalescing ratio.
+static PACKETS_SENT: AtomicU64 = AtomicU64::new(0);
+static MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+
+/// How many downstream VPN packets each message carried on average.
+pub fn coalescing_ratio() -> f64 {
+    let messages = MESSAGES_SENT.load(Ordering::Relaxed);
+    if messages == 0 {
+        return 0.0;
+    }
+    PACKETS_SENT.load(Ordering::Relaxed) as f64 / messages as f64
+}
+
+/// The next downstream packet for a batch that started at the given time: one already waiting, or with `vpn_coalescing`, one that arrives within the latency budget while the batch is still small. None once the batch should go out.
+async fn next_coalesced(
+    downstream: &SmartReceiver<Bytes>,
+    batch: &[Bytes],
+    started: Instant,
+) -> Option<Bytes> {
+    if let Ok(next) = downstream.try_recv() {
+        return Some(next);
+    }
+    let coalescing = CONFIG.vpn_coalescing().as_ref()?;
+    let bytes: usize = batch.iter().map(|pkt| pkt.len()).sum();
+    if batch.is_empty() || bytes >= coalescing.max_bytes() {
+        return None;
+    }
+    let budget = Duration::from_micros(coalescing.window_us()).checked_sub(started.elapsed())?;
+    downstream.recv().timeout(budget).await?.ok()
+}
+
+/// Sends a batch of downstream packets to the client over its active path, counting it towards the session's meter. The batch is encoded into `encoded`, whose allocation is reused once the previous message is gone.
+async fn send_batch(
+    paths: &Paths<Stream>,
+    buff: &[Bytes],
+    encoded: &mut BytesMut,
+    downstream: &SmartReceiver<Bytes>,
+    meter: &SessionMeter,
+) -> anyhow::Result<()> {
+    let bts = encode_batch(encoded, buff);
+    meter.down.fetch_add(bts.len() as u64, Ordering::Relaxed);
+    meter.dropped.store(downstream.dropped(), Ordering::Relaxed);
+    let vpn_stream = paths.active().context("no path to send on")?;
+    PACKETS_SENT.fetch_add(buff.len() as u64, Ordering::Relaxed);
+    MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
+    vpn_stream.send_urel(bts).await?;
     Ok(())
 }
 
+/// Encodes a batch of packets exactly as stdcode encodes a `Vec<Bytes>`, which is what clients decode, but straight into a buffer sized up front rather than through serde.
+fn encode_batch(out: &mut BytesMut, batch: &[Bytes]) -> Bytes {
+    let len = varint_len(batch.len())
+        + batch
+            .iter()
+            .map(|pkt| varint_len(pkt.len()) + pkt.len())
+            .sum::<usize>();
+    out.reserve(len);
+    put_varint(out, batch.len());
+    for pkt in batch {
+        put_varint(out, pkt.len());
+        out.put_slice(pkt);
+    }
+    out.split().freeze()
+}
+
+/// Length of a bincode varint.
+fn varint_len(n: usize) -> usize {
+    match n {
+        0..=250 => 1,
+        251..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+/// Writes a bincode varint: small values as a byte, larger ones as a marker byte and a little-endian integer.
+fn put_varint(out: &mut BytesMut, n: usize) {
+    match n {
+        0..=250 => out.put_u8(n as u8),
+        251..=0xffff => {
+            out.put_u8(251);
+            out.put_u16_le(n as u16);
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.put_u8(252);
+            out.put_u32_le(n as u32);
+        }
+        _ => {
+            out.put_u8(253);
+            out.put_u64_le(n as u64);
+        }
+    }
+}
+
+/// JSON-RPC method, outside the client-exit protocol, that hands an authenticated client a token for resuming its session.
+const RESUMPTION_TOKEN_METHOD: &str = "exit_resumption_token";
+
+/// JSON-RPC method, outside the client-exit protocol, that resumes a lost session with its token instead of authenticating.
+const RESUME_METHOD: &str = "exit_resume";
+
+/// JSON-RPC method, outside the client-exit protocol, that asks for stats frames every `session_stats_secs`.
+const SUBSCRIBE_STATS_METHOD: &str = "exit_subscribe_stats";
+
+/// JSON-RPC method, outside the client-exit protocol, that lists the `bypass_routes` the client should reach without this exit.
+const BYPASS_ROUTES_METHOD: &str = "exit_bypass_routes";
+
+/// JSON-RPC method, outside the client-exit protocol, that forwards a public port of the exit to a Plus client's VPN address, preferably the port given.
+const FORWARD_PORT_METHOD: &str = "exit_forward_port";
+
+/// JSON-RPC method, outside the client-exit protocol, that stops forwarding a port.
+const CLOSE_PORT_METHOD: &str = "exit_close_port";
+
+/// JSON-RPC method, outside the client-exit protocol, that hands a client with a running VPN a token for attaching another session to it.
+const BOND_TOKEN_METHOD: &str = "exit_bond_token";
+
+/// JSON-RPC method, outside the client-exit protocol, that attaches this session to another one's VPN with its bonding token, instead of authenticating.
+const BOND_METHOD: &str = "exit_bond";
+
+/// JSON-RPC method, outside the client-exit protocol, that asks for the downstream data of proxied streams opened afterwards to be framed and compressed. Answers whether the client's tier gets compression.
+const COMPRESSION_METHOD: &str = "exit_compression";
+
 /// Encapsulates the client-exit protocol state.
 struct ClientExitImpl {
     is_plus: AtomicBool,
     authed: AtomicU64,
-    vpn_ipv4: Option<Ipv4Addr>,
+    vpn_ipv4: RwLock<Option<AssignedIpv4Addr>>,
+    /// Set once the client has started the VPN, after which its address can no longer change.
+    vpn_started: AtomicBool,
+    identity: Arc<ExitIdentity>,
+    /// The token that the client can resume this session with, if it asked for one.
+    resume_token: Mutex<Option<[u8; 32]>>,
+    /// Notified when another session resumes this one.
+    superseded: Arc<Event>,
+    /// When the client last did anything, for reaping idle sessions.
+    last_active: Mutex<Instant>,
+    /// Proxied connections and UDP relays currently open.
+    active_conns: AtomicUsize,
+    /// The @client-exit stream, where the client is told why the session ends.
+    control: Mutex<Option<Stream>>,
+    /// Traffic of the session, for stats frames.
+    meter: Arc<SessionMeter>,
+    /// Whether the client asked for stats frames.
+    stats_subscribed: AtomicBool,
+    /// Whether new proxied streams are compressed, once the client negotiated it.
+    compress: AtomicBool,
+    /// What the pipes of the session carried, for the admin API.
+    link: Arc<LinkStats>,
+    /// Ports forwarded to the session's VPN address, until it ends.
+    forwards: Mutex<Vec<PortForward>>,
+    /// The bond of the session's running VPN, which other sessions can attach to.
+    bond: Mutex<Option<Arc<Bond>>>,
+    /// The token that attaches other sessions to the bond, if the client asked for one.
+    bond_token: Mutex<Option<[u8; 32]>>,
+    /// The bond of another session that this one attached to, whose VPN traffic it carries instead of running its own.
+    bonded: Mutex<Option<Weak<Bond>>>,
 }
 
 impl ClientExitImpl {
-    /// Creates a new ClientExitImpl.
-    pub fn new(vpn_ipv4: Option<Ipv4Addr>) -> Self {
+    /// Creates a new ClientExitImpl. In dev mode, it starts out authenticated with the static dev token.
+    pub fn new(
+        vpn_ipv4: Option<AssignedIpv4Addr>,
+        identity: Arc<ExitIdentity>,
+        link: Arc<LinkStats>,
+    ) -> Self {
+        let (is_plus, authed) = if CONFIG.dev_mode() {
+            (true, DEV_TOKEN_ID)
+        } else {
+            (false, 0) // FIX LATER
+        };
+        link.token_id.store(authed, Ordering::Relaxed);
         Self {
-            is_plus: AtomicBool::new(false),
-            authed: AtomicU64::new(0), // FIX LATER
+            is_plus: AtomicBool::new(is_plus),
+            authed: AtomicU64::new(authed),
+            vpn_ipv4: RwLock::new(vpn_ipv4),
+            vpn_started: AtomicBool::new(false),
+            identity,
+            resume_token: Mutex::new(None),
+            superseded: Arc::new(Event::new()),
+            last_active: Mutex::new(Instant::now()),
+            active_conns: AtomicUsize::new(0),
+            control: Mutex::new(None),
+            meter: Default::default(),
+            stats_subscribed: AtomicBool::new(false),
+            compress: AtomicBool::new(false),
+            link,
+            forwards: Mutex::new(Vec::new()),
+            bond: Mutex::new(None),
+            bond_token: Mutex::new(None),
+            bonded: Mutex::new(None),
+        }
+    }
+
+    /// Records that the client did something, which keeps the session from being reaped as idle.
+    fn on_activity(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+
+    /// Answers the resumption, bonding, stats, compression, bypass route and port forwarding methods, which the client-exit protocol doesn't know about. Returns None for every other method.
+    async fn respond_extension(&self, req: &JrpcRequest) -> Option<JrpcResponse> {
+        let result = match req.method.as_str() {
+            SUBSCRIBE_STATS_METHOD => {
+                self.stats_subscribed.store(true, Ordering::Relaxed);
+                serde_json::Value::Bool(true)
+            }
+            RESUMPTION_TOKEN_METHOD => self.resumption_token().map(hex::encode).into(),
+            RESUME_METHOD => req
+                .params
+                .first()
+                .and_then(|token| hex::decode(token.as_str()?).ok()?.try_into().ok())
+                .map(|token| self.resume(token))
+                .unwrap_or_default()
+                .into(),
+            COMPRESSION_METHOD => {
+                let allowed = compress::allowed(self.is_plus());
+                self.compress.store(allowed, Ordering::Relaxed);
+                allowed.into()
+            }
+            BOND_TOKEN_METHOD => self.bond_token().map(hex::encode).into(),
+            BOND_METHOD => req
+                .params
+                .first()
+                .and_then(|token| hex::decode(token.as_str()?).ok()?.try_into().ok())
+                .map(|token| self.attach(token))
+                .unwrap_or_default()
+                .into(),
+            BYPASS_ROUTES_METHOD => serde_json::to_value(CONFIG.bypass_routes()).ok()?,
+            FORWARD_PORT_METHOD => {
+                let requested = req
+                    .params
+                    .first()
+                    .and_then(|port| port.as_u64()?.try_into().ok());
+                self.forward_port(requested).await.into()
+            }
+            CLOSE_PORT_METHOD => {
+                let port = req.params.first().and_then(|port| port.as_u64());
+                let mut forwards = self.forwards.lock();
+                let before = forwards.len();
+                forwards.retain(|forward| Some(forward.port() as u64) != port);
+                (forwards.len() < before).into()
+            }
+            _ => return None,
+        };
+        Some(JrpcResponse {
+            jsonrpc: "2.0".into(),
+            result: Some(result),
+            error: None,
+            id: req.id.clone(),
+        })
+    }
+
+    /// Forwards a port to the session's VPN address, returning it. None if the session isn't a Plus one with a started VPN, or the port can't be forwarded.
+    async fn forward_port(&self, requested: Option<u16>) -> Option<u16> {
+        let token_id = self.authed()?;
+        // the address of a running VPN can't change under the forward
+        if !self.is_plus() || !self.vpn_started.load(Ordering::SeqCst) {
+            return None;
+        }
+        let vpn_ipv4 = self.vpn_ipv4.read().as_ref()?.addr();
+        match PortForward::open(token_id, vpn_ipv4, requested, None).await {
+            Ok(forward) => {
+                let port = forward.port();
+                self.forwards.lock().push(forward);
+                Some(port)
+            }
+            Err(err) => {
+                log::debug!("cannot forward a port: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Creates, renews or deletes the port forward that a PCP MAP request asks for, returning its public port or a PCP result code.
+    async fn map_pcp(&self, request: &pcp::MapRequest) -> Result<u16, u8> {
+        let token_id = self.authed().ok_or(pcp::NOT_AUTHORIZED)?;
+        let vpn_ipv4 = self.vpn_ipv4.read().as_ref().map(|ip| ip.addr());
+        let vpn_ipv4 = vpn_ipv4
+            .filter(|_| self.is_plus())
+            .ok_or(pcp::NOT_AUTHORIZED)?;
+        let existing = |forwards: &[PortForward]| {
+            forwards
+                .iter()
+                .find(|forward| forward.internal_port() == request.internal_port)
+                .map(|forward| forward.port())
+        };
+        {
+            let mut forwards = self.forwards.lock();
+            if request.lifetime == 0 {
+                forwards.retain(|forward| forward.internal_port() != request.internal_port);
+                return Ok(0);
+            }
+            if let Some(port) = existing(&forwards) {
+                return Ok(port);
+            }
+        }
+        let forward = PortForward::open(
+            token_id,
             vpn_ipv4,
+            request.suggested_port,
+            Some(request.internal_port),
+        )
+        .await
+        .map_err(|err| {
+            log::debug!("cannot map a port for PCP: {:?}", err);
+            pcp::NO_RESOURCES
+        })?;
+        let mut forwards = self.forwards.lock();
+        // a concurrent request may have mapped the same port meanwhile, in which case this forward is dropped
+        if let Some(port) = existing(&forwards) {
+            return Ok(port);
+        }
+        let port = forward.port();
+        forwards.push(forward);
+        Ok(port)
+    }
+
+    /// Hands out a token for resuming this session, the same one every time. None if the session isn't authenticated or resumption is disabled.
+    fn resumption_token(&self) -> Option<[u8; 32]> {
+        let mut resume_token = self.resume_token.lock();
+        if resume_token.is_none() {
+            let state = ResumeState {
+                token_id: self.authed()?,
+                is_plus: self.is_plus(),
+                vpn_ipv4: self.vpn_ipv4.read().clone(),
+            };
+            *resume_token = Some(resume::issue(state, self.superseded.clone())?);
+        }
+        *resume_token
+    }
+
+    /// Takes over the authentication and VPN address of the session with the given token, ending that session if it's still running.
+    fn resume(&self, token: [u8; 32]) -> bool {
+        // the address of a running VPN can't change under it, so the VPN can't start until the address is swapped
+        let mut vpn_ipv4 = self.vpn_ipv4.write();
+        if self.vpn_started.load(Ordering::SeqCst) {
+            return false;
+        }
+        let Some(state) = resume::resume(token) else {
+            ROOT_CTX.incr_stat(Metric::SessionResumeFailed);
+            return false;
+        };
+        if ROOT_CTX.is_revoked(state.token_id) {
+            ROOT_CTX.incr_stat(Metric::SessionRevoked);
+            return false;
+        }
+        self.is_plus.store(state.is_plus, Ordering::SeqCst);
+        self.set_authed(state.token_id);
+        if state.vpn_ipv4.is_some() {
+            *vpn_ipv4 = state.vpn_ipv4;
+        }
+        ROOT_CTX.incr_stat(Metric::SessionResumed);
+        true
+    }
+
+    /// Hands out a token for attaching other sessions to this one's VPN, the same one every time. None if the session isn't authenticated or its VPN isn't running.
+    fn bond_token(&self) -> Option<[u8; 32]> {
+        let token_id = self.authed()?;
+        let bond = self.bond.lock().clone()?;
+        let mut bond_token = self.bond_token.lock();
+        if bond_token.is_none() {
+            *bond_token = Some(bond::issue(Attachment {
+                token_id,
+                is_plus: self.is_plus(),
+                bond: Arc::downgrade(&bond),
+            }));
+        }
+        *bond_token
+    }
+
+    /// Attaches this session to the VPN of the session with the given bonding token, taking over its authentication.
+    fn attach(&self, token: [u8; 32]) -> bool {
+        // a session whose VPN is running can't carry another one's, and the VPN can't start while attaching
+        let _vpn_ipv4 = self.vpn_ipv4.write();
+        if self.vpn_started.load(Ordering::SeqCst) {
+            return false;
+        }
+        let Some(attachment) = bond::find(token) else {
+            ROOT_CTX.incr_stat(Metric::SessionBondFailed);
+            return false;
+        };
+        if ROOT_CTX.is_revoked(attachment.token_id) {
+            ROOT_CTX.incr_stat(Metric::SessionRevoked);
+            return false;
+        }
+        self.is_plus.store(attachment.is_plus, Ordering::SeqCst);
+        self.set_authed(attachment.token_id);
+        *self.bonded.lock() = Some(attachment.bond);
+        ROOT_CTX.incr_stat(Metric::SessionBonded);
+        true
+    }
+
+    /// Stops other sessions from attaching to the session's VPN, once it stops. Attached sessions end their VPN when they next hand it a batch.
+    fn unbond(&self) {
+        self.bond.lock().take();
+        if let Some(token) = self.bond_token.lock().take() {
+            bond::revoke(token);
+        }
+    }
+
+    /// Records the client as the holder of the session's address. Before the VPN starts, it first switches to the address the client had before a restart, or to a new address in its tier's pool or at its hashed position.
+    fn bind_lease(&self, token_id: u64) {
+        let mut vpn_ipv4 = self.vpn_ipv4.write();
+        let Some(current) = vpn_ipv4.as_ref() else {
+            return;
+        };
+        if !self.vpn_started.load(Ordering::SeqCst) {
+            if let Some(restored) = ROOT_CTX.vpn().take_restored(token_id) {
+                ROOT_CTX.incr_stat(Metric::LeaseRestored);
+                *vpn_ipv4 = Some(restored);
+                return;
+            }
+            let vpn = ROOT_CTX.vpn();
+            let tier = if self.is_plus() {
+                PoolTier::Plus
+            } else {
+                PoolTier::Free
+            };
+            let tier_pool = CONFIG
+                .cgnat_pools()
+                .iter()
+                .find(|pool| pool.tier() == tier)
+                .and_then(|pool| vpn.pool(pool.cidr()));
+            let assigner = tier_pool.unwrap_or_else(|| vpn.assigner());
+            let moved = match CONFIG.cgnat_assignment() {
+                CgnatAssignment::Hashed => Some(assigner.assign_hashed(token_id, &CGNAT_HASH_KEY)),
+                CgnatAssignment::Random if tier_pool.is_some() => Some(assigner.assign()),
+                CgnatAssignment::Random => None,
+            };
+            // if the pool is full, the client keeps the address it has
+            if let Some(Ok(moved)) = moved {
+                moved.set_client(token_id);
+                *vpn_ipv4 = Some(moved);
+                return;
+            }
+        }
+        current.set_client(token_id);
+    }
+
+    /// Parks this session for resumption once it ends, if the client asked for a token.
+    fn park(&self) {
+        if let Some(token) = *self.resume_token.lock() {
+            resume::park(token);
         }
     }
 
@@ -237,6 +1301,89 @@ impl ClientExitImpl {
         })
     }
 
+    /// Gets the data quota of the authenticated user, if their tier has one.
+    pub fn quota(&self) -> Option<QuotaHandle> {
+        QuotaHandle::new(self.authed()?, self.is_plus())
+    }
+
+    /// Whether the authenticated user is over a quota that cuts them off entirely.
+    pub fn quota_terminated(&self) -> bool {
+        self.quota()
+            .map(|quota| quota.terminated())
+            .unwrap_or_default()
+    }
+
+    /// What the gateway's status page shows about the session.
+    fn gateway_status(&self, vpn_ipv4: Ipv4Addr) -> SessionStatus {
+        let (daily_quota_left, monthly_quota_left) = self
+            .quota()
+            .map(|quota| quota.remaining())
+            .unwrap_or_default();
+        SessionStatus {
+            vpn_ipv4,
+            plus: self.is_plus(),
+            limit_kb: self.limiter().and_then(|limiter| limiter.limit_kb()),
+            daily_quota_left,
+            monthly_quota_left,
+        }
+    }
+
+    /// Sends the client a stats frame every `session_stats_secs`, once it has subscribed to them.
+    async fn stats_loop(&self, control: &Stream) -> anyhow::Result<()> {
+        let interval = Duration::from_secs(CONFIG.session_stats_secs());
+        let (mut last_up, mut last_down) = (0, 0);
+        loop {
+            smol::Timer::after(interval).await;
+            let up = self.meter.up.load(Ordering::Relaxed);
+            let down = self.meter.down.load(Ordering::Relaxed);
+            let (up_bps, down_bps) = (
+                (up - last_up) / interval.as_secs(),
+                (down - last_down) / interval.as_secs(),
+            );
+            (last_up, last_down) = (up, down);
+            if !self.stats_subscribed.load(Ordering::Relaxed) {
+                continue;
+            }
+            let (daily_quota_left, monthly_quota_left) = self
+                .quota()
+                .map(|quota| quota.remaining())
+                .unwrap_or_default();
+            let frame = StatsFrame {
+                up_bps,
+                down_bps,
+                dropped: self.meter.dropped.load(Ordering::Relaxed),
+                limit_kb: self.limiter().and_then(|limiter| limiter.limit_kb()),
+                daily_quota_left,
+                monthly_quota_left,
+            };
+            let _ = control.send_urel(frame.encode().into()).await;
+        }
+    }
+
+    /// Marks the session as authenticated with the given token ID.
+    fn set_authed(&self, token_id: u64) {
+        self.authed.store(token_id, Ordering::SeqCst);
+        self.link.token_id.store(token_id, Ordering::Relaxed);
+    }
+
+    /// Authenticates the session if the binder accepted the token, or couldn't be asked. Only a token the binder vouched for makes the session Plus.
+    fn accept_verdict(&self, token_id: u64, plus: bool, verdict: anyhow::Result<bool>) -> bool {
+        match verdict {
+            Ok(true) => {
+                if plus {
+                    self.is_plus.store(true, Ordering::SeqCst);
+                }
+                self.set_authed(token_id);
+                true
+            }
+            Ok(false) => false,
+            Err(_) => {
+                self.set_authed(token_id);
+                true
+            }
+        }
+    }
+
     /// Checks whether or not the authentication has completed.
     pub fn authed(&self) -> Option<u64> {
         let out = self.authed.load(Ordering::SeqCst);
@@ -256,6 +1403,9 @@ impl ClientExitImpl {
 #[async_trait]
 impl ClientExitProtocol for ClientExitImpl {
     async fn validate(&self, token: BlindToken) -> bool {
+        if CONFIG.dev_mode() {
+            return true;
+        }
         // fail-open
         let fallible = async {
             if let Some(client) = ROOT_CTX.binder_client.as_ref() {
@@ -266,24 +1416,131 @@ impl ClientExitProtocol for ClientExitImpl {
         };
         let h = blake3::hash(&token.stdcode());
         let token_id = u64::from_le_bytes(*array_ref![h.as_bytes(), 0, 8]);
-        match fallible.await {
-            Ok(val) => {
-                if token.level == Level::Plus {
-                    self.is_plus.store(true, Ordering::SeqCst);
-                }
-                self.authed.store(token_id, Ordering::SeqCst);
-                val
-            }
-            Err(_) => {
-                self.authed.store(token_id, Ordering::SeqCst);
-                true
-            }
+        if ROOT_CTX.is_revoked(token_id) {
+            log::debug!("refusing revoked token {}", redact::client(token_id));
+            ROOT_CTX.incr_stat(Metric::SessionRevoked);
+            return false;
+        }
+        let valid = self.accept_verdict(token_id, token.level == Level::Plus, fallible.await);
+        if valid {
+            self.bind_lease(token_id);
         }
+        valid
     }
 
     async fn telemetry_heartbeat(&self, _tele: ClientTelemetry) {}
 
     async fn get_vpn_ipv4(&self) -> Option<Ipv4Addr> {
-        self.vpn_ipv4
+        self.vpn_ipv4.read().as_ref().map(|addr| addr.addr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::smartchan::smart_channel;
+
+    use super::*;
+
+    #[test]
+    fn batch_encoding() {
+        let batch: Vec<Bytes> = [0, 1, 250, 251, 1500, 0xffff, 0x1_0000]
+            .iter()
+            .map(|len| (0..*len).map(|i| i as u8).collect::<Vec<u8>>().into())
+            .collect();
+        let mut encoded = BytesMut::new();
+        for n in [0, 1, batch.len()] {
+            let bts = encode_batch(&mut encoded, &batch[..n]);
+            assert_eq!(
+                bts[..],
+                stdcode::serialize(&batch[..n].to_vec()).unwrap()[..]
+            );
+            let decoded: Vec<Bytes> = stdcode::deserialize(&bts).unwrap();
+            assert_eq!(decoded, batch[..n]);
+        }
+    }
+
+    #[test]
+    fn coalescing() {
+        let (send, recv) = smart_channel(100, Duration::from_secs(1));
+        let small = Bytes::from_static(&[0; 100]);
+        let one = vec![small.clone()];
+        smol::block_on(async {
+            send.send_or_drop(small.clone());
+            let started = Instant::now();
+            assert!(next_coalesced(&recv, &[], started).await.is_some());
+            // a small batch waits out its budget for more
+            assert!(next_coalesced(&recv, &one, started).await.is_none());
+            assert!(started.elapsed() >= Duration::from_micros(2000));
+
+            let started = Instant::now();
+            let sender = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_micros(200));
+                send.send_or_drop(Bytes::from_static(&[1; 100]));
+                send
+            });
+            assert!(next_coalesced(&recv, &one, started).await.is_some());
+            let send = sender.join().unwrap();
+            // a full batch goes out at once
+            send.send_or_drop(small.clone());
+            recv.try_recv().unwrap();
+            let full = vec![small; 12];
+            let started = Instant::now();
+            assert!(next_coalesced(&recv, &full, started).await.is_none());
+            assert!(started.elapsed() < Duration::from_micros(2000));
+        });
+    }
+
+    /// A session as it starts outside dev mode, before the client authenticates.
+    fn unauthed_session() -> ClientExitImpl {
+        let identity = ExitIdentity::load(
+            String::new(),
+            CONFIG.secret_key(),
+            CONFIG.secret_sosistab2_key(),
+            CONFIG.sosistab2_listen().clone(),
+            None,
+        );
+        let session = ClientExitImpl::new(None, Arc::new(identity), Default::default());
+        session.set_authed(0);
+        session.is_plus.store(false, Ordering::SeqCst);
+        session
+    }
+
+    #[test]
+    fn rejected_token() {
+        let session = unauthed_session();
+        assert!(!session.accept_verdict(42, true, Ok(false)));
+        assert_eq!(session.authed(), None);
+        assert!(!session.is_plus());
+        assert_eq!(session.link.token_id.load(Ordering::Relaxed), 0);
+
+        // an unreachable binder lets the client in, but not as Plus
+        assert!(session.accept_verdict(42, true, Err(anyhow::anyhow!("unreachable"))));
+        assert_eq!(session.authed(), Some(42));
+        assert!(!session.is_plus());
+
+        let session = unauthed_session();
+        assert!(session.accept_verdict(43, true, Ok(true)));
+        assert_eq!(session.authed(), Some(43));
+        assert!(session.is_plus());
+    }
+
+    #[test]
+    fn rejected_token_gets_no_extensions() {
+        let session = unauthed_session();
+        session.vpn_started.store(true, Ordering::SeqCst);
+        assert!(!session.accept_verdict(42, true, Ok(false)));
+        assert_eq!(smol::block_on(session.forward_port(None)), None);
+        let request = pcp::MapRequest {
+            protocol: 6,
+            internal_port: 8080,
+            suggested_port: None,
+            lifetime: 3600,
+        };
+        assert_eq!(
+            smol::block_on(session.map_pcp(&request)),
+            Err(pcp::NOT_AUTHORIZED)
+        );
+        assert_eq!(session.resumption_token(), None);
+        assert_eq!(session.bond_token(), None);
     }
 }
@@ -23,6 +23,21 @@ pub static WHITE_PORTS: Lazy<FxHashSet<u16>> = Lazy::new(|| {
     toret.into_iter().collect()
 });
 
-/// List of blacklisted ports
-pub static BLACK_PORTS: Lazy<FxHashSet<u16>> =
-    Lazy::new(|| vec![25u16, 10000].into_iter().collect());
+/// List of blacklisted ports. Mail ports are left to the SMTP policy.
+pub static BLACK_PORTS: Lazy<FxHashSet<u16>> = Lazy::new(|| vec![10000u16].into_iter().collect());
+
+/// Ports of latency-sensitive protocols, whose VPN packets are served first: SSH, DNS, DNS-over-TLS, XMPP, STUN/TURN, and mosh.
+pub static INTERACTIVE_PORTS: Lazy<FxHashSet<u16>> = Lazy::new(|| {
+    let mut toret: FxHashSet<u16> = vec![22u16, 53, 853, 5222, 5223, 3478, 3479]
+        .into_iter()
+        .collect();
+    toret.extend(60000..=61000);
+    toret
+});
+
+/// Ports of bulk-transfer protocols, whose VPN packets are served last: FTP data, rsync, NNTP, and BitTorrent.
+pub static BULK_PORTS: Lazy<FxHashSet<u16>> = Lazy::new(|| {
+    let mut toret: FxHashSet<u16> = vec![20u16, 119, 563, 873].into_iter().collect();
+    toret.extend(6881..=6889);
+    toret
+});
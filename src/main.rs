@@ -1,21 +1,69 @@
-use std::{net::SocketAddr, ops::Deref};
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::Deref,
+};
 
+use cidr_utils::cidr::{IpCidr, Ipv4Cidr};
 use env_logger::Env;
 
 use smol::process::Command;
 
-use crate::{config::CONFIG, listen::main_loop};
+use crate::{
+    config::{Config, EgressRoute, CONFIG, OPT},
+    listen::main_loop,
+};
 
+mod admin;
 mod amnesiac_counter;
 mod asn;
+mod billing;
+mod bridge;
+mod clock;
+mod close_reason;
+mod compress;
 mod config;
 mod connect;
+mod dns;
+mod error;
+mod events;
+mod gateway;
+mod geoip;
+#[cfg(feature = "harness")]
+mod harness;
+mod health;
+mod identity;
 mod listen;
 mod lists;
+#[cfg(feature = "tcp-repair")]
+mod migrate;
+mod nat64;
+mod outbound;
+mod packet;
+mod panics;
+mod pcap;
+mod pcp;
+mod peer;
+mod port_forward;
+mod priority;
+mod quota;
 mod ratelimit;
+mod redact;
+mod relay;
+mod rollout;
 mod root_ctx;
+mod runtime;
+mod shadow;
 mod smartchan;
+mod smtp;
+mod sniff;
+mod sockbuf;
+mod stats;
+mod stats_client;
 mod stats_pipe;
+mod status_api;
+mod tun_backend;
+mod udp_relay;
+mod upgrade;
 mod vpn;
 
 // #[global_allocator]
@@ -28,22 +76,84 @@ fn main() -> anyhow::Result<()> {
         smolscale::permanently_single_threaded();
     }
     env_logger::Builder::from_env(Env::default().default_filter_or("geph4_exit=debug,warn")).init();
+    panics::install();
+
+    if OPT.check_config {
+        Config::parse(&std::fs::read_to_string(&OPT.config)?)?.validate()?;
+        println!("configuration file {:?} is valid", OPT.config);
+        return Ok(());
+    }
+    CONFIG.validate()?;
+    shadow::install();
+    runtime::configure_process();
+    let took_over = upgrade::take_over()?;
+
+    #[cfg(feature = "harness")]
+    if let Some(config::Command::Bench {
+        sessions,
+        secs,
+        packet_size,
+    }) = OPT.command.clone()
+    {
+        if !CONFIG.dev_mode() || CONFIG.nat_external_iface().is_none() {
+            anyhow::bail!("bench needs dev_mode and nat_external_iface in the configuration")
+        }
+        return smolscale::block_on(harness::bench(
+            sessions,
+            std::time::Duration::from_secs(secs),
+            packet_size,
+        ));
+    }
 
     log::info!(
         "read configuration file:\n{}",
         serde_json::to_string_pretty(&CONFIG.deref())?
     );
+    redact::announce();
 
-    if let Some(nat_interface) = CONFIG.nat_external_iface().as_ref() {
+    if CONFIG.dev_mode() {
+        log::warn!("running in dev mode, NOT configuring iptables or contacting any binder");
+    } else if took_over {
+        // flushing and rebuilding would leave new flows without NAT or redirects until it's done
+        log::warn!("keeping the iptables rules, IP rules and routes of the exit we took over from");
+    } else if let Some(nat_interface) = CONFIG.nat_external_iface().as_ref() {
         config_iptables(
             nat_interface,
             *CONFIG.force_dns(),
             !CONFIG.disable_tcp_termination(),
+            CONFIG.transparent_listen().port(),
+            CONFIG.nat64().as_ref().map(|nat64| nat64.range()),
+            CONFIG.egress_routes(),
         )?;
     }
 
     smolscale::block_on(async move {
-        if let Some(range) = CONFIG.random_ipv6_range() {
+        if !CONFIG.dev_mode() && !took_over {
+            for route in CONFIG.egress_routes() {
+                if let (Some(mark), Some(table)) = (route.fwmark(), route.table()) {
+                    for family in ["-4", "-6"] {
+                        let rule = [
+                            "fwmark".to_string(),
+                            mark.to_string(),
+                            "table".to_string(),
+                            table.to_string(),
+                        ];
+                        // deleting first keeps restarts from stacking duplicate rules
+                        Command::new("ip")
+                            .args([family, "rule", "del"])
+                            .args(&rule)
+                            .output()
+                            .await?;
+                        Command::new("ip")
+                            .args([family, "rule", "add"])
+                            .args(&rule)
+                            .output()
+                            .await?;
+                    }
+                }
+            }
+        }
+        if let Some(range) = CONFIG.random_ipv6_range().filter(|_| !took_over) {
             if let Some(iface) = CONFIG.ipv6_interface() {
                 Command::new("ip")
                     .arg("-6")
@@ -72,11 +182,48 @@ fn main() -> anyhow::Result<()> {
     })
 }
 
+/// The iptables rules that steer VPN traffic by egress route. Only IPv4 prefixes apply, and these rules come before the default masquerading.
+fn egress_route_rules(egress_routes: &[EgressRoute]) -> String {
+    let mut rules = String::new();
+    for route in egress_routes {
+        for prefix in route.prefixes() {
+            let IpCidr::V4(prefix) = prefix else {
+                continue;
+            };
+            if let Some(mark) = route.fwmark() {
+                rules += &format!(
+                    "iptables -t mangle -A PREROUTING -i tun-geph -d {} -j MARK --set-mark {}\n",
+                    prefix, mark
+                );
+            }
+            if let Some(IpAddr::V4(source)) = route.source_ip() {
+                for pool in CONFIG.all_cgnat_pools() {
+                    rules += &format!(
+                        "iptables -t nat -A POSTROUTING -s {} -d {} -j SNAT --to-source {} --random-fully\n",
+                        pool, prefix, source
+                    );
+                }
+            }
+        }
+        // marked traffic may leave through another interface than the usual one
+        if let (Some(mark), None | Some(IpAddr::V6(_))) = (route.fwmark(), route.source_ip()) {
+            rules += &format!(
+                "iptables -t nat -A POSTROUTING -m mark --mark {} -j MASQUERADE --random-fully\n",
+                mark
+            );
+        }
+    }
+    rules
+}
+
 /// Configures iptables.
 fn config_iptables(
     nat_interface: &str,
     force_dns: Option<SocketAddr>,
     tcp_redirect: bool,
+    transparent_port: u16,
+    nat64_range: Option<Ipv4Cidr>,
+    egress_routes: &[EgressRoute],
 ) -> anyhow::Result<()> {
     let to_run = format!(
         r#"
@@ -87,6 +234,10 @@ iptables --flush
 iptables -t nat -F
 iptables -t mangle -F
 
+# only connections redirected from tun-geph may reach the transparent proxy
+iptables -A INPUT -p tcp --dport {} ! -i tun-geph -j DROP
+{}
+{}
 {}
 {}
 
@@ -96,10 +247,14 @@ iptables -A FORWARD -i tun-geph -o $INTERFACE -j ACCEPT
 iptables -t mangle -A FORWARD -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1240
 "#,
         nat_interface,
+        transparent_port,
         if tcp_redirect {
-            "iptables -t nat -A PREROUTING -i tun-geph -p tcp --syn -j REDIRECT --match multiport --dports 80,443,8080 --to-ports 10000"
+            format!(
+                "iptables -t nat -A PREROUTING -i tun-geph -p tcp --syn -j REDIRECT --match multiport --dports 80,443,8080 --to-ports {}",
+                transparent_port
+            )
         } else {
-            ""
+            String::new()
         },
         force_dns
             .map(|d| {
@@ -108,7 +263,17 @@ iptables -t mangle -A FORWARD -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss
                     d
                 )
             })
-            .unwrap_or_default()
+            .unwrap_or_default(),
+        // every TCP port, since the transparent proxy is the only way to reach these
+        nat64_range
+            .map(|range| {
+                format!(
+                    "iptables -t nat -A PREROUTING -i tun-geph -p tcp --syn -d {} -j REDIRECT --to-ports {}",
+                    range, transparent_port
+                )
+            })
+            .unwrap_or_default(),
+        egress_route_rules(egress_routes)
     );
     let mut cmd = std::process::Command::new("sh")
         .arg("-c")
@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::unix::prelude::{AsRawFd, OwnedFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use event_listener::Event;
+use libc::{c_int, c_void, socklen_t, IPPROTO_TCP};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::Async;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{config::CONFIG, ratelimit, relay};
+
+/// Queues that `TCP_REPAIR_QUEUE` selects, from linux/tcp.h.
+const TCP_NO_QUEUE: c_int = 0;
+const TCP_RECV_QUEUE: c_int = 1;
+const TCP_SEND_QUEUE: c_int = 2;
+
+/// Options that `TCP_REPAIR_OPTIONS` restores, by their TCP option kinds.
+const TCPOPT_MSS: u32 = 2;
+const TCPOPT_WINDOW: u32 = 3;
+const TCPOPT_SACK_PERM: u32 = 4;
+const TCPOPT_TIMESTAMP: u32 = 8;
+
+/// Bits of `tcpi_options` in `TCP_INFO`.
+const TCPI_OPT_TIMESTAMPS: u8 = 1;
+const TCPI_OPT_SACK: u8 = 2;
+const TCPI_OPT_WSCALE: u8 = 4;
+
+/// `struct tcp_repair_window`
+#[repr(C)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct RepairWindow {
+    snd_wl1: u32,
+    snd_wnd: u32,
+    max_window: u32,
+    rcv_wnd: u32,
+    rcv_wup: u32,
+}
+
+/// `struct tcp_repair_opt`
+#[repr(C)]
+struct RepairOpt {
+    code: u32,
+    value: u32,
+}
+
+/// The state of an established TCP connection, enough to rebuild its socket.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TcpSnapshot {
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// Sequence number of the first byte of each queue
+    send_seq: u32,
+    recv_seq: u32,
+    /// Bytes sent but not acknowledged, or not sent yet
+    send_queue: Vec<u8>,
+    /// Bytes received but not read
+    recv_queue: Vec<u8>,
+    mss: u32,
+    /// Our window scale and the peer's, if negotiated
+    wscale: Option<(u8, u8)>,
+    sack: bool,
+    timestamp: Option<u32>,
+    /// Not available before Linux 4.8
+    window: Option<RepairWindow>,
+}
+
+impl TcpSnapshot {
+    /// Puts the socket in repair mode, which silences it and makes closing it send nothing, and takes its state.
+    pub fn take(socket: &TcpStream) -> io::Result<Self> {
+        let fd = socket.as_raw_fd();
+        setsockopt(fd, libc::TCP_REPAIR, &1 as &c_int)?;
+        let (send_seq, send_queue) = take_queue(fd, TCP_SEND_QUEUE, libc::TIOCOUTQ)?;
+        let (recv_seq, recv_queue) = take_queue(fd, TCP_RECV_QUEUE, libc::FIONREAD)?;
+        setsockopt(fd, libc::TCP_REPAIR_QUEUE, &TCP_NO_QUEUE)?;
+        let mss: c_int = getsockopt(fd, libc::TCP_MAXSEG)?;
+        // only the leading bytes of struct tcp_info are needed: tcpi_options, then the window scales as two nibbles
+        let info: [u8; 8] = getsockopt(fd, libc::TCP_INFO)?;
+        let options = info[5];
+        let timestamp = if options & TCPI_OPT_TIMESTAMPS != 0 {
+            Some(getsockopt(fd, libc::TCP_TIMESTAMP)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            local: socket.local_addr()?,
+            remote: socket.peer_addr()?,
+            send_seq,
+            recv_seq,
+            send_queue,
+            recv_queue,
+            mss: mss as u32,
+            wscale: (options & TCPI_OPT_WSCALE != 0).then_some((info[6] & 0xf, info[6] >> 4)),
+            sack: options & TCPI_OPT_SACK != 0,
+            timestamp,
+            window: getsockopt(fd, libc::TCP_REPAIR_WINDOW).ok(),
+        })
+    }
+
+    /// Rebuilds the connection in a new socket, which carries on from where the snapshot's left off. The old socket must be closed first.
+    pub fn restore(&self) -> io::Result<TcpStream> {
+        let socket = Socket::new(
+            Domain::for_address(self.remote),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        let fd = socket.as_raw_fd();
+        // repair mode also lets the socket bind to the port of a listener
+        setsockopt(fd, libc::TCP_REPAIR, &1 as &c_int)?;
+        socket.bind(&self.local.into())?;
+        setsockopt(fd, libc::TCP_REPAIR_QUEUE, &TCP_RECV_QUEUE)?;
+        setsockopt(fd, libc::TCP_QUEUE_SEQ, &self.recv_seq)?;
+        setsockopt(fd, libc::TCP_REPAIR_QUEUE, &TCP_SEND_QUEUE)?;
+        setsockopt(fd, libc::TCP_QUEUE_SEQ, &self.send_seq)?;
+        // in repair mode, this makes the socket established without a handshake
+        socket.connect(&self.remote.into())?;
+
+        let mut options = vec![RepairOpt {
+            code: TCPOPT_MSS,
+            value: self.mss,
+        }];
+        if let Some((ours, theirs)) = self.wscale {
+            options.push(RepairOpt {
+                code: TCPOPT_WINDOW,
+                value: ours as u32 | (theirs as u32) << 16,
+            });
+        }
+        if self.sack {
+            options.push(RepairOpt {
+                code: TCPOPT_SACK_PERM,
+                value: 0,
+            });
+        }
+        if let Some(timestamp) = self.timestamp {
+            options.push(RepairOpt {
+                code: TCPOPT_TIMESTAMP,
+                value: 0,
+            });
+            setsockopt(fd, libc::TCP_TIMESTAMP, &timestamp)?;
+        }
+        setsockopt_raw(
+            fd,
+            libc::TCP_REPAIR_OPTIONS,
+            options.as_ptr() as *const c_void,
+            std::mem::size_of_val(&options[..]),
+        )?;
+
+        // what is restored into the send queue counts as sent, and is retransmitted unless the peer already has it
+        restore_queue(fd, TCP_RECV_QUEUE, &self.recv_queue)?;
+        restore_queue(fd, TCP_SEND_QUEUE, &self.send_queue)?;
+        // checked against the restored receive queue
+        if let Some(window) = &self.window {
+            setsockopt(fd, libc::TCP_REPAIR_WINDOW, window)?;
+        }
+        setsockopt(fd, libc::TCP_REPAIR_QUEUE, &TCP_NO_QUEUE)?;
+        setsockopt(fd, libc::TCP_REPAIR, &0 as &c_int)?;
+        Ok(socket.into())
+    }
+}
+
+/// Reads the sequence number and the contents of one queue of a socket in repair mode.
+fn take_queue(fd: RawFd, queue: c_int, size_ioctl: libc::Ioctl) -> io::Result<(u32, Vec<u8>)> {
+    setsockopt(fd, libc::TCP_REPAIR_QUEUE, &queue)?;
+    // the sequence number after the end of the queue
+    let end_seq: u32 = getsockopt(fd, libc::TCP_QUEUE_SEQ)?;
+    let mut len: c_int = 0;
+    if unsafe { libc::ioctl(fd, size_ioctl, &mut len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; len as usize];
+    if len > 0 {
+        let n = unsafe {
+            libc::recv(
+                fd,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n as usize != buf.len() {
+            return Err(io::Error::other("queue changed while being read"));
+        }
+    }
+    Ok((end_seq.wrapping_sub(len as u32), buf))
+}
+
+/// Writes bytes into one queue of a socket in repair mode.
+fn restore_queue(fd: RawFd, queue: c_int, mut bytes: &[u8]) -> io::Result<()> {
+    setsockopt(fd, libc::TCP_REPAIR_QUEUE, &queue)?;
+    while !bytes.is_empty() {
+        let n = unsafe { libc::send(fd, bytes.as_ptr() as *const c_void, bytes.len(), 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        bytes = &bytes[n as usize..];
+    }
+    Ok(())
+}
+
+fn setsockopt<T>(fd: RawFd, opt: c_int, value: &T) -> io::Result<()> {
+    setsockopt_raw(
+        fd,
+        opt,
+        value as *const T as *const c_void,
+        std::mem::size_of::<T>(),
+    )
+}
+
+fn setsockopt_raw(fd: RawFd, opt: c_int, value: *const c_void, len: usize) -> io::Result<()> {
+    if unsafe { libc::setsockopt(fd, IPPROTO_TCP, opt, value, len as socklen_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads a TCP socket option of a plain-old-data type.
+fn getsockopt<T: Copy>(fd: RawFd, opt: c_int) -> io::Result<T> {
+    let mut value = std::mem::MaybeUninit::<T>::zeroed();
+    let mut len = std::mem::size_of::<T>() as socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            fd,
+            IPPROTO_TCP,
+            opt,
+            value.as_mut_ptr() as *mut c_void,
+            &mut len,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { value.assume_init() })
+}
+
+/// A transparent-proxy connection that can move to a new exit process on upgrade.
+struct Entry {
+    client: TcpStream,
+    remote: TcpStream,
+    /// Whether both of its relays wrote out what they held after the freeze
+    drained: AtomicBool,
+}
+
+static ENTRIES: Lazy<Mutex<HashMap<u64, Arc<Entry>>>> = Lazy::new(Default::default);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Whether connections were frozen for a handoff.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Notified when a handoff of frozen connections failed.
+static ABANDONED: Lazy<Event> = Lazy::new(Event::new);
+
+/// A registered connection, unregistered when dropped.
+pub struct Registration {
+    id: u64,
+    entry: Arc<Entry>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        ENTRIES.lock().remove(&self.id);
+    }
+}
+
+impl Registration {
+    /// Marks the connection as drained, then waits for the process to exit after handing it off. Returns if the handoff failed, after which the connection can only be closed.
+    pub async fn drained(&self) {
+        let abandoned = ABANDONED.listen();
+        self.entry.drained.store(true, Ordering::Relaxed);
+        abandoned.await
+    }
+}
+
+/// Registers the sockets of a transparent-proxy connection to be handed off on upgrade.
+pub fn register(client: &TcpStream, remote: &TcpStream) -> io::Result<Registration> {
+    let entry = Arc::new(Entry {
+        client: client.try_clone()?,
+        remote: remote.try_clone()?,
+        drained: AtomicBool::new(false),
+    });
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    ENTRIES.lock().insert(id, entry.clone());
+    Ok(Registration { id, entry })
+}
+
+/// Whether connections were frozen for a handoff, after which relays that return must call [`Registration::drained`] rather than close their connections.
+pub fn frozen() -> bool {
+    FROZEN.load(Ordering::Relaxed)
+}
+
+/// Freezes the registered connections, then waits up to the given time for their relays to write out what they already read. Returns the sockets of the connections that drained, as client and remote pairs.
+///
+/// Freezing shuts down reading on both sockets, which puts nothing on the wire but makes the relays see the end of their streams. Anything that arrives afterwards waits in the kernel's receive queues, which the new process restores along with the rest of the connection, clearing the shutdown.
+pub async fn freeze(timeout: Duration) -> Vec<(RawFd, RawFd)> {
+    FROZEN.store(true, Ordering::Relaxed);
+    let entries: Vec<Arc<Entry>> = ENTRIES.lock().values().cloned().collect();
+    for entry in entries.iter() {
+        let _ = entry.client.shutdown(Shutdown::Read);
+        let _ = entry.remote.shutdown(Shutdown::Read);
+    }
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline
+        && !entries
+            .iter()
+            .all(|entry| entry.drained.load(Ordering::Relaxed))
+    {
+        smol::Timer::after(Duration::from_millis(10)).await;
+    }
+    // the registry holds on to the entries until the process exits
+    entries
+        .iter()
+        .filter(|entry| entry.drained.load(Ordering::Relaxed))
+        .map(|entry| (entry.client.as_raw_fd(), entry.remote.as_raw_fd()))
+        .collect()
+}
+
+/// Lets the frozen connections close, since they could not be handed off.
+pub fn abandon() {
+    ABANDONED.notify(usize::MAX);
+}
+
+/// Rebuilds the connections handed off by the previous exit process, which must have exited, and relays them. Returns how many it resumed.
+pub fn resume(connections: Vec<(OwnedFd, OwnedFd)>) -> usize {
+    let mut resumed = 0;
+    for (client, remote) in connections {
+        match rebuild(client.into(), remote.into()) {
+            Ok((client, remote)) => {
+                resumed += 1;
+                smolscale::spawn(async move {
+                    if let Err(err) = relay_resumed(client, remote).await {
+                        log::debug!("migrated connection ended: {:?}", err);
+                    }
+                })
+                .detach();
+            }
+            Err(err) => log::warn!("cannot resume a migrated connection: {:?}", err),
+        }
+    }
+    resumed
+}
+
+/// Rebuilds both sockets of a connection. Each old socket is closed right before its new one takes its place, so that the kernel has no socket for its packets only for a moment.
+fn rebuild(client: TcpStream, remote: TcpStream) -> io::Result<(TcpStream, TcpStream)> {
+    let snapshots = (TcpSnapshot::take(&client)?, TcpSnapshot::take(&remote)?);
+    drop(client);
+    let client = snapshots.0.restore()?;
+    drop(remote);
+    let remote = snapshots.1.restore()?;
+    Ok((client, remote))
+}
+
+/// Relays a resumed connection under the global rate limit, since its session is gone, keeping it migratable.
+async fn relay_resumed(client: TcpStream, remote: TcpStream) -> anyhow::Result<()> {
+    let registration = register(&client, &remote)?;
+    let client = async_dup::Arc::new(Async::new(client)?);
+    let remote = async_dup::Arc::new(Async::new(remote)?);
+    let buffers = CONFIG.relay_buffers();
+    let limiter = ratelimit::global_limiter();
+    let up = smolscale::spawn(relay::relay(remote.clone(), client.clone(), buffers, |n| {
+        limiter.wait(n)
+    }));
+    relay::relay(client, remote, buffers, |n| limiter.wait(n)).await?;
+    if frozen() {
+        up.await?;
+        registration.drained().await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        // what arrives after a freeze is kept for the rebuilt socket
+        server.shutdown(Shutdown::Read).unwrap();
+        client.write_all(b"unread").unwrap();
+        server.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let snapshot = match TcpSnapshot::take(&server) {
+            Ok(snapshot) => snapshot,
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                eprintln!("skipping: TCP_REPAIR needs CAP_NET_ADMIN");
+                return;
+            }
+            Err(err) => panic!("{:?}", err),
+        };
+        assert_eq!(snapshot.recv_queue, b"unread");
+        drop(server);
+        let mut server = snapshot.restore().unwrap();
+
+        let mut buf = [0; 6];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"unread");
+        server.write_all(b"again").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"again");
+        client.write_all(b"more").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"more");
+    }
+}
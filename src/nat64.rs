@@ -0,0 +1,177 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use arrayref::array_ref;
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol::{net::UdpSocket, Task};
+
+use crate::{
+    config::CONFIG,
+    packet::{build_udp, udp_payload, PacketHeaders},
+    redact,
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+    udp_relay::udp_allowed,
+};
+
+/// Synthetic addresses that haven't been looked up or used for this long are handed out again.
+const MAPPING_IDLE: Duration = Duration::from_secs(7200);
+
+/// UDP flows to IPv6 destinations that carry nothing for this long are closed.
+const FLOW_IDLE: Duration = Duration::from_secs(60);
+
+/// The IPv6 address each synthetic IPv4 address stands for.
+static FORWARD: Lazy<Cache<Ipv4Addr, Ipv6Addr>> =
+    Lazy::new(|| Cache::builder().time_to_idle(MAPPING_IDLE).build());
+
+/// The synthetic IPv4 address of each IPv6 address, which may be stale if [`FORWARD`] has reused it.
+static REVERSE: Lazy<Cache<Ipv6Addr, Ipv4Addr>> =
+    Lazy::new(|| Cache::builder().time_to_idle(MAPPING_IDLE).build());
+
+/// Whether the address is in the synthetic range.
+pub fn is_synthetic(addr: Ipv4Addr) -> bool {
+    CONFIG
+        .nat64()
+        .as_ref()
+        .map(|nat64| nat64.range().contains(addr))
+        .unwrap_or_default()
+}
+
+/// The IPv6 address that a synthetic address stands for, if it is mapped.
+pub fn translate(addr: Ipv4Addr) -> Option<Ipv6Addr> {
+    FORWARD.get(&addr)
+}
+
+/// Maps an IPv6 address to a synthetic IPv4 address, reusing its current one if it has one. Returns None if NAT64 is off or the range is full.
+pub fn synthesize(v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    let range = CONFIG.nat64().as_ref()?.range();
+    // allocation must not race, or two addresses could get the same synthetic one
+    static ALLOCATE: Mutex<()> = Mutex::new(());
+    let _guard = ALLOCATE.lock();
+    if let Some(v4) = REVERSE.get(&v6) {
+        if FORWARD.get(&v4) == Some(v6) {
+            return Some(v4);
+        }
+    }
+    // the first and last addresses of the range are never used
+    let size = range.size() as u32 - 2;
+    let hash = blake3::hash(&v6.octets());
+    let start = u32::from_le_bytes(*array_ref![hash.as_bytes(), 0, 4]) % size;
+    for offset in 0..size {
+        let candidate = Ipv4Addr::from(range.first() + 1 + (start + offset) % size);
+        if !FORWARD.contains_key(&candidate) {
+            FORWARD.insert(candidate, v6);
+            REVERSE.insert(v6, candidate);
+            return Some(candidate);
+        }
+    }
+    ROOT_CTX.incr_stat(Metric::Nat64RangeFull);
+    None
+}
+
+/// A UDP flow from a VPN client to an IPv6 destination.
+struct Flow {
+    socket: UdpSocket,
+    _replies: Task<()>,
+}
+
+/// The client address and port and the synthetic destination address and port of a flow.
+type FlowKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// Open flows.
+static FLOWS: Lazy<Cache<FlowKey, Arc<Flow>>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_idle(FLOW_IDLE)
+        .max_capacity(100_000)
+        .build()
+});
+
+/// Sends a UDP packet from a VPN client to a synthetic address on to the IPv6 address behind it. Replies come back through the VPN as if from the synthetic address.
+pub async fn send_udp(headers: &PacketHeaders, pkt: &[u8]) {
+    let (Some(source_port), Some(dest_port)) = (headers.source_port, headers.dest_port) else {
+        return;
+    };
+    let (Some(v6), Some(payload)) = (translate(headers.destination), udp_payload(pkt)) else {
+        ROOT_CTX.incr_stat(Metric::Nat64Unmapped);
+        return;
+    };
+    let dest = SocketAddr::new(v6.into(), dest_port);
+    if !udp_allowed(dest) {
+        return;
+    }
+    let key = (headers.source, source_port, headers.destination, dest_port);
+    let flow = match FLOWS.get(&key) {
+        Some(flow) => flow,
+        None => match open_flow(key, dest).await {
+            Ok(flow) => {
+                FLOWS.insert(key, flow.clone());
+                flow
+            }
+            Err(err) => {
+                log::debug!(
+                    "cannot open NAT64 flow to {}: {:?}",
+                    redact::destination(dest),
+                    err
+                );
+                return;
+            }
+        },
+    };
+    if let Err(err) = flow.socket.send(payload).await {
+        log::debug!(
+            "cannot send NAT64 datagram to {}: {:?}",
+            redact::destination(dest),
+            err
+        );
+    }
+}
+
+/// Opens a flow's socket, with a task that turns replies into IPv4 packets for the client.
+async fn open_flow(
+    (client, client_port, synthetic, synthetic_port): FlowKey,
+    dest: SocketAddr,
+) -> anyhow::Result<Arc<Flow>> {
+    let socket = UdpSocket::bind("[::]:0").await?;
+    socket.connect(dest).await?;
+    ROOT_CTX.incr_stat(Metric::Nat64UdpFlow);
+    let replies = smolscale::spawn({
+        let socket = socket.clone();
+        async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok(n) = socket.recv(&mut buf).await {
+                if let Some(pkt) = build_udp(
+                    (synthetic, synthetic_port),
+                    (client, client_port),
+                    &buf[..n],
+                ) {
+                    ROOT_CTX.vpn().dispatch_down(&pkt);
+                }
+            }
+        }
+    });
+    Ok(Arc::new(Flow {
+        socket,
+        _replies: replies,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesis() {
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let other: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let v4 = synthesize(v6).unwrap();
+        assert!(is_synthetic(v4));
+        assert_eq!(synthesize(v6), Some(v4));
+        assert_ne!(synthesize(other), Some(v4));
+        assert_eq!(translate(v4), Some(v6));
+    }
+}
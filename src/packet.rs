@@ -0,0 +1,245 @@
+//! Header parsing for the VPN packet path. This module only depends on `pnet_packet`, so that the fuzz targets in `fuzz/` can include it directly.
+
+use std::net::Ipv4Addr;
+
+use pnet_packet::{
+    icmp::{
+        self,
+        destination_unreachable::{IcmpCodes, MutableDestinationUnreachablePacket},
+        IcmpTypes, MutableIcmpPacket,
+    },
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket},
+    udp::{self, MutableUdpPacket, UdpPacket},
+    Packet,
+};
+
+/// The parts of an IPv4 packet's headers that the exit makes decisions on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketHeaders {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: IpNextHeaderProtocol,
+    /// Source port, if this is a TCP or UDP packet with a complete transport header.
+    pub source_port: Option<u16>,
+    /// Destination port, if this is a TCP or UDP packet with a complete transport header.
+    pub dest_port: Option<u16>,
+}
+
+impl PacketHeaders {
+    /// Parses the headers of a raw IPv4 packet. Returns None if the packet is too short to be IPv4.
+    pub fn parse(bts: &[u8]) -> Option<Self> {
+        let pkt = Ipv4Packet::new(bts)?;
+        let protocol = pkt.get_next_level_protocol();
+        let ports = match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                TcpPacket::new(pkt.payload()).map(|v| (v.get_source(), v.get_destination()))
+            }
+            IpNextHeaderProtocols::Udp => {
+                UdpPacket::new(pkt.payload()).map(|v| (v.get_source(), v.get_destination()))
+            }
+            _ => None,
+        };
+        Some(Self {
+            source: pkt.get_source(),
+            destination: pkt.get_destination(),
+            protocol,
+            source_port: ports.map(|p| p.0),
+            dest_port: ports.map(|p| p.1),
+        })
+    }
+
+    /// Whether this is a UDP packet.
+    pub fn is_udp(&self) -> bool {
+        self.protocol == IpNextHeaderProtocols::Udp
+    }
+
+    /// Whether this is a TCP packet.
+    pub fn is_tcp(&self) -> bool {
+        self.protocol == IpNextHeaderProtocols::Tcp
+    }
+}
+
+/// Returns the payload of a raw IPv4 UDP packet.
+pub fn udp_payload(bts: &[u8]) -> Option<&[u8]> {
+    let pkt = Ipv4Packet::new(bts)?;
+    if pkt.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let header_len = pkt.get_header_length() as usize * 4;
+    let udp = bts.get(header_len..)?;
+    UdpPacket::new(udp)?;
+    udp.get(8..)
+}
+
+/// The sequencing of a TCP segment in a raw IPv4 packet, with its payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpSegment<'a> {
+    pub seq: u32,
+    pub ack: u32,
+    /// The flags, as in [`pnet_packet::tcp::TcpFlags`].
+    pub flags: u16,
+    pub payload: &'a [u8],
+}
+
+/// Returns the TCP segment of a raw IPv4 packet.
+pub fn tcp_segment(bts: &[u8]) -> Option<TcpSegment<'_>> {
+    let pkt = Ipv4Packet::new(bts)?;
+    if pkt.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let header_len = pkt.get_header_length() as usize * 4;
+    let segment = bts.get(header_len..pkt.get_total_length() as usize)?;
+    let tcp = TcpPacket::new(segment)?;
+    Some(TcpSegment {
+        seq: tcp.get_sequence(),
+        ack: tcp.get_acknowledgement(),
+        flags: tcp.get_flags(),
+        payload: segment.get(tcp.get_data_offset() as usize * 4..)?,
+    })
+}
+
+/// Builds a raw IPv4 TCP packet with no options, with checksums.
+pub fn build_tcp(
+    source: (Ipv4Addr, u16),
+    destination: (Ipv4Addr, u16),
+    segment: TcpSegment<'_>,
+) -> Option<Vec<u8>> {
+    let total_len = u16::try_from(40 + segment.payload.len()).ok()?;
+    let mut buf = vec![0u8; total_len as usize];
+    {
+        let mut tcp = MutableTcpPacket::new(&mut buf[20..])?;
+        tcp.set_source(source.1);
+        tcp.set_destination(destination.1);
+        tcp.set_sequence(segment.seq);
+        tcp.set_acknowledgement(segment.ack);
+        tcp.set_data_offset(5);
+        tcp.set_flags(segment.flags);
+        tcp.set_window(65535);
+        tcp.set_payload(segment.payload);
+        let checksum = tcp::ipv4_checksum(&tcp.to_immutable(), &source.0, &destination.0);
+        tcp.set_checksum(checksum);
+    }
+    write_ipv4_header(
+        &mut buf,
+        source.0,
+        destination.0,
+        IpNextHeaderProtocols::Tcp,
+    )?;
+    Some(buf)
+}
+
+/// Builds a raw IPv4 UDP packet, with checksums.
+pub fn build_udp(
+    source: (Ipv4Addr, u16),
+    destination: (Ipv4Addr, u16),
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let udp_len = u16::try_from(8 + payload.len()).ok()?;
+    let total_len = udp_len.checked_add(20)?;
+    let mut buf = vec![0u8; total_len as usize];
+    {
+        let mut udp = MutableUdpPacket::new(&mut buf[20..])?;
+        udp.set_source(source.1);
+        udp.set_destination(destination.1);
+        udp.set_length(udp_len);
+        udp.set_payload(payload);
+        let checksum = udp::ipv4_checksum(&udp.to_immutable(), &source.0, &destination.0);
+        udp.set_checksum(checksum);
+    }
+    write_ipv4_header(
+        &mut buf,
+        source.0,
+        destination.0,
+        IpNextHeaderProtocols::Udp,
+    )?;
+    Some(buf)
+}
+
+/// Builds the TCP reset that refuses a raw IPv4 TCP packet, as if sent by its destination, following RFC 793 for segments to a closed port. Returns None for resets, which must not be answered.
+pub fn build_tcp_reset(original: &[u8]) -> Option<Vec<u8>> {
+    let headers = PacketHeaders::parse(original)?;
+    let segment = tcp_segment(original)?;
+    if segment.flags & TcpFlags::RST != 0 {
+        return None;
+    }
+    let reply = if segment.flags & TcpFlags::ACK != 0 {
+        TcpSegment {
+            seq: segment.ack,
+            ack: 0,
+            flags: TcpFlags::RST,
+            payload: &[],
+        }
+    } else {
+        // SYN and FIN each take up a sequence number
+        let len = segment.payload.len() as u32
+            + (segment.flags & TcpFlags::SYN != 0) as u32
+            + (segment.flags & TcpFlags::FIN != 0) as u32;
+        TcpSegment {
+            seq: 0,
+            ack: segment.seq.wrapping_add(len),
+            flags: TcpFlags::RST | TcpFlags::ACK,
+            payload: &[],
+        }
+    };
+    build_tcp(
+        (headers.destination, headers.dest_port?),
+        (headers.source, headers.source_port?),
+        reply,
+    )
+}
+
+/// Builds the ICMP "communication administratively prohibited" error for a raw IPv4 packet, as if sent by its destination. Returns None for packets that must not get an ICMP error, such as ICMP packets and fragments other than the first.
+pub fn build_icmp_prohibited(original: &[u8]) -> Option<Vec<u8>> {
+    let pkt = Ipv4Packet::new(original)?;
+    if pkt.get_next_level_protocol() == IpNextHeaderProtocols::Icmp
+        || pkt.get_fragment_offset() != 0
+    {
+        return None;
+    }
+    // the original IP header and the first 8 bytes of its payload, as RFC 792 asks for
+    let header_len = pkt.get_header_length() as usize * 4;
+    let quoted = &original[..(header_len + 8).min(original.len())];
+    let total_len = 20 + 8 + quoted.len();
+    let mut buf = vec![0u8; total_len];
+    {
+        let mut icmp = MutableDestinationUnreachablePacket::new(&mut buf[20..])?;
+        icmp.set_icmp_type(IcmpTypes::DestinationUnreachable);
+        icmp.set_icmp_code(IcmpCodes::CommunicationAdministrativelyProhibited);
+        icmp.set_payload(quoted);
+    }
+    {
+        let mut icmp = MutableIcmpPacket::new(&mut buf[20..])?;
+        let checksum = icmp::checksum(&icmp.to_immutable());
+        icmp.set_checksum(checksum);
+    }
+    write_ipv4_header(
+        &mut buf,
+        pkt.get_destination(),
+        pkt.get_source(),
+        IpNextHeaderProtocols::Icmp,
+    )?;
+    Some(buf)
+}
+
+/// Fills in the 20-byte IPv4 header at the start of a packet whose payload is already written.
+fn write_ipv4_header(
+    buf: &mut [u8],
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+) -> Option<()> {
+    let total_len = u16::try_from(buf.len()).ok()?;
+    let mut ip = MutableIpv4Packet::new(buf)?;
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length(total_len);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(protocol);
+    ip.set_source(source);
+    ip.set_destination(destination);
+    let checksum = ipv4::checksum(&ip.to_immutable());
+    ip.set_checksum(checksum);
+    Some(())
+}
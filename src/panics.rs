@@ -0,0 +1,237 @@
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    cell::RefCell,
+    future::Future,
+    panic::{AssertUnwindSafe, PanicHookInfo},
+    pin::Pin,
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use once_cell::sync::Lazy;
+
+use crate::{config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Reports waiting to be sent. Reports past this are dropped, so a panic storm can't pile up memory.
+const REPORT_BACKLOG: usize = 16;
+
+/// How long sending one report may take.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// What the task being polled on this thread is doing, for panics to be logged with.
+    static CONTEXT: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Installs the panic hook, which logs every panic with the context of its task, counts it, and reports it if `panic_report_dsn` is set.
+pub fn install() {
+    std::panic::set_hook(Box::new(|info| {
+        let context = CONTEXT
+            .try_with(|context| context.borrow().clone())
+            .ok()
+            .flatten();
+        let context = context.as_deref().unwrap_or("a background task");
+        let message = panic_message(info);
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            log::error!(
+                "panic in {} at {}: {}\n{}",
+                context,
+                location,
+                message,
+                backtrace
+            );
+        } else {
+            log::error!("panic in {} at {}: {}", context, location, message);
+        }
+        // the panic may have happened while these were being initialized
+        if Lazy::get(&ROOT_CTX).is_some() {
+            ROOT_CTX.incr_stat(Metric::Panic);
+        }
+        if Lazy::get(&CONFIG).is_some() && CONFIG.panic_report_dsn().is_some() {
+            let event = sentry_event(context, &message, &location, &backtrace);
+            let _ = REPORTER.try_send(event);
+        }
+    }));
+}
+
+fn panic_message(info: &PanicHookInfo) -> String {
+    if let Some(message) = info.payload().downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = info.payload().downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".into()
+    }
+}
+
+/// Runs a future with the given context, turning a panic inside it into an error instead of unwinding into the executor.
+pub fn guarded<T, F: Future<Output = anyhow::Result<T>>>(
+    context: impl Into<Arc<str>>,
+    inner: F,
+) -> Guarded<F> {
+    Guarded {
+        context: Some(context.into()),
+        inner: Box::pin(inner),
+        panicked: false,
+    }
+}
+
+/// Spawns a guarded task that inherits the context of the task spawning it.
+pub fn spawn<T: Send + 'static>(
+    inner: impl Future<Output = anyhow::Result<T>> + Send + 'static,
+) -> smol::Task<anyhow::Result<T>> {
+    let context = CONTEXT.with(|context| context.borrow().clone());
+    smolscale::spawn(Guarded {
+        context,
+        inner: Box::pin(inner),
+        panicked: false,
+    })
+}
+
+/// A future run by [`guarded`].
+pub struct Guarded<F> {
+    context: Option<Arc<str>>,
+    inner: Pin<Box<F>>,
+    panicked: bool,
+}
+
+impl<T, F: Future<Output = anyhow::Result<T>>> Future for Guarded<F> {
+    type Output = anyhow::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.panicked {
+            return Poll::Ready(Err(anyhow::anyhow!("task already panicked")));
+        }
+        let previous = CONTEXT.with(|context| context.replace(self.context.clone()));
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| self.inner.as_mut().poll(cx)));
+        CONTEXT.with(|context| *context.borrow_mut() = previous);
+        result.unwrap_or_else(|_| {
+            self.panicked = true;
+            Poll::Ready(Err(anyhow::anyhow!("task panicked")))
+        })
+    }
+}
+
+/// Where to send reports, parsed from a DSN like `https://<key>@sentry.example.com/<project>`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SentryDsn {
+    store_url: String,
+    key: String,
+}
+
+impl SentryDsn {
+    pub fn parse(dsn: &str) -> anyhow::Result<Self> {
+        let (scheme, rest) = dsn.split_once("://").context("DSN has no scheme")?;
+        let (key, rest) = rest.split_once('@').context("DSN has no public key")?;
+        let (host, project) = rest
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .context("DSN has no project ID")?;
+        let key = key.split(':').next().unwrap_or_default();
+        if key.is_empty() || host.is_empty() || project.parse::<u64>().is_err() {
+            anyhow::bail!("malformed DSN {:?}", dsn)
+        }
+        Ok(Self {
+            store_url: format!("{}://{}/api/{}/store/", scheme, host, project),
+            key: key.into(),
+        })
+    }
+}
+
+/// Sends reports one at a time on a thread of its own, so that panicking tasks never wait on the network.
+static REPORTER: Lazy<SyncSender<serde_json::Value>> = Lazy::new(|| {
+    let (send, recv) = sync_channel::<serde_json::Value>(REPORT_BACKLOG);
+    std::thread::Builder::new()
+        .name("panic-reporter".into())
+        .spawn(move || {
+            let Some(dsn) = CONFIG.panic_report_dsn() else {
+                return;
+            };
+            // checked by the config validation
+            let dsn = SentryDsn::parse(dsn).unwrap();
+            for event in recv {
+                let resp = crate::outbound::request("POST", &dsn.store_url)
+                    .set("Content-Type", "application/json")
+                    .set(
+                        "X-Sentry-Auth",
+                        &format!(
+                            "Sentry sentry_version=7, sentry_client=geph4-exit/{}, sentry_key={}",
+                            env!("CARGO_PKG_VERSION"),
+                            dsn.key
+                        ),
+                    )
+                    .timeout(REPORT_TIMEOUT)
+                    .send_string(&event.to_string());
+                if !resp.ok() {
+                    log::warn!("cannot report a panic: HTTP status {}", resp.status());
+                }
+            }
+        })
+        .expect("cannot start the panic reporter");
+    send
+});
+
+/// A Sentry event describing a panic.
+fn sentry_event(
+    context: &str,
+    message: &str,
+    location: &str,
+    backtrace: &Backtrace,
+) -> serde_json::Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let exit = CONFIG
+        .official()
+        .as_ref()
+        .map(|official| official.exit_hostname().clone());
+    serde_json::json!({
+        "event_id": format!("{:032x}", rand::random::<u128>()),
+        "timestamp": timestamp,
+        "platform": "rust",
+        "level": "fatal",
+        "logger": "geph4-exit",
+        "release": env!("CARGO_PKG_VERSION"),
+        "server_name": exit,
+        "message": { "formatted": message },
+        "culprit": location,
+        "tags": { "context": context },
+        "extra": {
+            "thread": std::thread::current().name(),
+            "backtrace": (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string()),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_becomes_error() {
+        // indexing an empty packet, like a parser that trusts a length field
+        let empty: Vec<u8> = Vec::new();
+        let result = smol::block_on(guarded("test task", async move { Ok(empty[0]) }));
+        assert!(result.is_err());
+
+        assert_eq!(
+            SentryDsn::parse("https://abc@sentry.example.com/42").unwrap(),
+            SentryDsn {
+                store_url: "https://sentry.example.com/api/42/store/".into(),
+                key: "abc".into(),
+            }
+        );
+        assert!(SentryDsn::parse("https://sentry.example.com/42").is_err());
+    }
+}
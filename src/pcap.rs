@@ -0,0 +1,209 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::Ipv4Addr,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+
+/// Link type of raw IPv4 packets, with no link-layer header.
+const LINKTYPE_IPV4: u32 = 228;
+
+/// Size of the pcap header written before every packet.
+const RECORD_HEADER_LEN: u64 = 16;
+
+/// Whether a capture is running, so that the packet path doesn't take the lock when none is.
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+static CAPTURE: Lazy<Mutex<Option<Capture>>> = Lazy::new(Default::default);
+
+/// A running capture of one VPN session's inner packets.
+struct Capture {
+    session: Ipv4Addr,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    bytes_written: u64,
+    max_bytes: u64,
+    deadline: Instant,
+}
+
+impl Capture {
+    fn info(&self) -> CaptureInfo {
+        CaptureInfo {
+            session: self.session,
+            path: self.path.clone(),
+            bytes_written: self.bytes_written,
+            secs_left: self
+                .deadline
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        }
+    }
+}
+
+/// The state of a capture, as reported by the admin API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CaptureInfo {
+    pub session: Ipv4Addr,
+    pub path: PathBuf,
+    pub bytes_written: u64,
+    pub secs_left: u64,
+}
+
+/// Starts capturing the packets of the VPN session with the given address into a new file in `capture_dir`. Only one capture runs at a time.
+pub fn start(
+    session: Ipv4Addr,
+    max_bytes: u64,
+    max_duration: Duration,
+) -> anyhow::Result<CaptureInfo> {
+    let mut capture = CAPTURE.lock();
+    if let Some(capture) = capture.as_ref() {
+        anyhow::bail!("already capturing session {}", capture.session)
+    }
+    let unix_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let path = CONFIG
+        .capture_dir()
+        .join(format!("geph4-exit-{}-{}.pcap", session, unix_time));
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    let mut writer = BufWriter::new(file);
+    write_header(&mut writer)?;
+    let new_capture = Capture {
+        session,
+        path,
+        writer,
+        bytes_written: 24,
+        max_bytes,
+        deadline: Instant::now() + max_duration,
+    };
+    let info = new_capture.info();
+    *capture = Some(new_capture);
+    CAPTURING.store(true, Ordering::SeqCst);
+    log::warn!(
+        "started capturing the packets of session {} to {:?}",
+        session,
+        info.path
+    );
+    // stop on time even if the session goes quiet
+    let path = info.path.clone();
+    smolscale::spawn(async move {
+        smol::Timer::after(max_duration).await;
+        let mut capture = CAPTURE.lock();
+        if capture.as_ref().map(|c| &c.path) == Some(&path) {
+            finish(&mut capture);
+        }
+    })
+    .detach();
+    Ok(info)
+}
+
+/// Stops the running capture, if any, returning what it captured.
+pub fn stop() -> Option<CaptureInfo> {
+    finish(&mut CAPTURE.lock())
+}
+
+/// The running capture, if any.
+pub fn status() -> Option<CaptureInfo> {
+    CAPTURE.lock().as_ref().map(|c| c.info())
+}
+
+/// Records a packet that the VPN session with the given address sent or is about to receive, if that session is being captured.
+pub fn observe(session: Ipv4Addr, pkt: &[u8]) {
+    if !CAPTURING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut capture = CAPTURE.lock();
+    let Some(inner) = capture.as_mut() else {
+        return;
+    };
+    if inner.session != session {
+        return;
+    }
+    if inner.bytes_written + RECORD_HEADER_LEN + pkt.len() as u64 > inner.max_bytes
+        || Instant::now() >= inner.deadline
+    {
+        finish(&mut capture);
+        return;
+    }
+    match write_record(&mut inner.writer, SystemTime::now(), pkt) {
+        Ok(()) => inner.bytes_written += RECORD_HEADER_LEN + pkt.len() as u64,
+        Err(err) => {
+            log::warn!("cannot write to packet capture: {:?}", err);
+            finish(&mut capture);
+        }
+    }
+}
+
+/// Ends the capture, flushing its file.
+fn finish(capture: &mut Option<Capture>) -> Option<CaptureInfo> {
+    let mut capture = capture.take()?;
+    CAPTURING.store(false, Ordering::SeqCst);
+    if let Err(err) = capture.writer.flush() {
+        log::warn!("cannot flush packet capture: {:?}", err);
+    }
+    log::warn!(
+        "stopped capturing session {} after {} bytes",
+        capture.session,
+        capture.bytes_written
+    );
+    Some(capture.info())
+}
+
+/// Writes the pcap file header.
+fn write_header(w: &mut impl Write) -> std::io::Result<()> {
+    w.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    w.write_all(&2u16.to_le_bytes())?;
+    w.write_all(&4u16.to_le_bytes())?;
+    // timezone offset and timestamp accuracy
+    w.write_all(&[0; 8])?;
+    w.write_all(&65535u32.to_le_bytes())?;
+    w.write_all(&LINKTYPE_IPV4.to_le_bytes())
+}
+
+/// Writes one packet record.
+fn write_record(w: &mut impl Write, time: SystemTime, pkt: &[u8]) -> std::io::Result<()> {
+    let time = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    w.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+    w.write_all(&time.subsec_micros().to_le_bytes())?;
+    w.write_all(&(pkt.len() as u32).to_le_bytes())?;
+    w.write_all(&(pkt.len() as u32).to_le_bytes())?;
+    w.write_all(pkt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_format() {
+        let mut out = vec![];
+        write_header(&mut out).unwrap();
+        assert_eq!(out.len(), 24);
+        assert_eq!(out[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(out[20..], [228, 0, 0, 0]);
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_000_000_250);
+        write_record(&mut out, time, &[0x45; 20]).unwrap();
+        let record = &out[24..];
+        assert_eq!(record.len() as u64, RECORD_HEADER_LEN + 20);
+        assert_eq!(record[..4], 1000u32.to_le_bytes());
+        assert_eq!(record[4..8], 250u32.to_le_bytes());
+        assert_eq!(record[8..12], 20u32.to_le_bytes());
+        assert_eq!(record[12..16], 20u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,171 @@
+use std::{net::Ipv4Addr, time::Instant};
+
+use once_cell::sync::Lazy;
+
+/// Port that PCP servers listen on.
+pub const PCP_PORT: u16 = 5351;
+
+/// The PCP version spoken, from RFC 6887.
+const VERSION: u8 = 2;
+
+/// The only opcode answered, which asks for an inbound mapping.
+const OPCODE_MAP: u8 = 1;
+
+/// Result codes, from RFC 6887.
+pub const SUCCESS: u8 = 0;
+pub const UNSUPP_VERSION: u8 = 1;
+pub const NOT_AUTHORIZED: u8 = 2;
+pub const MALFORMED_REQUEST: u8 = 3;
+pub const UNSUPP_OPCODE: u8 = 4;
+pub const NO_RESOURCES: u8 = 8;
+pub const UNSUPP_PROTOCOL: u8 = 9;
+
+/// Longest lifetime granted to a mapping. Mappings actually last as long as the session, but clients renew them within this.
+const MAX_LIFETIME: u32 = 3600;
+
+/// Lifetime given with errors, which tells clients when to retry.
+const ERROR_LIFETIME: u32 = 30;
+
+/// When the responder started, which PCP clients use to notice that mappings were lost.
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// A request for a mapping from a public port to a port of the client.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MapRequest {
+    /// The IP protocol number, TCP or UDP.
+    pub protocol: u8,
+    /// The client's port.
+    pub internal_port: u16,
+    /// The public port the client would like, if any.
+    pub suggested_port: Option<u16>,
+    /// Requested lifetime in seconds. Zero deletes the mapping.
+    pub lifetime: u32,
+}
+
+/// Answers a PCP request with the help of `map`, which creates, renews or deletes a mapping and returns its public port, or a result code. Returns None for datagrams that must not be answered.
+pub async fn respond(
+    request: &[u8],
+    external_ip: Ipv4Addr,
+    map: impl AsyncFnOnce(&MapRequest) -> Result<u16, u8>,
+) -> Option<Vec<u8>> {
+    // too short to answer, or itself a response
+    if request.len() < 2 || request[1] & 0x80 != 0 {
+        return None;
+    }
+    let opcode = request[1];
+    if request[0] != VERSION {
+        return Some(header(opcode, UNSUPP_VERSION, ERROR_LIFETIME));
+    }
+    if request.len() < 24 || request.len() > 1100 || !request.len().is_multiple_of(4) {
+        return Some(header(opcode, MALFORMED_REQUEST, ERROR_LIFETIME));
+    }
+    if opcode != OPCODE_MAP {
+        return Some(header(opcode, UNSUPP_OPCODE, ERROR_LIFETIME));
+    }
+    let Some(payload) = request.get(24..60) else {
+        return Some(header(opcode, MALFORMED_REQUEST, ERROR_LIFETIME));
+    };
+    let lifetime = u32::from_be_bytes(request[4..8].try_into().unwrap());
+    let suggested_port = u16::from_be_bytes([payload[18], payload[19]]);
+    let req = MapRequest {
+        protocol: payload[12],
+        internal_port: u16::from_be_bytes([payload[16], payload[17]]),
+        suggested_port: (suggested_port != 0).then_some(suggested_port),
+        lifetime,
+    };
+    let result = if req.protocol != 6 && req.protocol != 17 {
+        Err(UNSUPP_PROTOCOL)
+    } else if req.internal_port == 0 {
+        Err(MALFORMED_REQUEST)
+    } else {
+        map(&req).await
+    };
+    let (result, lifetime, port) = match result {
+        Ok(port) => (SUCCESS, lifetime.min(MAX_LIFETIME), port),
+        Err(code) => (code, ERROR_LIFETIME, 0),
+    };
+    let mut response = header(opcode, result, lifetime);
+    // the nonce, protocol and internal port are echoed back
+    response.extend_from_slice(&payload[..18]);
+    response.extend_from_slice(&port.to_be_bytes());
+    response.extend_from_slice(&external_ip.to_ipv6_mapped().octets());
+    Some(response)
+}
+
+/// A response header.
+fn header(opcode: u8, result: u8, lifetime: u32) -> Vec<u8> {
+    let mut header = vec![0u8; 24];
+    header[0] = VERSION;
+    header[1] = 0x80 | opcode;
+    header[3] = result;
+    header[4..8].copy_from_slice(&lifetime.to_be_bytes());
+    header[8..12].copy_from_slice(&(EPOCH.elapsed().as_secs() as u32).to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_request(protocol: u8, internal_port: u16, suggested_port: u16) -> Vec<u8> {
+        let mut req = vec![0u8; 60];
+        req[0] = VERSION;
+        req[1] = OPCODE_MAP;
+        req[4..8].copy_from_slice(&7200u32.to_be_bytes());
+        req[24..36].copy_from_slice(b"nonce-nonce!");
+        req[36] = protocol;
+        req[40..42].copy_from_slice(&internal_port.to_be_bytes());
+        req[42..44].copy_from_slice(&suggested_port.to_be_bytes());
+        req
+    }
+
+    #[test]
+    fn map() {
+        let ip = Ipv4Addr::new(203, 0, 113, 1);
+        let resp = smol::block_on(respond(&map_request(6, 8080, 40001), ip, async |req| {
+            assert_eq!(
+                *req,
+                MapRequest {
+                    protocol: 6,
+                    internal_port: 8080,
+                    suggested_port: Some(40001),
+                    lifetime: 7200
+                }
+            );
+            Ok(40001)
+        }))
+        .unwrap();
+        assert_eq!(resp.len(), 60);
+        assert_eq!(
+            (resp[0], resp[1], resp[3]),
+            (VERSION, 0x80 | OPCODE_MAP, SUCCESS)
+        );
+        assert_eq!(&resp[4..8], &MAX_LIFETIME.to_be_bytes());
+        assert_eq!(&resp[24..36], b"nonce-nonce!");
+        assert_eq!(&resp[40..42], &8080u16.to_be_bytes());
+        assert_eq!(&resp[42..44], &40001u16.to_be_bytes());
+        assert_eq!(&resp[44..60], &ip.to_ipv6_mapped().octets());
+
+        let refused = smol::block_on(respond(&map_request(6, 8080, 0), ip, async |_| {
+            Err(NO_RESOURCES)
+        }))
+        .unwrap();
+        assert_eq!(refused[3], NO_RESOURCES);
+        let sctp = smol::block_on(respond(
+            &map_request(132, 8080, 0),
+            ip,
+            async |_| unreachable!(),
+        ))
+        .unwrap();
+        assert_eq!(sctp[3], UNSUPP_PROTOCOL);
+        let mut announce = map_request(6, 8080, 0);
+        announce[1] = 0;
+        assert_eq!(
+            smol::block_on(respond(&announce, ip, async |_| unreachable!())).unwrap()[3],
+            UNSUPP_OPCODE
+        );
+        // responses are never answered
+        announce[1] = 0x80;
+        assert!(smol::block_on(respond(&announce, ip, async |_| unreachable!())).is_none());
+    }
+}
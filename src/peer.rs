@@ -0,0 +1,301 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol::future::FutureExt;
+use smol_timeout::TimeoutExt;
+use sosistab2::{Multiplex, MuxPublic, MuxSecret, Pipe, Stream};
+use sosistab2_obfsudp::{ObfsUdpListener, ObfsUdpPipe, ObfsUdpPublic, ObfsUdpSecret};
+
+use crate::{config::CONFIG, connect, ratelimit, redact, relay, root_ctx::ROOT_CTX, stats::Metric};
+
+/// What a peer first sends on every stream: whether it reached the destination.
+const CONNECTED: u8 = 0;
+const FAILED: u8 = 1;
+
+/// Label of the streams that health checks open, which no destination can have since it lacks a port.
+const HEALTH_CHECK: &str = "health-check";
+
+/// How long connecting to a peer, and then through it to a destination, may take.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer exit, along with the multiplex to it once connected.
+struct Peer {
+    address: SocketAddr,
+    key: MuxPublic,
+    local_sk: MuxSecret,
+    /// Whether it passed its latest health check
+    healthy: AtomicBool,
+    mux: Mutex<Option<Arc<Multiplex>>>,
+}
+
+/// The configured peers, in order of preference.
+static PEERS: Lazy<Vec<Peer>> = Lazy::new(|| {
+    CONFIG
+        .peer_mesh()
+        .iter()
+        .flat_map(|mesh| mesh.peers())
+        .map(|peer| {
+            let mut key = [0; 32];
+            key.copy_from_slice(&hex::decode(peer.key()).expect("validated peer key"));
+            Peer::new(
+                peer.address(),
+                MuxPublic::from_bytes(key),
+                ROOT_CTX.main_identity().sosistab2_sk.clone(),
+            )
+        })
+        .collect()
+});
+
+impl Peer {
+    fn new(address: SocketAddr, key: MuxPublic, local_sk: MuxSecret) -> Self {
+        Self {
+            address,
+            key,
+            local_sk,
+            healthy: AtomicBool::new(false),
+            mux: Default::default(),
+        }
+    }
+
+    /// The multiplex to the peer, connecting it if needed. The peer's key authenticates it, and ours authenticates us.
+    async fn mux(&self) -> anyhow::Result<Arc<Multiplex>> {
+        if let Some(mux) = self.mux.lock().clone() {
+            return Ok(mux);
+        }
+        // obfsudp keys are the same x25519 keys as sosistab2 ones
+        let pipe = ObfsUdpPipe::connect(
+            self.address,
+            ObfsUdpPublic::from_bytes(*self.key.as_bytes()),
+            &hex::encode(rand::random::<[u8; 16]>()),
+        )
+        .await?;
+        let mux = Arc::new(Multiplex::new(self.local_sk.clone(), Some(self.key)));
+        mux.add_pipe(pipe);
+        *self.mux.lock() = Some(mux.clone());
+        Ok(mux)
+    }
+
+    /// Opens a stream through the peer to the given destination, or for a health check.
+    async fn open(&self, label: &str) -> anyhow::Result<Stream> {
+        async {
+            let mut stream = self.mux().await?.open_conn(label).await?;
+            let mut status = [0];
+            stream.read_exact(&mut status).await?;
+            if status[0] != CONNECTED {
+                anyhow::bail!("peer cannot reach the destination either")
+            }
+            Ok(stream)
+        }
+        .timeout(PEER_TIMEOUT)
+        .await
+        .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+    }
+}
+
+/// Connects to a destination through the first healthy peer that can reach it, if any.
+pub async fn connect(addr: &str) -> Option<Stream> {
+    for peer in PEERS
+        .iter()
+        .filter(|peer| peer.healthy.load(Ordering::Relaxed))
+    {
+        match peer.open(addr).await {
+            Ok(stream) => {
+                ROOT_CTX.incr_stat(Metric::PeerEgress);
+                return Some(stream);
+            }
+            Err(err) => {
+                ROOT_CTX.incr_stat(Metric::PeerEgressFailed);
+                log::debug!(
+                    "cannot reach {} through peer {}: {:?}",
+                    redact::destination(addr),
+                    peer.address,
+                    err
+                );
+            }
+        }
+    }
+    None
+}
+
+/// Checks every peer regularly, reconnecting to those that fail.
+pub async fn health_check_loop() -> anyhow::Result<Infallible> {
+    let Some(mesh) = CONFIG.peer_mesh() else {
+        return smol::future::pending().await;
+    };
+    loop {
+        for peer in PEERS.iter() {
+            match peer.open(HEALTH_CHECK).await {
+                Ok(_) => {
+                    if !peer.healthy.swap(true, Ordering::Relaxed) {
+                        log::info!("peer exit {} is healthy", peer.address);
+                    }
+                }
+                Err(err) => {
+                    ROOT_CTX.incr_stat(Metric::PeerUnhealthy);
+                    if peer.healthy.swap(false, Ordering::Relaxed) {
+                        log::warn!(
+                            "peer exit {} failed its health check: {:?}",
+                            peer.address,
+                            err
+                        );
+                    }
+                    *peer.mux.lock() = None;
+                }
+            }
+        }
+        smol::Timer::after(Duration::from_secs(mesh.health_check_secs())).await;
+    }
+}
+
+/// Serves the configured peers on `peer_mesh.listen`.
+pub async fn peer_listen() -> anyhow::Result<Infallible> {
+    let Some(listen) = CONFIG.peer_mesh().as_ref().and_then(|mesh| mesh.listen()) else {
+        return smol::future::pending().await;
+    };
+    let sk = ROOT_CTX.main_identity().sosistab2_sk.clone();
+    let listener = ObfsUdpListener::bind(listen, ObfsUdpSecret::from_bytes(sk.to_bytes())).await?;
+    log::info!(
+        "serving peer exits on {} with key {}",
+        listen,
+        hex::encode(sk.to_public().as_bytes())
+    );
+    serve(listener, sk, PEERS.iter().map(|peer| peer.key).collect()).await
+}
+
+/// Serves peers with the given keys that connect to the listener.
+async fn serve(
+    listener: ObfsUdpListener,
+    sk: MuxSecret,
+    allowed: Vec<MuxPublic>,
+) -> anyhow::Result<Infallible> {
+    let muxes: DashMap<String, Weak<Multiplex>> = DashMap::new();
+    loop {
+        let pipe = listener.accept().await?;
+        let existing = muxes
+            .get(pipe.peer_metadata())
+            .and_then(|mux| mux.upgrade());
+        if let Some(mux) = existing {
+            mux.add_pipe(pipe);
+            continue;
+        }
+        muxes.retain(|_, mux| mux.strong_count() > 0);
+        let mux = Arc::new(Multiplex::new(sk.clone(), None));
+        muxes.insert(pipe.peer_metadata().to_owned(), Arc::downgrade(&mux));
+        mux.add_pipe(pipe);
+        let allowed = allowed.clone();
+        smolscale::spawn(async move {
+            if let Err(err) = serve_peer(mux, &allowed).await {
+                log::debug!("peer multiplex ended: {:?}", err);
+            }
+        })
+        .detach();
+    }
+}
+
+/// Serves the streams of one peer's multiplex, once the peer proves it has one of the allowed keys.
+async fn serve_peer(mux: Arc<Multiplex>, allowed: &[MuxPublic]) -> anyhow::Result<()> {
+    loop {
+        let stream = mux
+            .accept_conn()
+            .timeout(Duration::from_secs(3600))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("peer went silent"))??;
+        if !mux.peer_pk().is_some_and(|key| allowed.contains(&key)) {
+            ROOT_CTX.incr_stat(Metric::PeerRefused);
+            anyhow::bail!("refusing a multiplex from a key that isn't a peer")
+        }
+        smolscale::spawn(async move {
+            if let Err(err) = serve_stream(stream).await {
+                log::debug!("peer stream ended: {:?}", err);
+            }
+        })
+        .detach();
+    }
+}
+
+/// Connects a peer's stream to its destination and relays between them.
+async fn serve_stream(mut stream: Stream) -> anyhow::Result<()> {
+    if stream.label() == HEALTH_CHECK {
+        stream.write_all(&[CONNECTED]).await?;
+        // the peer hangs up once it has read the status
+        let _ = stream.read(&mut [0]).timeout(PEER_TIMEOUT).await;
+        return Ok(());
+    }
+    let remote = match connect::connect_for_peer(stream.label()).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            stream.write_all(&[FAILED]).await?;
+            return Err(err);
+        }
+    };
+    stream.write_all(&[CONNECTED]).await?;
+    ROOT_CTX.incr_stat(Metric::PeerServed);
+    let remote = async_dup::Arc::new(remote);
+    let buffers = CONFIG.relay_buffers();
+    let limiter = ratelimit::global_limiter();
+    relay::relay(stream.clone(), remote.clone(), buffers, |n| limiter.wait(n))
+        .race(relay::relay(remote, stream, buffers, |n| limiter.wait(n)))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn through_peer() {
+        // obfsudp listeners otherwise ignore handshakes for a minute after starting, against replays
+        std::env::set_var("SOSISTAB2_NO_SLEEP", "1");
+        smol::block_on(async {
+            let echo = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let echo_addr = echo.local_addr().unwrap();
+            let _echo = smolscale::spawn(async move {
+                let (conn, _) = echo.accept().await.unwrap();
+                smol::io::copy(conn.clone(), conn).await.unwrap();
+            });
+
+            let peer_addr = std::net::UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let (server_sk, client_sk) = (MuxSecret::generate(), MuxSecret::generate());
+            let listener =
+                ObfsUdpListener::bind(peer_addr, ObfsUdpSecret::from_bytes(server_sk.to_bytes()))
+                    .await
+                    .unwrap();
+            let _server = smolscale::spawn(serve(
+                listener,
+                server_sk.clone(),
+                vec![client_sk.to_public()],
+            ));
+
+            let peer = Peer::new(peer_addr, server_sk.to_public(), client_sk);
+            peer.open(HEALTH_CHECK).await.unwrap();
+            let mut stream = peer.open(&echo_addr.to_string()).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // exits that aren't peers are turned away
+            let stranger = Peer::new(peer_addr, server_sk.to_public(), MuxSecret::generate());
+            let refused = stranger
+                .open(HEALTH_CHECK)
+                .timeout(Duration::from_secs(2))
+                .await;
+            assert!(refused.is_none_or(|res| res.is_err()));
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Forwarded ports, with the user and VPN address each is forwarded to.
+static FORWARDS: Lazy<Mutex<HashMap<u16, (u64, Ipv4Addr)>>> = Lazy::new(Default::default);
+
+/// A public port forwarded to a port of a VPN address, until this is dropped.
+#[derive(Debug)]
+pub struct PortForward {
+    port: u16,
+    vpn_ipv4: Ipv4Addr,
+    internal_port: u16,
+}
+
+impl PortForward {
+    /// Forwards a public port to the user's VPN address: the requested one if it's free, otherwise any free one. Traffic goes to the given internal port, or to the same port if None. Fails if port forwarding is off, the user already has their share of ports, or none is free.
+    pub async fn open(
+        token_id: u64,
+        vpn_ipv4: Ipv4Addr,
+        requested: Option<u16>,
+        internal_port: Option<u16>,
+    ) -> anyhow::Result<Self> {
+        let Some(config) = CONFIG.port_forwarding() else {
+            anyhow::bail!("port forwarding is disabled")
+        };
+        let port = {
+            let mut forwards = FORWARDS.lock();
+            let owned = forwards
+                .values()
+                .filter(|(owner, _)| *owner == token_id)
+                .count();
+            if owned >= config.ports_per_user() {
+                ROOT_CTX.incr_stat(Metric::PortForwardRefused);
+                anyhow::bail!("already forwarding {} ports", owned)
+            }
+            let range = config.first_port()..=config.last_port();
+            let port = match requested {
+                Some(port) if range.contains(&port) && !forwards.contains_key(&port) => port,
+                _ => {
+                    let Some(port) = range.clone().find(|port| !forwards.contains_key(port)) else {
+                        ROOT_CTX.incr_stat(Metric::PortForwardRefused);
+                        anyhow::bail!("every forwardable port is taken")
+                    };
+                    port
+                }
+            };
+            // reserved while the rules go in, which is done without the lock
+            forwards.insert(port, (token_id, vpn_ipv4));
+            port
+        };
+        let internal_port = internal_port.unwrap_or(port);
+        if let Err(err) =
+            smol::unblock(move || set_rules(port, vpn_ipv4, internal_port, true)).await
+        {
+            FORWARDS.lock().remove(&port);
+            return Err(err);
+        }
+        ROOT_CTX.incr_stat(Metric::PortForwardOpened);
+        log::debug!("forwarding port {} to {}:{}", port, vpn_ipv4, internal_port);
+        Ok(Self {
+            port,
+            vpn_ipv4,
+            internal_port,
+        })
+    }
+
+    /// The forwarded public port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The port of the VPN address that traffic goes to.
+    pub fn internal_port(&self) -> u16 {
+        self.internal_port
+    }
+}
+
+impl Drop for PortForward {
+    fn drop(&mut self) {
+        FORWARDS.lock().remove(&self.port);
+        let (port, vpn_ipv4, internal_port) = (self.port, self.vpn_ipv4, self.internal_port);
+        smolscale::spawn(async move {
+            if let Err(err) =
+                smol::unblock(move || set_rules(port, vpn_ipv4, internal_port, false)).await
+            {
+                log::warn!("cannot stop forwarding port {}: {:?}", port, err);
+            }
+        })
+        .detach();
+    }
+}
+
+/// Adds or deletes the iptables rules that forward a port. If adding fails partway, the rules already added are deleted again. Deleting goes through every rule even if some fail. Dev mode leaves iptables alone.
+fn set_rules(port: u16, vpn_ipv4: Ipv4Addr, internal_port: u16, add: bool) -> anyhow::Result<()> {
+    if CONFIG.dev_mode() {
+        return Ok(());
+    }
+    let Some(iface) = CONFIG.nat_external_iface() else {
+        anyhow::bail!("port forwarding needs nat_external_iface")
+    };
+    let rules: Vec<String> = ["tcp", "udp"]
+        .into_iter()
+        .flat_map(|proto| {
+            [
+                format!(
+                    "PREROUTING -t nat -i {} -p {} --dport {} -j DNAT --to-destination {}:{}",
+                    iface, proto, port, vpn_ipv4, internal_port
+                ),
+                format!(
+                    "FORWARD -i {} -o tun-geph -p {} -d {} --dport {} -j ACCEPT",
+                    iface, proto, vpn_ipv4, internal_port
+                ),
+            ]
+        })
+        .collect();
+    if !add {
+        let mut result = Ok(());
+        for rule in &rules {
+            if let Err(err) = iptables("-D", rule) {
+                result = Err(err);
+            }
+        }
+        return result;
+    }
+    for (applied, rule) in rules.iter().enumerate() {
+        if let Err(err) = iptables("-A", rule) {
+            for rule in rules[..applied].iter().rev() {
+                if let Err(err) = iptables("-D", rule) {
+                    log::warn!("cannot roll back a port forwarding rule: {:?}", err);
+                }
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Runs iptables to add (`-A`) or delete (`-D`) a rule, which starts with its chain.
+fn iptables(action: &str, rule: &str) -> anyhow::Result<()> {
+    let status = std::process::Command::new("iptables")
+        .arg(action)
+        .args(rule.split_whitespace())
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("iptables {} {} failed", action, rule)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_and_teardown() {
+        smol::block_on(async {
+            let ip = Ipv4Addr::new(10, 77, 0, 9);
+            let first = PortForward::open(1, ip, Some(40005), None).await.unwrap();
+            assert_eq!(first.port(), 40005);
+            // taken, so another port is picked
+            let second = PortForward::open(2, ip, Some(40005), None).await.unwrap();
+            assert_ne!(second.port(), 40005);
+            let third = PortForward::open(1, ip, Some(80), None).await.unwrap();
+            assert!((40000..=40009).contains(&third.port()));
+            // user 1 has both their ports
+            assert!(PortForward::open(1, ip, None, None).await.is_err());
+            drop(first);
+            assert_eq!(
+                PortForward::open(1, ip, Some(40005), None)
+                    .await
+                    .unwrap()
+                    .port(),
+                40005
+            );
+        })
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use crate::{
+    lists::{BULK_PORTS, INTERACTIVE_PORTS},
+    packet::PacketHeaders,
+};
+
+/// How urgently a downstream VPN packet should be delivered, from most to least urgent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrafficClass {
+    Interactive = 0,
+    Default = 1,
+    Bulk = 2,
+}
+
+impl TrafficClass {
+    /// Classifies a downstream packet by the port of the remote host, which is its source port.
+    pub fn of_downstream(headers: &PacketHeaders) -> Self {
+        match headers.source_port {
+            Some(port) if INTERACTIVE_PORTS.contains(&port) => Self::Interactive,
+            Some(port) if BULK_PORTS.contains(&port) => Self::Bulk,
+            _ => Self::Default,
+        }
+    }
+}
+
+/// A bounded queue of downstream packets that pops more urgent classes first.
+pub struct PriorityQueue {
+    queues: [VecDeque<Bytes>; 3],
+    capacity: usize,
+}
+
+impl PriorityQueue {
+    /// Creates a queue holding at most `capacity` packets.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queues: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Pushes a packet. If the queue is full, the oldest packet of the least urgent class is dropped to make room, unless that class is more urgent than the new packet's.
+    pub fn push(&mut self, pkt: Bytes, class: TrafficClass) {
+        if self.len() >= self.capacity {
+            let victim = self
+                .queues
+                .iter()
+                .rposition(|q| !q.is_empty())
+                .expect("full queue cannot be empty");
+            if victim < class as usize {
+                return;
+            }
+            self.queues[victim].pop_front();
+        }
+        self.queues[class as usize].push_back(pkt);
+    }
+
+    /// Pops the oldest packet of the most urgent class.
+    pub fn pop(&mut self) -> Option<Bytes> {
+        self.queues.iter_mut().find_map(|q| q.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{QuotaAction, TierQuota, CONFIG},
+    ratelimit::RateLimiter,
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+/// Bytes used by every user, keyed by token ID.
+pub static QUOTAS: Lazy<QuotaTable> = Lazy::new(QuotaTable::load);
+
+/// The usage of one user in the current day and month.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct Usage {
+    day: u64,
+    day_bytes: u64,
+    month: u64,
+    month_bytes: u64,
+}
+
+impl Usage {
+    /// Rolls the counters over if the day or month has changed.
+    fn roll_over(&mut self, day: u64) {
+        if self.day != day {
+            self.day = day;
+            self.day_bytes = 0;
+        }
+        let month = month_of_day(day);
+        if self.month != month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+}
+
+/// Per-user usage, persisted to disk so that restarting the exit doesn't reset quotas.
+pub struct QuotaTable {
+    usage: DashMap<u64, Usage>,
+}
+
+impl QuotaTable {
+    fn load() -> Self {
+        let mut usage = DashMap::new();
+        if let Some(quotas) = CONFIG.quotas() {
+            match std::fs::read(quotas.state_path()) {
+                Ok(bts) => match serde_json::from_slice::<HashMap<u64, Usage>>(&bts) {
+                    Ok(saved) => usage.extend(saved),
+                    Err(err) => {
+                        log::warn!("cannot parse saved quota state, starting over: {}", err)
+                    }
+                },
+                Err(err) => log::warn!("cannot read saved quota state, starting over: {}", err),
+            }
+        }
+        Self { usage }
+    }
+
+    /// Adds to a user's usage, returning the current day and month totals.
+    fn add(&self, token_id: u64, bytes: usize) -> (u64, u64) {
+        let mut entry = self.usage.entry(token_id).or_default();
+        entry.roll_over(today());
+        entry.day_bytes += bytes as u64;
+        entry.month_bytes += bytes as u64;
+        (entry.day_bytes, entry.month_bytes)
+    }
+
+    /// Gets a user's current day and month totals.
+    fn get(&self, token_id: u64) -> (u64, u64) {
+        self.usage
+            .get(&token_id)
+            .map(|usage| {
+                let mut usage = *usage;
+                usage.roll_over(today());
+                (usage.day_bytes, usage.month_bytes)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Periodically saves the table to disk.
+    pub async fn persist_loop(&self) -> anyhow::Result<Infallible> {
+        if CONFIG.quotas().is_none() {
+            return smol::future::pending().await;
+        }
+        loop {
+            smol::Timer::after(Duration::from_secs(60)).await;
+            self.save().await;
+        }
+    }
+
+    /// Saves the table to disk if quotas are on, forgetting users who haven't been seen this month. Failures are logged.
+    pub async fn save(&self) {
+        let Some(quotas) = CONFIG.quotas() else {
+            return;
+        };
+        let this_month = month_of_day(today());
+        self.usage.retain(|_, usage| usage.month == this_month);
+        let snapshot: HashMap<u64, Usage> = self
+            .usage
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        let tmp_path = quotas.state_path().with_extension("tmp");
+        let fallible = async {
+            smol::fs::write(&tmp_path, serde_json::to_vec(&snapshot)?).await?;
+            smol::fs::rename(&tmp_path, quotas.state_path()).await?;
+            anyhow::Ok(())
+        };
+        if let Err(err) = fallible.await {
+            log::error!("cannot save quota state: {:?}", err);
+        }
+    }
+}
+
+/// The quota of one session's user, which also meters their usage for billing.
+pub struct QuotaHandle {
+    token_id: u64,
+    /// The quota of the user's tier, with its trickle limit.
+    tier: Option<(&'static TierQuota, RateLimiter)>,
+}
+
+impl QuotaHandle {
+    /// Creates a handle for the given user, if their tier has a quota or billing exports are on.
+    pub fn new(token_id: u64, is_plus: bool) -> Option<Self> {
+        let tier = CONFIG.quotas().as_ref().and_then(|quotas| {
+            if is_plus {
+                quotas.plus().as_ref()
+            } else {
+                quotas.free().as_ref()
+            }
+        });
+        if tier.is_none() && CONFIG.billing().is_none() {
+            return None;
+        }
+        Some(Self {
+            token_id,
+            tier: tier.map(|tier| {
+                (
+                    tier,
+                    RateLimiter::new(tier.trickle_limit(), tier.trickle_limit()),
+                )
+            }),
+        })
+    }
+
+    /// Whether the user has gone over quota and should be cut off entirely.
+    pub fn terminated(&self) -> bool {
+        match self.tier {
+            Some((tier, _)) => {
+                tier.action() == QuotaAction::Terminate && self.is_over(QUOTAS.get(self.token_id))
+            }
+            None => false,
+        }
+    }
+
+    /// Waits until the user has gone over quota and should be cut off entirely.
+    pub async fn wait_terminated(&self) {
+        while !self.terminated() {
+            smol::Timer::after(Duration::from_secs(10)).await;
+        }
+        ROOT_CTX.incr_stat(Metric::QuotaTerminated);
+    }
+
+    /// Charges the user for some bytes, without slowing them down.
+    pub fn record(&self, bytes: usize) {
+        crate::billing::record(self.token_id, bytes);
+        if self.tier.is_some() {
+            QUOTAS.add(self.token_id, bytes);
+        }
+    }
+
+    /// Charges the user for some bytes, slowing down to a trickle if they are over a throttling quota.
+    pub async fn charge(&self, bytes: usize) {
+        crate::billing::record(self.token_id, bytes);
+        let Some((tier, trickle)) = self.tier.as_ref() else {
+            return;
+        };
+        let usage = QUOTAS.add(self.token_id, bytes);
+        if self.is_over(usage) && tier.action() == QuotaAction::Throttle {
+            ROOT_CTX.incr_stat(Metric::QuotaThrottled);
+            trickle.wait(bytes).await;
+        }
+    }
+
+    /// The bytes left in the user's daily and monthly quotas, each None if there is no such quota.
+    pub fn remaining(&self) -> (Option<u64>, Option<u64>) {
+        let Some((tier, _)) = self.tier.as_ref() else {
+            return (None, None);
+        };
+        let (day_bytes, month_bytes) = QUOTAS.get(self.token_id);
+        let left = |limit_mb: Option<u64>, bytes: u64| {
+            limit_mb.map(|limit_mb| limit_mb.saturating_mul(1_000_000).saturating_sub(bytes))
+        };
+        (
+            left(tier.daily_mb(), day_bytes),
+            left(tier.monthly_mb(), month_bytes),
+        )
+    }
+
+    fn is_over(&self, (day_bytes, month_bytes): (u64, u64)) -> bool {
+        let Some((tier, _)) = self.tier.as_ref() else {
+            return false;
+        };
+        let over = |limit_mb: Option<u64>, bytes: u64| {
+            limit_mb
+                .map(|limit_mb| bytes >= limit_mb.saturating_mul(1_000_000))
+                .unwrap_or_default()
+        };
+        over(tier.daily_mb(), day_bytes) || over(tier.monthly_mb(), month_bytes)
+    }
+}
+
+/// Days since the Unix epoch.
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 86400
+}
+
+/// A number identifying the calendar month (UTC) that a day since the Unix epoch falls in.
+fn month_of_day(day: u64) -> u64 {
+    // Howard Hinnant's civil_from_days algorithm
+    let z = day + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    year * 12 + month - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use smol::future::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn month_boundaries() {
+        // 1970-01-01 and 1970-01-31
+        assert_eq!(month_of_day(0), 1970 * 12);
+        assert_eq!(month_of_day(30), 1970 * 12);
+        // 1970-02-01
+        assert_eq!(month_of_day(31), 1970 * 12 + 1);
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(month_of_day(19782), 2024 * 12 + 1);
+        assert_eq!(month_of_day(19783), 2024 * 12 + 2);
+    }
+
+    /// A handle for a user of a tier with the given quota, which the test config doesn't have.
+    fn handle(token_id: u64, quota: &str) -> QuotaHandle {
+        let tier: &'static TierQuota = Box::leak(Box::new(toml::from_str(quota).unwrap()));
+        QuotaHandle {
+            token_id,
+            tier: Some((
+                tier,
+                RateLimiter::new(tier.trickle_limit(), tier.trickle_limit()),
+            )),
+        }
+    }
+
+    #[test]
+    fn throttle_at_limit() {
+        let handle = handle(
+            u64::MAX - 1,
+            "daily_mb = 1\naction = \"throttle\"\ntrickle_limit = 1",
+        );
+        smol::block_on(async {
+            let start = Instant::now();
+            handle.charge(999_000).await;
+            assert!(start.elapsed() < Duration::from_millis(100));
+            assert_eq!(handle.remaining(), (Some(1_000), None));
+            // this takes the user over quota, so it goes through the 1 KB/s trickle, whose 1 KB burst covers only part of it
+            let start = Instant::now();
+            handle.charge(1_536).await;
+            assert!(start.elapsed() >= Duration::from_millis(400));
+            assert_eq!(handle.remaining(), (Some(0), None));
+        });
+        assert!(!handle.terminated());
+    }
+
+    #[test]
+    fn terminate_at_limit() {
+        let handle = handle(u64::MAX - 2, "monthly_mb = 1\naction = \"terminate\"");
+        handle.record(999_999);
+        assert!(!handle.terminated());
+        handle.record(1);
+        assert!(handle.terminated());
+        // the session watching for termination ends right away
+        smol::block_on(async {
+            handle
+                .wait_terminated()
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(1)).await;
+                    panic!("session not terminated")
+                })
+                .await
+        });
+    }
+
+    #[test]
+    fn huge_limits_saturate() {
+        // the largest limit TOML can hold, which is far more bytes than a u64 can
+        let handle = handle(u64::MAX - 3, &format!("daily_mb = {}", i64::MAX));
+        handle.record(1_000);
+        assert_eq!(handle.remaining(), (Some(u64::MAX - 1_000), None));
+        assert!(!handle.is_over((u64::MAX - 1, 0)));
+    }
+}
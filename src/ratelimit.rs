@@ -1,85 +1,456 @@
 use atomic_float::AtomicF64;
-use governor::{state::NotKeyed, NegativeMultiDecision, Quota};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 
 use std::{
-    num::NonZeroU32,
-    sync::{atomic::Ordering, Arc},
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
 
+use event_listener::Event;
+
+use crate::{
+    config::{BandwidthProfile, BulkThrottleConfig, CONFIG},
+    root_ctx::ROOT_CTX,
+    shadow::{self, Candidate},
+    stats::Metric,
+};
+
+pub use self::bucket::TokenBucket;
+use self::bucket::UNLIMITED;
+
 pub static BW_MULTIPLIER: AtomicF64 = AtomicF64::new(1.0);
 
-/// A generic rate limiter.
+/// Scales free users' limiters so that scheduled limits apply to limiters that already exist.
+pub static FREE_SCHEDULE_MULTIPLIER: AtomicF64 = AtomicF64::new(1.0);
+
+/// Scales Plus users' limiters like [`FREE_SCHEDULE_MULTIPLIER`]. Zero means Plus users are unlimited.
+pub static PLUS_SCHEDULE_MULTIPLIER: AtomicF64 = AtomicF64::new(1.0);
+
+/// The base speed limit of Plus users' limiters, in KB/s.
+pub const PLUS_BASE_LIMIT: u32 = 1903;
+
+mod bucket;
+
+/// One token bucket in the tree of limiters.
+struct Bucket {
+    inner: TokenBucket,
+    /// Whether [`BW_MULTIPLIER`] applies. It doesn't to the global and tier buckets, since it already reacts to the exit as a whole being congested.
+    congestion_scaled: bool,
+    schedule_multiplier: Option<&'static AtomicF64>,
+    /// Cleared while the bucket's limit is overridden, since overrides ignore the schedule.
+    follows_schedule: AtomicBool,
+    parent: Option<Arc<Bucket>>,
+}
+
+impl Bucket {
+    fn multiplier(&self) -> f64 {
+        let congestion = if self.congestion_scaled {
+            BW_MULTIPLIER.load(Ordering::Relaxed)
+        } else {
+            1.0
+        };
+        congestion
+            * self
+                .schedule_multiplier
+                .filter(|_| self.follows_schedule.load(Ordering::Relaxed))
+                .map(|m| m.load(Ordering::Relaxed))
+                .unwrap_or(1.0)
+    }
+
+    /// The bytes to take out of this bucket, or None if it lets them through for free.
+    fn cost(&self, bytes: usize) -> Option<u64> {
+        Some(((bytes as f64) * self.multiplier()) as u64).filter(|cost| *cost > 0)
+    }
+
+    /// This bucket and every bucket above it.
+    fn ancestry(self: &Arc<Self>) -> impl Iterator<Item = &Arc<Bucket>> {
+        std::iter::successors(Some(self), |bucket| bucket.parent.as_ref())
+    }
+}
+
+/// The signature of [`RateLimiter::costs`].
+type LimiterCostsFn = fn(&RateLimiter, usize) -> Vec<Option<u64>>;
+
+/// A rewrite of [`RateLimiter::costs`] being rolled out, which shadow mode checks against it before it replaces it.
+static LIMITER_COSTS_CANDIDATE: Candidate<LimiterCostsFn> = Candidate::new();
+
+/// Lets shadow mode check [`costs_fixed_point`] against [`RateLimiter::costs`].
+pub fn install_shadow_candidate() {
+    LIMITER_COSTS_CANDIDATE.set(Some(costs_fixed_point));
+}
+
+/// [`RateLimiter::costs`] with multipliers in fixed point, in 1/65536ths, which keeps floating point off the data path.
+fn costs_fixed_point(limiter: &RateLimiter, bytes: usize) -> Vec<Option<u64>> {
+    limiter
+        .bucket
+        .iter()
+        .flat_map(|bucket| bucket.ancestry())
+        .map(|bucket| {
+            let multiplier = (bucket.multiplier() * 65536.0) as u64;
+            Some((bytes as u64).saturating_mul(multiplier) >> 16).filter(|cost| *cost > 0)
+        })
+        .collect()
+}
+
+/// A rate limiter in a tree of token buckets: global, then tier, then user, then session. Traffic has to get through every bucket from the limiter up to the root, so limits at every level compose.
 #[derive(Clone)]
 pub struct RateLimiter {
-    inner: Arc<
-        governor::RateLimiter<
-            NotKeyed,
-            governor::state::InMemoryState,
-            governor::clock::MonotonicClock,
-        >,
-    >,
-    unlimited: bool,
+    /// None if nothing limits this traffic at all, so that it skips every atomic operation.
+    bucket: Option<Arc<Bucket>>,
 }
 
 impl RateLimiter {
-    /// Creates a new rate limiter with the given speed limit, in KB/s
+    /// Creates a new rate limiter with the given speed limit, in KB/s, at the root of its own tree
     pub fn new(limit_kb: u32, burst_kb: u32) -> Self {
-        let limit = NonZeroU32::new((limit_kb + 1) * 1024).unwrap();
-        let burst_size = NonZeroU32::new(burst_kb * 1024).unwrap();
-        let inner = governor::RateLimiter::new(
-            Quota::per_second(limit).allow_burst(burst_size),
-            governor::state::InMemoryState::default(),
-            &governor::clock::MonotonicClock,
-        );
-        inner.check_n(burst_size).expect("this should never happen");
+        Self::unlimited().child(limit_kb, burst_kb)
+    }
+
+    /// Creates a rate limiter with the given speed limit, in KB/s, under this one.
+    pub fn child(&self, limit_kb: u32, burst_kb: u32) -> Self {
+        self.child_inner(Some(limit_kb), burst_kb, true)
+    }
+
+    /// Creates a rate limiter under this one that lets everything through until it is retuned.
+    pub fn unlimited_child(&self) -> Self {
+        self.child_inner(None, 1, true)
+    }
+
+    /// Creates a limiter for a share of the exit's capacity, which the congestion multiplier doesn't apply to.
+    fn capacity_child(&self, limit_kb: u32) -> Self {
+        self.child_inner(Some(limit_kb), limit_kb.max(1024), false)
+    }
+
+    fn child_inner(&self, limit_kb: Option<u32>, burst_kb: u32, congestion_scaled: bool) -> Self {
+        let inner = TokenBucket::new(rate_of(limit_kb), burst_kb.max(1) as u64 * 1024);
         Self {
-            inner: Arc::new(inner),
-            unlimited: false,
+            bucket: Some(Arc::new(Bucket {
+                inner,
+                congestion_scaled,
+                schedule_multiplier: None,
+                follows_schedule: AtomicBool::new(true),
+                parent: self.bucket.clone(),
+            })),
+        }
+    }
+
+    /// Changes the speed limit, in KB/s, of this limiter's own bucket, or lifts it if None. Streams already going through the limiter slow down or speed up right away.
+    pub fn retune(&self, limit_kb: Option<u32>, burst_kb: u32, follow_schedule: bool) {
+        if let Some(bucket) = self.bucket.as_ref() {
+            bucket
+                .inner
+                .set_rate(rate_of(limit_kb), burst_kb.max(1) as u64 * 1024);
+            bucket
+                .follows_schedule
+                .store(follow_schedule, Ordering::Relaxed);
+        }
+    }
+
+    /// Makes the limiter follow one of the schedule multipliers.
+    pub fn scheduled(mut self, multiplier: &'static AtomicF64) -> Self {
+        if let Some(bucket) = self.bucket.as_mut().and_then(Arc::get_mut) {
+            bucket.schedule_multiplier = Some(multiplier);
+        }
+        self
+    }
+
+    /// The speed limit of this limiter's own bucket right now, in KB/s, after the schedule and congestion multipliers. None if it lets everything through.
+    pub fn limit_kb(&self) -> Option<u32> {
+        let bucket = self.bucket.as_ref()?;
+        let multiplier = bucket.multiplier();
+        if bucket.inner.rate() == UNLIMITED || multiplier <= 0.0 {
+            return None;
         }
+        Some((bucket.inner.rate() as f64 / 1024.0 / multiplier) as u32)
     }
 
     /// Creates a new unlimited ratelimit.
     pub fn unlimited() -> Self {
-        let inner = Arc::new(governor::RateLimiter::new(
-            Quota::per_second(NonZeroU32::new(128 * 1024).unwrap()),
-            governor::state::InMemoryState::default(),
-            &governor::clock::MonotonicClock,
-        ));
-        Self {
-            inner,
-            unlimited: true,
+        Self { bucket: None }
+    }
+
+    /// What each bucket from this limiter up to the root charges for the given bytes, or None for the buckets that let them through for free. This is what both [`Self::wait`] and [`Self::check`] decide on.
+    pub fn costs(&self, bytes: usize) -> Vec<Option<u64>> {
+        self.bucket
+            .iter()
+            .flat_map(|bucket| bucket.ancestry())
+            .map(|bucket| bucket.cost(bytes))
+            .collect()
+    }
+
+    /// Compares the costs with those of the candidate rewrite, if there is one.
+    fn shadow_costs(&self, bytes: usize) {
+        if let Some(candidate) = LIMITER_COSTS_CANDIDATE.get() {
+            shadow::compare("limiter_costs", &self.costs(bytes), || {
+                candidate(self, bytes)
+            });
         }
     }
 
     /// Waits until the given number of bytes can be let through.
     pub async fn wait(&self, bytes: usize) {
-        let bytes = ((bytes as f64) * BW_MULTIPLIER.load(Ordering::Relaxed)) as u32;
-        if bytes == 0 || self.unlimited {
+        let Some(bucket) = self.bucket.as_ref() else {
             return;
-        }
-
-        let bytes = NonZeroU32::new(bytes).unwrap();
-        while let Err(err) = self.inner.check_n(bytes) {
-            match err {
-                NegativeMultiDecision::BatchNonConforming(_, until) => {
-                    smol::Timer::at(until.earliest_possible()).await;
-                }
-                NegativeMultiDecision::InsufficientCapacity(_) => {
-                    log::error!("INSUFFICIENT CAP");
-                    return;
-                }
+        };
+        self.shadow_costs(bytes);
+        for bucket in bucket.ancestry() {
+            if let Some(delay) = bucket.cost(bytes).and_then(|cost| bucket.inner.take(cost)) {
+                smol::Timer::after(delay).await;
             }
         }
     }
 
-    /// Checks whether the number of bytes can be let through.
+    /// Checks whether the number of bytes can be let through. Buckets below the one that refuses them still count them.
     pub fn check(&self, bytes: usize) -> bool {
-        let bytes = ((bytes as f64) * BW_MULTIPLIER.load(Ordering::Relaxed)) as u32;
-        if bytes == 0 || self.unlimited {
+        let Some(bucket) = self.bucket.as_ref() else {
             return true;
+        };
+        self.shadow_costs(bytes);
+        bucket.ancestry().all(|bucket| match bucket.cost(bytes) {
+            Some(cost) => bucket.inner.try_take(cost),
+            None => true,
+        })
+    }
+}
+
+/// A speed limit that the binder or the admin API set for one user, replacing their tier's.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateOverride {
+    /// Speed limit, in KB/s, or None for no limit.
+    #[serde(default)]
+    pub limit_kb: Option<u32>,
+    /// Burst allowance, in KB. If not present, the tier's applies.
+    #[serde(default)]
+    pub burst_kb: Option<u32>,
+}
+
+/// The refill rate, in bytes per second, of a speed limit in KB/s.
+fn rate_of(limit_kb: Option<u32>) -> u64 {
+    limit_kb
+        .map(|limit_kb| (limit_kb as u64 + 1) * 1024)
+        .unwrap_or(UNLIMITED)
+}
+
+/// The root of the tree, limiting the whole exit to `global_limit` if it is set.
+static GLOBAL_LIMITER: Lazy<RateLimiter> = Lazy::new(|| match CONFIG.global_limit() {
+    Some(limit) => RateLimiter::unlimited().capacity_child(limit),
+    None => RateLimiter::unlimited(),
+});
+
+/// The limiter of all free users combined.
+static FREE_TIER_LIMITER: Lazy<RateLimiter> = Lazy::new(|| match CONFIG.tier_limits().free() {
+    Some(limit) => GLOBAL_LIMITER.capacity_child(limit),
+    None => GLOBAL_LIMITER.clone(),
+});
+
+/// The limiter of all Plus users combined.
+static PLUS_TIER_LIMITER: Lazy<RateLimiter> = Lazy::new(|| match CONFIG.tier_limits().plus() {
+    Some(limit) => GLOBAL_LIMITER.capacity_child(limit),
+    None => GLOBAL_LIMITER.clone(),
+});
+
+/// The root limiter, which traffic that belongs to no tier, such as pipes forwarded by a bridge, goes under directly.
+pub fn global_limiter() -> &'static RateLimiter {
+    &GLOBAL_LIMITER
+}
+
+/// The limiter that every limiter of a tier's users goes under.
+pub fn tier_limiter(is_plus: bool) -> &'static RateLimiter {
+    if is_plus {
+        &PLUS_TIER_LIMITER
+    } else {
+        &FREE_TIER_LIMITER
+    }
+}
+
+/// The bandwidth profile that applies right now, if any.
+pub fn active_profile() -> Option<&'static BandwidthProfile> {
+    let minute = (SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+        % 1440) as u32;
+    CONFIG
+        .bandwidth_schedule()
+        .iter()
+        .find(|profile| profile.contains(minute))
+}
+
+/// The global speed limit right now, in KB/s.
+pub fn current_all_limit() -> u32 {
+    active_profile()
+        .and_then(|profile| profile.all_limit())
+        .unwrap_or(*CONFIG.all_limit())
+}
+
+/// Whether Plus users need a limiter at all, either because the exit also serves free users or because some profile limits them.
+pub fn plus_limited() -> bool {
+    base_free_limit() > 0
+        || CONFIG
+            .bandwidth_schedule()
+            .iter()
+            .any(|profile| profile.plus_limit().is_some())
+}
+
+fn base_free_limit() -> u32 {
+    CONFIG
+        .official()
+        .as_ref()
+        .and_then(|s| *s.free_limit())
+        .unwrap_or_default()
+}
+
+/// The bulk-transfer policy step of one free-tier connection: once it has carried `bulk_throttle.after_mb`, it gets its own limiter, which tightens as it carries more.
+pub struct BulkThrottle {
+    config: &'static BulkThrottleConfig,
+    limiter: RateLimiter,
+    /// The limit in force, in KB/s, or 0 before the connection is throttled
+    limit_kb: AtomicU32,
+    engaged: Event,
+}
+
+impl BulkThrottle {
+    /// The policy step for a free-tier connection, if `bulk_throttle` is set.
+    pub fn new() -> Option<Self> {
+        let config = CONFIG.bulk_throttle().as_ref()?;
+        Some(Self {
+            config,
+            limiter: RateLimiter::unlimited().unlimited_child(),
+            limit_kb: AtomicU32::new(0),
+            engaged: Event::new(),
+        })
+    }
+
+    /// Whether connections to the port are throttled at all.
+    pub fn covers(&self, port: u16) -> bool {
+        self.config.ports().is_empty() || self.config.ports().contains(&port)
+    }
+
+    /// Waits until `bytes` more can be let through a connection that has carried `transferred` bytes so far.
+    pub async fn wait(&self, transferred: u64, bytes: usize) {
+        let Some(limit_kb) = bulk_limit_kb(self.config, transferred) else {
+            return;
+        };
+        let previous = self.limit_kb.swap(limit_kb, Ordering::Relaxed);
+        if previous != limit_kb {
+            self.limiter.retune(Some(limit_kb), limit_kb, false);
+            if previous == 0 {
+                ROOT_CTX.incr_stat(Metric::BulkThrottled);
+                self.engaged.notify(usize::MAX);
+            }
+        }
+        self.limiter.wait(bytes).await
+    }
+
+    /// Waits until the connection is first throttled, so that its client can be told why.
+    pub async fn engaged(&self) {
+        loop {
+            let listener = self.engaged.listen();
+            if self.limit_kb.load(Ordering::Relaxed) != 0 {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+/// The speed limit, in KB/s, of a free-tier connection that has carried `transferred` bytes, or None while it is not throttled yet.
+fn bulk_limit_kb(config: &BulkThrottleConfig, transferred: u64) -> Option<u32> {
+    const MB: u64 = 1024 * 1024;
+    let beyond = transferred.checked_sub(config.after_mb() * MB)?;
+    let halvings = (beyond / (config.halve_every_mb() * MB)).min(31);
+    Some((config.start_kb() >> halvings).max(config.floor_kb()))
+}
+
+/// Applies the bandwidth schedule to the per-tier multipliers, live.
+pub async fn schedule_loop() -> anyhow::Result<Infallible> {
+    if CONFIG.bandwidth_schedule().is_empty() {
+        return smol::future::pending().await;
+    }
+    let mut last_profile = None;
+    loop {
+        let profile = active_profile();
+        let free_limit = profile
+            .and_then(|profile| profile.free_limit())
+            .unwrap_or_else(base_free_limit);
+        if base_free_limit() > 0 && free_limit > 0 {
+            FREE_SCHEDULE_MULTIPLIER.store(
+                base_free_limit() as f64 / free_limit as f64,
+                Ordering::Relaxed,
+            );
         }
+        let plus_multiplier = match profile.and_then(|profile| profile.plus_limit()) {
+            Some(plus_limit) => PLUS_BASE_LIMIT as f64 / plus_limit as f64,
+            None if base_free_limit() > 0 => 1.0,
+            None => 0.0,
+        };
+        PLUS_SCHEDULE_MULTIPLIER.store(plus_multiplier, Ordering::Relaxed);
+        let profile_window = profile.map(|profile| (profile.start(), profile.end()));
+        if profile_window != last_profile {
+            match profile_window {
+                Some((start, end)) => {
+                    log::info!("bandwidth profile {}-{} is now active", start, end)
+                }
+                None => log::info!("no bandwidth profile is active, using the usual limits"),
+            }
+            last_profile = profile_window;
+        }
+        smol::Timer::after(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let bytes = NonZeroU32::new(bytes).unwrap();
-        self.inner.check_n(bytes).is_ok()
+    #[test]
+    fn parent_limits_child() {
+        let parent = RateLimiter::new(100, 100);
+        let child = parent.child(10_000, 10_000);
+        std::thread::sleep(Duration::from_millis(200));
+        // the child alone would let this through, but the parent has only refilled about 20 KB
+        assert!(!child.check(50_000));
+        assert!(parent.check(5_000));
+        assert!(RateLimiter::unlimited().check(1_000_000));
+    }
+
+    #[test]
+    fn bulk_steps() {
+        let config: BulkThrottleConfig =
+            toml::from_str("after_mb = 100\nstart_kb = 800\nhalve_every_mb = 50\nfloor_kb = 150")
+                .unwrap();
+        const MB: u64 = 1024 * 1024;
+        assert_eq!(bulk_limit_kb(&config, 0), None);
+        assert_eq!(bulk_limit_kb(&config, 100 * MB - 1), None);
+        assert_eq!(bulk_limit_kb(&config, 100 * MB), Some(800));
+        assert_eq!(bulk_limit_kb(&config, 150 * MB), Some(400));
+        assert_eq!(bulk_limit_kb(&config, 220 * MB), Some(200));
+        // halving stops at the floor
+        assert_eq!(bulk_limit_kb(&config, 250 * MB), Some(150));
+        assert_eq!(bulk_limit_kb(&config, u64::MAX), Some(150));
+    }
+
+    #[test]
+    fn retune() {
+        let limiter = RateLimiter::new(1, 1);
+        assert!(!limiter.check(100_000));
+        limiter.retune(None, 1, false);
+        assert!(limiter.check(100_000));
+        limiter.retune(Some(1), 1, false);
+        assert!(!limiter.check(100_000));
+    }
+
+    #[test]
+    fn fixed_point_costs() {
+        let limiter = RateLimiter::new(100, 100).child(10, 10);
+        for bytes in [0, 1, 1500, 65536] {
+            assert_eq!(costs_fixed_point(&limiter, bytes), limiter.costs(bytes));
+        }
+        assert!(costs_fixed_point(&RateLimiter::unlimited(), 1500).is_empty());
     }
 }
@@ -0,0 +1,144 @@
+use std::{
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How often a bucket is refilled at most. Refilling is the only compare-and-swap, so batching it keeps most takes to one atomic add.
+const REFILL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The rate of a bucket that lets everything through.
+pub const UNLIMITED: u64 = u64::MAX;
+
+/// A token bucket of bytes, shared between threads without locks. Takes never fail: a bucket that runs dry goes into debt, and the taker waits until the debt would be paid off.
+pub struct TokenBucket {
+    /// Bytes available, or owed if negative.
+    tokens: AtomicI64,
+    /// When the bucket was last refilled, in nanoseconds since `epoch`.
+    last_refill: AtomicU64,
+    epoch: Instant,
+    /// Refill rate, in bytes per second, or [`UNLIMITED`].
+    rate: AtomicU64,
+    burst: AtomicI64,
+}
+
+impl TokenBucket {
+    /// Creates an empty bucket that fills at the given bytes per second, up to the given burst.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            tokens: AtomicI64::new(0),
+            last_refill: AtomicU64::new(0),
+            epoch: Instant::now(),
+            rate: AtomicU64::new(rate.max(1)),
+            burst: AtomicI64::new(burst.min(i64::MAX as u64) as i64),
+        }
+    }
+
+    /// Creates a bucket that starts out holding its whole burst.
+    pub fn full(rate: u64, burst: u64) -> Self {
+        let bucket = Self::new(rate, burst);
+        bucket
+            .tokens
+            .store(burst.min(i64::MAX as u64) as i64, Ordering::Relaxed);
+        bucket
+    }
+
+    /// Changes the rate and burst. Takes that are already waiting keep their wait.
+    pub fn set_rate(&self, rate: u64, burst: u64) {
+        let burst = burst.min(i64::MAX as u64) as i64;
+        self.rate.store(rate.max(1), Ordering::Relaxed);
+        self.burst.store(burst, Ordering::Relaxed);
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                Some(tokens.min(burst))
+            });
+    }
+
+    /// The refill rate, in bytes per second, or [`UNLIMITED`].
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Takes bytes out of the bucket, returning how long to wait before sending them if it went into debt.
+    pub fn take(&self, bytes: u64) -> Option<Duration> {
+        if self.rate.load(Ordering::Relaxed) == UNLIMITED {
+            return None;
+        }
+        let bytes = bytes.min(i64::MAX as u64) as i64;
+        // the clock is only read once the bucket runs dry, so a bucket with bytes to spare costs one atomic add
+        if self.tokens.fetch_sub(bytes, Ordering::Relaxed) >= bytes {
+            return None;
+        }
+        self.refill();
+        let left = self.tokens.load(Ordering::Relaxed);
+        if left >= 0 {
+            None
+        } else {
+            Some(self.time_to_earn(left.unsigned_abs()))
+        }
+    }
+
+    /// Takes bytes out of the bucket only if they are all available.
+    pub fn try_take(&self, bytes: u64) -> bool {
+        if self.rate.load(Ordering::Relaxed) == UNLIMITED {
+            return true;
+        }
+        let bytes = bytes.min(i64::MAX as u64) as i64;
+        for attempt in 0..2 {
+            if self.tokens.fetch_sub(bytes, Ordering::Relaxed) >= bytes {
+                return true;
+            }
+            self.tokens.fetch_add(bytes, Ordering::Relaxed);
+            if attempt == 0 {
+                self.refill();
+            }
+        }
+        false
+    }
+
+    /// Adds the bytes earned since the last refill, if at least [`REFILL_INTERVAL`] has passed. Only one thread wins each refill. Since buckets are only refilled once they run dry, a bucket never gets more than its burst at once, which is at most what a continuously refilled one would allow.
+    fn refill(&self) {
+        let now = self.epoch.elapsed().as_nanos() as u64;
+        let last = self.last_refill.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(last);
+        if elapsed < REFILL_INTERVAL.as_nanos() as u64
+            || self
+                .last_refill
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        let rate = self.rate.load(Ordering::Relaxed);
+        let burst = self.burst.load(Ordering::Relaxed);
+        let earned = (elapsed as u128 * rate as u128 / 1_000_000_000).min(i64::MAX as u128) as i64;
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                Some(tokens.saturating_add(earned).min(burst))
+            });
+    }
+
+    fn time_to_earn(&self, bytes: u64) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed);
+        Duration::from_nanos((bytes as u128 * 1_000_000_000 / rate as u128) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debt_and_refill() {
+        let bucket = TokenBucket::new(10_000_000, 100_000);
+        // starts empty, so a megabyte takes about 100 ms
+        let wait = bucket.take(1_000_000).unwrap();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+        assert!(!bucket.try_take(1));
+        std::thread::sleep(Duration::from_millis(150));
+        // the debt is paid off, and the bucket never holds more than its burst
+        assert!(bucket.try_take(100));
+        assert!(!bucket.try_take(150_000));
+    }
+}
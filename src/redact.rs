@@ -0,0 +1,134 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::config::{LogPrivacy, CONFIG};
+
+/// What stands in for anything the privacy level keeps out of logs.
+const REDACTED: &str = "[REDACTED]";
+
+/// Key of the hashes that stand in for client identifiers at the `ops` level. It is random per run, so hashes can't be linked across restarts.
+static HASH_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// When `debug` log privacy falls back to `ops`.
+static DEBUG_UNTIL: Lazy<Instant> =
+    Lazy::new(|| Instant::now() + Duration::from_secs(CONFIG.log_debug_secs()));
+
+/// Logs the privacy level at startup, which also starts the `debug` time limit.
+pub fn announce() {
+    let level = CONFIG.log_privacy();
+    Lazy::force(&DEBUG_UNTIL);
+    if level == LogPrivacy::Debug {
+        log::warn!(
+            "logging clients and destinations in full for the next {} seconds",
+            CONFIG.log_debug_secs()
+        );
+    } else {
+        log::info!("log privacy level is {:?}", level);
+    }
+}
+
+/// The effective privacy level.
+pub fn level() -> LogPrivacy {
+    level_at(CONFIG.log_privacy(), Instant::now(), *DEBUG_UNTIL)
+}
+
+fn level_at(configured: LogPrivacy, now: Instant, debug_until: Instant) -> LogPrivacy {
+    match configured {
+        LogPrivacy::Debug if now >= debug_until => {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                log::warn!("debug log privacy expired, falling back to ops");
+            }
+            LogPrivacy::Ops
+        }
+        level => level,
+    }
+}
+
+/// Something that identifies a client: its address, VPN address, or token ID. Hashed at the `ops` level.
+pub fn client(id: impl Display) -> String {
+    client_at(level(), id)
+}
+
+fn client_at(level: LogPrivacy, id: impl Display) -> String {
+    match level {
+        LogPrivacy::Strict => REDACTED.into(),
+        LogPrivacy::Ops => {
+            let hash = blake3::keyed_hash(&HASH_KEY, id.to_string().as_bytes());
+            format!("client-{}", &hash.to_hex()[..12])
+        }
+        LogPrivacy::Debug => id.to_string(),
+    }
+}
+
+/// Where a client connects to, as a socket address or `host:port`. Only the port is kept at the `ops` level.
+pub fn destination(dest: impl Display) -> String {
+    destination_at(level(), dest)
+}
+
+fn destination_at(level: LogPrivacy, dest: impl Display) -> String {
+    let dest = dest.to_string();
+    match level {
+        LogPrivacy::Strict => REDACTED.into(),
+        LogPrivacy::Ops => {
+            let port = match dest.parse::<SocketAddr>() {
+                Ok(addr) => Some(addr.port()),
+                // a bare IPv6 address has colons but no port
+                Err(_) => dest
+                    .rsplit_once(':')
+                    .filter(|(host, _)| !host.contains(':'))
+                    .and_then(|(_, port)| port.parse::<u16>().ok()),
+            };
+            port.map(|port| format!("*:{}", port))
+                .unwrap_or_else(|| REDACTED.into())
+        }
+        LogPrivacy::Debug => dest,
+    }
+}
+
+/// Anything else that may reveal what a client does, such as the RPC lines it sends. Only kept at the `debug` level.
+pub fn sensitive(t: impl Display) -> String {
+    match level() {
+        LogPrivacy::Debug => t.to_string(),
+        _ => REDACTED.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let now = Instant::now();
+        assert_eq!(
+            level_at(LogPrivacy::Debug, now, now + Duration::from_secs(1)),
+            LogPrivacy::Debug
+        );
+        assert_eq!(level_at(LogPrivacy::Debug, now, now), LogPrivacy::Ops);
+
+        assert_eq!(client_at(LogPrivacy::Strict, "198.51.100.7"), REDACTED);
+        let hashed = client_at(LogPrivacy::Ops, "198.51.100.7");
+        assert!(!hashed.contains("198.51"));
+        assert_eq!(hashed, client_at(LogPrivacy::Ops, "198.51.100.7"));
+        assert_ne!(hashed, client_at(LogPrivacy::Ops, "198.51.100.8"));
+
+        assert_eq!(
+            destination_at(LogPrivacy::Strict, "example.com:443"),
+            REDACTED
+        );
+        assert_eq!(destination_at(LogPrivacy::Ops, "example.com:443"), "*:443");
+        assert_eq!(destination_at(LogPrivacy::Ops, "[2001:db8::1]:53"), "*:53");
+        assert_eq!(destination_at(LogPrivacy::Ops, "2001:db8::1"), REDACTED);
+        assert_eq!(
+            destination_at(LogPrivacy::Debug, "example.com:443"),
+            "example.com:443"
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use smol::{channel::TrySendError, future::FutureExt, Async};
+use socket2::SockRef;
+
+use crate::{config::RelayBuffers, root_ctx::ROOT_CTX, sockbuf, stats::Metric};
+
+/// Bytes read by all relays but not yet written.
+pub static RELAY_BUFFERED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Relay directions running right now.
+pub static RELAY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A direction that moves nothing for this long is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Copies the reader to the writer, calling `on_write` after each chunk is written. Reading and writing run side by side, but reading stops once the configured bytes are in flight, so that a slow writer backs up into the reader's TCP window rather than into memory.
+pub async fn relay<F: Future<Output = ()>>(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    buffers: &RelayBuffers,
+    mut on_write: impl FnMut(usize) -> F,
+) -> std::io::Result<()> {
+    RELAY_COUNT.fetch_add(1, Ordering::Relaxed);
+    scopeguard::defer!({
+        RELAY_COUNT.fetch_sub(1, Ordering::Relaxed);
+    });
+    let buffer_bytes = buffers.buffer_bytes();
+    let (send, recv) = smol::channel::bounded::<Chunk>(buffers.chunks_in_flight());
+    let reading = async move {
+        loop {
+            let mut buf = vec![0; buffer_bytes];
+            let n = idle_timeout(reader.read(&mut buf)).await?;
+            if n == 0 {
+                return std::io::Result::Ok(());
+            }
+            buf.truncate(n);
+            let chunk = match send.try_send(Chunk::new(buf)) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(chunk)) => chunk,
+                Err(TrySendError::Closed(_)) => return Ok(()),
+            };
+            // the writer is behind, so stop reading until it catches up
+            ROOT_CTX.incr_stat(Metric::RelayBackpressured);
+            if !idle_timeout(async { Ok(send.send(chunk).await.is_ok()) }).await? {
+                return Ok(());
+            }
+        }
+    };
+    let writing = async move {
+        while let Ok(chunk) = recv.recv().await {
+            idle_timeout(writer.write_all(&chunk.0)).await?;
+            let n = chunk.0.len();
+            drop(chunk);
+            on_write(n).await;
+        }
+        Ok(())
+    };
+    futures_util::future::try_join(reading, writing).await?;
+    Ok(())
+}
+
+/// Bytes read but not yet written, counted in [`RELAY_BUFFERED_BYTES`] while they exist.
+struct Chunk(Vec<u8>);
+
+impl Chunk {
+    fn new(bytes: Vec<u8>) -> Self {
+        RELAY_BUFFERED_BYTES.fetch_add(bytes.len(), Ordering::Relaxed);
+        Self(bytes)
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        RELAY_BUFFERED_BYTES.fetch_sub(self.0.len(), Ordering::Relaxed);
+    }
+}
+
+/// Fails an I/O operation that makes no progress within [`IDLE_TIMEOUT`].
+async fn idle_timeout<T>(fut: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+    fut.or(async {
+        smol::Timer::after(IDLE_TIMEOUT).await;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "relay idle timeout",
+        ))
+    })
+    .await
+}
+
+/// How full the relays' buffers are on average, as a fraction of what they may hold.
+pub fn utilization(buffers: &RelayBuffers) -> f64 {
+    let capacity = RELAY_COUNT.load(Ordering::Relaxed) * buffers.in_flight_bytes();
+    if capacity == 0 {
+        return 0.0;
+    }
+    RELAY_BUFFERED_BYTES.load(Ordering::Relaxed) as f64 / capacity as f64
+}
+
+/// What one proxied connection has carried, counted as it goes. Its socket's path is only read from the kernel when asked for.
+pub struct RelayStats {
+    /// The destination, as logs would show it
+    destination: String,
+    started: Instant,
+    /// The destination socket, or None through a peer exit
+    socket: Option<async_dup::Arc<Async<std::net::TcpStream>>>,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+}
+
+/// The statistics of a proxied connection, as shown by the admin API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RelayInfo {
+    pub destination: String,
+    pub age_secs: u64,
+    /// Bytes from the client to the destination
+    pub bytes_up: u64,
+    /// Bytes from the destination to the client
+    pub bytes_down: u64,
+    /// Smoothed round-trip time to the destination, in milliseconds. None through a peer exit.
+    pub rtt_ms: Option<f64>,
+    /// Segments retransmitted to the destination. None through a peer exit.
+    pub retransmits: Option<u32>,
+}
+
+impl RelayStats {
+    pub fn new(
+        destination: String,
+        socket: Option<async_dup::Arc<Async<std::net::TcpStream>>>,
+    ) -> Self {
+        Self {
+            destination,
+            started: Instant::now(),
+            socket,
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_up(&self, n: usize) {
+        self.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_down(&self, n: usize) {
+        self.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes carried both ways so far.
+    pub fn transferred(&self) -> u64 {
+        self.bytes_up.load(Ordering::Relaxed) + self.bytes_down.load(Ordering::Relaxed)
+    }
+
+    pub fn info(&self) -> RelayInfo {
+        let tcp_info = self
+            .socket
+            .as_ref()
+            .and_then(|socket| sockbuf::tcp_info(&SockRef::from(socket.get_ref())).ok());
+        RelayInfo {
+            destination: self.destination.clone(),
+            age_secs: self.started.elapsed().as_secs(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            rtt_ms: tcp_info
+                .as_ref()
+                .map(|info| info.rtt.as_secs_f64() * 1000.0),
+            retransmits: tcp_info.map(|info| info.total_retrans),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    };
+
+    use super::*;
+
+    /// Endless zeroes, counting how many were read.
+    struct Counted(Arc<AtomicUsize>);
+
+    impl AsyncRead for Counted {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            buf.fill(0);
+            self.0.fetch_add(buf.len(), Ordering::Relaxed);
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    /// A writer that never accepts anything, like a tunnel that has stalled.
+    struct Stalled;
+
+    impl AsyncWrite for Stalled {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let buffers: RelayBuffers =
+            serde_json::from_str(r#"{"buffer_bytes": 1024, "in_flight_bytes": 8192}"#).unwrap();
+        smol::block_on(async {
+            let input: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            let mut output = Vec::new();
+            let mut written = 0;
+            relay(&input[..], &mut output, &buffers, |n| {
+                written += n;
+                async {}
+            })
+            .await
+            .unwrap();
+            assert_eq!(output, input);
+            assert_eq!(written, input.len());
+
+            let read = Arc::new(AtomicUsize::new(0));
+            let stalled = relay(Counted(read.clone()), Stalled, &buffers, |_| async {}).or(async {
+                smol::Timer::after(Duration::from_millis(100)).await;
+                Ok(())
+            });
+            stalled.await.unwrap();
+            // the chunks queued, one being written and one waiting to be queued
+            assert!(read.load(Ordering::Relaxed) <= 8192 + 2 * 1024);
+        });
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{config::ListRollout, ratelimit::RateOverride, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Sessions killed because their token was revoked, which a bad revocation list shows up in.
+pub static REVOKED_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts the metrics that staged lists are judged by.
+pub fn on_metric(metric: Metric) {
+    if metric == Metric::SessionRevoked {
+        REVOKED_SESSIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How a staged list undoes a version that failed its soak.
+pub trait Rollback: Clone + PartialEq {
+    /// What to put in force instead of `failed`, which replaced `previous`.
+    fn rolled_back(previous: &Self, failed: &Self) -> Self {
+        let _ = failed;
+        previous.clone()
+    }
+}
+
+/// A failed revocation list is judged by the very revocations it causes, so rolling it back only lets the tokens it added back in. Tokens that were already revoked stay revoked, and tokens it dropped stay dropped.
+impl Rollback for HashSet<u64> {
+    fn rolled_back(previous: &Self, failed: &Self) -> Self {
+        failed.intersection(previous).copied().collect()
+    }
+}
+
+impl Rollback for HashMap<u64, RateOverride> {}
+
+/// A list that the binder updates at runtime, applied blue/green: a new version soaks for a while with the previous one kept, and is rolled back if the disruption it can cause spikes meanwhile. A rolled-back version is not applied again, though the next different one is.
+pub struct Staged<T> {
+    name: &'static str,
+    /// Monotonic count of what the list disrupts, such as killed sessions or dropped packets
+    signal: &'static AtomicU64,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    /// The last version that passed its soak, while a newer one soaks
+    previous: Option<T>,
+    soak: Option<Soak>,
+    rejected: Option<T>,
+    /// Start of the window that the baseline disruption rate is measured over
+    calm_since: (Instant, u64),
+}
+
+struct Soak {
+    started: Instant,
+    count: u64,
+    baseline_per_sec: f64,
+}
+
+impl<T: Rollback> Staged<T> {
+    pub fn new(name: &'static str, signal: &'static AtomicU64) -> Self {
+        Self {
+            name,
+            signal,
+            state: Mutex::new(State {
+                previous: None,
+                soak: None,
+                rejected: None,
+                calm_since: (Instant::now(), signal.load(Ordering::Relaxed)),
+            }),
+        }
+    }
+
+    /// Decides what to put in force when the binder offers a version while `active` is in force. Returns the version to apply, if it differs from `active`.
+    pub fn offer(&self, rollout: Option<&ListRollout>, active: &T, offered: T) -> Option<T> {
+        self.offer_at(
+            rollout,
+            active,
+            offered,
+            Instant::now(),
+            self.signal.load(Ordering::Relaxed),
+        )
+    }
+
+    fn offer_at(
+        &self,
+        rollout: Option<&ListRollout>,
+        active: &T,
+        offered: T,
+        now: Instant,
+        count: u64,
+    ) -> Option<T> {
+        let Some(rollout) = rollout else {
+            return (offered != *active).then_some(offered);
+        };
+        let mut state = self.state.lock();
+        if let Some(soak) = state.soak.as_ref() {
+            let elapsed = now.duration_since(soak.started).as_secs_f64().max(1.0);
+            let events = count - soak.count;
+            if events >= rollout.min_events()
+                && events as f64 / elapsed > soak.baseline_per_sec * rollout.spike_factor()
+            {
+                log::warn!(
+                    "{} update disrupted too much ({} events in {:.0}s), rolling it back",
+                    self.name,
+                    events,
+                    elapsed
+                );
+                ROOT_CTX.incr_stat(Metric::ListRolledBack);
+                state.rejected = Some(active.clone());
+                state.soak = None;
+                state.calm_since = (now, count);
+                return state
+                    .previous
+                    .take()
+                    .map(|previous| T::rolled_back(&previous, active));
+            }
+            if now.duration_since(soak.started) >= Duration::from_secs(rollout.soak_secs()) {
+                log::info!("{} update passed its soak", self.name);
+                state.soak = None;
+                state.previous = None;
+                state.calm_since = (now, count);
+            }
+        }
+        if offered == *active || state.rejected.as_ref() == Some(&offered) {
+            return None;
+        }
+        // a newer version replaces one still soaking, but the last good one stays the fallback
+        if state.soak.is_none() {
+            state.previous = Some(active.clone());
+        }
+        let (calm_start, calm_count) = state.calm_since;
+        let calm_secs = now.duration_since(calm_start).as_secs_f64().max(1.0);
+        state.soak = Some(Soak {
+            started: now,
+            count,
+            baseline_per_sec: (count - calm_count) as f64 / calm_secs,
+        });
+        Some(offered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Rollback for u32 {}
+
+    #[test]
+    fn rollback_on_spike() {
+        static SIGNAL: AtomicU64 = AtomicU64::new(0);
+        let rollout: ListRollout =
+            toml::from_str("soak_secs = 600\nspike_factor = 3.0\nmin_events = 10").unwrap();
+        let staged = Staged::new("test list", &SIGNAL);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // 10 events over the first 100 seconds make a baseline of 0.1/s
+        let v2 = staged.offer_at(Some(&rollout), &1, 2, at(100), 10);
+        assert_eq!(v2, Some(2));
+        // still calm halfway through the soak
+        assert_eq!(staged.offer_at(Some(&rollout), &2, 2, at(200), 15), None);
+        // 100 events in 200 seconds is well over three times the baseline
+        assert_eq!(
+            staged.offer_at(Some(&rollout), &2, 2, at(300), 110),
+            Some(1)
+        );
+        // the rolled-back version is not applied again, but the next one is
+        assert_eq!(staged.offer_at(Some(&rollout), &1, 2, at(400), 110), None);
+        assert_eq!(
+            staged.offer_at(Some(&rollout), &1, 3, at(500), 110),
+            Some(3)
+        );
+        // once soaked, the version stays even if disruption spikes later
+        assert_eq!(staged.offer_at(Some(&rollout), &3, 3, at(1200), 111), None);
+        assert_eq!(staged.offer_at(Some(&rollout), &3, 3, at(1300), 500), None);
+
+        // without a rollout, versions are applied right away
+        assert_eq!(staged.offer_at(None, &3, 4, at(1400), 500), Some(4));
+        assert_eq!(staged.offer_at(None, &4, 4, at(1500), 500), None);
+    }
+
+    #[test]
+    fn revocation_rollback_keeps_old_tokens() {
+        static SIGNAL: AtomicU64 = AtomicU64::new(0);
+        let rollout: ListRollout = toml::from_str("min_events = 10").unwrap();
+        let staged = Staged::new("test revocations", &SIGNAL);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let set = |tokens: &[u64]| tokens.iter().copied().collect::<HashSet<u64>>();
+
+        let v2 = set(&[2, 3, 4]);
+        assert_eq!(
+            staged.offer_at(Some(&rollout), &set(&[1, 2, 3]), v2.clone(), at(100), 0),
+            Some(v2.clone())
+        );
+        // token 4 kills a burst of sessions, so only it is let back in
+        assert_eq!(
+            staged.offer_at(Some(&rollout), &v2, v2.clone(), at(200), 50),
+            Some(set(&[2, 3]))
+        );
+    }
+}
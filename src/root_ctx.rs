@@ -1,118 +1,179 @@
 use std::{
-    sync::{atomic::AtomicUsize, Arc},
-    time::Duration,
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use atomic_float::AtomicF64;
+use dashmap::DashMap;
 use event_listener::Event;
 
 use geph4_protocol::binder::{client::E2eeHttpTransport, protocol::BinderClient};
 use moka::sync::Cache;
-use once_cell::sync::Lazy;
-use smol::fs::unix::PermissionsExt;
-use sosistab2::MuxSecret;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
 
-use crate::{amnesiac_counter::AmnesiacCounter, config::CONFIG, ratelimit::RateLimiter};
+use crate::{
+    amnesiac_counter::AmnesiacCounter,
+    close_reason::CloseReason,
+    config::CONFIG,
+    events,
+    identity::ExitIdentity,
+    outbound::BinderTransport,
+    ratelimit::{
+        plus_limited, tier_limiter, RateLimiter, RateOverride, FREE_SCHEDULE_MULTIPLIER,
+        PLUS_BASE_LIMIT, PLUS_SCHEDULE_MULTIPLIER,
+    },
+    rollout,
+    stats::{self, Metric},
+    stats_client::StatsClient,
+    tun_backend::open_tun,
+    vpn::VpnCtx,
+};
+
+/// The key of a user's limiter in `mass_ratelimits`. Free and Plus limiters of the same token are kept apart.
+fn ratelimit_key(token_id: u64, free: bool) -> u64 {
+    if free {
+        token_id
+    } else {
+        token_id.rotate_left(3)
+    }
+}
 
 /// the root context
 pub struct RootCtx {
-    pub stat_client: Option<Arc<statsd::Client>>,
+    pub stat_client: Option<Arc<StatsClient>>,
     pub binder_client: Option<Arc<BinderClient>>,
-    pub signing_sk: ed25519_dalek::Keypair,
-
-    pub sosistab2_sk: MuxSecret,
+    /// Every exit identity served by this process. The first one is the main identity, from the top level of the config. During a key rotation, the old keys of the main identity are the last one.
+    pub identities: Vec<Arc<ExitIdentity>>,
 
     pub session_counter: AmnesiacCounter,
+    pub live_sessions: AtomicUsize,
     pub conn_count: AtomicUsize,
     pub control_count: AtomicUsize,
 
     pub kill_event: Event,
 
     pub load_factor: Arc<AtomicF64>,
+    /// Latest CPU usage, from 0 to 1
+    pub cpu_usage: AtomicF64,
+    /// Latest egress bandwidth, in bytes per second
+    pub bw_usage: AtomicF64,
+    /// Egress bandwidth over the last minute, in bytes per second
+    pub bw_last_minute: AtomicF64,
+    /// When the exit started
+    pub started: Instant,
+    /// Whether the exit is draining: it refuses new sessions and tells the binder to stop sending clients.
+    pub draining: AtomicBool,
 
     pub mass_ratelimits: Cache<u64, RateLimiter>,
+    /// Speed limits of particular users, replacing their tier's
+    rate_overrides: DashMap<u64, RateOverride>,
+
+    /// Token IDs of clients banned by the binder
+    pub revoked_tokens: RwLock<HashSet<u64>>,
+    /// Notified whenever the revocation list changes, so that live sessions can recheck themselves
+    pub revocation_event: Event,
+
+    /// The VPN state, set up when the first VPN session needs it
+    vpn: OnceCell<Arc<VpnCtx>>,
 }
 
 pub static ROOT_CTX: Lazy<RootCtx> = Lazy::new(|| {
-    let sosistab2_sk = {
-        match std::fs::read(CONFIG.secret_sosistab2_key()) {
-            Ok(vec) => bincode::deserialize(&vec).expect("failed to deserialize my own secret key"),
-            Err(err) => {
-                log::warn!(
-                    "can't read signing_sk, so creating one and saving it! {}",
-                    err
-                );
-                let new_keypair = MuxSecret::generate();
-                if let Err(err) = std::fs::write(
-                    CONFIG.secret_sosistab2_key(),
-                    bincode::serialize(&new_keypair).unwrap(),
-                ) {
-                    log::error!("cannot save signing_sk persistently!!! {}", err);
-                } else {
-                    let mut perms = std::fs::metadata(CONFIG.secret_sosistab2_key())
-                        .unwrap()
-                        .permissions();
-                    perms.set_readonly(true);
-                    perms.set_mode(0o600);
-                    std::fs::set_permissions(CONFIG.secret_sosistab2_key(), perms).unwrap();
-                }
-                new_keypair
-            }
-        }
-    };
-    let signing_sk = {
-        match std::fs::read(CONFIG.secret_key()) {
-            Ok(vec) => bincode::deserialize(&vec).expect("failed to deserialize my own secret key"),
-            Err(err) => {
-                log::warn!(
-                    "can't read signing_sk, so creating one and saving it! {}",
-                    err
-                );
-                let new_keypair = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng {});
-                if let Err(err) = std::fs::write(
-                    CONFIG.secret_key(),
-                    bincode::serialize(&new_keypair).unwrap(),
-                ) {
-                    log::error!("cannot save signing_sk persistently!!! {}", err);
-                } else {
-                    let mut perms = std::fs::metadata(CONFIG.secret_key())
-                        .unwrap()
-                        .permissions();
-                    perms.set_readonly(true);
-                    perms.set_mode(0o600);
-                    std::fs::set_permissions(CONFIG.secret_key(), perms).unwrap();
-                }
-                new_keypair
-            }
-        }
-    };
-    log::info!("signing_sk = {}", hex::encode(signing_sk.public));
-
-    let load_factor = Arc::new(AtomicF64::new(0.0));
-    let stat_client = CONFIG
+    let main_identity = Arc::new(ExitIdentity::load(
+        CONFIG
+            .official()
+            .as_ref()
+            .map(|official| official.exit_hostname().to_owned())
+            .unwrap_or_default(),
+        CONFIG.secret_key(),
+        CONFIG.secret_sosistab2_key(),
+        CONFIG.sosistab2_listen().clone(),
+        CONFIG.country_acl().clone(),
+    ));
+    let extra_identities = CONFIG
         .official()
         .as_ref()
-        .map(|official| Arc::new(statsd::Client::new(official.statsd_addr(), "geph4").unwrap()));
+        .map(|official| official.extra_identities().as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|extra| {
+            Arc::new(ExitIdentity::load(
+                extra.exit_hostname().clone(),
+                extra.secret_key(),
+                extra.secret_sosistab2_key(),
+                extra.sosistab2_listen().clone(),
+                extra.country_acl().clone(),
+            ))
+        });
+    let retiring_identity = CONFIG.key_rotation().as_ref().map(|rotation| {
+        Arc::new(
+            ExitIdentity::load_retiring(
+                main_identity.hostname.clone(),
+                rotation.old_secret_key(),
+                rotation.old_secret_sosistab2_key(),
+                rotation.old_sosistab2_listen().clone(),
+                Instant::now() + Duration::from_secs(rotation.overlap_secs()),
+                CONFIG.country_acl().clone(),
+            )
+            .expect("cannot load old keys for key rotation"),
+        )
+    });
+    let identities = std::iter::once(main_identity)
+        .chain(extra_identities)
+        .chain(retiring_identity)
+        .collect();
+
+    let load_factor = Arc::new(AtomicF64::new(0.0));
+    let stat_client = CONFIG.official().as_ref().map(|official| {
+        let endpoints: Vec<_> = std::iter::once(*official.statsd_addr())
+            .chain(official.statsd_fallback_addrs().iter().copied())
+            .collect();
+        Arc::new(
+            StatsClient::new(
+                &endpoints,
+                official.statsd_prefix(),
+                official.statsd_buffer_kb() * 1024,
+                crate::outbound::stats_proxy(),
+            )
+            .expect("cannot create the statsd client"),
+        )
+    });
     RootCtx {
         stat_client,
         binder_client: CONFIG.official().as_ref().map(|official| {
-            let bclient: Arc<BinderClient> = Arc::new(BinderClient::from(E2eeHttpTransport::new(
-                bincode::deserialize(
-                    &hex::decode(official.binder_master_pk()).expect("invalid hex in binder pk"),
-                )
-                .expect("invalid format of master binder pk"),
-                official.binder_http().clone(),
-                vec![],
-            )));
+            let binder_lpk: [u8; 32] = bincode::deserialize(
+                &hex::decode(official.binder_master_pk()).expect("invalid hex in binder pk"),
+            )
+            .expect("invalid format of master binder pk");
+            let endpoint = official.binder_http().clone();
+            let bclient: Arc<BinderClient> = if CONFIG.outbound_http().is_some() {
+                Arc::new(BinderClient::from(BinderTransport::new(
+                    binder_lpk, endpoint,
+                )))
+            } else {
+                Arc::new(BinderClient::from(E2eeHttpTransport::new(
+                    binder_lpk,
+                    endpoint,
+                    vec![],
+                )))
+            };
             bclient
         }),
-        signing_sk,
-
-        sosistab2_sk,
+        identities,
 
         load_factor,
+        cpu_usage: AtomicF64::new(0.0),
+        bw_usage: AtomicF64::new(0.0),
+        bw_last_minute: AtomicF64::new(0.0),
+        started: Instant::now(),
+        draining: AtomicBool::new(false),
 
         session_counter: AmnesiacCounter::new(Duration::from_secs(300)),
+        live_sessions: Default::default(),
         conn_count: Default::default(),
         control_count: Default::default(),
 
@@ -121,6 +182,12 @@ pub static ROOT_CTX: Lazy<RootCtx> = Lazy::new(|| {
         mass_ratelimits: Cache::builder()
             .time_to_idle(Duration::from_secs(86400))
             .build(),
+        rate_overrides: Default::default(),
+
+        revoked_tokens: Default::default(),
+        revocation_event: Event::new(),
+
+        vpn: OnceCell::new(),
     }
 });
 
@@ -129,22 +196,79 @@ impl RootCtx {
         self.session_counter.insert(id);
     }
 
-    pub fn incr_throughput(&self, delta: usize) {
+    /// The VPN state, setting it up with the TUN device if this is the first use.
+    pub fn vpn(&self) -> &Arc<VpnCtx> {
+        self.vpn.get_or_init(|| {
+            let vpn = VpnCtx::new(CONFIG.all_cgnat_pools(), open_tun);
+            if let Some(path) = CONFIG.lease_snapshot_path() {
+                if let Err(err) = vpn.restore_leases(path) {
+                    log::warn!("cannot restore lease snapshot, starting over: {:?}", err);
+                }
+            }
+            vpn
+        })
+    }
+
+    /// The VPN state, if it has been set up.
+    pub fn vpn_if_started(&self) -> Option<&Arc<VpnCtx>> {
+        self.vpn.get()
+    }
+
+    /// The main exit identity.
+    pub fn main_identity(&self) -> &Arc<ExitIdentity> {
+        &self.identities[0]
+    }
+
+    pub fn incr_throughput(&self, identity: &ExitIdentity, delta: usize) {
         if fastrand::f64() < delta as f64 / 1_000_000.0 {
             if let Some(client) = self.stat_client.as_ref() {
-                let stat_key = format!("exit_usage.{}", self.exit_hostname_dashed());
-                client.count(&stat_key, 1_000_000.0);
+                client.count(
+                    &stats::identity_key(Metric::ExitUsage, &identity.hostname, &[]),
+                    1_000_000.0,
+                );
             }
         }
     }
 
-    pub fn exit_hostname_dashed(&self) -> String {
+    /// Increments a per-exit statsd counter.
+    pub fn incr_stat(&self, metric: Metric) {
+        events::on_metric(metric);
+        rollout::on_metric(metric);
+        if let Some(client) = self.stat_client.as_ref() {
+            client.incr(&stats::key(metric, &[]));
+        }
+    }
+
+    /// Whether the exit has reached its hard session cap.
+    pub fn sessions_full(&self) -> bool {
         CONFIG
-            .official()
-            .as_ref()
-            .map(|official| official.exit_hostname().to_owned())
+            .max_sessions()
+            .map(|max| self.live_sessions.load(Ordering::Relaxed) >= max)
+            .unwrap_or_default()
+    }
+
+    /// Why new sessions are currently being refused, if they are.
+    pub fn session_refusal(&self) -> Option<CloseReason> {
+        if self.draining.load(Ordering::Relaxed) {
+            Some(CloseReason::Draining)
+        } else if self.sessions_full() {
+            Some(CloseReason::ExitFull)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the given token ID has been revoked by the binder.
+    pub fn is_revoked(&self, token_id: u64) -> bool {
+        self.revoked_tokens.read().contains(&token_id)
+    }
+
+    /// Whether the exit has reached its hard proxied-connection cap.
+    pub fn connections_full(&self) -> bool {
+        CONFIG
+            .max_proxied_connections()
+            .map(|max| self.conn_count.load(Ordering::Relaxed) >= max)
             .unwrap_or_default()
-            .replace('.', "-")
     }
 
     pub fn exit_hostname(&self) -> String {
@@ -156,29 +280,59 @@ impl RootCtx {
     }
 
     pub fn get_ratelimit(&self, key: u64, free: bool) -> RateLimiter {
+        self.mass_ratelimits.get_with(ratelimit_key(key, free), || {
+            let multiplier = if free {
+                &FREE_SCHEDULE_MULTIPLIER
+            } else {
+                &PLUS_SCHEDULE_MULTIPLIER
+            };
+            let limiter = tier_limiter(!free).unlimited_child().scheduled(multiplier);
+            let (limit, burst, follow_schedule) = self.user_rate(key, free);
+            limiter.retune(limit, burst, follow_schedule);
+            limiter
+        })
+    }
+
+    /// Sets or clears the speed limit override of a user, applying it to their live sessions right away.
+    pub fn set_rate_override(&self, token_id: u64, rate: Option<RateOverride>) {
+        match rate {
+            Some(rate) => {
+                self.rate_overrides.insert(token_id, rate);
+            }
+            None => {
+                self.rate_overrides.remove(&token_id);
+            }
+        }
+        for free in [true, false] {
+            if let Some(limiter) = self.mass_ratelimits.get(&ratelimit_key(token_id, free)) {
+                let (limit, burst, follow_schedule) = self.user_rate(token_id, free);
+                limiter.retune(limit, burst, follow_schedule);
+            }
+        }
+    }
+
+    /// The speed limit in KB/s (None for no limit), burst in KB, and whether the bandwidth schedule applies, of a user's limiter.
+    fn user_rate(&self, token_id: u64, free: bool) -> (Option<u32>, u32, bool) {
+        let burst = if free {
+            CONFIG.tier_bursts().free()
+        } else {
+            CONFIG.tier_bursts().plus()
+        };
+        if let Some(rate) = self.rate_overrides.get(&token_id) {
+            return (rate.limit_kb, rate.burst_kb.unwrap_or(burst), false);
+        }
         if free {
-            self.mass_ratelimits.get_with(key, || {
-                RateLimiter::new(
-                    CONFIG
-                        .official()
-                        .as_ref()
-                        .and_then(|s| *s.free_limit())
-                        .unwrap_or_default(),
-                    1024,
-                )
-            })
-        } else if CONFIG
-            .official()
-            .as_ref()
-            .and_then(|s| *s.free_limit())
-            .unwrap_or_default()
-            > 0
-        {
-            // plus on free
-            self.mass_ratelimits
-                .get_with(key.rotate_left(3), || RateLimiter::new(1903, 5_000_000))
+            let free_limit = CONFIG
+                .official()
+                .as_ref()
+                .and_then(|s| *s.free_limit())
+                .unwrap_or_default();
+            (Some(free_limit), burst, true)
+        } else if plus_limited() {
+            // plus on free, or plus with a scheduled limit
+            (Some(PLUS_BASE_LIMIT), burst, true)
         } else {
-            RateLimiter::unlimited()
+            (None, burst, true)
         }
     }
 }
@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+use once_cell::sync::OnceCell;
+
+use crate::config::CONFIG;
+
+/// How many file descriptors proxied connections may hold at once.
+static FD_BUDGET: OnceCell<u64> = OnceCell::new();
+
+/// File descriptors held by proxied connections.
+static FDS_IN_USE: AtomicU64 = AtomicU64::new(0);
+
+/// Applies the runtime tuning that must happen before the executor starts. Threads started afterwards inherit the affinity of the main thread.
+pub fn configure_process() {
+    let runtime = CONFIG.runtime();
+    if runtime.single_threaded() {
+        smolscale::permanently_single_threaded();
+    }
+    if !runtime.executor_cores().is_empty() {
+        log::info!(
+            "pinning the executor to cores {:?}",
+            runtime.executor_cores()
+        );
+        pin_current_thread(runtime.executor_cores());
+    }
+    let limit = raise_nofile_limit();
+    let budget = limit.saturating_sub(runtime.fd_headroom());
+    if budget < runtime.fd_headroom() {
+        log::warn!(
+            "RLIMIT_NOFILE is only {}, leaving {} file descriptors for proxied connections",
+            limit,
+            budget
+        );
+    }
+    let _ = FD_BUDGET.set(budget);
+}
+
+/// Raises the soft RLIMIT_NOFILE to the hard limit, returning the resulting soft limit.
+fn raise_nofile_limit() -> u64 {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        log::warn!(
+            "cannot read RLIMIT_NOFILE: {:?}",
+            std::io::Error::last_os_error()
+        );
+        return u64::MAX;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            log::info!(
+                "raised RLIMIT_NOFILE from {} to {}",
+                limit.rlim_cur,
+                raised.rlim_cur
+            );
+            limit = raised;
+        } else {
+            log::warn!(
+                "cannot raise RLIMIT_NOFILE: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    limit.rlim_cur
+}
+
+/// File descriptors counted against the budget until dropped.
+pub struct FdPermit(u64);
+
+impl Drop for FdPermit {
+    fn drop(&mut self) {
+        FDS_IN_USE.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
+
+/// Reserves file descriptors for a proxied connection, or None if that would exceed the budget.
+pub fn reserve_fds(count: u64) -> Option<FdPermit> {
+    let budget = FD_BUDGET.get().copied().unwrap_or(u64::MAX);
+    FDS_IN_USE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_use| {
+            (in_use + count <= budget).then_some(in_use + count)
+        })
+        .ok()
+        .map(|_| FdPermit(count))
+}
+
+/// Applies the placement and priority of the TUN threads to the calling thread.
+pub fn configure_tun_thread() {
+    let runtime = CONFIG.runtime();
+    if !runtime.tun_cores().is_empty() {
+        pin_current_thread(runtime.tun_cores());
+    }
+    if let Some(nice) = runtime.tun_nice() {
+        // on Linux, PRIO_PROCESS with a thread ID sets the priority of just that thread
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+            log::warn!(
+                "cannot set TUN thread priority: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Restricts the calling thread to the given cores.
+fn pin_current_thread(cores: &[usize]) {
+    let result = (|| {
+        let mut set = CpuSet::new();
+        for core in cores {
+            set.set(*core)?;
+        }
+        sched_setaffinity(Pid::from_raw(0), &set)
+    })();
+    if let Err(err) = result {
+        log::warn!("cannot pin thread to cores {:?}: {:?}", cores, err);
+    }
+}
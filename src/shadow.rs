@@ -0,0 +1,133 @@
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::RwLock;
+use rand::Rng;
+
+use crate::{
+    config::{ShadowPath, CONFIG},
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+/// Mismatches logged per minute at most, so that a badly broken candidate doesn't flood the log.
+const MAX_LOGGED_PER_MINUTE: u64 = 10;
+
+/// Mismatches since startup, across all data paths.
+pub static MISMATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// The candidate rewrite of one data-path decision. While it is empty, that path is never compared.
+pub struct Candidate<F> {
+    candidate: RwLock<Option<F>>,
+    /// Whether `candidate` is set, so that the data path only takes the lock when there is something to compare.
+    installed: AtomicBool,
+}
+
+impl<F: Copy> Candidate<F> {
+    pub const fn new() -> Self {
+        Self {
+            candidate: parking_lot::const_rwlock(None),
+            installed: AtomicBool::new(false),
+        }
+    }
+
+    /// Installs a candidate, or removes it if None.
+    pub fn set(&self, candidate: Option<F>) {
+        let mut slot = self.candidate.write();
+        self.installed.store(candidate.is_some(), Ordering::Release);
+        *slot = candidate;
+    }
+
+    /// The candidate, if shadow mode is on and one is installed. Otherwise this takes no lock.
+    pub fn get(&self) -> Option<F> {
+        if CONFIG.shadow().is_none() || !self.installed.load(Ordering::Acquire) {
+            return None;
+        }
+        *self.candidate.read()
+    }
+}
+
+/// Installs the candidate rewrites of the data paths that shadow mode is configured to run. Called at startup.
+pub fn install() {
+    let Some(shadow) = CONFIG.shadow() else {
+        return;
+    };
+    for path in shadow.paths() {
+        match path {
+            ShadowPath::UpstreamVerdict => crate::vpn::install_shadow_candidate(),
+            ShadowPath::LimiterCosts => crate::ratelimit::install_shadow_candidate(),
+        }
+        log::info!(
+            "shadow mode runs the {:?} candidate on {}% of decisions",
+            path,
+            shadow.percent()
+        );
+    }
+}
+
+/// Runs a candidate rewrite of a data-path decision next to the current implementation, on the configured share of calls, and reports when they disagree. The current implementation's result is always the one used.
+pub fn compare<T: PartialEq + Debug>(
+    path: &'static str,
+    current: &T,
+    candidate: impl FnOnce() -> T,
+) {
+    let Some(shadow) = CONFIG.shadow() else {
+        return;
+    };
+    if rand::thread_rng().gen::<f64>() * 100.0 >= shadow.percent() {
+        return;
+    }
+    ROOT_CTX.incr_stat(Metric::ShadowCompared);
+    let candidate = candidate();
+    if &candidate != current {
+        ROOT_CTX.incr_stat(Metric::ShadowMismatch);
+        MISMATCHES.fetch_add(1, Ordering::Relaxed);
+        let minute = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+        if should_log(minute) {
+            log::warn!(
+                "shadow mismatch in {}: current gave {:?}, candidate gave {:?}",
+                path,
+                current,
+                candidate
+            );
+        }
+    }
+}
+
+/// Whether another mismatch may be logged in the given minute.
+fn should_log(minute: u64) -> bool {
+    // the minute in the high bits, mismatches logged during it in the low ones
+    static LOGGED: AtomicU64 = AtomicU64::new(0);
+    let mut allowed = false;
+    let _ = LOGGED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |logged| {
+        let count = if logged >> 32 == minute {
+            logged & 0xffff_ffff
+        } else {
+            0
+        };
+        allowed = count < MAX_LOGGED_PER_MINUTE;
+        allowed.then_some((minute << 32) | (count + 1))
+    });
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatch_logging() {
+        for _ in 0..MAX_LOGGED_PER_MINUTE {
+            assert!(should_log(5));
+        }
+        assert!(!should_log(5));
+        assert!(should_log(6));
+    }
+}
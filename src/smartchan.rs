@@ -1,7 +1,7 @@
 use std::{
     collections::VecDeque,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -11,8 +11,19 @@ use anyhow::Context;
 use event_listener::Event;
 use parking_lot::Mutex;
 
+use crate::{config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Bytes held in all smart channels, counted against `buffer_budget_mb`.
+pub static BUFFERED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Items dropped by all smart channels, which a bad speed limit shows up in.
+pub static DROPPED_ITEMS: AtomicU64 = AtomicU64::new(0);
+
+/// No channel's share of the buffer budget goes below this, however many sessions there are.
+const MIN_CHANNEL_SHARE: usize = 64 * 1024;
+
 /// Creates a new "smart channel" with a given capacity and time bound.
-pub fn smart_channel<T>(
+pub fn smart_channel<T: AsRef<[u8]>>(
     capacity: usize,
     time_limit: Duration,
 ) -> (SmartSender<T>, SmartReceiver<T>) {
@@ -22,21 +33,61 @@ pub fn smart_channel<T>(
         time_limit,
         notify: Arc::new(Event::new()),
         death: Default::default(),
+        dropped: Default::default(),
     };
     let receiver = SmartReceiver {
         inner: sender.inner.clone(),
         notify: sender.notify.clone(),
         death: sender.death.clone(),
+        dropped: sender.dropped.clone(),
     };
     (sender, receiver)
 }
 
+/// The queue of a smart channel, which keeps [`BUFFERED_BYTES`] up to date.
+struct Queue<T> {
+    items: VecDeque<(T, Instant)>,
+    bytes: usize,
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self {
+            items: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> Queue<T> {
+    fn push_back(&mut self, elem: T) {
+        self.bytes += elem.as_ref().len();
+        BUFFERED_BYTES.fetch_add(elem.as_ref().len(), Ordering::Relaxed);
+        self.items.push_back((elem, Instant::now()));
+    }
+
+    fn pop_front(&mut self) -> Option<(T, Instant)> {
+        let elem = self.items.pop_front()?;
+        self.bytes -= elem.0.as_ref().len();
+        BUFFERED_BYTES.fetch_sub(elem.0.as_ref().len(), Ordering::Relaxed);
+        Some(elem)
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        BUFFERED_BYTES.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
 pub struct SmartSender<T> {
-    inner: Arc<Mutex<VecDeque<(T, Instant)>>>,
+    inner: Arc<Mutex<Queue<T>>>,
     count_limit: usize,
     time_limit: Duration,
     notify: Arc<Event>,
     death: Arc<AtomicBool>,
+    /// Items dropped so far
+    dropped: Arc<AtomicU64>,
 }
 
 impl<T> Drop for SmartSender<T> {
@@ -46,31 +97,69 @@ impl<T> Drop for SmartSender<T> {
     }
 }
 
-impl<T> SmartSender<T> {
+impl<T: AsRef<[u8]>> SmartSender<T> {
+    fn count_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        DROPPED_ITEMS.fetch_add(1, Ordering::Relaxed);
+    }
+
     /// Attempts to send into the channel. If full, silently drops.
+    ///
+    /// Besides the count and time limits, every channel gets an equal share of the global buffer budget, and nothing is queued once the whole budget is used up.
     pub fn send_or_drop(&self, elem: T) {
+        let len = elem.as_ref().len();
+        let budget = buffer_budget();
+        let share =
+            (budget / ROOT_CTX.live_sessions.load(Ordering::Relaxed).max(1)).max(MIN_CHANNEL_SHARE);
         let mut inner = self.inner.lock();
-        if inner.len() + 1 >= self.count_limit
+        if inner.items.len() + 1 >= self.count_limit
             || inner
+                .items
                 .front()
                 .map(|b| b.1.elapsed() > self.time_limit)
                 .unwrap_or_default()
         {
             // HEAD drop!
-            inner.pop_front();
+            if inner.pop_front().is_some() {
+                self.count_drop();
+            }
+        }
+        while inner.bytes + len > share && inner.pop_front().is_some() {
+            self.count_drop();
+        }
+        if BUFFERED_BYTES.load(Ordering::Relaxed) + len > budget {
+            ROOT_CTX.incr_stat(Metric::BufferBudgetDropped);
+            self.count_drop();
+            return;
         }
-        inner.push_back((elem, Instant::now()));
+        inner.push_back(elem);
         self.notify.notify_additional(1);
     }
+
+    /// Items waiting in the channel.
+    pub fn queued(&self) -> usize {
+        self.inner.lock().items.len()
+    }
+
+    /// Whether this sender feeds the given receiver.
+    pub fn feeds(&self, receiver: &SmartReceiver<T>) -> bool {
+        Arc::ptr_eq(&self.inner, &receiver.inner)
+    }
+}
+
+/// The global buffer budget, in bytes.
+fn buffer_budget() -> usize {
+    CONFIG.buffer_budget_mb() * 1024 * 1024
 }
 
 pub struct SmartReceiver<T> {
-    inner: Arc<Mutex<VecDeque<(T, Instant)>>>,
+    inner: Arc<Mutex<Queue<T>>>,
     notify: Arc<Event>,
     death: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
 }
 
-impl<T> SmartReceiver<T> {
+impl<T: AsRef<[u8]>> SmartReceiver<T> {
     /// Blocks until something arrives.
     pub async fn recv(&self) -> anyhow::Result<T> {
         loop {
@@ -93,4 +182,9 @@ impl<T> SmartReceiver<T> {
         let mut inner = self.inner.lock();
         inner.pop_front().map(|s| s.0).context("channel is empty")
     }
+
+    /// How many items the channel has dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
 }
@@ -0,0 +1,116 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{close_reason::CloseReason, config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// The SMTP relay port.
+const RELAY_PORT: u16 = 25;
+
+/// The mail submission ports, over implicit TLS and STARTTLS.
+const SUBMISSION_PORTS: [u16; 2] = [465, 587];
+
+/// The window of `connects_per_hour`.
+const RATE_WINDOW: Duration = Duration::from_secs(3600);
+
+/// The mail connections of one session.
+#[derive(Default)]
+struct SmtpSession {
+    open: AtomicU32,
+    /// When the connections of the last hour were opened
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+/// Sessions that have recently opened mail connections, by client ID.
+static SESSIONS: Lazy<Cache<u64, Arc<SmtpSession>>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_idle(RATE_WINDOW)
+        .max_capacity(100_000)
+        .build()
+});
+
+/// Whether the SMTP policy lets traffic to this port through at all. Other ports are always let through.
+pub fn port_allowed(port: u16) -> bool {
+    let policy = CONFIG.smtp();
+    if port == RELAY_PORT {
+        policy.allow_relay()
+    } else if SUBMISSION_PORTS.contains(&port) {
+        policy.allow_submission()
+    } else {
+        true
+    }
+}
+
+/// An open mail connection, counted against its session's cap until dropped.
+pub struct SmtpGuard(Arc<SmtpSession>);
+
+impl Drop for SmtpGuard {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Admits a proxied connection of the given session under the SMTP policy. Returns a guard for mail connections, None for everything else, and the reason for refused ones.
+pub fn admit(client_id: u64, port: u16) -> Result<Option<SmtpGuard>, CloseReason> {
+    if port != RELAY_PORT && !SUBMISSION_PORTS.contains(&port) {
+        return Ok(None);
+    }
+    if !port_allowed(port) {
+        ROOT_CTX.incr_stat(Metric::SmtpBlocked);
+        return Err(CloseReason::BlockedPort);
+    }
+    let policy = CONFIG.smtp();
+    let session = SESSIONS.get_with(client_id, Default::default);
+    {
+        let mut recent = session.recent.lock();
+        while recent
+            .front()
+            .map(|opened| opened.elapsed() >= RATE_WINDOW)
+            .unwrap_or_default()
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= policy.connects_per_hour() as usize {
+            ROOT_CTX.incr_stat(Metric::SmtpRateLimited);
+            return Err(CloseReason::MailLimited);
+        }
+        if session.open.load(Ordering::Relaxed) >= policy.max_open_per_session() {
+            ROOT_CTX.incr_stat(Metric::SmtpCapped);
+            return Err(CloseReason::MailLimited);
+        }
+        recent.push_back(Instant::now());
+        session.open.fetch_add(1, Ordering::Relaxed);
+    }
+    ROOT_CTX.incr_stat(Metric::SmtpConnect);
+    Ok(Some(SmtpGuard(session)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps() {
+        let client_id = rand::random();
+        assert!(admit(client_id, 443).unwrap().is_none());
+        assert_eq!(admit(client_id, 25).err(), Some(CloseReason::BlockedPort));
+        let max_open = CONFIG.smtp().max_open_per_session();
+        let mut guards: Vec<SmtpGuard> = (0..max_open)
+            .map(|_| admit(client_id, 587).unwrap().unwrap())
+            .collect();
+        assert_eq!(admit(client_id, 465).err(), Some(CloseReason::MailLimited));
+        // closing a connection frees its slot, and other sessions have their own
+        guards.pop();
+        assert!(admit(client_id, 465).unwrap().is_some());
+        assert!(admit(client_id + 1, 465).unwrap().is_some());
+    }
+}
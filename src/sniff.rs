@@ -0,0 +1,257 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use event_listener::Event;
+use once_cell::sync::OnceCell;
+use smol::io::AsyncRead;
+
+use crate::{
+    config::{SniffAction, CONFIG},
+    ratelimit::RateLimiter,
+    root_ctx::ROOT_CTX,
+    stats::Metric,
+};
+
+/// A function that recognizes a protocol from the first bytes one side of a connection sends.
+pub type Sniff = fn(&[u8]) -> Option<Protocol>;
+
+/// The first bytes of a BitTorrent peer handshake.
+const BITTORRENT_MAGIC: &[u8] = b"\x13BitTorrent protocol";
+
+/// The protocol ID that starts a UDP tracker connect request.
+const UDP_TRACKER_MAGIC: &[u8] = &0x41727101980u64.to_be_bytes();
+
+/// The STUN magic cookie, which tells STUN apart from uTP packets that look alike.
+const STUN_MAGIC_COOKIE: &[u8] = &[0x21, 0x12, 0xa4, 0x42];
+
+/// A protocol recognized from the first bytes of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Smtp,
+    BitTorrent,
+}
+
+impl Protocol {
+    /// What the config says to do with connections carrying this protocol.
+    fn action(self) -> SniffAction {
+        let sniffing = CONFIG.protocol_sniffing();
+        match self {
+            Protocol::Smtp => sniffing.smtp(),
+            Protocol::BitTorrent => sniffing.bittorrent(),
+        }
+    }
+
+    fn metric(self) -> Metric {
+        match self {
+            Protocol::Smtp => Metric::SniffedSmtp,
+            Protocol::BitTorrent => Metric::SniffedBitTorrent,
+        }
+    }
+}
+
+/// Recognizes a protocol from the first bytes the client sends.
+pub fn sniff_client(first: &[u8]) -> Option<Protocol> {
+    if first.starts_with(BITTORRENT_MAGIC) {
+        return Some(Protocol::BitTorrent);
+    }
+    let greeting = first.get(..5)?.to_ascii_uppercase();
+    if greeting == b"EHLO " || greeting == b"HELO " {
+        return Some(Protocol::Smtp);
+    }
+    None
+}
+
+/// Recognizes a protocol from the first bytes the server sends.
+pub fn sniff_server(first: &[u8]) -> Option<Protocol> {
+    if first.starts_with(BITTORRENT_MAGIC) {
+        return Some(Protocol::BitTorrent);
+    }
+    // FTP servers also greet with 220, so the banner must name SMTP
+    let line = first.split(|b| *b == b'\n').next()?;
+    if line.starts_with(b"220") && line.to_ascii_uppercase().windows(4).any(|w| w == b"SMTP") {
+        return Some(Protocol::Smtp);
+    }
+    None
+}
+
+/// Recognizes BitTorrent from a UDP payload: DHT messages, uTP packets, and UDP tracker requests.
+pub fn sniff_udp(payload: &[u8]) -> Option<Protocol> {
+    let is_dht = payload.starts_with(b"d1:") && payload.windows(5).any(|w| w == b"1:y1:");
+    let is_tracker = payload.len() >= 16 && payload.starts_with(UDP_TRACKER_MAGIC);
+    (is_dht || is_tracker || is_utp(payload)).then_some(Protocol::BitTorrent)
+}
+
+/// Whether a UDP payload is a uTP packet: a 20-byte header of version 1, a valid extension chain, and a payload only if it is a data packet.
+fn is_utp(payload: &[u8]) -> bool {
+    let (Some(&first), Some(&extension)) = (payload.first(), payload.get(1)) else {
+        return false;
+    };
+    let (packet_type, version) = (first >> 4, first & 0x0f);
+    if payload.len() < 20 || version != 1 || packet_type > 4 || payload[4..8] == *STUN_MAGIC_COOKIE
+    {
+        return false;
+    }
+    let mut extension = extension;
+    let mut offset = 20;
+    while extension != 0 {
+        let (Some(&next), Some(&len)) = (payload.get(offset), payload.get(offset + 1)) else {
+            return false;
+        };
+        if extension > 2 {
+            return false;
+        }
+        extension = next;
+        offset += 2 + len as usize;
+    }
+    // only ST_DATA packets carry a payload, which also rules out QUIC short headers that start like ST_SYN
+    offset == payload.len() || (packet_type == 0 && offset < payload.len())
+}
+
+/// The verdict on one proxied connection, shared by both of its directions.
+#[derive(Default)]
+pub struct ConnSniffer {
+    throttle: OnceCell<RateLimiter>,
+    blocked: AtomicBool,
+    on_block: Event,
+}
+
+impl ConnSniffer {
+    /// The limiter of the connection, if it carries a throttled protocol.
+    pub fn throttle(&self) -> Option<&RateLimiter> {
+        self.throttle.get()
+    }
+
+    /// Whether the connection carries a blocked protocol.
+    pub fn blocked(&self) -> bool {
+        self.blocked.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the connection is found to carry a blocked protocol.
+    pub async fn wait_blocked(&self) {
+        loop {
+            let listener = self.on_block.listen();
+            if self.blocked() {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Applies the action for a recognized protocol.
+    fn apply(&self, protocol: Protocol) {
+        ROOT_CTX.incr_stat(protocol.metric());
+        match protocol.action() {
+            SniffAction::Allow => {}
+            SniffAction::Throttle => {
+                let limit = CONFIG.protocol_sniffing().throttle_kb();
+                let _ = self.throttle.set(RateLimiter::new(limit, limit));
+            }
+            SniffAction::Block => {
+                log::debug!("blocking a {:?} connection", protocol);
+                self.blocked.store(true, Ordering::SeqCst);
+                self.on_block.notify(usize::MAX);
+            }
+        }
+    }
+}
+
+/// A reader that sniffs the first bytes read from it, failing every read once its connection is blocked.
+pub struct SniffReader<R> {
+    inner: R,
+    sniffer: Arc<ConnSniffer>,
+    sniff: Option<Sniff>,
+}
+
+impl<R> SniffReader<R> {
+    /// Wraps a reader of one direction of a connection, recognizing protocols with the given function.
+    pub fn new(inner: R, sniffer: Arc<ConnSniffer>, sniff: Sniff) -> Self {
+        Self {
+            inner,
+            sniffer,
+            sniff: Some(sniff),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SniffReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.sniffer.blocked() {
+            return Poll::Ready(Err(io::ErrorKind::PermissionDenied.into()));
+        }
+        let n = match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if n > 0 {
+            if let Some(protocol) = this.sniff.take().and_then(|sniff| sniff(&buf[..n])) {
+                this.sniffer.apply(protocol);
+                if this.sniffer.blocked() {
+                    return Poll::Ready(Err(io::ErrorKind::PermissionDenied.into()));
+                }
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognize() {
+        assert_eq!(
+            sniff_client(b"\x13BitTorrent protocol\0\0\0\0\0\x10\0\x05"),
+            Some(Protocol::BitTorrent)
+        );
+        assert_eq!(
+            sniff_client(b"ehlo mail.example.com\r\n"),
+            Some(Protocol::Smtp)
+        );
+        assert_eq!(
+            sniff_server(b"220 mx.example.com ESMTP Postfix\r\n"),
+            Some(Protocol::Smtp)
+        );
+        // FTP banners and ordinary traffic are left alone
+        assert_eq!(sniff_server(b"220 ProFTPD Server ready.\r\n"), None);
+        assert_eq!(sniff_client(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(sniff_client(b"\x16\x03\x01\x02\x00\x01"), None);
+    }
+
+    #[test]
+    fn recognize_udp() {
+        assert_eq!(
+            sniff_udp(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"),
+            Some(Protocol::BitTorrent)
+        );
+        let mut tracker = UDP_TRACKER_MAGIC.to_vec();
+        tracker.extend_from_slice(&[0, 0, 0, 0, 1, 2, 3, 4]);
+        assert_eq!(sniff_udp(&tracker), Some(Protocol::BitTorrent));
+        // a uTP SYN has no payload, and a data packet has one
+        let mut syn = vec![0x41, 0];
+        syn.resize(20, 7);
+        assert_eq!(sniff_udp(&syn), Some(Protocol::BitTorrent));
+        let mut data = vec![0x01, 0];
+        data.resize(1200, 7);
+        assert_eq!(sniff_udp(&data), Some(Protocol::BitTorrent));
+        // a QUIC short header packet starting with the same byte as a SYN, and a STUN binding response
+        let mut quic = vec![0x41, 0];
+        quic.resize(1200, 7);
+        assert_eq!(sniff_udp(&quic), None);
+        let mut stun = vec![0x01, 0x01, 0, 0, 0x21, 0x12, 0xa4, 0x42];
+        stun.resize(32, 7);
+        assert_eq!(sniff_udp(&stun), None);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    convert::Infallible,
+    io,
+    os::fd::AsRawFd,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use libc::c_int;
+use socket2::SockRef;
+
+use crate::{config::CONFIG, root_ctx::ROOT_CTX, stats::Metric};
+
+/// Buffer bytes that tuned sockets hold beyond `min_buffer_bytes`, across both directions.
+pub static TUNED_BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Byte offset of tcpi_rtt, in microseconds, within struct tcp_info.
+const TCPI_RTT_OFFSET: usize = 68;
+
+/// Byte offset of tcpi_total_retrans within struct tcp_info.
+const TCPI_TOTAL_RETRANS_OFFSET: usize = 100;
+
+/// Keeps a destination socket's buffers at about twice the bandwidth-delay product that its connection has been seeing. `transferred` gives the bytes relayed both ways so far. Without `socket_tuning`, the kernel's own autotuning is left alone.
+pub async fn tune_loop(socket: &std::net::TcpStream, transferred: impl Fn() -> u64) -> Infallible {
+    let Some(config) = CONFIG.socket_tuning() else {
+        return smol::future::pending().await;
+    };
+    let socket = SockRef::from(socket);
+    if config.notsent_lowat_bytes() > 0 {
+        if let Err(err) = set_notsent_lowat(&socket, config.notsent_lowat_bytes()) {
+            log::debug!("cannot set TCP_NOTSENT_LOWAT: {:?}", err);
+        }
+    }
+    let interval = Duration::from_secs(config.interval_secs());
+    let mut held = Held(0);
+    let mut last_transferred = 0;
+    loop {
+        smol::Timer::after(interval).await;
+        let total = transferred();
+        let rate = (total - last_transferred) / interval.as_secs();
+        last_transferred = total;
+        // a connection that has been idle so far keeps the kernel's defaults
+        if rate == 0 && held.0 == 0 {
+            continue;
+        }
+        let Ok(TcpInfo { rtt, .. }) = tcp_info(&socket) else {
+            return smol::future::pending().await;
+        };
+        let wanted = buffer_size(
+            rate,
+            rtt,
+            config.min_buffer_bytes(),
+            config.max_buffer_bytes(),
+        );
+        let extra = held.resize(
+            wanted - config.min_buffer_bytes(),
+            config.total_buffer_bytes(),
+        );
+        let Some(extra) = extra else {
+            continue;
+        };
+        let size = config.min_buffer_bytes() + extra;
+        // setting either size turns off the kernel's autotuning of that buffer for the socket
+        if let Err(err) = socket
+            .set_send_buffer_size(size)
+            .and_then(|_| socket.set_recv_buffer_size(size))
+        {
+            log::debug!("cannot resize socket buffers: {:?}", err);
+            return smol::future::pending().await;
+        }
+        ROOT_CTX.incr_stat(Metric::SocketBuffersResized);
+    }
+}
+
+/// Twice the bandwidth-delay product, rounded up to a power of two so that small changes in the measurements don't resize the buffers, and clamped to the limits.
+fn buffer_size(rate: u64, rtt: Duration, min: usize, max: usize) -> usize {
+    let bdp = (rate as f64 * rtt.as_secs_f64()) as usize;
+    (bdp * 2).next_power_of_two().clamp(min, max)
+}
+
+/// A socket's share of [`TUNED_BUFFER_BYTES`], per direction, given back on drop.
+struct Held(usize);
+
+impl Held {
+    /// Takes the wanted bytes, or as many as the global limit leaves room for. Returns the new share if it changed.
+    fn resize(&mut self, wanted: usize, limit: usize) -> Option<usize> {
+        let others = TUNED_BUFFER_BYTES.load(Ordering::Relaxed) - self.0 * 2;
+        let granted = wanted.min(limit.saturating_sub(others) / 2);
+        if granted == self.0 {
+            return None;
+        }
+        if granted > self.0 {
+            TUNED_BUFFER_BYTES.fetch_add((granted - self.0) * 2, Ordering::Relaxed);
+        } else {
+            TUNED_BUFFER_BYTES.fetch_sub((self.0 - granted) * 2, Ordering::Relaxed);
+        }
+        self.0 = granted;
+        Some(granted)
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        TUNED_BUFFER_BYTES.fetch_sub(self.0 * 2, Ordering::Relaxed);
+    }
+}
+
+/// What the kernel knows of a connection's path.
+pub struct TcpInfo {
+    /// Smoothed round-trip time
+    pub rtt: Duration,
+    /// Segments retransmitted over the connection's life
+    pub total_retrans: u32,
+}
+
+/// Reads the parts of struct tcp_info that the exit uses.
+pub fn tcp_info(socket: &SockRef) -> io::Result<TcpInfo> {
+    let mut info = [0u8; TCPI_TOTAL_RETRANS_OFFSET + 4];
+    let mut len = info.len() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            info.as_mut_ptr().cast(),
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let field = |offset: usize| u32::from_ne_bytes(info[offset..offset + 4].try_into().unwrap());
+    Ok(TcpInfo {
+        rtt: Duration::from_micros(field(TCPI_RTT_OFFSET).into()),
+        total_retrans: field(TCPI_TOTAL_RETRANS_OFFSET),
+    })
+}
+
+/// Caps the bytes sent but not yet on the wire, so that data waits in the relay rather than in an oversized send buffer.
+fn set_notsent_lowat(socket: &SockRef, bytes: u32) -> io::Result<()> {
+    let value = bytes as c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_NOTSENT_LOWAT,
+            (&value as *const c_int).cast(),
+            std::mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bdp_sizing() {
+        let min = 65536;
+        let max = 4 << 20;
+        // 10 MB/s over 150ms is a 1.5 MB product
+        assert_eq!(
+            buffer_size(10_000_000, Duration::from_millis(150), min, max),
+            4 << 20
+        );
+        assert_eq!(
+            buffer_size(1_000_000, Duration::from_millis(150), min, max),
+            512 << 10
+        );
+        assert_eq!(buffer_size(1000, Duration::from_millis(10), min, max), min);
+        assert_eq!(buffer_size(0, Duration::ZERO, min, max), min);
+
+        let mut first = Held(0);
+        let mut second = Held(0);
+        assert_eq!(first.resize(300, 1000), Some(300));
+        assert_eq!(second.resize(300, 1000), Some(200));
+        assert_eq!(second.resize(200, 1000), None);
+        assert_eq!(first.resize(100, 1000), Some(100));
+        drop(first);
+        drop(second);
+    }
+}
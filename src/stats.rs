@@ -0,0 +1,327 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+use crate::config::{StatsdTagStyle, CONFIG};
+
+macro_rules! metrics {
+    ($($variant:ident => $name:literal,)*) => {
+        /// Every metric the exit sends to statsd, so that a misspelled name is a compile error rather than a new series.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Metric {
+            $($variant,)*
+        }
+
+        impl Metric {
+            /// The name of the metric, before the prefix and tags.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Metric::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+metrics! {
+    AsnTraffic => "asn_traffic",
+    BridgeForwarded => "bridge_forwarded",
+    BridgeNoUpstream => "bridge_no_upstream",
+    BridgeUpstreamFailed => "bridge_upstream_failed",
+    BufferBudgetDropped => "buffer_budget_dropped",
+    BypassRouteRefused => "bypass_route_refused",
+    BufferedBytes => "buffered_bytes",
+    BulkThrottled => "bulk_throttled",
+    BytesAllocated => "bytes_allocated",
+    CgnatOccupancy => "cgnat_occupancy",
+    CgnatPoolFull => "cgnat_pool_full",
+    ClockSkew => "clock_skew",
+    CompressedStream => "compressed_stream",
+    CompressionRatio => "compression_ratio",
+    CompressionSkippedCpu => "compression_skipped_cpu",
+    ConnCount => "conn_count",
+    ConnHandshakeTimeout => "conn_handshake_timeout",
+    ConnIdleTimeout => "conn_idle_timeout",
+    ConnMigrated => "conn_migrated",
+    ConnMigrationFailed => "conn_migration_failed",
+    ConnRejectedFds => "conn_rejected_fds",
+    ConnRejectedFull => "conn_rejected_full",
+    ConnectFailover => "connect_failover",
+    ConnectFailureCached => "connect_failure_cached",
+    ControlCount => "control_count",
+    CpuUsage => "cpu_usage",
+    DataPathError => "data_path_error",
+    DnsCacheHit => "dns_cache_hit",
+    DnsQueryDropped => "dns_query_dropped",
+    DnsTcpRefused => "dns_tcp_refused",
+    EgressProbeFailed => "egress_probe_failed",
+    EgressRouted => "egress_routed",
+    ExitUsage => "exit_usage",
+    HandshakeRateLimited => "handshake_rate_limited",
+    IdleJitter => "idlejitter",
+    InternalDestinationRefused => "internal_destination_refused",
+    LeaseRestored => "lease_restored",
+    ListenerActivePipes => "listener_active_pipes",
+    ListenerDecodeFailures => "listener_decode_failures",
+    ListenerDuplicateRate => "listener_duplicate_rate",
+    ListenerHandshakeRate => "listener_handshake_rate",
+    ListenerRotated => "listener_rotated",
+    ListRolledBack => "list_rolled_back",
+    LoadFactor => "load_factor",
+    Nat64RangeFull => "nat64_range_full",
+    Nat64Synthesized => "nat64_synthesized",
+    Nat64UdpFlow => "nat64_udp_flow",
+    Nat64Unmapped => "nat64_unmapped",
+    Panic => "panic",
+    PeerEgress => "peer_egress",
+    PeerEgressFailed => "peer_egress_failed",
+    PeerRefused => "peer_refused",
+    PeerServed => "peer_served",
+    PeerUnhealthy => "peer_unhealthy",
+    PipeDuplicate => "pipe_duplicate",
+    PipeReattached => "pipe_reattached",
+    PipeRebindRefused => "pipe_rebind_refused",
+    PipeRebound => "pipe_rebound",
+    PortForwardOpened => "port_forward_opened",
+    PortForwardRefused => "port_forward_refused",
+    PowChallenged => "pow_challenged",
+    PowFailed => "pow_failed",
+    PowSolved => "pow_solved",
+    QuotaTerminated => "quota_terminated",
+    QuotaThrottled => "quota_throttled",
+    RawExitUsage => "raw_exit_usage",
+    RelayBackpressured => "relay_backpressured",
+    RelayBufferUtilization => "relay_buffer_utilization",
+    RelayBufferedBytes => "relay_buffered_bytes",
+    RawFlow => "raw_flow",
+    SessionBondFailed => "session_bond_failed",
+    SessionBonded => "session_bonded",
+    SessionCount => "session_count",
+    SessionCountryRejected => "session_country_rejected",
+    SessionHandshakeTimeout => "session_handshake_timeout",
+    SessionReapedFree => "session_reaped_free",
+    SessionReapedPlus => "session_reaped_plus",
+    SessionRejected => "session_rejected",
+    SessionResumeFailed => "session_resume_failed",
+    SessionResumed => "session_resumed",
+    SessionRevoked => "session_revoked",
+    SessionSuperseded => "session_superseded",
+    ShadowCompared => "shadow_compared",
+    ShadowMismatch => "shadow_mismatch",
+    SniffedBitTorrent => "sniffed_bittorrent",
+    SniffedSmtp => "sniffed_smtp",
+    SmtpBlocked => "smtp_blocked",
+    SmtpCapped => "smtp_capped",
+    SmtpConnect => "smtp_connect",
+    SmtpRateLimited => "smtp_rate_limited",
+    SocketBufferBytes => "socket_buffer_bytes",
+    SocketBuffersResized => "socket_buffers_resized",
+    StatsDropped => "stats_dropped",
+    TaskCount => "task_count",
+    ThreadCount => "thread_key",
+    TransparentAcceptFailed => "transparent_accept_failed",
+    TransparentAcceptShed => "transparent_accept_shed",
+    TransparentProxyReset => "transparent_proxy_reset",
+    TransparentSourceRejected => "transparent_source_rejected",
+    UdpFlowLimit => "udp_flow_limit",
+    VpnBadPacket => "vpn_bad_packet",
+    VpnBitTorrentUdp => "vpn_bittorrent_udp",
+    VpnCoalescingRatio => "vpn_coalescing_ratio",
+    VpnDispatchQueueFull => "vpn_dispatch_queue_full",
+    VpnHairpinDropped => "vpn_hairpin_dropped",
+    VpnIcmpRejected => "vpn_icmp_rejected",
+    VpnTunBacklogDropped => "vpn_tun_backlog_dropped",
+    VpnTunPacketsPerSlab => "vpn_tun_packets_per_slab",
+    VpnTunQueueFull => "vpn_tun_queue_full",
+}
+
+/// Tag value that replaces the real ones once `statsd_max_keys` is reached.
+const OVERFLOW_TAG: &str = "other";
+
+/// The hostname of the main exit identity, which every stat is tagged with unless it names another identity.
+static EXIT_HOSTNAME: Lazy<String> = Lazy::new(|| {
+    CONFIG
+        .official()
+        .as_ref()
+        .map(|official| official.exit_hostname().clone())
+        .unwrap_or_default()
+});
+
+/// The operator's tags attached to every stat, in tag name order.
+static EXTRA_TAGS: Lazy<Vec<(String, String)>> = Lazy::new(|| {
+    CONFIG
+        .official()
+        .as_ref()
+        .map(|official| {
+            official
+                .statsd_extra_tags()
+                .iter()
+                .map(|(tag, value)| (tag.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+/// A metric, the hostname of its exit identity (None for the main one), and its tag values.
+type KeyParts = (Metric, Option<String>, Vec<String>);
+
+/// Keys built so far.
+static KEYS: Lazy<DashMap<KeyParts, Arc<str>>> = Lazy::new(Default::default);
+
+/// The key of a stat of the main exit identity.
+pub fn key(metric: Metric, tags: &[(&str, &str)]) -> Arc<str> {
+    key_inner(metric, None, tags)
+}
+
+/// The key of a stat of the exit identity with the given hostname.
+pub fn identity_key(metric: Metric, hostname: &str, tags: &[(&str, &str)]) -> Arc<str> {
+    key_inner(metric, Some(hostname), tags)
+}
+
+fn key_inner(metric: Metric, hostname: Option<&str>, tags: &[(&str, &str)]) -> Arc<str> {
+    let values: Vec<String> = tags.iter().map(|(_, value)| value.to_string()).collect();
+    let cache_key = (metric, hostname.map(str::to_owned), values);
+    if let Some(key) = KEYS.get(&cache_key) {
+        return key.clone();
+    }
+    let (style, max_keys) = CONFIG
+        .official()
+        .as_ref()
+        .map(|official| (official.statsd_tags(), official.statsd_max_keys()))
+        .unwrap_or((StatsdTagStyle::Dotted, usize::MAX));
+    let exit = hostname.unwrap_or(&EXIT_HOSTNAME);
+    if KEYS.len() >= max_keys && !tags.is_empty() {
+        // past the cap, every new combination of tag values shares one key per metric
+        let overflow: Vec<(&str, &str)> =
+            tags.iter().map(|(tag, _)| (*tag, OVERFLOW_TAG)).collect();
+        if cache_key.2.iter().any(|value| value != OVERFLOW_TAG) {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "reached {} stat keys, new tag values are now reported as {:?}",
+                    max_keys,
+                    OVERFLOW_TAG
+                );
+            }
+            return key_inner(metric, hostname, &overflow);
+        }
+    }
+    let extra: Vec<(&str, &str)> = EXTRA_TAGS
+        .iter()
+        .map(|(tag, value)| (tag.as_str(), value.as_str()))
+        .collect();
+    let key: Arc<str> = format_key(style, metric, exit, &extra, tags).into();
+    KEYS.insert(cache_key, key.clone());
+    key
+}
+
+/// Formats a key. Tags are dotted segments after the exit hostname, or statsd tags in the given style. The extra tags are dotted segments before the metric name, or statsd tags right after the exit hostname.
+fn format_key(
+    style: StatsdTagStyle,
+    metric: Metric,
+    exit: &str,
+    extra: &[(&str, &str)],
+    tags: &[(&str, &str)],
+) -> String {
+    let mut key = String::new();
+    match style {
+        StatsdTagStyle::Dotted => {
+            for (_, value) in extra {
+                key.push_str(&value.replace('.', "-"));
+                key.push('.');
+            }
+            key.push_str(metric.name());
+            for (_, value) in std::iter::once(("exit", exit)).chain(tags.iter().copied()) {
+                key.push('.');
+                key.push_str(&value.replace('.', "-"));
+            }
+        }
+        StatsdTagStyle::Influx | StatsdTagStyle::Graphite => {
+            let separator = if style == StatsdTagStyle::Influx {
+                ','
+            } else {
+                ';'
+            };
+            key.push_str(metric.name());
+            let tags = std::iter::once(("exit", exit))
+                .chain(extra.iter().copied())
+                .chain(tags.iter().copied());
+            for (tag, value) in tags {
+                key.push(separator);
+                key.push_str(tag);
+                key.push('=');
+                key.extend(value.chars().map(|c| {
+                    if matches!(c, ',' | ';' | '=' | ':' | '|' | '#' | ' ') {
+                        '_'
+                    } else {
+                        c
+                    }
+                }));
+            }
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_styles() {
+        let tags = [("bridge_group", "us.east")];
+        assert_eq!(
+            format_key(
+                StatsdTagStyle::Dotted,
+                Metric::RawFlow,
+                "exit.example.com",
+                &[],
+                &tags
+            ),
+            "raw_flow.exit-example-com.us-east"
+        );
+        assert_eq!(
+            format_key(
+                StatsdTagStyle::Influx,
+                Metric::RawFlow,
+                "exit.example.com",
+                &[],
+                &tags
+            ),
+            "raw_flow,exit=exit.example.com,bridge_group=us.east"
+        );
+        assert_eq!(
+            format_key(
+                StatsdTagStyle::Graphite,
+                Metric::SessionCount,
+                "a b",
+                &[],
+                &[]
+            ),
+            "session_count;exit=a_b"
+        );
+    }
+
+    #[test]
+    fn extra_tags() {
+        let extra = [("provider", "hetzner"), ("region", "eu.central")];
+        assert_eq!(
+            format_key(StatsdTagStyle::Dotted, Metric::ExitUsage, "e1", &extra, &[]),
+            "hetzner.eu-central.exit_usage.e1"
+        );
+        assert_eq!(
+            format_key(
+                StatsdTagStyle::Influx,
+                Metric::RawFlow,
+                "e1",
+                &extra,
+                &[("bridge_group", "SELF")]
+            ),
+            "raw_flow,exit=e1,provider=hetzner,region=eu.central,bridge_group=SELF"
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use arc_swap::ArcSwapOption;
+use parking_lot::Mutex;
+
+use crate::outbound::Socks5Proxy;
+
+/// A statsd client that fails over between endpoints. Each endpoint gets a connected socket, so that one whose daemon is gone fails sends, after the first, with the ICMP error the kernel got back. Lines that no endpoint takes are buffered, up to a budget, and sent once one does.
+///
+/// Proxied stats go through the proxy's UDP relay instead, which is associated in the background and again whenever its control connection closes. Until it is, lines are buffered.
+pub struct StatsClient {
+    prefix: String,
+    /// The endpoints, with the socket connected to each unless stats are proxied
+    endpoints: Vec<(SocketAddr, Option<UdpSocket>)>,
+    /// Index of the endpoint lines go to
+    active: AtomicUsize,
+    buffer: Mutex<Buffer>,
+    buffer_bytes: usize,
+    /// Lines dropped because the buffer was full, not yet reported
+    dropped: AtomicU64,
+    /// The SOCKS5 proxy that stats go through, if they are proxied
+    proxy: Option<Socks5Proxy>,
+    /// The proxy's UDP relay, while it is associated
+    relay: Arc<ArcSwapOption<Relay>>,
+    /// Whether a thread is associating with the relay
+    associating: Arc<AtomicBool>,
+}
+
+/// A SOCKS5 UDP relay: the control connection, which keeps the relay open, and a socket connected to the relay.
+struct Relay {
+    control: TcpStream,
+    socket: UdpSocket,
+}
+
+impl Relay {
+    fn associate(proxy: &Socks5Proxy) -> anyhow::Result<Self> {
+        let (control, relay_addr) = proxy.udp_associate()?;
+        control.set_nonblocking(true)?;
+        let socket = bind_connected(relay_addr)?;
+        Ok(Self { control, socket })
+    }
+
+    /// Whether the proxy still keeps the relay open, as it does for as long as the control connection is.
+    fn is_open(&self) -> bool {
+        match self.control.peek(&mut [0]) {
+            Ok(n) => n > 0,
+            Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+/// A nonblocking UDP socket connected to the given address.
+fn bind_connected(target: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = if target.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")?
+    } else {
+        UdpSocket::bind("[::]:0")?
+    };
+    socket.set_nonblocking(true)?;
+    socket.connect(target)?;
+    Ok(socket)
+}
+
+#[derive(Default)]
+struct Buffer {
+    lines: VecDeque<String>,
+    bytes: usize,
+}
+
+impl StatsClient {
+    /// Creates a client for the given endpoints, in order of preference, buffering up to the given bytes while none works. Given a SOCKS5 proxy, from [`crate::outbound::stats_proxy`], it sends through the proxy's UDP relay.
+    pub fn new(
+        endpoints: &[SocketAddr],
+        prefix: &str,
+        buffer_bytes: usize,
+        proxy: Option<Socks5Proxy>,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(!endpoints.is_empty(), "no statsd endpoints");
+        let endpoints = endpoints
+            .iter()
+            .map(|addr| {
+                let socket = match proxy {
+                    Some(_) => None,
+                    None => Some(bind_connected(*addr)?),
+                };
+                anyhow::Ok((*addr, socket))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let client = Self {
+            prefix: prefix.into(),
+            endpoints,
+            active: AtomicUsize::new(0),
+            buffer: Default::default(),
+            buffer_bytes,
+            dropped: AtomicU64::new(0),
+            proxy,
+            relay: Default::default(),
+            associating: Default::default(),
+        };
+        client.check_relay();
+        Ok(client)
+    }
+
+    /// Drops the relay if the proxy closed it, and associates with it in the background if there is none.
+    fn check_relay(&self) {
+        let Some(proxy) = self.proxy.as_ref() else {
+            return;
+        };
+        if let Some(relay) = self.relay.load().as_ref() {
+            if relay.is_open() {
+                return;
+            }
+            log::warn!("the SOCKS5 relay for stats closed, associating again");
+            self.relay.store(None);
+        }
+        if self.associating.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let proxy = proxy.clone();
+        let relay = self.relay.clone();
+        let associating = self.associating.clone();
+        std::thread::spawn(move || {
+            match Relay::associate(&proxy) {
+                Ok(associated) => relay.store(Some(Arc::new(associated))),
+                Err(err) => log::warn!(
+                    "cannot reach the SOCKS5 relay for stats, buffering them: {:?}",
+                    err
+                ),
+            }
+            associating.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Adds to a counter.
+    pub fn count(&self, metric: &str, value: f64) {
+        self.send(format!("{}:{}|c", metric, value));
+    }
+
+    /// Adds 1 to a counter.
+    pub fn incr(&self, metric: &str) {
+        self.count(metric, 1.0);
+    }
+
+    /// Sets a gauge.
+    pub fn gauge(&self, metric: &str, value: f64) {
+        self.send(format!("{}:{}|g", metric, value));
+    }
+
+    /// Records a timing, in milliseconds.
+    pub fn timer(&self, metric: &str, value: f64) {
+        self.send(format!("{}:{}|ms", metric, value));
+    }
+
+    /// Sends what was buffered and the count of dropped lines, going back to the preferred endpoint first and associating with the proxy's relay again if it closed. Called regularly.
+    pub fn flush(&self) {
+        self.check_relay();
+        self.active.store(0, Ordering::Relaxed);
+        let mut buffer = self.buffer.lock();
+        while let Some(line) = buffer.lines.pop_front() {
+            if !self.send_any(&line) {
+                buffer.lines.push_front(line);
+                return;
+            }
+            buffer.bytes -= line.len();
+        }
+        drop(buffer);
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("dropped {} stats while statsd was unreachable", dropped);
+            self.count(
+                &crate::stats::key(crate::stats::Metric::StatsDropped, &[]),
+                dropped as f64,
+            );
+        }
+    }
+
+    fn send(&self, line: String) {
+        let line = if self.prefix.is_empty() {
+            line
+        } else {
+            format!("{}.{}", self.prefix, line)
+        };
+        if self.send_any(&line) {
+            return;
+        }
+        let mut buffer = self.buffer.lock();
+        if buffer.bytes + line.len() > self.buffer_bytes {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        buffer.bytes += line.len();
+        buffer.lines.push_back(line);
+    }
+
+    /// Sends a line to the active endpoint, failing over to the others in turn. Returns false if none took it.
+    fn send_any(&self, line: &str) -> bool {
+        if self.proxy.is_some() {
+            return self.send_relayed(line);
+        }
+        let start = self.active.load(Ordering::Relaxed);
+        for offset in 0..self.endpoints.len() {
+            let index = (start + offset) % self.endpoints.len();
+            let (addr, socket) = &self.endpoints[index];
+            let Some(socket) = socket else {
+                continue;
+            };
+            match socket.send(line.as_bytes()) {
+                Ok(_) => {
+                    if index != start {
+                        log::warn!("statsd failed over to {}", addr);
+                        self.active.store(index, Ordering::Relaxed);
+                    }
+                    return true;
+                }
+                Err(err) => log::debug!("cannot send stats to {}: {}", addr, err),
+            }
+        }
+        false
+    }
+
+    /// Sends a line through the proxy's relay to the preferred endpoint, since the relay hides which endpoints are gone. Returns false if there is no relay, or it failed, in which case it is associated again at the next flush.
+    fn send_relayed(&self, line: &str) -> bool {
+        let Some(relay) = self.relay.load_full() else {
+            return false;
+        };
+        let addr = self.endpoints[0].0;
+        match relay
+            .socket
+            .send(&crate::outbound::socks5_udp_frame(addr, line.as_bytes()))
+        {
+            Ok(_) => true,
+            Err(err) => {
+                log::debug!("cannot send stats through the SOCKS5 relay: {}", err);
+                self.relay.store(None);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// An address that nothing listens on.
+    fn dead_addr() -> SocketAddr {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn failover_and_buffering() {
+        let live = UdpSocket::bind("127.0.0.1:0").unwrap();
+        live.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let client = StatsClient::new(
+            &[dead_addr(), live.local_addr().unwrap()],
+            "geph4",
+            1000,
+            None,
+        )
+        .unwrap();
+        // the first line is lost to the dead endpoint, whose ICMP error fails over the ones after it
+        for _ in 0..5 {
+            client.incr("test");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut buf = [0; 100];
+        let n = live.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"geph4.test:1|c");
+
+        // with nowhere to send them, lines are buffered within the budget and the rest dropped
+        let client = StatsClient::new(&[dead_addr()], "", 10, None).unwrap();
+        for _ in 0..10 {
+            client.gauge("g", 1.0);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(client.buffer.lock().bytes <= 10);
+        assert!(client.dropped.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn relay_reassociation() {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+            time::Instant,
+        };
+
+        let wait_for_relay = |client: &StatsClient| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while client.relay.load().is_none() {
+                assert!(Instant::now() < deadline, "relay never associated");
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        let endpoint: SocketAddr = "192.0.2.1:8125".parse().unwrap();
+
+        // with the proxy down, the client still starts and buffers
+        let dead_proxy = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = Socks5Proxy::parse(&format!("socks5://{}", dead_proxy)).unwrap();
+        let client = StatsClient::new(&[endpoint], "", 1000, Some(proxy)).unwrap();
+        client.incr("early");
+        assert!(client.buffer.lock().bytes > 0);
+
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let relay_port = relay.local_addr().unwrap().port().to_be_bytes();
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy =
+            Socks5Proxy::parse(&format!("socks5://{}", server.local_addr().unwrap())).unwrap();
+        let fake = std::thread::spawn(move || {
+            // the first relay closes right away, and the second stays open until the end
+            let associate = || {
+                let (mut conn, _) = server.accept().unwrap();
+                let mut buf = [0; 10];
+                conn.read_exact(&mut buf[..3]).unwrap();
+                conn.write_all(&[5, 0]).unwrap();
+                conn.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[..2], &[5, 3]);
+                conn.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, relay_port[0], relay_port[1]])
+                    .unwrap();
+                conn
+            };
+            drop(associate());
+            associate()
+        });
+        let client = StatsClient::new(&[endpoint], "", 1000, Some(proxy)).unwrap();
+        wait_for_relay(&client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client
+            .relay
+            .load()
+            .as_ref()
+            .is_some_and(|relay| relay.is_open())
+        {
+            assert!(Instant::now() < deadline, "relay never closed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        client.flush();
+        wait_for_relay(&client);
+        client.incr("test");
+        let mut buf = [0; 100];
+        let n = relay.recv(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..n],
+            crate::outbound::socks5_udp_frame(endpoint, b"test:1|c").as_slice()
+        );
+        drop(fake.join().unwrap());
+    }
+}
@@ -4,14 +4,16 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use sosistab2::Pipe;
 
+use crate::stats_client::StatsClient;
+
 pub struct StatsPipe<P: Pipe> {
     inner: P,
-    statsd_client: Arc<statsd::Client>,
-    flow_key: String,
+    statsd_client: Arc<StatsClient>,
+    flow_key: Arc<str>,
 }
 
 impl<P: Pipe> StatsPipe<P> {
-    pub fn new(pipe: P, statsd_client: Arc<statsd::Client>, flow_key: String) -> Self {
+    pub fn new(pipe: P, statsd_client: Arc<StatsClient>, flow_key: Arc<str>) -> Self {
         Self {
             inner: pipe,
             statsd_client,
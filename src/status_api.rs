@@ -0,0 +1,167 @@
+use std::{
+    convert::Infallible,
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use native_tls::{Identity, TlsAcceptor};
+
+use crate::{config::CONFIG, health, listen::control::dummy_tls_config, root_ctx::ROOT_CTX};
+
+/// Most requests served at once. Connections past this are closed right away.
+const MAX_CONCURRENT: usize = 16;
+
+/// Largest request head read.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// How long a client may take to send its request and read the response, all told.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the JSON status endpoint, if `status_api` is set.
+pub async fn status_api_loop() -> anyhow::Result<Infallible> {
+    let Some(config) = CONFIG.status_api() else {
+        return smol::future::pending().await;
+    };
+    let acceptor = match (config.tls_cert(), config.tls_key()) {
+        (Some(cert), Some(key)) => TlsAcceptor::new(Identity::from_pkcs8(
+            &std::fs::read(cert).with_context(|| format!("cannot read {:?}", cert))?,
+            &std::fs::read(key).with_context(|| format!("cannot read {:?}", key))?,
+        )?)?,
+        _ => dummy_tls_config(),
+    };
+    let listener = smol::Async::<std::net::TcpListener>::bind(config.listen())?;
+    log::info!("serving the status API on {}", config.listen());
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let (conn, _) = listener.accept().await?;
+        if IN_FLIGHT.fetch_add(1, Ordering::Relaxed) >= MAX_CONCURRENT {
+            IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+            continue;
+        }
+        let acceptor = acceptor.clone();
+        // native-tls is blocking, which is fine for the odd monitoring request
+        smol::unblock(move || {
+            if let Err(err) = serve(&acceptor, conn) {
+                log::debug!("status API request failed: {:?}", err);
+            }
+            IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        })
+        .detach();
+    }
+}
+
+/// Serves one request on a freshly accepted connection.
+fn serve(acceptor: &TlsAcceptor, conn: smol::Async<TcpStream>) -> anyhow::Result<()> {
+    let conn = conn.into_inner()?;
+    conn.set_nonblocking(false)?;
+    conn.set_read_timeout(Some(IO_TIMEOUT))?;
+    conn.set_write_timeout(Some(IO_TIMEOUT))?;
+    // the socket timeouts only bound each read and write, so a client trickling bytes in would hold its slot for much longer
+    let watchdog = conn.try_clone()?;
+    let deadline = Instant::now() + IO_TIMEOUT;
+    let _watchdog = smolscale::spawn(async move {
+        smol::Timer::at(deadline).await;
+        let _ = watchdog.shutdown(Shutdown::Both);
+    });
+    let mut tls = acceptor.accept(conn)?;
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = tls.read(&mut buf)?;
+        anyhow::ensure!(n > 0, "connection closed before the request ended");
+        head.extend_from_slice(&buf[..n]);
+        anyhow::ensure!(head.len() <= MAX_HEAD_LEN, "request head too long");
+    }
+    let token = CONFIG
+        .status_api()
+        .as_ref()
+        .context("no status API")?
+        .token();
+    let (status, body) = respond(&String::from_utf8_lossy(&head), token);
+    write!(
+        tls,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    tls.flush()?;
+    Ok(())
+}
+
+/// The status line and JSON body answering a request head.
+fn respond(head: &str, token: &str) -> (&'static str, String) {
+    let error =
+        |status, message: &str| (status, serde_json::json!({ "error": message }).to_string());
+    let mut lines = head.lines();
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (request.next(), request.next());
+    // compared as hashes, which is constant-time
+    let expected = blake3::hash(format!("Bearer {}", token).as_bytes());
+    let authorized = lines
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .any(|(_, value)| blake3::hash(value.trim().as_bytes()) == expected);
+    if !authorized {
+        return error("401 Unauthorized", "missing or wrong bearer token");
+    }
+    match (method, path) {
+        (Some("GET"), Some("/status")) => ("200 OK", status_document().to_string()),
+        (Some(_), Some("/status")) => error("405 Method Not Allowed", "only GET is allowed"),
+        _ => error("404 Not Found", "no such endpoint"),
+    }
+}
+
+/// The status of the exit.
+fn status_document() -> serde_json::Value {
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "exit_hostname": ROOT_CTX.exit_hostname(),
+        "uptime_secs": ROOT_CTX.started.elapsed().as_secs(),
+        "sessions": ROOT_CTX.live_sessions.load(Ordering::Relaxed),
+        "max_sessions": CONFIG.max_sessions(),
+        "connections": ROOT_CTX.conn_count.load(Ordering::Relaxed),
+        "cgnat_occupancy": ROOT_CTX.vpn_if_started().map(|vpn| vpn.occupancy()),
+        "bandwidth_last_minute": ROOT_CTX.bw_last_minute.load(Ordering::Relaxed),
+        "draining": ROOT_CTX.draining.load(Ordering::Relaxed),
+        "egress_healthy": health::is_healthy(),
+        "egress_latency_ms": health::latency_ms(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_and_routes() {
+        let token = "0123456789abcdef";
+        let request = |line: &str, auth: &str| {
+            respond(
+                &format!("{}\r\nHost: exit\r\n{}\r\n\r\n", line, auth),
+                token,
+            )
+            .0
+        };
+        let auth = "authorization: Bearer 0123456789abcdef";
+        assert_eq!(request("GET /status HTTP/1.1", auth), "200 OK");
+        assert_eq!(
+            request("GET /status HTTP/1.1", "Authorization: Bearer nope"),
+            "401 Unauthorized"
+        );
+        assert_eq!(request("GET /status HTTP/1.1", ""), "401 Unauthorized");
+        assert_eq!(
+            request("POST /status HTTP/1.1", auth),
+            "405 Method Not Allowed"
+        );
+        assert_eq!(request("GET /leases HTTP/1.1", auth), "404 Not Found");
+
+        let (_, body) = respond(&format!("GET /status HTTP/1.1\r\n{}\r\n\r\n", auth), token);
+        let doc: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(doc["version"], env!("CARGO_PKG_VERSION"));
+        assert!(doc["draining"].is_boolean());
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    io::{Read, Write},
+    net::Ipv4Addr,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use tun::{platform::Device, Device as Device2};
+
+use crate::{asn::next_ip, config::CONFIG, root_ctx::ROOT_CTX, stats::Metric, vpn::VpnCtx};
+
+/// Where VPN sessions write their upstream packets.
+pub trait TunBackend: Send + Sync + 'static {
+    /// Writes a packet from the session with the given address. Packets that cannot be written are dropped.
+    fn write(&self, session: Ipv4Addr, pkt: &[u8]);
+}
+
+/// Opens the TUN backend of this process: the OS device, or an in-memory one with the `harness` feature.
+pub fn open_tun(vpn: Weak<VpnCtx>) -> Box<dyn TunBackend> {
+    #[cfg(feature = "harness")]
+    {
+        let _ = vpn;
+        Box::new(crate::harness::HARNESS_TUN.clone())
+    }
+    #[cfg(not(feature = "harness"))]
+    Box::new(OsTun::open(vpn).expect("cannot open the TUN device"))
+}
+
+/// Longest queue of packets waiting for the TUN writer.
+const TUN_QUEUE_LEN: usize = 10000;
+
+/// Most packets that one session may have waiting for the TUN writer, so that a single flooding session cannot fill the whole queue.
+const TUN_SESSION_BACKLOG: usize = 1000;
+
+/// Most packets the TUN writer takes off the queue per wakeup.
+const TUN_WRITE_BATCH: usize = 64;
+
+/// Bytes of the slabs that TUN readers read packets into.
+const SLAB_BYTES: usize = 64 * 1024;
+
+/// Room left for every read, which is more than the device's MTU.
+const MAX_PACKET: usize = 2048;
+
+/// Packets waiting for each dispatcher thread, beyond which they are dropped.
+const DISPATCH_QUEUE_LEN: usize = 4096;
+
+/// Packets read from the TUN device, and slabs reserved to hold them, for the packets-per-allocation gauge.
+pub static PACKETS_READ: AtomicU64 = AtomicU64::new(0);
+pub static SLABS_RESERVED: AtomicU64 = AtomicU64::new(0);
+
+/// How many packets read from the TUN device shared each slab reservation, on average.
+pub fn packets_per_slab() -> f64 {
+    let slabs = SLABS_RESERVED.load(Ordering::Relaxed);
+    if slabs == 0 {
+        return 0.0;
+    }
+    PACKETS_READ.load(Ordering::Relaxed) as f64 / slabs as f64
+}
+
+/// Where a TUN reader reads packets. Each packet is read straight into a slab and split off it, so that it travels through the downstream channel to the session without being copied, and one allocation serves many packets. A slab whose packets have all been sent is reused when the reader reserves its next one; packets for a session that is behind are copied out instead, so that they don't keep slabs alive.
+#[derive(Default)]
+#[cfg_attr(feature = "harness", allow(dead_code))]
+struct PacketSlab {
+    buf: BytesMut,
+}
+
+#[cfg_attr(feature = "harness", allow(dead_code))]
+impl PacketSlab {
+    /// Reads one packet.
+    fn read(&mut self, reader: &mut impl Read) -> std::io::Result<Bytes> {
+        if self.buf.capacity() < MAX_PACKET {
+            self.buf.reserve(SLAB_BYTES);
+            SLABS_RESERVED.fetch_add(1, Ordering::Relaxed);
+        }
+        self.buf.resize(MAX_PACKET, 0);
+        let n = match reader.read(&mut self.buf) {
+            Ok(n) => n,
+            Err(err) => {
+                self.buf.clear();
+                return Err(err);
+            }
+        };
+        self.buf.truncate(n);
+        PACKETS_READ.fetch_add(1, Ordering::Relaxed);
+        Ok(self.buf.split().freeze())
+    }
+}
+
+/// Threads that route packets read from the TUN device to their sessions, each fed by its own queue.
+#[cfg_attr(feature = "harness", allow(dead_code))]
+struct Dispatchers {
+    queues: Vec<smol::channel::Sender<Bytes>>,
+}
+
+#[cfg_attr(feature = "harness", allow(dead_code))]
+impl Dispatchers {
+    /// Starts `runtime.tun_dispatchers` threads, which stop once the VPN state is gone. None if there are to be none.
+    fn start(vpn: &Weak<VpnCtx>) -> anyhow::Result<Option<Arc<Self>>> {
+        let count = CONFIG.runtime().tun_dispatchers();
+        if count == 0 {
+            return Ok(None);
+        }
+        let mut queues = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (send, recv) = smol::channel::bounded::<Bytes>(DISPATCH_QUEUE_LEN);
+            let vpn = vpn.clone();
+            std::thread::Builder::new()
+                .name("tun-dispatcher".into())
+                .spawn(move || {
+                    crate::runtime::configure_tun_thread();
+                    while let Ok(pkt) = recv.recv_blocking() {
+                        let Some(vpn) = vpn.upgrade() else {
+                            return;
+                        };
+                        vpn.dispatch_down_owned(pkt);
+                    }
+                })?;
+            queues.push(send);
+        }
+        Ok(Some(Arc::new(Self { queues })))
+    }
+
+    /// Queues a packet for the dispatcher of its destination, dropping it if that one is behind.
+    fn dispatch(&self, pkt: Bytes) {
+        let queue = &self.queues[dispatcher_of(&pkt, self.queues.len())];
+        if queue.try_send(pkt).is_err() {
+            ROOT_CTX.incr_stat(Metric::VpnDispatchQueueFull);
+        }
+    }
+}
+
+/// Which of `count` dispatchers routes a packet, by a hash of its destination address. Packets too short to have one all go to the first.
+fn dispatcher_of(pkt: &[u8], count: usize) -> usize {
+    let Some(destination) = pkt.get(16..20) else {
+        return 0;
+    };
+    // Fibonacci hashing, so that neighbouring addresses spread out
+    let destination = u32::from_be_bytes(destination.try_into().unwrap());
+    (destination.wrapping_mul(0x9e37_79b9) >> 16) as usize % count
+}
+
+/// The OS TUN device, with reader threads that dispatch downstream packets and a writer thread fed by a queue.
+#[cfg_attr(feature = "harness", allow(dead_code))]
+pub struct OsTun {
+    send: smol::channel::Sender<(Ipv4Addr, Vec<u8>)>,
+    backlog: Arc<Backlog>,
+}
+
+#[cfg_attr(feature = "harness", allow(dead_code))]
+impl OsTun {
+    /// Creates the `tun-geph` device, or takes over the queues of the previous exit process, and starts its threads. Packets read from it are dispatched to the given VPN state, and the threads stop once it is gone.
+    pub fn open(vpn: Weak<VpnCtx>) -> anyhow::Result<Self> {
+        let inherited = crate::upgrade::take_tun_queues();
+        if !inherited.is_empty() {
+            log::info!("taking over tun-geph with {} queues", inherited.len());
+            let writer = inherited[0].try_clone()?;
+            for queue in inherited.iter() {
+                crate::upgrade::register_tun_queue(queue.as_raw_fd());
+            }
+            return Self::start(vpn, inherited, Box::new(writer));
+        }
+        log::info!("initializing tun-geph");
+        let queue_count = std::thread::available_parallelism()?.get();
+        let pool = CONFIG.cgnat_pool();
+        let mut dev = Device::new(
+            tun::Configuration::default()
+                .name("tun-geph")
+                .address(next_ip(pool.first_as_ipv4_addr()))
+                .netmask(pool.get_mask_as_ipv4_addr())
+                .mtu(1280)
+                .up()
+                .layer(tun::Layer::L3)
+                .queues(queue_count),
+        )?;
+        // the tier pools get their gateways as extra addresses, which also routes them to the device
+        for pool in CONFIG.cgnat_pools() {
+            let status = std::process::Command::new("ip")
+                .arg("addr")
+                .arg("add")
+                .arg(format!(
+                    "{}/{}",
+                    next_ip(pool.cidr().first_as_ipv4_addr()),
+                    pool.cidr().get_bits()
+                ))
+                .arg("dev")
+                .arg("tun-geph")
+                .status()?;
+            if !status.success() {
+                anyhow::bail!("cannot add the gateway of CGNAT pool {}", pool.cidr())
+            }
+        }
+        // TODO: is this remotely safe??
+        let mut readers = Vec::with_capacity(queue_count);
+        for q in 0..queue_count {
+            let queue_fd = dev.queue(q).unwrap().as_raw_fd();
+            crate::upgrade::register_tun_queue(queue_fd);
+            readers.push(unsafe { std::fs::File::from_raw_fd(queue_fd) });
+        }
+        Self::start(vpn, readers, Box::new(dev))
+    }
+
+    /// Starts a reader thread for every queue, and the writer thread.
+    fn start(
+        vpn: Weak<VpnCtx>,
+        readers: Vec<std::fs::File>,
+        mut writer: Box<dyn Write + Send>,
+    ) -> anyhow::Result<Self> {
+        let dispatchers = Dispatchers::start(&vpn)?;
+        for mut reader in readers {
+            let vpn = vpn.clone();
+            let dispatchers = dispatchers.clone();
+            std::thread::Builder::new()
+                .name("tun-reader".into())
+                .spawn(move || {
+                    crate::runtime::configure_tun_thread();
+                    // great now we can do our magic
+                    let mut slab = PacketSlab::default();
+                    loop {
+                        match slab.read(&mut reader) {
+                            Ok(pkt) => match (&dispatchers, vpn.upgrade()) {
+                                (Some(dispatchers), Some(_)) => dispatchers.dispatch(pkt),
+                                (None, Some(vpn)) => vpn.dispatch_down_owned(pkt),
+                                (_, None) => return,
+                            },
+                            Err(err) => {
+                                log::error!("cannot read from tun device: {:?}", err);
+                                std::thread::sleep(Duration::from_millis(100));
+                            }
+                        }
+                    }
+                })?;
+        }
+        let (send, recv) = smol::channel::bounded::<(Ipv4Addr, Vec<u8>)>(TUN_QUEUE_LEN);
+        let backlog = Arc::new(Backlog::default());
+        let writer_backlog = backlog.clone();
+        std::thread::Builder::new()
+            .name("tun-writer".into())
+            .spawn(move || {
+                crate::runtime::configure_tun_thread();
+                let mut batch = Vec::with_capacity(TUN_WRITE_BATCH);
+                loop {
+                    // take whatever has piled up, so that a busy queue costs one wakeup per batch rather than per packet
+                    let Ok(next) = recv.recv_blocking() else {
+                        // the backend was dropped
+                        return;
+                    };
+                    batch.push(next);
+                    while batch.len() < TUN_WRITE_BATCH {
+                        match recv.try_recv() {
+                            Ok(next) => batch.push(next),
+                            Err(_) => break,
+                        }
+                    }
+                    for (session, pkt) in batch.drain(..) {
+                        let _ = writer.write_all(&pkt);
+                        writer_backlog.release(session);
+                    }
+                }
+            })?;
+        Ok(Self { send, backlog })
+    }
+}
+
+impl TunBackend for OsTun {
+    fn write(&self, session: Ipv4Addr, pkt: &[u8]) {
+        if !self.backlog.reserve(session) {
+            ROOT_CTX.incr_stat(Metric::VpnTunBacklogDropped);
+            return;
+        }
+        if self.send.try_send((session, pkt.to_vec())).is_err() {
+            ROOT_CTX.incr_stat(Metric::VpnTunQueueFull);
+            self.backlog.release(session);
+        }
+    }
+}
+
+/// Number of packets each session has waiting for the TUN writer.
+#[derive(Default)]
+struct Backlog(DashMap<Ipv4Addr, usize>);
+
+impl Backlog {
+    /// Counts one more queued packet for the session, unless it is at its limit.
+    fn reserve(&self, session: Ipv4Addr) -> bool {
+        let mut backlog = self.0.entry(session).or_default();
+        if *backlog >= TUN_SESSION_BACKLOG {
+            return false;
+        }
+        *backlog += 1;
+        true
+    }
+
+    /// Counts one less queued packet for the session.
+    fn release(&self, session: Ipv4Addr) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut entry) = self.0.entry(session) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// An in-memory TUN backend that keeps every packet written to it, for tests and benchmarks. Clones share the same packets.
+#[cfg(any(test, feature = "harness"))]
+#[derive(Clone)]
+pub struct MemoryTun {
+    send: smol::channel::Sender<bytes::Bytes>,
+    recv: smol::channel::Receiver<bytes::Bytes>,
+}
+
+#[cfg(any(test, feature = "harness"))]
+impl MemoryTun {
+    pub fn new() -> Self {
+        let (send, recv) = smol::channel::unbounded();
+        Self { send, recv }
+    }
+
+    /// Waits for the next packet written to the device.
+    pub async fn recv(&self) -> anyhow::Result<bytes::Bytes> {
+        Ok(self.recv.recv().await?)
+    }
+}
+
+#[cfg(any(test, feature = "harness"))]
+impl TunBackend for MemoryTun {
+    fn write(&self, _session: Ipv4Addr, pkt: &[u8]) {
+        let _ = self.send.try_send(bytes::Bytes::copy_from_slice(pkt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device that yields packets of the given sizes, one per read.
+    struct Packets(Vec<usize>);
+
+    impl Read for Packets {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.0.pop().unwrap_or(0);
+            buf[..len].fill(len as u8);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn packet_slab() {
+        let mut device = Packets(vec![1500; 100]);
+        let mut slab = PacketSlab::default();
+        let before = SLABS_RESERVED.load(Ordering::Relaxed);
+        let mut held = vec![];
+        for _ in 0..100 {
+            let pkt = slab.read(&mut device).unwrap();
+            assert_eq!(pkt.len(), 1500);
+            assert!(pkt.iter().all(|b| *b == 1500u16 as u8));
+            held.push(pkt);
+        }
+        // a slab holds dozens of packets, even while every one of them is still queued
+        assert!(SLABS_RESERVED.load(Ordering::Relaxed) - before <= 4);
+    }
+
+    #[test]
+    fn dispatcher_spread() {
+        let packet_to = |last: u8| {
+            let mut pkt = vec![0x45; 40];
+            pkt[16..20].copy_from_slice(&[100, 64, 0, last]);
+            pkt
+        };
+        // one destination always goes to the same dispatcher
+        assert_eq!(
+            dispatcher_of(&packet_to(7), 4),
+            dispatcher_of(&packet_to(7), 4)
+        );
+        let used: std::collections::HashSet<usize> = (0..=255)
+            .map(|last| dispatcher_of(&packet_to(last), 4))
+            .collect();
+        assert_eq!(used.len(), 4);
+        assert_eq!(dispatcher_of(&[0x45; 10], 4), 0);
+    }
+
+    #[test]
+    fn session_backlog() {
+        let backlog = Backlog::default();
+        let session = Ipv4Addr::new(100, 64, 200, 1);
+        for _ in 0..TUN_SESSION_BACKLOG {
+            assert!(backlog.reserve(session));
+        }
+        assert!(!backlog.reserve(session));
+        backlog.release(session);
+        assert!(backlog.reserve(session));
+        for _ in 0..TUN_SESSION_BACKLOG {
+            backlog.release(session);
+        }
+        assert!(backlog.0.is_empty());
+    }
+}
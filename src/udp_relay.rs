@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use smol::{
+    channel::Sender,
+    future::FutureExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+};
+
+use crate::{
+    config::CONFIG, connect::is_internal, identity::ExitIdentity, quota::QuotaHandle,
+    ratelimit::RateLimiter, root_ctx::ROOT_CTX, stats::Metric,
+};
+
+/// Label of the mux stream that carries a UDP relay session.
+pub const UDP_SESSION_PSEUDOHOST: &str = "@udp-session";
+
+/// How long a flow lasts without datagrams in either direction before it stops counting against `udp_max_flows`.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The remote hosts that a relay session exchanges datagrams with, and when each was last used.
+struct Flows {
+    idle_timeout: Duration,
+    last_used: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Flows {
+    fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            last_used: Default::default(),
+        }
+    }
+
+    /// Lets a datagram go to the given host, opening a flow to it if there isn't one. False if there are already `max` flows that aren't idle.
+    fn admit(&self, dest: SocketAddr, max: usize) -> bool {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock();
+        if let Some(used) = last_used.get_mut(&dest) {
+            *used = now;
+            return true;
+        }
+        if last_used.len() >= max {
+            last_used.retain(|_, used| now.duration_since(*used) < self.idle_timeout);
+            if last_used.len() >= max {
+                return false;
+            }
+        }
+        last_used.insert(dest, now);
+        true
+    }
+
+    /// Whether a datagram from the given host belongs to a flow that isn't idle, which it then keeps open.
+    fn touch(&self, source: SocketAddr) -> bool {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock();
+        match last_used.get_mut(&source) {
+            Some(used) if now.duration_since(*used) < self.idle_timeout => {
+                *used = now;
+                true
+            }
+            Some(_) => {
+                last_used.remove(&source);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Relays UDP datagrams between a client stream and the Internet.
+///
+/// Every datagram, in both directions, is framed as a big-endian u16 length followed by the remote address (one byte of 4 or 6, the IP address, and a big-endian u16 port) and the payload.
+pub async fn udp_relay_loop(
+    rate_limit: Arc<RateLimiter>,
+    client: impl AsyncRead + AsyncWrite + Clone + Unpin + Send + 'static,
+    identity: Arc<ExitIdentity>,
+    quota: Option<Arc<QuotaHandle>>,
+) -> anyhow::Result<()> {
+    let socket_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let socket_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+    let flows = Flows::new(FLOW_IDLE_TIMEOUT);
+
+    let up_loop = async {
+        let mut client = client.clone();
+        loop {
+            let (dest, payload) = read_frame(&mut client).await?;
+            if !udp_allowed(dest) {
+                log::debug!(
+                    "dropping UDP datagram to {}",
+                    crate::redact::destination(dest)
+                );
+                continue;
+            }
+            if !flows.admit(dest, CONFIG.udp_max_flows()) {
+                ROOT_CTX.incr_stat(Metric::UdpFlowLimit);
+                continue;
+            }
+            ROOT_CTX.incr_throughput(&identity, payload.len());
+            crate::asn::record_traffic(dest.ip(), payload.len());
+            if let Some(quota) = quota.as_ref() {
+                quota.record(payload.len());
+            }
+            let socket = if dest.is_ipv4() {
+                Some(&socket_v4)
+            } else {
+                socket_v6.as_ref()
+            };
+            if let Some(socket) = socket {
+                if let Err(err) = socket.send_to(&payload, dest).await {
+                    log::debug!("cannot send UDP datagram: {:?}", err);
+                }
+            }
+        }
+    };
+    // datagrams from both sockets go through one writer, so that frames never interleave
+    let (send_down, recv_down) = smol::channel::bounded::<(SocketAddr, Bytes)>(100);
+    let write_loop = async {
+        let mut client = client.clone();
+        loop {
+            let (source, payload) = recv_down.recv().await?;
+            write_frame(&mut client, source, &payload).await?;
+            ROOT_CTX.incr_throughput(&identity, payload.len());
+            crate::asn::record_traffic(source.ip(), payload.len());
+            rate_limit.wait(payload.len()).await;
+            if let Some(quota) = quota.as_ref() {
+                quota.charge(payload.len()).await;
+            }
+        }
+    };
+    let down_v4 = relay_down(&socket_v4, &flows, &send_down);
+    let down_v6 = async {
+        match socket_v6.as_ref() {
+            Some(socket_v6) => relay_down(socket_v6, &flows, &send_down).await,
+            None => smol::future::pending().await,
+        }
+    };
+    up_loop.race(write_loop).race(down_v4).race(down_v6).await
+}
+
+/// Receives datagrams from a socket, passing on those that come from remote hosts the client has recently sent to.
+async fn relay_down(
+    socket: &UdpSocket,
+    flows: &Flows,
+    send_down: &Sender<(SocketAddr, Bytes)>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, source) = socket.recv_from(&mut buf).await?;
+        if flows.touch(source) {
+            // drop rather than block if the client is slow, like a real UDP path would
+            let _ = send_down.try_send((source, Bytes::copy_from_slice(&buf[..n])));
+        }
+    }
+}
+
+/// Whether a client may send UDP datagrams to this address. IPv4-mapped IPv6 addresses are judged as the IPv4 addresses they stand for.
+pub fn udp_allowed(dest: SocketAddr) -> bool {
+    let dest_ip = dest.ip().to_canonical();
+    let ip_ok = match dest_ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_link_local()
+                || CONFIG.all_cgnat_pools().any(|pool| pool.contains(ip)))
+        }
+        IpAddr::V6(ip) => {
+            // unique local (fc00::/7) and link-local (fe80::/10) addresses
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    };
+    ip_ok
+        && !is_internal(dest_ip)
+        && !CONFIG.destination_blocked(dest_ip)
+        && !CONFIG.bypass_routes().covers_ip(dest_ip)
+        && !crate::lists::BLACK_PORTS.contains(&dest.port())
+        && crate::smtp::port_allowed(dest.port())
+        && (!CONFIG.port_whitelist() || crate::lists::WHITE_PORTS.contains(&dest.port()))
+}
+
+/// Reads one framed datagram from the client.
+async fn read_frame(client: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<(SocketAddr, Bytes)> {
+    let mut len = [0u8; 2];
+    client.read_exact(&mut len).await?;
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    client.read_exact(&mut frame).await?;
+    let (ip, rest): (IpAddr, &[u8]) = match frame.first() {
+        Some(4) if frame.len() >= 7 => (
+            Ipv4Addr::from(<[u8; 4]>::try_from(&frame[1..5])?).into(),
+            &frame[5..],
+        ),
+        Some(6) if frame.len() >= 19 => (
+            Ipv6Addr::from(<[u8; 16]>::try_from(&frame[1..17])?).into(),
+            &frame[17..],
+        ),
+        _ => anyhow::bail!("malformed UDP frame"),
+    };
+    let port = u16::from_be_bytes(rest[..2].try_into().context("no port in UDP frame")?);
+    Ok((
+        SocketAddr::new(ip, port),
+        Bytes::copy_from_slice(&rest[2..]),
+    ))
+}
+
+/// Writes one framed datagram to the client.
+async fn write_frame(
+    client: &mut (impl AsyncWrite + Unpin),
+    source: SocketAddr,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 21);
+    frame.extend_from_slice(&[0, 0]);
+    match source.ip() {
+        IpAddr::V4(ip) => {
+            frame.push(4);
+            frame.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            frame.push(6);
+            frame.extend_from_slice(&ip.octets());
+        }
+    }
+    frame.extend_from_slice(&source.port().to_be_bytes());
+    frame.extend_from_slice(payload);
+    let len = u16::try_from(frame.len() - 2).context("UDP datagram too big")?;
+    frame[..2].copy_from_slice(&len.to_be_bytes());
+    client.write_all(&frame).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_udp_destinations() {
+        let allowed = |addr: &str| udp_allowed(addr.parse().unwrap());
+        assert!(allowed("93.184.216.34:443"));
+        assert!(allowed("[2606:4700::1]:443"));
+        for addr in [
+            "127.0.0.1:443",
+            "[::ffff:127.0.0.1]:443",
+            "[::ffff:10.1.2.3]:443",
+            "[::ffff:192.168.1.1]:443",
+            "[::1]:443",
+            "[fc00::1]:443",
+            "[fd12:3456::1]:443",
+            "[fe80::1]:443",
+            "[febf::1]:443",
+        ] {
+            assert!(!allowed(addr), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn idle_flows_expire() {
+        let flows = Flows::new(Duration::from_millis(50));
+        let first: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        let second: SocketAddr = "93.184.216.35:443".parse().unwrap();
+        assert!(flows.admit(first, 1));
+        assert!(flows.touch(first));
+        assert!(!flows.admit(second, 1));
+        std::thread::sleep(Duration::from_millis(60));
+        // the first flow went idle, so it no longer takes up the only slot
+        assert!(flows.admit(second, 1));
+        assert!(!flows.touch(first));
+        assert!(flows.touch(second));
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    convert::Infallible,
+    fs::File,
+    io::{IoSlice, IoSliceMut, Read},
+    os::unix::{
+        fs::PermissionsExt,
+        net::UnixStream,
+        prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use nix::{
+    sys::socket::{
+        getsockopt, recvmsg, sendmsg, sockopt::PeerCredentials, ControlMessage,
+        ControlMessageOwned, MsgFlags, UnixAddr,
+    },
+    unistd::getuid,
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing,
+    config::CONFIG,
+    quota::QUOTAS,
+    root_ctx::ROOT_CTX,
+    stats::{self, Metric},
+    vpn,
+};
+
+/// Most file descriptors one handoff can carry, which is the kernel's limit for one message.
+const MAX_HANDOFF_FDS: usize = 253;
+
+/// How long the new process waits for the old one to exit after the handoff.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a handoff waits for migrating connections to write out what they hold.
+#[cfg(feature = "tcp-repair")]
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a handoff carries, in the order its file descriptors are sent.
+#[derive(Serialize, Deserialize, Default)]
+struct Handoff {
+    /// Transparent proxy listeners
+    transparent: usize,
+    /// TUN queues
+    tun: usize,
+    /// Migrated connections, as pairs of client and remote sockets, sent in messages of their own after this one
+    #[serde(default)]
+    connections: usize,
+}
+
+/// The file descriptors of this process that a new one takes over, registered as they are opened.
+#[derive(Default)]
+struct Handed {
+    transparent: Vec<RawFd>,
+    tun: Vec<RawFd>,
+}
+
+static HANDED: Lazy<Mutex<Handed>> = Lazy::new(Default::default);
+
+/// File descriptors taken over from the previous exit process, until they are used.
+#[derive(Default)]
+struct Inherited {
+    transparent: Vec<OwnedFd>,
+    tun: Vec<OwnedFd>,
+    connections: Vec<(OwnedFd, OwnedFd)>,
+}
+
+static INHERITED: Lazy<Mutex<Inherited>> = Lazy::new(Default::default);
+
+/// Registers a transparent proxy listener to be handed off on upgrade.
+pub fn register_transparent_listener(fd: RawFd) {
+    HANDED.lock().transparent.push(fd);
+}
+
+/// Registers a TUN queue to be handed off on upgrade.
+pub fn register_tun_queue(fd: RawFd) {
+    HANDED.lock().tun.push(fd);
+}
+
+/// The transparent proxy listeners taken over from the previous process, if any.
+pub fn take_transparent_listeners() -> Vec<std::net::TcpListener> {
+    std::mem::take(&mut INHERITED.lock().transparent)
+        .into_iter()
+        .map(std::net::TcpListener::from)
+        .collect()
+}
+
+/// The TUN queues taken over from the previous process, if any. The device lives on as long as one of its queues is open, with its addresses and routes.
+pub fn take_tun_queues() -> Vec<File> {
+    std::mem::take(&mut INHERITED.lock().tun)
+        .into_iter()
+        .map(File::from)
+        .collect()
+}
+
+/// Takes over from the exit process listening on `upgrade_socket`, if there is one. Returns once it has handed off its file descriptors and exited, which frees the ports of its sosistab2 listeners, with whether there was one to take over from.
+pub fn take_over() -> anyhow::Result<bool> {
+    let Some(path) = CONFIG.upgrade_socket() else {
+        return Ok(false);
+    };
+    // nothing listening means there is no running exit to take over from
+    let Ok(conn) = UnixStream::connect(path) else {
+        return Ok(false);
+    };
+    log::warn!("taking over from the running exit at {:?}", path);
+    let mut inherited = recv_handoff(&conn).context("cannot receive the handoff")?;
+    log::info!(
+        "took over {} transparent proxy listeners, {} TUN queues, and {} connections",
+        inherited.transparent.len(),
+        inherited.tun.len(),
+        inherited.connections.len()
+    );
+    let connections = std::mem::take(&mut inherited.connections);
+    *INHERITED.lock() = inherited;
+    conn.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    let closed = (&conn)
+        .read(&mut [0; 1])
+        .context("the old exit did not exit")?;
+    anyhow::ensure!(closed == 0, "the old exit sent more than a handoff");
+    if !connections.is_empty() {
+        let total = connections.len();
+        // the old exit froze the connections' sockets, which only rebuilding them undoes
+        #[cfg(feature = "tcp-repair")]
+        let resumed = crate::migrate::resume(connections);
+        #[cfg(not(feature = "tcp-repair"))]
+        let resumed = {
+            log::warn!(
+                "dropping migrated connections, since this build lacks the tcp-repair feature"
+            );
+            0
+        };
+        log::info!("resumed {} of {} migrated connections", resumed, total);
+        if let Some(client) = ROOT_CTX.stat_client.as_ref() {
+            client.count(&stats::key(Metric::ConnMigrated, &[]), resumed as f64);
+            client.count(
+                &stats::key(Metric::ConnMigrationFailed, &[]),
+                (total - resumed) as f64,
+            );
+        }
+    }
+    Ok(true)
+}
+
+/// Waits for a new exit process to take over on `upgrade_socket`, then hands off this process's listeners and TUN queues and exits.
+pub async fn handoff_loop() -> anyhow::Result<Infallible> {
+    let Some(path) = CONFIG.upgrade_socket() else {
+        return smol::future::pending().await;
+    };
+    // a leftover socket file, or the one of the process we took over from
+    let _ = std::fs::remove_file(path);
+    let listener = smol::net::unix::UnixListener::bind(path)
+        .with_context(|| format!("cannot bind upgrade socket {:?}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("cannot restrict upgrade socket {:?}", path))?;
+    loop {
+        let (conn, _) = listener.accept().await?;
+        if let Err(err) = check_peer(conn.as_raw_fd()) {
+            log::warn!("refusing to hand off on the upgrade socket: {:?}", err);
+            continue;
+        }
+        log::warn!("a new exit is taking over, handing off and exiting");
+        vpn::save_lease_snapshot();
+        QUOTAS.save().await;
+        billing::save().await;
+        #[cfg(feature = "tcp-repair")]
+        let connections = if CONFIG.migrate_connections() {
+            crate::migrate::freeze(MIGRATE_TIMEOUT).await
+        } else {
+            vec![]
+        };
+        #[cfg(not(feature = "tcp-repair"))]
+        let connections = vec![];
+        let handed = HANDED.lock();
+        match send_handoff(
+            conn.as_raw_fd(),
+            &handed.transparent,
+            &handed.tun,
+            &connections,
+        ) {
+            // exiting closes the connection, telling the new process to go ahead
+            Ok(()) => std::process::exit(0),
+            Err(err) => {
+                log::error!("cannot hand off to the new exit: {:?}", err);
+                // this process goes on counting, so a later start must not count the saved usage again
+                billing::discard_saved();
+                #[cfg(feature = "tcp-repair")]
+                crate::migrate::abandon();
+            }
+        }
+    }
+}
+
+/// Only a process running as the exit's own user, or as root, may take over its listeners and TUN queues.
+fn check_peer(conn: RawFd) -> anyhow::Result<()> {
+    let uid = getsockopt(conn, PeerCredentials)?.uid();
+    anyhow::ensure!(
+        uid == 0 || uid == getuid().as_raw(),
+        "peer runs as uid {}",
+        uid
+    );
+    Ok(())
+}
+
+fn send_handoff(
+    conn: RawFd,
+    transparent: &[RawFd],
+    tun: &[RawFd],
+    connections: &[(RawFd, RawFd)],
+) -> anyhow::Result<()> {
+    let fds: Vec<RawFd> = transparent.iter().chain(tun).copied().collect();
+    anyhow::ensure!(
+        fds.len() <= MAX_HANDOFF_FDS,
+        "too many file descriptors to hand off"
+    );
+    // a message without file descriptors could be read together with the next one
+    anyhow::ensure!(
+        connections.is_empty() || !fds.is_empty(),
+        "cannot hand off connections without listeners"
+    );
+    let header = serde_json::to_vec(&Handoff {
+        transparent: transparent.len(),
+        tun: tun.len(),
+        connections: connections.len(),
+    })?;
+    sendmsg::<UnixAddr>(
+        conn,
+        &[IoSlice::new(&header)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    for batch in connections.chunks(MAX_HANDOFF_FDS / 2) {
+        let fds: Vec<RawFd> = batch
+            .iter()
+            .flat_map(|(client, remote)| [*client, *remote])
+            .collect();
+        sendmsg::<UnixAddr>(
+            conn,
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// Receives one message of a handoff, returning its bytes and file descriptors.
+fn recv_fds(conn: &UnixStream, buf: &mut [u8]) -> anyhow::Result<(usize, Vec<OwnedFd>)> {
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_HANDOFF_FDS]);
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<UnixAddr>(
+        conn.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+            // owned right away, so that they are closed if the handoff turns out to be bad
+            fds.extend(
+                raw_fds
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    Ok((msg.bytes, fds))
+}
+
+fn recv_handoff(conn: &UnixStream) -> anyhow::Result<Inherited> {
+    let mut buf = [0; 1024];
+    let (len, fds) = recv_fds(conn, &mut buf)?;
+    let mut fds = fds.into_iter();
+    let header: Handoff = serde_json::from_slice(&buf[..len])?;
+    let mut inherited = Inherited {
+        transparent: fds.by_ref().take(header.transparent).collect(),
+        tun: fds.by_ref().take(header.tun).collect(),
+        connections: Vec::new(),
+    };
+    anyhow::ensure!(
+        inherited.transparent.len() == header.transparent
+            && inherited.tun.len() == header.tun
+            && fds.next().is_none(),
+        "handoff has the wrong number of file descriptors"
+    );
+    while inherited.connections.len() < header.connections {
+        let (_, fds) = recv_fds(conn, &mut [0])?;
+        anyhow::ensure!(
+            !fds.is_empty() && fds.len() % 2 == 0,
+            "handoff has the wrong number of file descriptors"
+        );
+        let mut fds = fds.into_iter();
+        while let (Some(client), Some(remote)) = (fds.next(), fds.next()) {
+            inherited.connections.push((client, remote));
+        }
+    }
+    anyhow::ensure!(
+        inherited.connections.len() == header.connections,
+        "handoff has the wrong number of connections"
+    );
+    Ok(inherited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handoff() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tun = File::open("/dev/null").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        let (old, new) = UnixStream::pair().unwrap();
+        // the new process runs as the same user
+        check_peer(old.as_raw_fd()).unwrap();
+        send_handoff(
+            old.as_raw_fd(),
+            &[listener.as_raw_fd()],
+            &[tun.as_raw_fd()],
+            &[(client.as_raw_fd(), remote.as_raw_fd())],
+        )
+        .unwrap();
+        let inherited = recv_handoff(&new).unwrap();
+        assert_eq!(inherited.tun.len(), 1);
+        let [(taken_client, _)] = &inherited.connections[..] else {
+            panic!("expected one connection");
+        };
+        let taken_client = std::net::TcpStream::from(taken_client.try_clone().unwrap());
+        assert_eq!(
+            taken_client.local_addr().unwrap(),
+            client.local_addr().unwrap()
+        );
+        let [taken] = &inherited.transparent[..] else {
+            panic!("expected one listener");
+        };
+        let taken = std::net::TcpListener::from(taken.try_clone().unwrap());
+        assert_eq!(taken.local_addr().unwrap(), listener.local_addr().unwrap());
+    }
+}
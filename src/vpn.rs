@@ -1,8 +1,9 @@
 use anyhow::Context;
+use arrayref::array_ref;
 use bytes::Bytes;
 
 use cidr_utils::cidr::Ipv4Cidr;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use futures_util::TryFutureExt;
 use libc::{c_void, SOL_IP, SO_ORIGINAL_DST};
 
@@ -11,28 +12,41 @@ use moka::sync::Cache;
 use once_cell::sync::Lazy;
 use os_socketaddr::OsSocketAddr;
 use parking_lot::Mutex;
-use pnet_packet::{
-    ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, tcp::TcpPacket, udp::UdpPacket, Packet,
-};
+use pnet_packet::ipv4::Ipv4Packet;
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use smol::future::FutureExt;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::{
-    collections::HashSet,
+    collections::{hash_map::RandomState, HashMap, HashSet},
     convert::Infallible,
-    io::{Read, Write},
+    fs::File,
+    hash::BuildHasher,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     ops::Deref,
-    os::unix::prelude::{AsRawFd, FromRawFd},
-    sync::Arc,
-    time::Duration,
+    os::unix::prelude::AsRawFd,
+    path::Path,
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
 };
-use tun::{platform::Device, Device as Device2};
 
 use crate::{
+    asn::{next_ip, MY_PUBLIC_IP},
+    close_reason::CloseReason,
     config::CONFIG,
     connect::proxy_loop,
-    ratelimit::RateLimiter,
+    error::ExitError,
+    identity::ExitIdentity,
+    packet::{build_icmp_prohibited, PacketHeaders},
+    panics,
+    ratelimit::{BulkThrottle, RateLimiter},
+    redact,
     root_ctx::ROOT_CTX,
+    shadow::{self, Candidate},
     smartchan::{smart_channel, SmartReceiver, SmartSender},
+    stats::Metric,
+    tun_backend::TunBackend,
+    upgrade,
 };
 
 /// Runs the transparent proxy helper
@@ -40,21 +54,122 @@ pub async fn transparent_proxy_helper() -> anyhow::Result<Infallible> {
     if CONFIG.nat_external_iface().is_none() {
         return smol::future::pending().await;
     }
-    // always run on port 10000
-    // TODO this should bind dynamically
-    let listen_addr: SocketAddr = "0.0.0.0:10000".parse().unwrap();
-    let listener = smol::Async::<std::net::TcpListener>::bind(listen_addr).unwrap();
+    let listen_addr = CONFIG.transparent_listen();
+    let mut listeners = upgrade::take_transparent_listeners()
+        .into_iter()
+        .map(smol::Async::new)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if listeners.is_empty() {
+        for _ in 0..CONFIG.acceptor_threads() {
+            listeners.push(
+                reuseport_listener(listen_addr)
+                    .context("cannot bind transparent proxy listener")?,
+            );
+        }
+    }
+    let mut acceptors = Vec::new();
+    for listener in listeners {
+        upgrade::register_transparent_listener(listener.as_raw_fd());
+        acceptors.push(smolscale::spawn(transparent_accept_loop(listener)));
+    }
+    let first = acceptors.remove(0);
+    acceptors
+        .into_iter()
+        .fold(first.boxed(), |a, b| a.race(b).boxed())
+        .await
+}
+
+/// Binds a TCP listener with SO_REUSEPORT, so that several of them can share the address and the kernel spreads connections between them.
+fn reuseport_listener(addr: SocketAddr) -> anyhow::Result<smol::Async<std::net::TcpListener>> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    // the address of tun-geph only exists once the first VPN session has started
+    socket.set_freebind(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(smol::Async::new(std::net::TcpListener::from(socket))?)
+}
+
+/// How long a transparent-proxy client keeps its client ID.
+const CLIENT_ID_TTL: Duration = Duration::from_secs(3600);
+
+/// The client ID of a transparent-proxy client. IDs are cached by a keyed hash of the client's address, whose key is random per boot, so that no plaintext client addresses are kept around.
+fn transparent_client_id(peer_addr: IpAddr) -> u64 {
+    static ADDR_HASHER: Lazy<RandomState> = Lazy::new(RandomState::new);
+    static CLIENT_ID_CACHE: Lazy<Cache<u64, u64>> = Lazy::new(|| {
+        Cache::builder()
+            .max_capacity(1_000_000)
+            .time_to_live(CLIENT_ID_TTL)
+            .build()
+    });
+    CLIENT_ID_CACHE.get_with(ADDR_HASHER.hash_one(peer_addr), || rand::thread_rng().gen())
+}
+
+/// Whether a transparent-proxy connection comes from a VPN client, rather than from anything else that can reach the listener.
+fn is_vpn_client(peer_addr: IpAddr) -> bool {
+    match peer_addr {
+        IpAddr::V4(addr) => CONFIG.all_cgnat_pools().any(|pool| pool.contains(addr)),
+        IpAddr::V6(addr) => addr
+            .to_ipv4_mapped()
+            .is_some_and(|addr| is_vpn_client(addr.into())),
+    }
+}
+
+/// Shortest pause after a failed accept, doubled on every further failure.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
 
+/// Longest pause after a failed accept.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Accepts the next connection, riding out errors with a backoff. When out of file descriptors, a descriptor held in reserve is given up to accept and reset the next pending connection, so that its client fails at once instead of waiting in the backlog.
+async fn accept_resilient(
+    listener: &smol::Async<std::net::TcpListener>,
+    reserve: &mut Option<File>,
+) -> smol::Async<std::net::TcpStream> {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
     loop {
-        let (client, _) = listener.accept().await.unwrap();
+        let err = match listener.accept().await {
+            Ok((client, _)) => return client,
+            Err(err) => err,
+        };
+        ROOT_CTX.incr_stat(Metric::TransparentAcceptFailed);
+        log::warn!("cannot accept transparent proxy connection: {}", err);
+        if matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+            && reserve.take().is_some()
+        {
+            if let Ok((shed, _)) = listener.get_ref().accept() {
+                ROOT_CTX.incr_stat(Metric::TransparentAcceptShed);
+                let _ = SockRef::from(&shed).set_linger(Some(Duration::ZERO));
+            }
+            *reserve = File::open("/dev/null").ok();
+        }
+        smol::Timer::after(backoff).await;
+        backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+    }
+}
+
+/// Accepts connections to the transparent proxy from one listener.
+async fn transparent_accept_loop(
+    listener: smol::Async<std::net::TcpListener>,
+) -> anyhow::Result<Infallible> {
+    let mut reserve = File::open("/dev/null").ok();
+    loop {
+        let client = accept_resilient(&listener, &mut reserve).await;
+        match client.get_ref().peer_addr() {
+            Ok(peer_addr) if is_vpn_client(peer_addr.ip()) => {}
+            _ => {
+                ROOT_CTX.incr_stat(Metric::TransparentSourceRejected);
+                let _ = SockRef::from(client.get_ref()).set_linger(Some(Duration::ZERO));
+                continue;
+            }
+        }
 
         let rate_limit = Arc::new(RateLimiter::unlimited());
         let conn_task = smolscale::spawn(
-            async move {
-                static CLIENT_ID_CACHE: Lazy<Cache<IpAddr, u64>> =
-                    Lazy::new(|| Cache::new(1_000_000));
+            panics::guarded("transparent proxy connection", async move {
                 let peer_addr = client.as_ref().peer_addr().context("no peer addr")?.ip();
-                let client_id = CLIENT_ID_CACHE.get_with(peer_addr, || rand::thread_rng().gen());
+                let client_id = transparent_client_id(peer_addr);
                 let client_fd = client.as_raw_fd();
                 let addr = unsafe {
                     let raw_addr = OsSocketAddr::new();
@@ -75,131 +190,461 @@ pub async fn transparent_proxy_helper() -> anyhow::Result<Infallible> {
                         anyhow::bail!("SO_ORIGINAL_DST is not an IP address, aborting");
                     }
                 };
+                let addr = match addr.ip() {
+                    IpAddr::V4(ip) if crate::nat64::is_synthetic(ip) => {
+                        let v6 = crate::nat64::translate(ip)
+                            .context("connection to an unmapped NAT64 address")?;
+                        SocketAddr::new(v6.into(), addr.port())
+                    }
+                    _ => addr,
+                };
                 let client = async_dup::Arc::new(client);
                 client
                     .get_ref()
                     .set_nodelay(true)
                     .context("cannot set nodelay")?;
-                proxy_loop(rate_limit, client, client_id, addr.to_string(), false).await
-            }
+                crate::connect::set_keepalive(client.get_ref()).context("cannot set keepalive")?;
+                // there is no stream to send a notice on, so free-tier clients are throttled without one
+                let bulk = match peer_addr {
+                    IpAddr::V4(ip) => ROOT_CTX.vpn().is_free_tier(ip),
+                    IpAddr::V6(_) => false,
+                }
+                .then(BulkThrottle::new)
+                .flatten()
+                .map(Arc::new);
+                let result = proxy_loop(
+                    rate_limit,
+                    client.clone(),
+                    client_id,
+                    client_id,
+                    addr.to_string(),
+                    Some(client.get_ref()),
+                    None,
+                    bulk,
+                    ROOT_CTX.main_identity().clone(),
+                    None,
+                    false,
+                )
+                .await;
+                if let Err(err) = &result {
+                    if let Some(reason) = ExitError::of(err).close_reason() {
+                        reset_connection(client.get_ref(), reason);
+                    }
+                }
+                result
+            })
             .map_err(|e| log::debug!("vpn conn closed: {:?}", e)),
         );
         conn_task.detach();
     }
 }
 
-/// Subscribes to downstream packets
-pub fn vpn_subscribe_down(addr: Ipv4Addr) -> SmartReceiver<Bytes> {
-    let (send_down, recv_down) = smart_channel(1000, Duration::from_millis(50));
-    INCOMING_MAP.insert(addr, send_down);
-    recv_down
+/// Makes a refused transparent-proxy connection end with a RST as soon as it is dropped, rather than a FIN the client may not act on until its own timeout.
+fn reset_connection(client: &std::net::TcpStream, reason: CloseReason) {
+    log::debug!(
+        "resetting transparent proxy connection: {} (code {})",
+        reason,
+        reason.code()
+    );
+    ROOT_CTX.incr_stat(Metric::TransparentProxyReset);
+    if let Err(err) = SockRef::from(client).set_linger(Some(Duration::ZERO)) {
+        log::debug!("cannot set SO_LINGER for a reset: {:?}", err);
+    }
+}
+
+/// Packets a session may have queued before those read from the TUN device are copied out of their slab. Each queued packet keeps its whole slab alive, which the buffer budget doesn't see, so a session that falls behind gets copies sized to its packets instead.
+const ZERO_COPY_QUEUE_LEN: usize = 8;
+
+/// The VPN state of an exit: the TUN device, the downstream channels of the sessions, and the pool their addresses come from.
+pub struct VpnCtx {
+    tun: Box<dyn TunBackend>,
+    incoming: DashMap<Ipv4Addr, SmartSender<Bytes>>,
+    /// Addresses of the sessions in `incoming` that belong to free-tier users
+    free_tier: DashSet<Ipv4Addr>,
+    /// One assigner per CGNAT pool, the default pool first
+    pools: Vec<IpAddrAssigner>,
+    /// Addresses restored from a lease snapshot, held for the clients that had them
+    restored: Cache<u64, AssignedIpv4Addr>,
 }
 
-/// Writes a raw, upacket
-pub async fn vpn_send_up(assigned_ip: Ipv4Addr, bts: &[u8]) {
-    ROOT_CTX.incr_throughput(bts.len());
-    let pkt = Ipv4Packet::new(bts);
-    if let Some(pkt) = pkt {
-        // source must be correct and destination must not be banned
-        if pkt.get_source() != assigned_ip
-            || pkt.get_destination().is_loopback()
-            || pkt.get_destination().is_private()
-            || pkt.get_destination().is_unspecified()
-            || pkt.get_destination().is_broadcast()
+impl VpnCtx {
+    /// Creates the VPN state for the given address pools, the default one first. The TUN backend is opened with a handle to the new state, so that it can dispatch the packets it reads.
+    pub fn new(
+        pools: impl IntoIterator<Item = Ipv4Cidr>,
+        open_tun: impl FnOnce(Weak<VpnCtx>) -> Box<dyn TunBackend>,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            tun: open_tun(this.clone()),
+            incoming: DashMap::new(),
+            free_tier: DashSet::new(),
+            pools: pools.into_iter().map(IpAddrAssigner::new).collect(),
+            restored: Cache::builder().time_to_live(RESTORED_LEASE_TTL).build(),
+        })
+    }
+
+    /// Holds the addresses in a lease snapshot for their clients, so that they get them back when they reconnect.
+    pub fn restore_leases(&self, path: &Path) -> anyhow::Result<()> {
+        let leases: Vec<LeaseInfo> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let mut restored = 0;
+        for lease in leases {
+            let Some(client) = lease.client else {
+                continue;
+            };
+            if self.restored.contains_key(&client) {
+                continue;
+            }
+            if let Some(addr) = self.pools.iter().find_map(|pool| pool.claim(lease.addr)) {
+                addr.set_client(client);
+                self.restored.insert(client, addr);
+                restored += 1;
+            }
+        }
+        log::info!("restored {} leases from {:?}", restored, path);
+        Ok(())
+    }
+
+    /// Takes the address restored for the given client, if any.
+    pub fn take_restored(&self, client: u64) -> Option<AssignedIpv4Addr> {
+        let addr = self.restored.get(&client)?;
+        self.restored.invalidate(&client);
+        Some(addr)
+    }
+
+    /// The address assigner of the default pool, where sessions get their addresses before they authenticate.
+    pub fn assigner(&self) -> &IpAddrAssigner {
+        &self.pools[0]
+    }
+
+    /// The address assigner of the given pool.
+    pub fn pool(&self, cidr: Ipv4Cidr) -> Option<&IpAddrAssigner> {
+        self.pools.iter().find(|pool| pool.cidr == cidr)
+    }
+
+    /// Every assigned address, in every pool.
+    pub fn leases(&self) -> Vec<LeaseInfo> {
+        self.pools.iter().flat_map(|pool| pool.leases()).collect()
+    }
+
+    /// Fraction of the usable addresses of all pools currently assigned.
+    pub fn occupancy(&self) -> f64 {
+        let assigned: f64 = self
+            .pools
+            .iter()
+            .map(|pool| pool.occupancy() * pool.capacity() as f64)
+            .sum();
+        let capacity: f64 = self.pools.iter().map(|pool| pool.capacity() as f64).sum();
+        assigned / capacity
+    }
+
+    /// Subscribes to downstream packets, for a session of the given tier
+    pub fn subscribe_down(&self, addr: Ipv4Addr, free_tier: bool) -> SmartReceiver<Bytes> {
+        let (send_down, recv_down) = smart_channel(1000, Duration::from_millis(50));
+        self.incoming.insert(addr, send_down);
+        if free_tier {
+            self.free_tier.insert(addr);
+        } else {
+            self.free_tier.remove(&addr);
+        }
+        recv_down
+    }
+
+    /// Unsubscribes from downstream packets, closing the session's channel. Does nothing if another session has subscribed to the address since.
+    pub fn unsubscribe_down(&self, addr: Ipv4Addr, downstream: &SmartReceiver<Bytes>) {
+        if self
+            .incoming
+            .remove_if(&addr, |_, sender| sender.feeds(downstream))
+            .is_some()
         {
-            return;
+            self.free_tier.remove(&addr);
         }
-        // must not be blacklisted
-        let port = {
-            match pkt.get_next_level_protocol() {
-                IpNextHeaderProtocols::Tcp => {
-                    TcpPacket::new(pkt.payload()).map(|v| v.get_destination())
-                }
-                IpNextHeaderProtocols::Udp => {
-                    UdpPacket::new(pkt.payload()).map(|v| v.get_destination())
-                }
-                _ => None,
+    }
+
+    /// Whether the VPN session with the given address belongs to a free-tier user.
+    pub fn is_free_tier(&self, addr: Ipv4Addr) -> bool {
+        self.free_tier.contains(&addr)
+    }
+
+    /// Whether a VPN session currently has the given address.
+    pub fn session_exists(&self, addr: Ipv4Addr) -> bool {
+        self.incoming.contains_key(&addr)
+    }
+
+    /// Routes a downstream packet to the session it is destined for, copying it only if there is one.
+    pub fn dispatch_down(&self, pkt: &[u8]) {
+        if let Some(dest) = self.route_down(pkt) {
+            dest.send_or_drop(Bytes::copy_from_slice(pkt));
+        }
+    }
+
+    /// Routes a packet read from the TUN device to the session it is destined for, without copying it unless the session is behind.
+    pub fn dispatch_down_owned(&self, pkt: Bytes) {
+        if let Some(dest) = self.route_down(&pkt) {
+            if dest.queued() >= ZERO_COPY_QUEUE_LEN {
+                dest.send_or_drop(Bytes::copy_from_slice(&pkt));
+            } else {
+                dest.send_or_drop(pkt);
             }
+        }
+    }
+
+    /// The channel of the session a downstream packet is destined for, after accounting for the packet.
+    fn route_down(
+        &self,
+        pkt: &[u8],
+    ) -> Option<dashmap::mapref::one::Ref<'_, Ipv4Addr, SmartSender<Bytes>>> {
+        let Some(parsed) = Ipv4Packet::new(pkt) else {
+            log::debug!("dropping invalid downstream packet of length {}", pkt.len());
+            ROOT_CTX.incr_stat(Metric::VpnBadPacket);
+            return None;
         };
-        if let Some(port) = port {
-            // Block QUIC due to it performing badly over sosistab etc
-            if pkt.get_next_level_protocol() == IpNextHeaderProtocols::Udp && port == 443 {
-                return;
-            }
-            if crate::lists::BLACK_PORTS.contains(&port) {
-                return;
+        let dest = self.incoming.get(&parsed.get_destination())?;
+        crate::pcap::observe(parsed.get_destination(), pkt);
+        crate::asn::record_traffic(parsed.get_source().into(), pkt.len());
+        Some(dest)
+    }
+
+    /// Writes a raw, upacket
+    pub async fn send_up(&self, identity: &ExitIdentity, assigned_ip: Ipv4Addr, bts: &[u8]) {
+        ROOT_CTX.incr_throughput(identity, bts.len());
+        crate::pcap::observe(assigned_ip, bts);
+        if let Some(pkt) = PacketHeaders::parse(bts) {
+            let verdict = upstream_verdict(&pkt, assigned_ip, &EXIT_OWN_IPS);
+            if let Some(candidate) = UPSTREAM_VERDICT_CANDIDATE.get() {
+                shadow::compare("upstream_verdict", &verdict, || {
+                    candidate(&pkt, assigned_ip, &EXIT_OWN_IPS)
+                });
             }
-            if CONFIG.port_whitelist() && !crate::lists::WHITE_PORTS.contains(&port) {
-                return;
+            match verdict {
+                UpstreamVerdict::Forward => {
+                    crate::asn::record_traffic(pkt.destination.into(), bts.len());
+                    self.tun.write(assigned_ip, bts);
+                    smol::future::yield_now().await;
+                }
+                UpstreamVerdict::Hairpin => self.hairpin(pkt.source, pkt.destination, bts),
+                UpstreamVerdict::Nat64 => crate::nat64::send_udp(&pkt, bts).await,
+                UpstreamVerdict::Drop(reason) => {
+                    log::trace!(
+                        "dropping upstream packet from {}: {}",
+                        redact::client(assigned_ip),
+                        reason
+                    )
+                }
+                UpstreamVerdict::Reject(reason) => {
+                    log::trace!(
+                        "rejecting upstream packet from {}: {}",
+                        redact::client(assigned_ip),
+                        reason
+                    );
+                    if CONFIG.icmp_reject() {
+                        if let Some(reply) = build_icmp_prohibited(bts) {
+                            ROOT_CTX.incr_stat(Metric::VpnIcmpRejected);
+                            self.dispatch_down(&reply);
+                        }
+                    }
+                }
             }
         }
-        RAW_TUN_WRITE(bts);
-        smol::future::yield_now().await;
+    }
+
+    /// Relays a packet from one VPN client to another on this exit, subject to a per-pair rate limit.
+    fn hairpin(&self, source: Ipv4Addr, destination: Ipv4Addr, bts: &[u8]) {
+        static PAIR_LIMITS: Lazy<Cache<(Ipv4Addr, Ipv4Addr), RateLimiter>> = Lazy::new(|| {
+            Cache::builder()
+                .time_to_idle(Duration::from_secs(600))
+                .max_capacity(100_000)
+                .build()
+        });
+        let limiter = PAIR_LIMITS.get_with((source, destination), || {
+            RateLimiter::new(CONFIG.vpn_hairpin_limit(), CONFIG.vpn_hairpin_limit())
+        });
+        if limiter.check(bts.len()) {
+            self.dispatch_down(bts);
+        } else {
+            ROOT_CTX.incr_stat(Metric::VpnHairpinDropped);
+        }
     }
 }
 
-/// Mapping for incoming packets
-#[allow(clippy::type_complexity)]
-static INCOMING_MAP: Lazy<DashMap<Ipv4Addr, SmartSender<Bytes>>> = Lazy::new(DashMap::new);
-
-/// The raw TUN device.
-static RAW_TUN_WRITE: Lazy<Box<dyn Fn(&[u8]) + Send + Sync + 'static>> = Lazy::new(|| {
-    log::info!("initializing tun-geph");
-    let queue_count = std::thread::available_parallelism().unwrap().get();
-    let mut dev = Device::new(
-        tun::Configuration::default()
-            .name("tun-geph")
-            .address("100.64.0.1")
-            .netmask("255.192.0.0")
-            .mtu(1280)
-            .up()
-            .layer(tun::Layer::L3)
-            .queues(queue_count),
-    )
-    .unwrap();
-    // TODO: is this remotely safe??
-    for q in 0..queue_count {
-        let queue = dev.queue(q).unwrap();
-        let queue_fd = queue.as_raw_fd();
-        std::thread::Builder::new()
-            .name("tun-reader".into())
-            .spawn(move || {
-                let mut reader = unsafe { std::fs::File::from_raw_fd(queue_fd) };
-                // great now we can do our magic
-                let mut buf = [0; 2048];
-                loop {
-                    let n = reader.read(&mut buf).expect("cannot read from tun device");
-                    let pkt = &buf[..n];
-                    let dest =
-                        Ipv4Packet::new(pkt).map(|pkt| INCOMING_MAP.get(&pkt.get_destination()));
-                    if let Some(Some(dest)) = dest {
-                        dest.send_or_drop(Bytes::copy_from_slice(pkt));
-                    }
-                }
-            })
-            .unwrap();
-    }
-    let (send, recv) = smol::channel::bounded::<Vec<u8>>(10000);
-    std::thread::Builder::new()
-        .name("tun-writer".into())
-        .spawn(move || loop {
-            let v = recv.recv_blocking().unwrap();
-            let _ = dev.write_all(&v);
-        })
-        .unwrap();
-    Box::new(move |b| {
-        let _ = send.try_send(b.to_vec());
-    })
+/// Every IPv4 address of the exit host itself. VPN clients must never reach these, since services listening on them may trust local traffic.
+static EXIT_OWN_IPS: Lazy<HashSet<Ipv4Addr>> = Lazy::new(|| {
+    let mut ips: HashSet<Ipv4Addr> = HashSet::new();
+    ips.insert(*MY_PUBLIC_IP);
+    match nix::ifaddrs::getifaddrs() {
+        Ok(ifaddrs) => ips.extend(ifaddrs.filter_map(|ifaddr| {
+            ifaddr
+                .address?
+                .as_sockaddr_in()
+                .map(|addr| Ipv4Addr::from(addr.ip()))
+        })),
+        Err(err) => log::warn!("cannot list the exit's own addresses: {:?}", err),
+    }
+    for identity in ROOT_CTX.identities.iter() {
+        if let Ok(SocketAddr::V4(addr)) = identity.sosistab2_listen.parse() {
+            ips.insert(*addr.ip());
+        }
+    }
+    ips.retain(|ip| !ip.is_unspecified());
+    ips
 });
 
-/// Global IpAddr assigner
-static CGNAT_IPASSIGN: Lazy<IpAddrAssigner> =
-    Lazy::new(|| IpAddrAssigner::new("100.64.0.0/10".parse().unwrap()));
+/// What to do with a packet that a VPN client sent up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UpstreamVerdict {
+    /// Write it into the TUN device.
+    Forward,
+    /// Relay it to another VPN client.
+    Hairpin,
+    /// Translate it to IPv6 in userspace.
+    Nat64,
+    /// Drop it, for the given reason.
+    Drop(&'static str),
+    /// Drop it because of the port policy, telling the client if `icmp_reject` is on.
+    Reject(&'static str),
+}
+
+/// The signature of [`upstream_verdict`].
+type UpstreamVerdictFn = fn(&PacketHeaders, Ipv4Addr, &HashSet<Ipv4Addr>) -> UpstreamVerdict;
+
+/// A rewrite of [`upstream_verdict`] being rolled out, which shadow mode checks against it before it replaces it.
+static UPSTREAM_VERDICT_CANDIDATE: Candidate<UpstreamVerdictFn> = Candidate::new();
+
+/// Lets shadow mode check [`upstream_verdict_table`] against [`upstream_verdict`].
+pub fn install_shadow_candidate() {
+    UPSTREAM_VERDICT_CANDIDATE.set(Some(upstream_verdict_table));
+}
+
+/// Non-public IPv4 ranges, as network and prefix length, that [`upstream_verdict_table`] drops packets to.
+const NON_PUBLIC_RANGES: &[(Ipv4Addr, u32)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8),
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(127, 0, 0, 0), 8),
+    (Ipv4Addr::new(169, 254, 0, 0), 16),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+    (Ipv4Addr::new(224, 0, 0, 0), 4),
+    (Ipv4Addr::new(255, 255, 255, 255), 32),
+];
+
+/// Whether a destination is non-public, by the standard library's classification.
+fn is_non_public(dest: Ipv4Addr) -> bool {
+    dest.is_loopback()
+        || dest.is_private()
+        || dest.is_unspecified()
+        || dest.is_broadcast()
+        || dest.is_link_local()
+        || dest.is_multicast()
+        || dest.octets()[0] == 0
+}
+
+/// Whether a destination is non-public, by [`NON_PUBLIC_RANGES`].
+fn is_non_public_table(dest: Ipv4Addr) -> bool {
+    let dest = u32::from(dest);
+    NON_PUBLIC_RANGES
+        .iter()
+        .any(|(net, prefix)| (dest ^ u32::from(*net)) >> (32 - prefix) == 0)
+}
+
+/// Decides what to do with a packet that a VPN client sent up.
+fn upstream_verdict(
+    pkt: &PacketHeaders,
+    assigned_ip: Ipv4Addr,
+    own_ips: &HashSet<Ipv4Addr>,
+) -> UpstreamVerdict {
+    upstream_verdict_with(pkt, assigned_ip, own_ips, is_non_public)
+}
+
+/// The rewrite of [`upstream_verdict`] that classifies destinations with a table of ranges.
+fn upstream_verdict_table(
+    pkt: &PacketHeaders,
+    assigned_ip: Ipv4Addr,
+    own_ips: &HashSet<Ipv4Addr>,
+) -> UpstreamVerdict {
+    upstream_verdict_with(pkt, assigned_ip, own_ips, is_non_public_table)
+}
+
+fn upstream_verdict_with(
+    pkt: &PacketHeaders,
+    assigned_ip: Ipv4Addr,
+    own_ips: &HashSet<Ipv4Addr>,
+    is_non_public: fn(Ipv4Addr) -> bool,
+) -> UpstreamVerdict {
+    let dest = pkt.destination;
+    if pkt.source != assigned_ip {
+        return UpstreamVerdict::Drop("spoofed source");
+    }
+    // packets to the CGNAT ranges never go through the TUN device, and only reach other clients if hairpinning is enabled
+    if let Some(pool) = CONFIG.all_cgnat_pools().find(|pool| pool.contains(dest)) {
+        return if dest == next_ip(pool.first_as_ipv4_addr()) {
+            UpstreamVerdict::Drop("gateway")
+        } else if CONFIG.vpn_hairpin() {
+            UpstreamVerdict::Hairpin
+        } else {
+            UpstreamVerdict::Drop("other client")
+        };
+    }
+    // TCP to synthetic addresses goes through the TUN device to the transparent proxy, which translates it
+    if crate::nat64::is_synthetic(dest) {
+        return if pkt.is_udp() {
+            UpstreamVerdict::Nat64
+        } else if pkt.is_tcp() {
+            UpstreamVerdict::Forward
+        } else {
+            UpstreamVerdict::Drop("untranslatable NAT64 packet")
+        };
+    }
+    if is_non_public(dest) {
+        return UpstreamVerdict::Drop("non-public destination");
+    }
+    if own_ips.contains(&dest) {
+        return UpstreamVerdict::Drop("exit's own address");
+    }
+    if CONFIG.destination_blocked(dest.into()) {
+        return UpstreamVerdict::Drop("blocked destination");
+    }
+    if CONFIG.bypass_routes().covers_ip(dest.into()) {
+        return UpstreamVerdict::Reject("bypass route");
+    }
+    if let Some(port) = pkt.dest_port {
+        // Block QUIC due to it performing badly over sosistab etc
+        if pkt.is_udp() && port == 443 {
+            return UpstreamVerdict::Drop("QUIC");
+        }
+        if crate::lists::BLACK_PORTS.contains(&port) {
+            return UpstreamVerdict::Reject("blacklisted port");
+        }
+        if !crate::smtp::port_allowed(port) {
+            return UpstreamVerdict::Reject("mail port");
+        }
+        if CONFIG.port_whitelist() && !crate::lists::WHITE_PORTS.contains(&port) {
+            return UpstreamVerdict::Reject("port not whitelisted");
+        }
+    }
+    UpstreamVerdict::Forward
+}
+
+/// Random addresses tried before searching the whole pool for a free one.
+const RANDOM_ASSIGN_TRIES: usize = 32;
 
 /// An IP address assigner
 pub struct IpAddrAssigner {
     cidr: Ipv4Cidr,
-    table: Arc<Mutex<HashSet<Ipv4Addr>>>,
+    table: Arc<Mutex<HashMap<Ipv4Addr, Lease>>>,
+}
+
+/// Who holds an assigned address, and since when.
+#[derive(Debug)]
+struct Lease {
+    client: Option<u64>,
+    assigned_at: SystemTime,
+}
+
+/// An assigned address, as shown by the admin API and saved in lease snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LeaseInfo {
+    pub addr: Ipv4Addr,
+    /// The token ID of the client, once it has authenticated
+    pub client: Option<u64>,
+    pub age_secs: u64,
 }
 
 impl IpAddrAssigner {
@@ -207,29 +652,90 @@ impl IpAddrAssigner {
     pub fn new(cidr: Ipv4Cidr) -> Self {
         Self {
             cidr,
-            table: Arc::new(Mutex::new(HashSet::new())),
+            table: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    /// Get the global CGNAT instance.
-    pub fn global() -> &'static Self {
-        &CGNAT_IPASSIGN
+    /// Assigns a new IP address, failing if the pool is full.
+    pub fn assign(&self) -> anyhow::Result<AssignedIpv4Addr> {
+        for _ in 0..RANDOM_ASSIGN_TRIES {
+            let candidate = rand::thread_rng().gen_range(0, self.capacity());
+            if let Some(assigned) = self.claim(self.usable_addr(candidate)) {
+                return Ok(assigned);
+            }
+        }
+        // the pool is nearly full, so look at every address
+        self.probe(rand::thread_rng().gen_range(0, self.capacity()))
     }
 
-    /// Assigns a new IP address.
-    pub fn assign(&self) -> AssignedIpv4Addr {
-        let first = self.cidr.first();
-        let last = self.cidr.last();
-        loop {
-            let candidate = rand::thread_rng().gen_range(first + 16, last - 16);
-            let candidate = Ipv4Addr::from(candidate);
-            let mut tab = self.table.lock();
-            if !tab.contains(&candidate) {
-                tab.insert(candidate);
-                log::trace!("assigned {}", candidate);
-                return AssignedIpv4Addr::new(self.table.clone(), candidate);
+    /// Assigns the address that the keyed hash of the client ID points to, or the next free one after it, failing if the pool is full.
+    pub fn assign_hashed(&self, client: u64, key: &[u8; 32]) -> anyhow::Result<AssignedIpv4Addr> {
+        let hash = blake3::keyed_hash(key, &client.to_le_bytes());
+        let start = u64::from_le_bytes(*array_ref![hash.as_bytes(), 0, 8]) % self.capacity() as u64;
+        self.probe(start as u32)
+    }
+
+    /// Assigns the first free address at or after the given index, wrapping around.
+    fn probe(&self, start: u32) -> anyhow::Result<AssignedIpv4Addr> {
+        for offset in 0..self.capacity() {
+            let candidate = self.usable_addr((start + offset) % self.capacity());
+            if let Some(assigned) = self.claim(candidate) {
+                return Ok(assigned);
             }
         }
+        anyhow::bail!("CGNAT pool {} is full", self.cidr)
+    }
+
+    /// Number of addresses that can be assigned. The first and last 16 addresses of the pool are never assigned.
+    pub fn capacity(&self) -> u32 {
+        self.cidr.last() - self.cidr.first() - 32
+    }
+
+    /// The usable address with the given index.
+    fn usable_addr(&self, index: u32) -> Ipv4Addr {
+        Ipv4Addr::from(self.cidr.first() + 16 + index)
+    }
+
+    /// Fraction of the usable addresses currently assigned.
+    pub fn occupancy(&self) -> f64 {
+        self.table.lock().len() as f64 / self.capacity() as f64
+    }
+
+    /// Assigns the given IP address, if it is in the pool and free.
+    pub fn claim(&self, addr: Ipv4Addr) -> Option<AssignedIpv4Addr> {
+        let n = u32::from(addr);
+        if n < self.cidr.first() + 16 || n >= self.cidr.last() - 16 {
+            return None;
+        }
+        let mut tab = self.table.lock();
+        if tab.contains_key(&addr) {
+            return None;
+        }
+        tab.insert(
+            addr,
+            Lease {
+                client: None,
+                assigned_at: SystemTime::now(),
+            },
+        );
+        log::trace!("assigned {}", redact::client(addr));
+        Some(AssignedIpv4Addr::new(self.table.clone(), addr))
+    }
+
+    /// Every address currently assigned.
+    pub fn leases(&self) -> Vec<LeaseInfo> {
+        let mut leases: Vec<LeaseInfo> = self
+            .table
+            .lock()
+            .iter()
+            .map(|(addr, lease)| LeaseInfo {
+                addr: *addr,
+                client: lease.client,
+                age_secs: lease.assigned_at.elapsed().unwrap_or_default().as_secs(),
+            })
+            .collect();
+        leases.sort_unstable_by_key(|lease| lease.addr);
+        leases
     }
 }
 
@@ -240,7 +746,7 @@ pub struct AssignedIpv4Addr {
 }
 
 impl AssignedIpv4Addr {
-    fn new(table: Arc<Mutex<HashSet<Ipv4Addr>>>, addr: Ipv4Addr) -> Self {
+    fn new(table: Arc<Mutex<HashMap<Ipv4Addr, Lease>>>, addr: Ipv4Addr) -> Self {
         Self {
             inner: Arc::new(AssignedIpv4AddrInner { addr, table }),
         }
@@ -248,6 +754,13 @@ impl AssignedIpv4Addr {
     pub fn addr(&self) -> Ipv4Addr {
         self.inner.addr
     }
+
+    /// Records which client holds the address.
+    pub fn set_client(&self, client: u64) {
+        if let Some(lease) = self.inner.table.lock().get_mut(&self.inner.addr) {
+            lease.client = Some(client);
+        }
+    }
 }
 
 impl PartialEq for AssignedIpv4Addr {
@@ -269,29 +782,350 @@ impl Deref for AssignedIpv4Addr {
 #[derive(Debug)]
 struct AssignedIpv4AddrInner {
     addr: Ipv4Addr,
-    table: Arc<Mutex<HashSet<Ipv4Addr>>>,
+    table: Arc<Mutex<HashMap<Ipv4Addr, Lease>>>,
 }
 
 impl Drop for AssignedIpv4AddrInner {
     fn drop(&mut self) {
-        log::trace!("dropped {}", self.addr);
-        if !self.table.lock().remove(&self.addr) {
+        log::trace!("dropped {}", redact::client(self.addr));
+        if self.table.lock().remove(&self.addr).is_none() {
             panic!("AssignedIpv4Addr double free?! {}", self.addr)
         }
     }
 }
 
+/// The key of the hash that picks addresses with `cgnat_assignment = "hashed"`. It comes from the exit's signing key, so it stays the same across restarts but can't be guessed by clients.
+pub static CGNAT_HASH_KEY: Lazy<[u8; 32]> = Lazy::new(|| {
+    blake3::derive_key(
+        "geph4-exit cgnat assignment",
+        ROOT_CTX.main_identity().signing_sk.secret.as_bytes(),
+    )
+});
+
+/// How long addresses restored from a lease snapshot are held for their clients.
+const RESTORED_LEASE_TTL: Duration = Duration::from_secs(600);
+
+/// Saves the CGNAT leases to `lease_snapshot_path` every 30 seconds.
+pub async fn lease_snapshot_loop() -> anyhow::Result<Infallible> {
+    if CONFIG.lease_snapshot_path().is_none() {
+        return smol::future::pending().await;
+    }
+    loop {
+        smol::Timer::after(Duration::from_secs(30)).await;
+        save_lease_snapshot();
+    }
+}
+
+/// Saves the CGNAT leases to `lease_snapshot_path`, if it is set and the VPN is running. Failures are logged.
+pub fn save_lease_snapshot() {
+    let (Some(path), Some(vpn)) = (CONFIG.lease_snapshot_path(), ROOT_CTX.vpn_if_started()) else {
+        return;
+    };
+    let leases: Vec<LeaseInfo> = vpn
+        .leases()
+        .into_iter()
+        .filter(|lease| lease.client.is_some())
+        .collect();
+    let write = || {
+        // write then rename, so that a crash never leaves a truncated snapshot
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&leases)?)?;
+        std::fs::rename(&tmp, path)?;
+        anyhow::Ok(())
+    };
+    if let Err(err) = write() {
+        log::error!("cannot save lease snapshot to {:?}: {:?}", path, err);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tun_backend::MemoryTun;
+    use smol_timeout::TimeoutExt;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn send_up() {
+        let tun = MemoryTun::new();
+        let vpn = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(tun.clone()));
+        let identity = ROOT_CTX.main_identity().clone();
+        let me = Ipv4Addr::new(100, 64, 0, 9);
+        let allowed =
+            crate::packet::build_udp((me, 40000), (Ipv4Addr::new(93, 184, 216, 34), 53), b"hi")
+                .unwrap();
+        let spoofed = crate::packet::build_udp(
+            (Ipv4Addr::new(100, 64, 0, 10), 40000),
+            (Ipv4Addr::new(93, 184, 216, 34), 53),
+            b"hi",
+        )
+        .unwrap();
+        smol::block_on(async {
+            vpn.send_up(&identity, me, &spoofed).await;
+            vpn.send_up(&identity, me, &allowed).await;
+            assert_eq!(tun.recv().await.unwrap(), allowed);
+            assert!(tun
+                .recv()
+                .timeout(Duration::from_millis(100))
+                .await
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn icmp_reject() {
+        let vpn = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(MemoryTun::new()));
+        let identity = ROOT_CTX.main_identity().clone();
+        let addr = vpn.assigner().assign().unwrap();
+        let downstream = vpn.subscribe_down(*addr, false);
+        let public = Ipv4Addr::new(93, 184, 216, 34);
+        let smtp = crate::packet::build_udp((*addr, 40000), (public, 25), b"hi").unwrap();
+        smol::block_on(vpn.send_up(&identity, *addr, &smtp));
+        let reply = downstream.try_recv().unwrap();
+        let parsed = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(parsed.get_source(), public);
+        assert_eq!(parsed.get_destination(), *addr);
+        // destination unreachable, communication administratively prohibited, quoting the original headers
+        assert_eq!(&reply[20..22], &[3, 13]);
+        assert_eq!(&reply[28..], &smtp[..28]);
+    }
+
+    #[test]
+    fn non_public_table() {
+        for (net, prefix) in NON_PUBLIC_RANGES {
+            let first = u32::from(*net);
+            let last = first | (u32::MAX >> 1 >> (prefix - 1));
+            for addr in [first.wrapping_sub(1), first, last, last.wrapping_add(1)] {
+                let addr = Ipv4Addr::from(addr);
+                assert_eq!(is_non_public_table(addr), is_non_public(addr), "{}", addr);
+            }
+        }
+        let public = Ipv4Addr::new(93, 184, 216, 34);
+        assert!(!is_non_public_table(public));
+    }
+
+    #[test]
+    fn shadow_candidate() {
+        let tun = MemoryTun::new();
+        let vpn = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(tun.clone()));
+        let identity = ROOT_CTX.main_identity().clone();
+        let me = Ipv4Addr::new(100, 64, 0, 11);
+        let pkt =
+            crate::packet::build_udp((me, 40000), (Ipv4Addr::new(93, 184, 216, 34), 53), b"hi")
+                .unwrap();
+        let before = shadow::MISMATCHES.load(Ordering::Relaxed);
+        // a candidate that drops everything disagrees, but the packet still goes out
+        UPSTREAM_VERDICT_CANDIDATE.set(Some(|_, _, _| UpstreamVerdict::Drop("candidate")));
+        smol::block_on(vpn.send_up(&identity, me, &pkt));
+        UPSTREAM_VERDICT_CANDIDATE.set(None);
+        assert!(shadow::MISMATCHES.load(Ordering::Relaxed) > before);
+        assert_eq!(smol::block_on(tun.recv()).unwrap(), pkt);
+    }
+
+    #[test]
+    fn backlogged_copies() {
+        let vpn = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(MemoryTun::new()));
+        let addr = vpn.assigner().assign().unwrap();
+        let downstream = vpn.subscribe_down(*addr, false);
+        let pkt =
+            crate::packet::build_udp((Ipv4Addr::new(93, 184, 216, 34), 53), (*addr, 40000), b"hi")
+                .unwrap();
+        let slab = Bytes::from(pkt.repeat(ZERO_COPY_QUEUE_LEN * 2));
+        let in_slab = |pkt: &Bytes| slab.as_ptr_range().contains(&pkt.as_ptr());
+        for chunk in 0..ZERO_COPY_QUEUE_LEN * 2 {
+            vpn.dispatch_down_owned(slab.slice(chunk * pkt.len()..(chunk + 1) * pkt.len()));
+        }
+        for n in 0..ZERO_COPY_QUEUE_LEN * 2 {
+            let received = downstream.try_recv().unwrap();
+            assert_eq!(received, pkt);
+            assert_eq!(in_slab(&received), n < ZERO_COPY_QUEUE_LEN, "{}", n);
+        }
+    }
+
+    #[test]
+    fn refused_connection_is_reset() {
+        use std::io::Read;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        reset_connection(&accepted, CloseReason::BlockedPort);
+        drop(accepted);
+        let err = client.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn independent_contexts() {
+        let first = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(MemoryTun::new()));
+        let second = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(MemoryTun::new()));
+        let addr = first.assigner().assign().unwrap();
+        let downstream = first.subscribe_down(*addr, false);
+        assert!(first.session_exists(*addr));
+        assert!(!second.session_exists(*addr));
+        let pkt =
+            crate::packet::build_udp((Ipv4Addr::new(93, 184, 216, 34), 53), (*addr, 40000), b"hi")
+                .unwrap();
+        second.dispatch_down(&pkt);
+        assert!(downstream.try_recv().is_err());
+        first.dispatch_down(&pkt);
+        assert_eq!(downstream.try_recv().unwrap(), pkt);
+        // tearing down a context closes its sessions' channels
+        drop(first);
+        assert!(smol::block_on(downstream.recv()).is_err());
+    }
+
+    #[test]
+    fn upstream_validation() {
+        use pnet_packet::ip::IpNextHeaderProtocols::{Tcp, Udp};
+
+        let me = Ipv4Addr::new(100, 64, 0, 5);
+        let own_ips: HashSet<Ipv4Addr> = [Ipv4Addr::new(198, 51, 100, 1)].into_iter().collect();
+        let verdict = |source: Ipv4Addr, destination: Ipv4Addr, udp: bool, port: u16| {
+            let pkt = PacketHeaders {
+                source,
+                destination,
+                protocol: if udp { Udp } else { Tcp },
+                source_port: Some(40000),
+                dest_port: Some(port),
+            };
+            upstream_verdict(&pkt, me, &own_ips)
+        };
+        let public = Ipv4Addr::new(93, 184, 216, 34);
+        let drop = |v: UpstreamVerdict| matches!(v, UpstreamVerdict::Drop(_));
+
+        assert_eq!(verdict(me, public, false, 80), UpstreamVerdict::Forward);
+        assert_eq!(verdict(me, public, true, 53), UpstreamVerdict::Forward);
+        // anti-spoofing
+        assert!(drop(verdict(
+            Ipv4Addr::new(100, 64, 0, 6),
+            public,
+            false,
+            80
+        )));
+        assert!(drop(verdict(public, public, false, 80)));
+        // non-public destinations
+        for dest in [
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(10, 1, 2, 3),
+            Ipv4Addr::new(172, 16, 0, 1),
+            Ipv4Addr::new(192, 168, 1, 1),
+            Ipv4Addr::new(169, 254, 169, 254),
+            Ipv4Addr::new(224, 0, 0, 251),
+            Ipv4Addr::new(0, 1, 2, 3),
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+        ] {
+            assert!(
+                drop(verdict(me, dest, false, 80)),
+                "{} was not dropped",
+                dest
+            );
+        }
+        // the exit itself and its gateway address
+        assert!(drop(verdict(me, Ipv4Addr::new(198, 51, 100, 1), false, 22)));
+        assert!(drop(verdict(me, Ipv4Addr::new(100, 64, 0, 1), false, 22)));
+        assert!(drop(verdict(me, Ipv4Addr::new(10, 77, 0, 1), false, 22)));
+        // other clients, since the test config enables hairpinning
+        assert_eq!(
+            verdict(me, Ipv4Addr::new(100, 64, 0, 6), true, 5000),
+            UpstreamVerdict::Hairpin
+        );
+        assert_eq!(
+            verdict(me, Ipv4Addr::new(10, 77, 3, 4), true, 5000),
+            UpstreamVerdict::Hairpin
+        );
+        // NAT64
+        assert_eq!(
+            verdict(me, Ipv4Addr::new(198, 18, 0, 9), true, 5000),
+            UpstreamVerdict::Nat64
+        );
+        assert_eq!(
+            verdict(me, Ipv4Addr::new(198, 18, 0, 9), false, 22),
+            UpstreamVerdict::Forward
+        );
+        // configured blocked ranges
+        assert!(drop(verdict(me, Ipv4Addr::new(192, 0, 2, 7), false, 80)));
+        // ports
+        assert!(drop(verdict(me, public, true, 443)));
+        assert_eq!(
+            verdict(me, public, false, 25),
+            UpstreamVerdict::Reject("mail port")
+        );
+    }
+
+    #[test]
+    fn lease_snapshot() {
+        let path = std::env::temp_dir().join(format!("geph4-leases-{}.json", fastrand::u64(..)));
+        let first = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(MemoryTun::new()));
+        let addr = first.assigner().assign().unwrap();
+        addr.set_client(42);
+        let leases = first.assigner().leases();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].client, Some(42));
+        std::fs::write(&path, serde_json::to_vec(&leases).unwrap()).unwrap();
+
+        let second = VpnCtx::new(CONFIG.all_cgnat_pools(), |_| Box::new(MemoryTun::new()));
+        second.restore_leases(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // held for the client, and nobody else
+        assert!(second.assigner().claim(*addr).is_none());
+        assert_eq!(second.take_restored(42).unwrap(), addr);
+        assert!(second.take_restored(42).is_none());
+    }
+
+    #[test]
+    fn hashed_assignment() {
+        let key = [7; 32];
+        let assigner = IpAddrAssigner::new("100.64.0.0/24".parse().unwrap());
+        let first = assigner.assign_hashed(42, &key).unwrap();
+        // a collision probes onward
+        let second = assigner.assign_hashed(42, &key).unwrap();
+        assert_ne!(first, second);
+        let first_addr = *first;
+        drop((first, second));
+        // stable without any saved state
+        let other = IpAddrAssigner::new("100.64.0.0/24".parse().unwrap());
+        assert_eq!(*other.assign_hashed(42, &key).unwrap(), first_addr);
+    }
 
     #[test]
     fn cgnat() {
         let assigner = IpAddrAssigner::new("100.64.0.0/10".parse().unwrap());
         let mut assigned = Vec::new();
         for _ in 0..2 {
-            assigned.push(assigner.assign());
+            assigned.push(assigner.assign().unwrap());
         }
         dbg!(assigned);
     }
+
+    #[test]
+    fn pool_exhaustion() {
+        let assigner = IpAddrAssigner::new("100.64.0.0/24".parse().unwrap());
+        let assigned: Vec<_> = (0..assigner.capacity())
+            .map(|_| assigner.assign().unwrap())
+            .collect();
+        assert_eq!(assigner.occupancy(), 1.0);
+        assert!(assigner.assign().is_err());
+        assert!(assigner.assign_hashed(42, &[7; 32]).is_err());
+        drop(assigned);
+        assert_eq!(assigner.occupancy(), 0.0);
+        assert!(assigner.assign().is_ok());
+    }
+
+    #[test]
+    fn transparent_client_ids() {
+        let a: IpAddr = "198.51.100.7".parse().unwrap();
+        let b: IpAddr = "198.51.100.8".parse().unwrap();
+        assert_eq!(transparent_client_id(a), transparent_client_id(a));
+        assert_ne!(transparent_client_id(a), transparent_client_id(b));
+    }
+
+    #[test]
+    fn transparent_sources() {
+        assert!(is_vpn_client("100.64.3.4".parse().unwrap()));
+        assert!(is_vpn_client("10.77.1.2".parse().unwrap()));
+        assert!(is_vpn_client("::ffff:100.64.3.4".parse().unwrap()));
+        assert!(!is_vpn_client("198.51.100.7".parse().unwrap()));
+        assert!(!is_vpn_client("2001:db8::1".parse().unwrap()));
+    }
 }